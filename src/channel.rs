@@ -15,10 +15,24 @@ use std::collections::BTreeMap;
 use std::fmt::Debug;
 use std::hash::Hash;
 
+use amplify::Wrapper;
+use bitcoin::secp256k1::{Message, PublicKey, SecretKey, Signature};
+use bitcoin::util::bip143::SigHashCache;
 use bitcoin::util::psbt::PartiallySignedTransaction as Psbt;
-use bitcoin::{OutPoint, Transaction, TxIn, TxOut};
-
-use super::extension::{self, ChannelExtension, Extension};
+use bitcoin::{OutPoint, SigHash, SigHashType, Transaction, TxIn, TxOut};
+use strict_encoding::{strict_serialize, StrictDecode, StrictEncode};
+use wallet::{WitnessScript, SECP256K1_PUBKEY_DUMB};
+
+use super::extension::{
+    self, ChannelExtension, Extension, ExtensionFactory, ExtensionKind,
+};
+use super::message::{ChannelReestablish, CommitmentSigned};
+use super::payment::fee::CommitmentFee;
+use super::payment::keys::SECP256K1;
+use super::payment::reestablish::{ReestablishAction, ReestablishError};
+use super::payment::signer::ChannelSigner;
+use super::payment::{Bolt3Error, HtlcError};
+use super::payment::{Operation, TxType};
 use super::Messages;
 
 #[derive(
@@ -40,17 +54,85 @@ pub enum Error {
     /// Extension-specific error: {0}
     Extension(String),
 
-    // HTLC Extension Errors
-    HTLC(String),
+    /// HTLC extension error: {0}
+    #[from]
+    Htlc(HtlcError),
+
+    /// invalid signature in `commitment_signed` message: {0}
+    #[from]
+    Signature(SignatureError),
+
+    /// Persisted channel state can't be decoded: {0}
+    StateEncoding(String),
+
+    /// Persisted channel state must contain data for exactly one constructor
+    /// extension, while {0} constructors were found
+    ConstructorCount(usize),
+
+    /// Extension {0} can't be restored from the persisted channel state
+    UnsupportedExtension(String),
+
+    /// BOLT-3 channel constructor error: {0}
+    #[from]
+    Bolt3(Bolt3Error),
+
+    /// channel reestablishment error: {0}
+    #[from]
+    Reestablish(ReestablishError),
+}
+
+/// Errors verifying signatures provided by the remote peer in
+/// `commitment_signed` message
+#[derive(
+    Clone,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Debug,
+    Display,
+    Error,
+    StrictEncode,
+    StrictDecode,
+)]
+#[display(doc_comments)]
+pub enum SignatureError {
+    /// signature of the commitment transaction is not valid
+    Commitment,
+
+    /// {0} HTLC signatures are provided for {1} HTLC transactions
+    HtlcCount(usize, usize),
+
+    /// signatures of the HTLC transactions with indexes {0:?} are not valid
+    Htlc(Vec<usize>),
 }
 
-/// Marker trait for any data that can be used as a part of the channel state
-pub trait State {}
+impl From<strict_encoding::Error> for Error {
+    fn from(err: strict_encoding::Error) -> Self {
+        Error::StateEncoding(err.to_string())
+    }
+}
+
+/// Trait for any data that can be used as a part of the channel state. The
+/// state must be strict-encodable, so it can be persisted and later used to
+/// restore the channel
+pub trait State: StrictEncode + StrictDecode {
+    /// Returns strict-encoded representation of the state
+    fn to_state_data(&self) -> Vec<u8>
+    where
+        Self: Sized,
+    {
+        strict_serialize(self)
+            .expect("In-memory strict encoding of channel state can't fail")
+    }
+}
 // Allow empty state
 impl State for () {}
 
-/// Channel state is a sum of the state from all its extensions
-pub type IntegralState<N> = BTreeMap<N, Box<dyn State>>;
+/// Channel state is a sum of the state from all its extensions, each kept in
+/// strict-encoded form under its extension id
+pub type IntegralState<N> = BTreeMap<N, Vec<u8>>;
 impl<N> State for IntegralState<N> where N: extension::Nomenclature {}
 
 pub type ExtensionQueue<N> =
@@ -111,6 +193,94 @@ where
             ),
         }
     }
+
+    /// Adds extender of a type different from the ones provided to
+    /// [`Channel::with`], replacing extender with the same id, if any
+    pub fn add_extender(
+        &mut self,
+        extender: impl ChannelExtension<Identity = N> + 'static,
+    ) {
+        self.extenders
+            .insert(extender.identity(), Box::new(extender));
+    }
+
+    /// Registers message sent to the remote peer which was not produced by a
+    /// channel operation, like `commitment_signed` or `revoke_and_ack` signed
+    /// by an external signer. Messages returned from `update_from_local` are
+    /// registered automatically and must not be passed here.
+    pub fn message_sent(&mut self, message: &Messages) -> Result<(), Error> {
+        self.constructor
+            .messages_sent(std::slice::from_ref(message))
+    }
+
+    /// Processes `channel_reestablish` received from the remote peer,
+    /// returning the action required to synchronize the channel. Must be
+    /// used instead of `update_from_peer` for this message.
+    pub fn reestablish(
+        &mut self,
+        message: &ChannelReestablish,
+        signer: &dyn ChannelSigner,
+    ) -> Result<ReestablishAction, Error> {
+        let action = self.constructor.reestablish(message, signer)?;
+        let message = Messages::ChannelReestablish(message.clone());
+        self.extenders
+            .iter_mut()
+            .try_for_each(|(_, e)| e.update_from_peer(&message))?;
+        self.modifiers
+            .iter_mut()
+            .try_for_each(|(_, e)| e.update_from_peer(&message))?;
+        Ok(action)
+    }
+
+    /// Returns channel state composed of the states of all channel
+    /// extensions, keyed by extension id
+    pub fn integral_state(&self) -> IntegralState<N> {
+        let mut data = IntegralState::<N>::new();
+        data.insert(
+            self.constructor.identity(),
+            self.constructor.channel_state(),
+        );
+        self.extenders.iter().for_each(|(id, e)| {
+            data.insert(*id, e.channel_state());
+        });
+        self.modifiers.iter().for_each(|(id, e)| {
+            data.insert(*id, e.channel_state());
+        });
+        data
+    }
+}
+
+impl<N> Channel<N>
+where
+    N: extension::Nomenclature + ExtensionFactory,
+{
+    /// Reconstructs channel from its persisted state, restoring each of the
+    /// channel extensions from the state data stored under its id
+    pub fn restore(state: IntegralState<N>) -> Result<Self, Error> {
+        let mut constructors = Vec::with_capacity(1);
+        let mut extenders = ExtensionQueue::<N>::new();
+        let mut modifiers = ExtensionQueue::<N>::new();
+        for (id, data) in state {
+            let extension = id.restore(&data)?;
+            match id.kind() {
+                ExtensionKind::Constructor => constructors.push(extension),
+                ExtensionKind::Extender => {
+                    extenders.insert(id, extension);
+                }
+                ExtensionKind::Modifier => {
+                    modifiers.insert(id, extension);
+                }
+            }
+        }
+        if constructors.len() != 1 {
+            return Err(Error::ConstructorCount(constructors.len()));
+        }
+        Ok(Self {
+            constructor: constructors.remove(0),
+            extenders,
+            modifiers,
+        })
+    }
 }
 
 /// Channel is the extension to itself :) so it receives the same input as any
@@ -133,10 +303,28 @@ where
         self.modifiers
             .iter_mut()
             .try_for_each(|(_, e)| e.update_from_peer(data))?;
+        self.constructor.message_received(data)?;
         Ok(())
     }
 
-    fn extension_state(&self) -> Box<dyn State> {
+    fn update_from_local(
+        &mut self,
+        operation: &Operation,
+    ) -> Result<Vec<Messages>, Error> {
+        let mut messages = self.constructor.update_from_local(operation)?;
+        for extension in self
+            .extenders
+            .values_mut()
+            .chain(self.modifiers.values_mut())
+        {
+            messages.extend(extension.update_from_local(operation)?);
+        }
+        self.constructor.operation_accepted(operation)?;
+        self.constructor.messages_sent(&messages)?;
+        Ok(messages)
+    }
+
+    fn extension_state(&self) -> Vec<u8> {
         let mut data = IntegralState::<N>::new();
         data.insert(
             self.constructor.identity(),
@@ -148,7 +336,7 @@ where
         self.modifiers.iter().for_each(|(id, e)| {
             data.insert(*id, e.extension_state());
         });
-        Box::new(data)
+        data.to_state_data()
     }
 }
 
@@ -158,19 +346,8 @@ impl<N> ChannelExtension for Channel<N>
 where
     N: 'static + extension::Nomenclature,
 {
-    fn channel_state(&self) -> Box<dyn State> {
-        let mut data = IntegralState::<N>::new();
-        data.insert(
-            self.constructor.identity(),
-            self.constructor.extension_state(),
-        );
-        self.extenders.iter().for_each(|(id, e)| {
-            data.insert(*id, e.extension_state());
-        });
-        self.modifiers.iter().for_each(|(id, e)| {
-            data.insert(*id, e.extension_state());
-        });
-        Box::new(data)
+    fn channel_state(&self) -> Vec<u8> {
+        self.integral_state().to_state_data()
     }
 
     fn apply(&mut self, tx_graph: &mut TxGraph) -> Result<(), Error> {
@@ -178,20 +355,51 @@ where
         self.extenders
             .iter_mut()
             .try_for_each(|(_, e)| e.apply(tx_graph))?;
+        self.constructor.finalize(tx_graph)?;
         self.modifiers
             .iter_mut()
             .try_for_each(|(_, e)| e.apply(tx_graph))?;
+        tx_graph.link_cmt_txid();
         Ok(())
     }
 }
 
+/// Channel party owning the commitment transaction constructed in the
+/// transaction graph, i.e. the party which is able to sign and publish it
+#[derive(
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+#[display(Debug)]
+pub enum CommitmentOwner {
+    /// Commitment transaction of the local node
+    Local,
+
+    /// Commitment transaction of the remote peer
+    Remote,
+}
+
 pub trait TxRole: Clone + From<u16> + Into<u16> {}
 pub trait TxIndex: Clone + From<u64> + Into<u64> {}
 
 impl TxRole for u16 {}
 impl TxIndex for u64 {}
 
-#[derive(Getters, Clone, PartialEq, StrictEncode, StrictDecode)]
+#[derive(Getters, Clone, PartialEq, Debug, StrictEncode, StrictDecode)]
 #[cfg_attr(
     feature = "serde",
     derive(Serialize, Deserialize),
@@ -204,14 +412,102 @@ pub struct TxGraph {
     funding_outpoint: OutPoint,
     commitment_outpoint: OutPoint, /* We should have a commitment outpoint
                                     * for HTLC success and timeout Tx */
+    pub cmt_owner: CommitmentOwner,
     pub cmt_version: i32,
     pub cmt_locktime: u32,
     pub cmt_sequence: u32,
     pub cmt_outs: Vec<TxOut>,
+    /* Commitment parameters set by the constructor for use by extenders */
+    pub cmt_fee: CommitmentFee,
+    pub cmt_dust_limit: u64,
+    pub cmt_to_self_delay: u16,
+    pub cmt_per_commitment_point: PublicKey,
+    /// CLTV expiry of the commitment outputs which have one (like HTLCs),
+    /// keyed by the output index
+    pub cmt_cltv_expiry: BTreeMap<u32, u32>,
+    /// Anchor outputs for the `to_local` and `to_remote` outputs (in this
+    /// order), or empty vector if the commitment does not use anchors.
+    /// Placed into the commitment transaction by the constructor, since it
+    /// depends on which outputs were trimmed.
+    pub cmt_anchors: Vec<TxOut>,
     graph: BTreeMap<u16, BTreeMap<u64, Psbt>>,
 }
 
 impl TxGraph {
+    /// Constructs empty transaction graph for the commitment transaction of
+    /// the given channel party
+    pub fn with_owner(owner: CommitmentOwner) -> Self {
+        Self {
+            cmt_owner: owner,
+            ..Self::default()
+        }
+    }
+
+    pub fn set_funding_outpoint(&mut self, outpoint: OutPoint) {
+        self.funding_outpoint = outpoint;
+    }
+
+    /// Sets funding transaction collecting inputs from all funding parties,
+    /// `funding_threshold` of which must sign the commitment transaction
+    pub fn set_funding_tx(
+        &mut self,
+        funding_parties: u8,
+        funding_threshold: u8,
+        funding_tx: Psbt,
+    ) {
+        self.funding_parties = funding_parties;
+        self.funding_threshold = funding_threshold;
+        self.funding_tx = funding_tx;
+    }
+
+    /// Returns outpoint for the commitment transaction output with a given
+    /// index, which must be used by the transactions in the graph spending
+    /// it, so they can be re-linked once commitment outputs are reordered
+    pub fn cmt_outpoint(&self, vout: u32) -> OutPoint {
+        OutPoint::new(self.commitment_outpoint.txid, vout)
+    }
+
+    /// Removes commitment transaction output with a given index, re-linking
+    /// the transactions in the graph spending the outputs following it
+    pub fn remove_cmt_out(&mut self, index: usize) -> TxOut {
+        let txout = self.cmt_outs.remove(index);
+        let index = index as u32;
+        self.cmt_cltv_expiry.remove(&index);
+        self.relink_cmt_outs(|vout| if vout > index { vout - 1 } else { vout });
+        txout
+    }
+
+    /// Reorders commitment transaction outputs according to the permutation
+    /// map, where `permutation[old_index]` is the new index of the output,
+    /// and re-links the transactions in the graph spending the commitment
+    /// outputs to their new indexes
+    pub fn permute_cmt_outs(&mut self, permutation: &[u32]) {
+        debug_assert_eq!(permutation.len(), self.cmt_outs.len());
+        let mut outs = self.cmt_outs.clone();
+        for (old, new) in permutation.iter().enumerate() {
+            outs[*new as usize] = self.cmt_outs[old].clone();
+        }
+        self.cmt_outs = outs;
+        self.relink_cmt_outs(|vout| permutation[vout as usize]);
+    }
+
+    fn relink_cmt_outs(&mut self, map: impl Fn(u32) -> u32) {
+        self.cmt_cltv_expiry = self
+            .cmt_cltv_expiry
+            .iter()
+            .map(|(vout, cltv_expiry)| (map(*vout), *cltv_expiry))
+            .collect();
+        let txid = self.commitment_outpoint.txid;
+        self.graph
+            .values_mut()
+            .flat_map(|map| map.values_mut())
+            .flat_map(|psbt| psbt.global.unsigned_tx.input.iter_mut())
+            .filter(|txin| txin.previous_output.txid == txid)
+            .for_each(|txin| {
+                txin.previous_output.vout = map(txin.previous_output.vout)
+            });
+    }
+
     pub fn tx<R, I>(&self, role: R, index: I) -> Option<&Psbt>
     where
         R: TxRole,
@@ -290,6 +586,144 @@ impl TxGraph {
         )
     }
 
+    /// Links the transactions spending commitment outputs to the txid of the
+    /// commitment transaction. Must be called once the commitment outputs
+    /// are final, since any change to them changes the txid.
+    pub fn link_cmt_txid(&mut self) {
+        let prev_txid = self.commitment_outpoint.txid;
+        let txid = self.render_cmt().global.unsigned_tx.txid();
+        self.graph
+            .values_mut()
+            .flat_map(|map| map.values_mut())
+            .flat_map(|psbt| psbt.global.unsigned_tx.input.iter_mut())
+            .filter(|txin| txin.previous_output.txid == prev_txid)
+            .for_each(|txin| txin.previous_output.txid = txid);
+        self.commitment_outpoint.txid = txid;
+    }
+
+    /// Returns second-stage HTLC transactions ordered by the commitment
+    /// outputs they spend, which is the order of HTLC signatures in
+    /// `commitment_signed` message
+    pub fn htlc_txs(&self) -> Vec<&Psbt> {
+        let mut txs = [TxType::HtlcSuccess, TxType::HtlcTimeout]
+            .iter()
+            .filter_map(|role| self.graph.get(&u16::from(*role)))
+            .flat_map(|map| map.values())
+            .collect::<Vec<_>>();
+        txs.sort_by_key(|psbt| {
+            psbt.global.unsigned_tx.input[0].previous_output.vout
+        });
+        txs
+    }
+
+    /// Computes BIP-143 signature hash of the commitment transaction spending
+    /// funding output of `funding_amount` sats locked with `funding_script`
+    pub fn cmt_sighash(
+        &self,
+        funding_script: &WitnessScript,
+        funding_amount: u64,
+    ) -> SigHash {
+        let cmt_tx = self.render_cmt().global.unsigned_tx;
+        SigHashCache::new(&cmt_tx).signature_hash(
+            0,
+            funding_script.as_inner(),
+            funding_amount,
+            SigHashType::All,
+        )
+    }
+
+    /// Computes BIP-143 signature hashes of the second-stage HTLC
+    /// transactions in the order of [`TxGraph::htlc_txs`]. Each hash commits
+    /// to the sighash type specified by the transaction PSBT, which is
+    /// `SIGHASH_SINGLE|SIGHASH_ANYONECANPAY` for the channels with anchors.
+    pub fn htlc_sighashes(&self) -> Result<Vec<SigHash>, Error> {
+        self.htlc_txs()
+            .into_iter()
+            .map(|psbt| -> Result<SigHash, Error> {
+                let input = &psbt.inputs[0];
+                let witness_script = input
+                    .witness_script
+                    .as_ref()
+                    .ok_or(HtlcError::NoWitnessScript)?;
+                let spent_output = input
+                    .witness_utxo
+                    .as_ref()
+                    .ok_or(HtlcError::NoSpentOutput)?;
+                Ok(SigHashCache::new(&psbt.global.unsigned_tx).signature_hash(
+                    0,
+                    witness_script,
+                    spent_output.value,
+                    input.sighash_type.unwrap_or(SigHashType::All),
+                ))
+            })
+            .collect()
+    }
+
+    /// Signs the commitment transaction with the funding key and all
+    /// second-stage HTLC transactions with the per-commitment HTLC key,
+    /// returning signatures for `commitment_signed` message
+    pub fn sign(
+        &self,
+        funding_script: &WitnessScript,
+        funding_amount: u64,
+        funding_key: &SecretKey,
+        htlc_key: &SecretKey,
+    ) -> Result<(Signature, Vec<Signature>), Error> {
+        let signature = sign_sighash(
+            self.cmt_sighash(funding_script, funding_amount),
+            funding_key,
+        );
+        let htlc_signatures = self
+            .htlc_sighashes()?
+            .into_iter()
+            .map(|sighash| sign_sighash(sighash, htlc_key))
+            .collect();
+        Ok((signature, htlc_signatures))
+    }
+
+    /// Verifies signatures from `commitment_signed` message received from the
+    /// remote peer against its funding key and per-commitment HTLC key
+    pub fn verify(
+        &self,
+        commitment_signed: &CommitmentSigned,
+        funding_script: &WitnessScript,
+        funding_amount: u64,
+        remote_funding_pubkey: PublicKey,
+        remote_htlcpubkey: PublicKey,
+    ) -> Result<(), Error> {
+        let sighash = self.cmt_sighash(funding_script, funding_amount);
+        if !verify_sighash(
+            sighash,
+            &commitment_signed.signature,
+            &remote_funding_pubkey,
+        ) {
+            return Err(SignatureError::Commitment.into());
+        }
+
+        let sighashes = self.htlc_sighashes()?;
+        let htlc_signatures = &commitment_signed.htlc_signatures;
+        if htlc_signatures.len() != sighashes.len() {
+            return Err(SignatureError::HtlcCount(
+                htlc_signatures.len(),
+                sighashes.len(),
+            )
+            .into());
+        }
+        let invalid = sighashes
+            .into_iter()
+            .zip(htlc_signatures)
+            .enumerate()
+            .filter(|(_, (sighash, signature))| {
+                !verify_sighash(*sighash, signature, &remote_htlcpubkey)
+            })
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+        if !invalid.is_empty() {
+            return Err(SignatureError::Htlc(invalid).into());
+        }
+        Ok(())
+    }
+
     pub fn iter(&self) -> GraphIter {
         GraphIter::with(self)
     }
@@ -306,6 +740,8 @@ impl TxGraph {
     }
 }
 
+impl State for TxGraph {}
+
 impl Default for TxGraph {
     fn default() -> Self {
         Self {
@@ -320,15 +756,38 @@ impl Default for TxGraph {
             .expect(""),
             funding_outpoint: none!(),
             commitment_outpoint: none!(),
+            cmt_owner: CommitmentOwner::Remote,
             cmt_version: 2,
             cmt_locktime: 0,
             cmt_sequence: 0,
             cmt_outs: none!(),
+            cmt_fee: CommitmentFee::default(),
+            cmt_dust_limit: 0,
+            cmt_to_self_delay: 0,
+            cmt_per_commitment_point: *SECP256K1_PUBKEY_DUMB,
+            cmt_cltv_expiry: empty!(),
+            cmt_anchors: none!(),
             graph: empty!(),
         }
     }
 }
 
+pub(crate) fn sign_sighash(sighash: SigHash, key: &SecretKey) -> Signature {
+    let message = Message::from_slice(&sighash[..])
+        .expect("signature hash is always 32 bytes");
+    SECP256K1.sign(&message, key)
+}
+
+fn verify_sighash(
+    sighash: SigHash,
+    signature: &Signature,
+    pubkey: &PublicKey,
+) -> bool {
+    let message = Message::from_slice(&sighash[..])
+        .expect("signature hash is always 32 bytes");
+    SECP256K1.verify(&message, signature, pubkey).is_ok()
+}
+
 pub struct GraphIter<'a> {
     graph: &'a TxGraph,
     curr_role: u16,
@@ -14,9 +14,13 @@
 use std::convert::TryFrom;
 use std::fmt::{Debug, Display};
 use std::hash::Hash;
-use strict_encoding;
+use strict_encoding::{self, StrictDecode, StrictEncode};
 
 use super::channel;
+use crate::message::ChannelReestablish;
+use crate::payment::reestablish::ReestablishAction;
+use crate::payment::signer::ChannelSigner;
+use crate::payment::Operation;
 use crate::Messages;
 
 /// Marker trait for creating extension nomenclatures, defining order in which
@@ -37,10 +41,41 @@ where
         + Display
         + Default
         + TryFrom<u16, Error = strict_encoding::Error>
-        + Into<u16>,
+        + Into<u16>
+        + StrictEncode
+        + StrictDecode,
 {
 }
 
+/// Kinds of channel extensions, defining the stage at which the extension is
+/// applied to the channel transaction graph
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Display)]
+#[display(Debug)]
+pub enum ExtensionKind {
+    /// Extension constructing base transaction graph
+    Constructor,
+
+    /// Extension adding outputs and transactions to the graph
+    Extender,
+
+    /// Extension modifying existing outputs and transactions of the graph
+    Modifier,
+}
+
+/// Nomenclature which is able to reconstruct channel extensions from their
+/// persisted state
+pub trait ExtensionFactory: Nomenclature {
+    /// Returns kind of the extension with the given id
+    fn kind(self) -> ExtensionKind;
+
+    /// Constructs extension with the given id from the strict-encoded state
+    /// data, previously returned by [`ChannelExtension::channel_state`]
+    fn restore(
+        self,
+        state: &[u8],
+    ) -> Result<Box<dyn ChannelExtension<Identity = Self>>, channel::Error>;
+}
+
 pub trait Extension {
     type Identity: Nomenclature;
 
@@ -53,12 +88,22 @@ pub trait Extension {
         data: &Messages,
     ) -> Result<(), channel::Error>;
 
+    /// Updates extension state according to the operation requested by the
+    /// local node, returning messages which has to be sent to the remote peer
+    /// as a result of the operation. Extensions not related to the operation
+    /// must return an empty list.
+    fn update_from_local(
+        &mut self,
+        operation: &Operation,
+    ) -> Result<Vec<Messages>, channel::Error>;
+
     /// Returns extension state for persistence & backups
     ///
     /// These are extension configuration data, like the data that are the part
     /// of the channel parameters negotiatied between peeers or preconfigured
-    /// parameters from the configuration file
-    fn extension_state(&self) -> Box<dyn channel::State>;
+    /// parameters from the configuration file. The state is returned in its
+    /// strict-encoded form.
+    fn extension_state(&self) -> Vec<u8>;
 }
 
 pub trait RoutingExtension: Extension {}
@@ -69,12 +114,72 @@ pub trait ChannelExtension: Extension {
     /// Returns channel state for persistence & backups.
     ///
     /// These are channel-specific data generated from channel operations,
-    /// including client-validated data
-    fn channel_state(&self) -> Box<dyn channel::State>;
+    /// including client-validated data. The state is returned in its
+    /// strict-encoded form and can be used to restore the extension with
+    /// [`ExtensionFactory::restore`].
+    fn channel_state(&self) -> Vec<u8>;
 
     /// Applies state to the channel transaction graph
     fn apply(
         &mut self,
         tx_graph: &mut channel::TxGraph,
     ) -> Result<(), channel::Error>;
+
+    /// Completes construction of the channel transaction graph once all
+    /// extenders were applied, but before modifiers. Called only for the
+    /// constructor extension, allowing it to account for the outputs added by
+    /// the extenders, like paying commitment transaction fees for them.
+    fn finalize(
+        &mut self,
+        _tx_graph: &mut channel::TxGraph,
+    ) -> Result<(), channel::Error> {
+        Ok(())
+    }
+
+    /// Updates extension state with all messages produced by the channel
+    /// extensions for a local operation, once every extension has accepted
+    /// the operation. Called only for the constructor extension, allowing it
+    /// to account for the updates originated by the extenders, like changes
+    /// of the party balances caused by HTLCs.
+    fn messages_sent(
+        &mut self,
+        _messages: &[Messages],
+    ) -> Result<(), channel::Error> {
+        Ok(())
+    }
+
+    /// Updates extension state with the message received from the remote
+    /// peer once every extension has accepted it. Called only for the
+    /// constructor extension, allowing it to account for the updates
+    /// validated by the extenders, like changes of the party balances caused
+    /// by HTLCs.
+    fn message_received(
+        &mut self,
+        _message: &Messages,
+    ) -> Result<(), channel::Error> {
+        Ok(())
+    }
+
+    /// Updates extension state according to the operation requested by the
+    /// local node once every extension has accepted it. Called only for the
+    /// constructor extension, before [`ChannelExtension::messages_sent`].
+    fn operation_accepted(
+        &mut self,
+        _operation: &Operation,
+    ) -> Result<(), channel::Error> {
+        Ok(())
+    }
+
+    /// Processes `channel_reestablish` received from the remote peer,
+    /// returning the action which has to be taken to synchronize the channel.
+    /// Called only for the constructor extension, which tracks the messages
+    /// requiring retransmission. Local per-commitment points and secrets
+    /// are provided by the signer.
+    fn reestablish(
+        &mut self,
+        _message: &ChannelReestablish,
+        _signer: &dyn ChannelSigner,
+    ) -> Result<ReestablishAction, channel::Error> {
+        Ok(ReestablishAction::Retransmit(vec![]))
+    }
 }
@@ -10,3 +10,736 @@
 // You should have received a copy of the MIT License
 // along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
+
+//! Channel factories funding multiple two-party sub-channels from a single
+//! on-chain output, following Burchert, Decker & Wattenhofer "Scalable
+//! Funding of Bitcoin Micropayment Channel Networks".
+//!
+//! The factory output, locked by all parties, is spent by the kickoff
+//! transaction, which starts the relative timelock of the allocation
+//! transaction distributing funds between the sub-channels and the
+//! unallocated balances of the parties. Each update of the allocation
+//! reduces its timelock, so the latest allocation becomes valid before any
+//! of the invalidated ones (Decker-Wattenhofer invalidation). Sub-channels
+//! are regular channels using the allocation outputs as their funding
+//! outpoints.
+
+use std::collections::BTreeMap;
+
+use amplify::Wrapper;
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::{OutPoint, Transaction, TxIn, TxOut};
+use wallet::{Psbt, WitnessScript};
+
+use crate::payment::bolt3::ScriptGenerators;
+use crate::payment::cpfp::TX_BASE_WEIGHT;
+use crate::payment::fee::{fee_for_weight, CommitmentFee};
+use crate::payment::multiparty::{
+    FundingParty, MultiParty, MultiPartyError, BALANCE_OUTPUT_WEIGHT,
+    FUNDING_KEY_WEIGHT, FUNDING_SIGNATURE_WEIGHT,
+};
+use crate::payment::{ExtensionId, Operation, TxType};
+use crate::{channel, ChannelExtension, Extension, Messages};
+
+/// Weight of the input spending factory output, not counting the keys and
+/// signatures in its witness
+pub const FACTORY_INPUT_WEIGHT: u64 = 170;
+
+/// Weight of P2WSH output funding a sub-channel or the allocation
+pub const P2WSH_OUTPUT_WEIGHT: u64 = 172;
+
+/// Identifier of the sub-channel within the factory, which does not change
+/// with the allocation updates (unlike the sub-channel funding outpoint)
+pub type SubchannelId = u16;
+
+/// Errors operating channel factory
+#[derive(
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Debug,
+    Display,
+    Error,
+    From,
+    StrictEncode,
+    StrictDecode,
+)]
+#[display(doc_comments)]
+pub enum FactoryError {
+    /// factory can't be funded: {0}
+    #[from]
+    Funding(MultiPartyError),
+
+    /// allocation delay {0} must be a non-zero multiple of the delay step {1}
+    InvalidDelay(u16, u16),
+
+    /// factory has no party #{0}
+    UnknownParty(u8),
+
+    /// sub-channel must be opened between two different parties, while both
+    /// are #{0}
+    SameParty(u8),
+
+    /// unallocated balance of the party #{0} is insufficient
+    InsufficientBalance(u8),
+
+    /// factory has no sub-channel #{0}
+    UnknownSubchannel(SubchannelId),
+
+    /// all {0} allocation updates are used; the factory must be closed
+    UpdatesExhausted(u16),
+}
+
+impl From<FactoryError> for channel::Error {
+    fn from(err: FactoryError) -> Self {
+        channel::Error::Extension(err.to_string())
+    }
+}
+
+/// Two-party channel funded by the factory allocation transaction
+#[derive(Clone, Copy, PartialEq, Eq, Debug, StrictEncode, StrictDecode)]
+pub struct Subchannel {
+    /// Index of the first party in the factory
+    pub party_a: u8,
+    /// Index of the second party in the factory
+    pub party_b: u8,
+    /// Funding key of the first party used in the sub-channel funding output
+    pub funding_pubkey_a: PublicKey,
+    /// Funding key of the second party used in the sub-channel funding output
+    pub funding_pubkey_b: PublicKey,
+    /// Balance of the first party in the sub-channel
+    pub amount_a: u64,
+    /// Balance of the second party in the sub-channel
+    pub amount_b: u64,
+}
+
+impl Subchannel {
+    #[inline]
+    pub fn capacity(&self) -> u64 {
+        self.amount_a + self.amount_b
+    }
+
+    /// Returns 2-of-2 script locking the sub-channel funding output
+    #[inline]
+    pub fn funding_script(&self) -> WitnessScript {
+        WitnessScript::ln_funding(
+            self.capacity(),
+            self.funding_pubkey_a,
+            self.funding_pubkey_b,
+        )
+    }
+}
+
+/// Channel factory constructor, building kickoff transaction as the factory
+/// "commitment" and the allocation transaction spending it
+#[derive(Clone, PartialEq, Eq, Debug, StrictEncode, StrictDecode)]
+pub struct Factory {
+    funding: MultiParty,
+    balances: Vec<u64>,
+    subchannels: BTreeMap<SubchannelId, Subchannel>,
+    next_subchannel_id: SubchannelId,
+    state_number: u16,
+    initial_delay: u16,
+    delay_step: u16,
+    feerate_per_kw: u32,
+    dust_limit: u64,
+}
+
+impl Factory {
+    /// Constructs factory funded by the given parties, all of which must sign
+    /// the factory transactions. The allocation transaction becomes valid
+    /// `initial_delay` blocks after the kickoff, reduced by `delay_step`
+    /// blocks with each update, which limits the number of the updates.
+    pub fn with(
+        parties: Vec<FundingParty>,
+        initial_delay: u16,
+        delay_step: u16,
+        feerate_per_kw: u32,
+        dust_limit: u64,
+    ) -> Result<Self, FactoryError> {
+        if delay_step == 0
+            || initial_delay == 0
+            || initial_delay % delay_step != 0
+        {
+            return Err(FactoryError::InvalidDelay(initial_delay, delay_step));
+        }
+        let threshold = parties.len() as u8;
+        let funding =
+            MultiParty::with(threshold, parties, feerate_per_kw, dust_limit)?;
+        let factory = Factory {
+            balances: funding.balances().to_vec(),
+            funding,
+            subchannels: empty!(),
+            next_subchannel_id: 0,
+            state_number: 0,
+            initial_delay,
+            delay_step,
+            feerate_per_kw,
+            dust_limit,
+        };
+        factory.check_fee_shares(&factory.balances, 0)?;
+        Ok(factory)
+    }
+
+    #[inline]
+    pub fn parties(&self) -> &[FundingParty] {
+        self.funding.parties()
+    }
+
+    /// Returns funds of the parties not allocated to any of the sub-channels
+    #[inline]
+    pub fn balances(&self) -> &[u64] {
+        &self.balances
+    }
+
+    #[inline]
+    pub fn subchannel(&self, id: SubchannelId) -> Option<&Subchannel> {
+        self.subchannels.get(&id)
+    }
+
+    /// Returns number of the current allocation state
+    #[inline]
+    pub fn state_number(&self) -> u16 {
+        self.state_number
+    }
+
+    /// Returns number of the allocation updates left before the factory
+    /// must be closed
+    #[inline]
+    pub fn updates_left(&self) -> u16 {
+        self.initial_delay / self.delay_step - 1 - self.state_number
+    }
+
+    /// Returns relative timelock of the current allocation transaction
+    #[inline]
+    pub fn allocation_delay(&self) -> u16 {
+        self.initial_delay - self.state_number * self.delay_step
+    }
+
+    /// Returns funding transaction collecting inputs of all parties
+    #[inline]
+    pub fn funding_psbt(&self) -> Psbt {
+        self.funding.funding_psbt()
+    }
+
+    /// Returns factory output locked by all parties
+    #[inline]
+    pub fn funding_outpoint(&self) -> OutPoint {
+        self.funding.funding_outpoint()
+    }
+
+    /// Returns N-of-N script locking the factory funding and kickoff outputs
+    #[inline]
+    pub fn funding_script(&self) -> WitnessScript {
+        self.funding.funding_script()
+    }
+
+    /// Opens sub-channel with the funds taken from the unallocated balances
+    /// of its parties, returning its id
+    pub fn open_subchannel(
+        &mut self,
+        subchannel: Subchannel,
+    ) -> Result<SubchannelId, FactoryError> {
+        if subchannel.party_a == subchannel.party_b {
+            return Err(FactoryError::SameParty(subchannel.party_a));
+        }
+        self.check_updates()?;
+        self.reallocate(
+            &subchannel,
+            (0, 0),
+            (subchannel.amount_a, subchannel.amount_b),
+            self.subchannels.len() + 1,
+        )?;
+
+        let id = self.next_subchannel_id;
+        self.next_subchannel_id += 1;
+        self.subchannels.insert(id, subchannel);
+        self.state_number += 1;
+        Ok(id)
+    }
+
+    /// Updates balances of the parties in the sub-channel, which may change
+    /// the sub-channel capacity: the difference is taken from (or returned
+    /// to) the unallocated balances of the parties
+    pub fn update_subchannel(
+        &mut self,
+        id: SubchannelId,
+        amount_a: u64,
+        amount_b: u64,
+    ) -> Result<(), FactoryError> {
+        let subchannel = *self
+            .subchannels
+            .get(&id)
+            .ok_or(FactoryError::UnknownSubchannel(id))?;
+        self.check_updates()?;
+        self.reallocate(
+            &subchannel,
+            (subchannel.amount_a, subchannel.amount_b),
+            (amount_a, amount_b),
+            self.subchannels.len(),
+        )?;
+
+        let subchannel = self
+            .subchannels
+            .get_mut(&id)
+            .expect("sub-channel presence is checked above");
+        subchannel.amount_a = amount_a;
+        subchannel.amount_b = amount_b;
+        self.state_number += 1;
+        Ok(())
+    }
+
+    /// Closes sub-channel cooperatively inside the factory, returning its
+    /// funds to the unallocated balances of its parties
+    pub fn close_subchannel(
+        &mut self,
+        id: SubchannelId,
+    ) -> Result<Subchannel, FactoryError> {
+        if !self.subchannels.contains_key(&id) {
+            return Err(FactoryError::UnknownSubchannel(id));
+        }
+        self.check_updates()?;
+        let subchannel = self
+            .subchannels
+            .remove(&id)
+            .expect("sub-channel presence is checked above");
+        self.balances[subchannel.party_a as usize] += subchannel.amount_a;
+        self.balances[subchannel.party_b as usize] += subchannel.amount_b;
+        self.state_number += 1;
+        Ok(subchannel)
+    }
+
+    /// Returns funding outpoint of the sub-channel in the current allocation
+    /// transaction, which changes with each allocation update
+    pub fn subchannel_outpoint(&self, id: SubchannelId) -> Option<OutPoint> {
+        let vout = self.subchannels.keys().position(|key| *key == id)?;
+        Some(OutPoint::new(self.allocation_tx().txid(), vout as u32))
+    }
+
+    /// Constructs kickoff transaction spending factory funding output into
+    /// the output with the same N-of-N script
+    pub fn kickoff_tx(&self) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: self.funding_outpoint(),
+                script_sig: empty!(),
+                sequence: core::u32::MAX,
+                witness: empty!(),
+            }],
+            output: vec![self.kickoff_output()],
+        }
+    }
+
+    /// Constructs allocation transaction for the current factory state
+    pub fn allocation_tx(&self) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(self.kickoff_tx().txid(), 0),
+                script_sig: empty!(),
+                sequence: self.allocation_delay() as u32,
+                witness: empty!(),
+            }],
+            output: self.allocation_outputs(),
+        }
+    }
+
+    fn check_updates(&self) -> Result<(), FactoryError> {
+        if self.updates_left() == 0 {
+            return Err(FactoryError::UpdatesExhausted(self.state_number));
+        }
+        Ok(())
+    }
+
+    /// Checks that each of the parties can pay its share of the fees from
+    /// the unallocated balance for the allocation with the given number of
+    /// sub-channels
+    fn check_fee_shares(
+        &self,
+        balances: &[u64],
+        subchannels: usize,
+    ) -> Result<(), FactoryError> {
+        match balances
+            .iter()
+            .zip(self.fee_shares(subchannels))
+            .position(|(balance, share)| *balance < share)
+        {
+            Some(party) => Err(FactoryError::InsufficientBalance(party as u8)),
+            None => Ok(()),
+        }
+    }
+
+    /// Moves funds of the sub-channel parties between their unallocated
+    /// balances and the sub-channel, changing their allocations from `prev`
+    /// to `next` amounts. The remaining balances must cover fee shares of
+    /// the allocation with `subchannels` sub-channels. Balances are left
+    /// intact on error.
+    fn reallocate(
+        &mut self,
+        subchannel: &Subchannel,
+        prev: (u64, u64),
+        next: (u64, u64),
+        subchannels: usize,
+    ) -> Result<(), FactoryError> {
+        let mut balances = self.balances.clone();
+        for (party, prev, next) in &[
+            (subchannel.party_a, prev.0, next.0),
+            (subchannel.party_b, prev.1, next.1),
+        ] {
+            let balance = balances
+                .get_mut(*party as usize)
+                .ok_or(FactoryError::UnknownParty(*party))?;
+            *balance = (*balance + prev)
+                .checked_sub(*next)
+                .ok_or(FactoryError::InsufficientBalance(*party))?;
+        }
+        self.check_fee_shares(&balances, subchannels)?;
+        self.balances = balances;
+        Ok(())
+    }
+
+    /// Weight of the transaction spending factory output with all parties
+    /// signatures
+    fn factory_spend_weight(&self, p2wsh: usize, p2wpkh: usize) -> u64 {
+        let parties = self.parties().len() as u64;
+        TX_BASE_WEIGHT
+            + FACTORY_INPUT_WEIGHT
+            + parties * (FUNDING_KEY_WEIGHT + FUNDING_SIGNATURE_WEIGHT)
+            + p2wsh as u64 * P2WSH_OUTPUT_WEIGHT
+            + p2wpkh as u64 * BALANCE_OUTPUT_WEIGHT
+    }
+
+    fn kickoff_fee(&self) -> u64 {
+        fee_for_weight(self.feerate_per_kw, self.factory_spend_weight(1, 0))
+    }
+
+    /// Fee of the allocation transaction with the given number of
+    /// sub-channels, computed before trimming of the dust balance outputs
+    fn allocation_fee(&self, subchannels: usize) -> u64 {
+        fee_for_weight(
+            self.feerate_per_kw,
+            self.factory_spend_weight(subchannels, self.balances.len()),
+        )
+    }
+
+    /// Splits kickoff and allocation fees equally between the parties, with
+    /// the remainder paid by the first party
+    fn fee_shares(&self, subchannels: usize) -> Vec<u64> {
+        let fee = self.kickoff_fee() + self.allocation_fee(subchannels);
+        let count = self.balances.len() as u64;
+        (0..count)
+            .map(|index| fee / count + if index == 0 { fee % count } else { 0 })
+            .collect()
+    }
+
+    fn kickoff_output(&self) -> TxOut {
+        TxOut {
+            value: self
+                .funding
+                .funding_amount()
+                .saturating_sub(self.kickoff_fee()),
+            script_pubkey: self.funding_script().to_p2wsh().into(),
+        }
+    }
+
+    /// Sub-channel funding outputs go first, in the order of their ids,
+    /// followed by the unallocated balances of the parties paying both
+    /// kickoff and allocation fees equally. Balances always cover the fee
+    /// shares, which is ensured on each reallocation.
+    fn allocation_outputs(&self) -> Vec<TxOut> {
+        let subchannel_outputs =
+            self.subchannels.values().map(|subchannel| TxOut {
+                value: subchannel.capacity(),
+                script_pubkey: subchannel.funding_script().to_p2wsh().into(),
+            });
+        let balance_outputs = self
+            .parties()
+            .iter()
+            .zip(&self.balances)
+            .zip(self.fee_shares(self.subchannels.len()))
+            .map(|((party, balance), share)| {
+                TxOut::ln_to_remote_v1(balance - share, party.payment_pubkey)
+            })
+            .filter(|txout| txout.value >= self.dust_limit);
+        subchannel_outputs.chain(balance_outputs).collect()
+    }
+}
+
+impl channel::State for Factory {}
+
+impl Extension for Factory {
+    type Identity = ExtensionId;
+
+    fn identity(&self) -> Self::Identity {
+        ExtensionId::Factory
+    }
+
+    fn update_from_peer(&mut self, _: &Messages) -> Result<(), channel::Error> {
+        // There are no channel factory messages in BOLTs yet
+        Ok(())
+    }
+
+    fn update_from_local(
+        &mut self,
+        _: &Operation,
+    ) -> Result<Vec<Messages>, channel::Error> {
+        Ok(vec![])
+    }
+
+    fn extension_state(&self) -> Vec<u8> {
+        channel::State::to_state_data(self)
+    }
+}
+
+impl ChannelExtension for Factory {
+    fn channel_state(&self) -> Vec<u8> {
+        channel::State::to_state_data(self)
+    }
+
+    /// Kickoff transaction is placed into the graph as the commitment
+    /// transaction, followed by the allocation transaction spending it
+    fn apply(
+        &mut self,
+        tx_graph: &mut channel::TxGraph,
+    ) -> Result<(), channel::Error> {
+        let parties = self.parties().len() as u8;
+        tx_graph.set_funding_outpoint(self.funding_outpoint());
+        tx_graph.set_funding_tx(parties, parties, self.funding_psbt());
+        tx_graph.cmt_version = 2;
+        tx_graph.cmt_locktime = 0;
+        tx_graph.cmt_sequence = core::u32::MAX;
+        tx_graph.cmt_fee =
+            CommitmentFee::with(self.feerate_per_kw, false, false);
+        tx_graph.cmt_anchors = none!();
+        tx_graph.cmt_dust_limit = self.dust_limit;
+        tx_graph.cmt_outs = vec![self.kickoff_output()];
+
+        let mut allocation_tx = self.allocation_tx();
+        allocation_tx.input[0].previous_output = tx_graph.cmt_outpoint(0);
+        let mut psbt = Psbt::from_unsigned_tx(allocation_tx).expect(
+            "PSBT construction fails only if script_sig and witness are not \
+             empty; which is not the case here",
+        );
+        psbt.inputs[0].witness_script =
+            Some(self.funding_script().into_inner());
+        psbt.inputs[0].witness_utxo = Some(self.kickoff_output());
+        for (output, subchannel) in
+            psbt.outputs.iter_mut().zip(self.subchannels.values())
+        {
+            output.witness_script =
+                Some(subchannel.funding_script().into_inner());
+        }
+        tx_graph.insert_tx(TxType::FactoryAllocation, 0u64, psbt);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::{Script, Txid};
+    use wallet::PubkeyScript;
+
+    use crate::channel::TxGraph;
+    use crate::payment::keys::test::pubkey;
+    use crate::payment::multiparty::MultisigGenerators;
+
+    fn party(byte: u8) -> FundingParty {
+        let mut prevouts = BTreeMap::new();
+        prevouts.insert(
+            OutPoint::new(Txid::default(), byte as u32),
+            TxOut {
+                value: 1_010_000,
+                script_pubkey: Script::new(),
+            },
+        );
+        FundingParty {
+            funding_pubkey: pubkey(byte),
+            payment_pubkey: pubkey(byte + 0x10),
+            prevouts,
+            contribution: 1_000_000,
+            change: None,
+        }
+    }
+
+    fn subchannel(party_a: u8, party_b: u8, amount: u64) -> Subchannel {
+        Subchannel {
+            party_a,
+            party_b,
+            funding_pubkey_a: pubkey(party_a + 0x20),
+            funding_pubkey_b: pubkey(party_b + 0x20),
+            amount_a: amount,
+            amount_b: amount,
+        }
+    }
+
+    fn factory() -> Factory {
+        Factory::with(vec![party(1), party(2), party(3)], 432, 144, 253, 546)
+            .unwrap()
+    }
+
+    #[test]
+    fn construction_errors() {
+        assert_eq!(
+            Factory::with(vec![party(1), party(2)], 100, 144, 253, 546),
+            Err(FactoryError::InvalidDelay(100, 144))
+        );
+        assert_eq!(
+            Factory::with(vec![party(1), party(2)], 144, 0, 253, 546),
+            Err(FactoryError::InvalidDelay(144, 0))
+        );
+        assert_eq!(
+            Factory::with(vec![party(1)], 144, 144, 253, 546),
+            Err(FactoryError::Funding(MultiPartyError::PartyCount(1)))
+        );
+    }
+
+    #[test]
+    fn subchannels() {
+        let mut factory = factory();
+        assert_eq!(factory.updates_left(), 2);
+        assert_eq!(factory.allocation_delay(), 432);
+
+        assert_eq!(
+            factory.open_subchannel(subchannel(0, 0, 100_000)),
+            Err(FactoryError::SameParty(0))
+        );
+        assert_eq!(
+            factory.open_subchannel(subchannel(0, 3, 100_000)),
+            Err(FactoryError::UnknownParty(3))
+        );
+        assert_eq!(
+            factory.open_subchannel(subchannel(0, 1, 2_000_000)),
+            Err(FactoryError::InsufficientBalance(0))
+        );
+        assert_eq!(factory.balances(), &[1_000_000; 3]);
+
+        let id = factory.open_subchannel(subchannel(0, 1, 400_000)).unwrap();
+        assert_eq!(factory.state_number(), 1);
+        assert_eq!(factory.allocation_delay(), 288);
+        assert_eq!(factory.balances(), &[600_000, 600_000, 1_000_000]);
+
+        assert_eq!(
+            factory.update_subchannel(id, 300_000, 1_100_000),
+            Err(FactoryError::InsufficientBalance(1))
+        );
+        assert_eq!(factory.balances(), &[600_000, 600_000, 1_000_000]);
+        factory.update_subchannel(id, 300_000, 500_000).unwrap();
+        assert_eq!(factory.subchannel(id).unwrap().capacity(), 800_000);
+        assert_eq!(factory.balances(), &[700_000, 500_000, 1_000_000]);
+        assert_eq!(factory.updates_left(), 0);
+
+        assert_eq!(
+            factory.close_subchannel(id),
+            Err(FactoryError::UpdatesExhausted(2))
+        );
+        assert_eq!(
+            factory.update_subchannel(5, 0, 0),
+            Err(FactoryError::UnknownSubchannel(5))
+        );
+    }
+
+    #[test]
+    fn transactions() {
+        let mut factory = factory();
+        let id = factory.open_subchannel(subchannel(0, 1, 400_000)).unwrap();
+
+        let kickoff = factory.kickoff_tx();
+        assert_eq!(
+            kickoff.input[0].previous_output,
+            factory.funding_outpoint()
+        );
+        assert_eq!(
+            kickoff.output[0].script_pubkey,
+            PubkeyScript::ln_multisig_funding(
+                3,
+                &[pubkey(1), pubkey(2), pubkey(3)]
+            )
+            .into_inner()
+        );
+        // 42 + 170 + 3 * (34 + 73) + 172 weight units at 253 sat/kw
+        assert_eq!(kickoff.output[0].value, 3_000_000 - 178);
+
+        let allocation = factory.allocation_tx();
+        assert_eq!(
+            allocation.input[0].previous_output,
+            OutPoint::new(kickoff.txid(), 0)
+        );
+        assert_eq!(allocation.input[0].sequence, 288);
+        assert_eq!(allocation.output.len(), 4);
+        assert_eq!(allocation.output[0].value, 800_000);
+        assert_eq!(
+            allocation.output[0].script_pubkey,
+            Script::from(
+                factory.subchannel(id).unwrap().funding_script().to_p2wsh()
+            )
+        );
+        // Allocation fee: 42 + 170 + 3 * (34 + 73) + 172 + 3 * 124 weight
+        // units at 253 sat/kw
+        let fee = 178 + 272;
+        assert_eq!(
+            allocation.output[1..]
+                .iter()
+                .map(|txout| txout.value)
+                .sum::<u64>(),
+            2_200_000 - fee
+        );
+        assert_eq!(
+            factory.subchannel_outpoint(id),
+            Some(OutPoint::new(allocation.txid(), 0))
+        );
+        assert_eq!(factory.subchannel_outpoint(1), None);
+
+        let mut tx_graph = TxGraph::default();
+        factory.apply(&mut tx_graph).unwrap();
+        tx_graph.link_cmt_txid();
+        assert_eq!(tx_graph.render_cmt().global.unsigned_tx, kickoff);
+        assert_eq!(*tx_graph.funding_parties(), 3);
+        assert_eq!(*tx_graph.funding_threshold(), 3);
+        let psbt = tx_graph.tx(TxType::FactoryAllocation, 0u64).unwrap();
+        assert_eq!(psbt.global.unsigned_tx, allocation);
+        assert_eq!(
+            psbt.inputs[0].witness_script,
+            Some(factory.funding_script().into_inner())
+        );
+    }
+
+    #[test]
+    fn fully_allocated() {
+        let mut factory = factory();
+        // Each party pays 150 sat of 178 sat kickoff and 272 sat allocation
+        // fees
+        assert_eq!(
+            factory.open_subchannel(subchannel(0, 1, 1_000_000)),
+            Err(FactoryError::InsufficientBalance(0))
+        );
+        assert_eq!(factory.balances(), &[1_000_000; 3]);
+
+        let id = factory.open_subchannel(subchannel(0, 1, 999_850)).unwrap();
+        assert_eq!(factory.balances(), &[150, 150, 1_000_000]);
+        let allocation = factory.allocation_tx();
+        assert_eq!(allocation.output.len(), 2);
+        assert_eq!(allocation.output[0].value, 1_999_700);
+        assert_eq!(allocation.output[1].value, 999_850);
+        assert_eq!(
+            allocation
+                .output
+                .iter()
+                .map(|txout| txout.value)
+                .sum::<u64>(),
+            factory.kickoff_tx().output[0].value - 272
+        );
+
+        assert_eq!(
+            factory.update_subchannel(id, 1_000_000, 999_850),
+            Err(FactoryError::InsufficientBalance(0))
+        );
+    }
+}
@@ -62,8 +62,8 @@ pub enum NoRequiredFeatureError {
     /// `basic_mpp` feature requires `payment_secret` feature
     PaymentSecret,
 
-    /// `option_anchor_outputs` feature requires `option_static_remotekey`
-    /// feature
+    /// `option_anchor_outputs` and `option_anchors_zero_fee_htlc_tx` features
+    /// require `option_static_remotekey` feature
     OptionStaticRemotekey,
 }
 
@@ -169,6 +169,10 @@ pub enum Feature {
     /// Anchor outputs
     #[display("option_anchor_outputs", alt = "20/21")]
     OptionAnchorOutputs = 20,
+
+    /// Anchor commitment type with zero fee HTLC transactions
+    #[display("option_anchors_zero_fee_htlc_tx", alt = "22/23")]
+    OptionAnchorsZeroFeeHtlcTx = 22,
 }
 
 impl Feature {
@@ -233,6 +237,9 @@ impl FromStr for Feature {
             s if s == Feature::OptionAnchorOutputs.to_string() => {
                 Feature::OptionAnchorOutputs
             }
+            s if s == Feature::OptionAnchorsZeroFeeHtlcTx.to_string() => {
+                Feature::OptionAnchorsZeroFeeHtlcTx
+            }
             other => return Err(UnknownFeatureError(other.to_owned())),
         };
         Ok(feature)
@@ -282,6 +289,9 @@ pub struct InitFeatures {
     /// Anchor outputs
     pub option_anchor_outputs: Option<bool>,
 
+    /// Anchor commitment type with zero fee HTLC transactions
+    pub option_anchors_zero_fee_htlc_tx: Option<bool>,
+
     /// Rest of feature flags which are unknown to the current implementation
     pub unknown: FlagVec,
 }
@@ -315,7 +325,8 @@ impl InitFeatures {
         if self.basic_mpp.is_some() && self.payment_secret.is_none() {
             return Err(NoRequiredFeatureError::PaymentSecret);
         }
-        if self.option_anchor_outputs.is_some()
+        if (self.option_anchor_outputs.is_some()
+            || self.option_anchors_zero_fee_htlc_tx.is_some())
             && self.option_static_remotekey.is_none()
         {
             return Err(NoRequiredFeatureError::OptionStaticRemotekey);
@@ -365,6 +376,9 @@ impl InitFeatures {
         if let Some(required) = self.option_anchor_outputs {
             map.insert(Feature::OptionAnchorOutputs, required);
         }
+        if let Some(required) = self.option_anchors_zero_fee_htlc_tx {
+            map.insert(Feature::OptionAnchorsZeroFeeHtlcTx, required);
+        }
         map
     }
 }
@@ -406,6 +420,9 @@ impl TryFrom<FlagVec> for InitFeatures {
                 Feature::OptionSupportLargeChannel,
             ),
             option_anchor_outputs: requirements(Feature::OptionAnchorOutputs),
+            option_anchors_zero_fee_htlc_tx: requirements(
+                Feature::OptionAnchorsZeroFeeHtlcTx,
+            ),
             unknown: none!(),
         };
 
@@ -0,0 +1,628 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Storage for the history of per-commitment channel states, required to
+//! identify and react on the publication of revoked commitment transactions.
+//!
+//! States are indexed by their height, i.e. the number of states pushed into
+//! the history before them, which matches commitment number if a new state is
+//! pushed for each of the channel commitments.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+use bitcoin::hashes::{sha256, Hash};
+use strict_encoding::{strict_deserialize, strict_serialize};
+
+use crate::channel::{History, State};
+
+/// Magic bytes starting each of history files
+pub const HISTORY_FILE_MAGIC: [u8; 4] = *b"LNPH";
+
+/// Current version of the history file format
+pub const HISTORY_FILE_VERSION: u16 = 1;
+
+/// Length of the history file header: magic bytes, format version and the
+/// height of the first state stored in the file
+const HEADER_LEN: u64 = 4 + 2 + 8;
+
+/// Length of the checksum following each of the history file records
+const CHECKSUM_LEN: usize = 4;
+
+/// Errors working with channel state history
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum Error {
+    /// state history is empty
+    Empty,
+
+    /// there is no state at height {0}; current history height is {1}
+    OutOfRange(usize, usize),
+
+    /// state at height {0} was removed from the history during compaction
+    Pruned(usize),
+
+    /// I/O error accessing state history: {0}
+    Io(String),
+
+    /// state history data can't be encoded or decoded: {0}
+    Encoding(String),
+
+    /// checksum of the history record #{0} does not match its data
+    Checksum(usize),
+
+    /// the file is not a channel state history file or has unsupported
+    /// version of the format
+    FileFormat,
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err.to_string())
+    }
+}
+
+impl From<strict_encoding::Error> for Error {
+    fn from(err: strict_encoding::Error) -> Self {
+        Error::Encoding(err.to_string())
+    }
+}
+
+/// In-memory history of channel states
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct VecHistory<S>
+where
+    S: State + Clone,
+{
+    states: Vec<S>,
+}
+
+impl<S> VecHistory<S>
+where
+    S: State + Clone,
+{
+    /// Constructs empty history
+    pub fn new() -> Self {
+        Self { states: vec![] }
+    }
+}
+
+impl<S> History for VecHistory<S>
+where
+    S: State + Clone,
+{
+    type State = S;
+    type Error = Error;
+
+    #[inline]
+    fn height(&self) -> usize {
+        self.states.len()
+    }
+
+    fn get(&self, height: usize) -> Result<S, Error> {
+        self.states
+            .get(height)
+            .cloned()
+            .ok_or(Error::OutOfRange(height, self.height()))
+    }
+
+    fn top(&self) -> Result<S, Error> {
+        self.states.last().cloned().ok_or(Error::Empty)
+    }
+
+    fn bottom(&self) -> Result<S, Error> {
+        self.states.first().cloned().ok_or(Error::Empty)
+    }
+
+    /// Returns the state preceding the top one, i.e. the state of the last
+    /// revoked commitment
+    fn dig(&self) -> Result<S, Error> {
+        match self.height() {
+            0 => Err(Error::Empty),
+            1 => Err(Error::OutOfRange(0, 1)),
+            height => self.get(height - 2),
+        }
+    }
+
+    fn push(&mut self, state: S) -> Result<&mut Self, Error> {
+        self.states.push(state);
+        Ok(self)
+    }
+}
+
+/// File-based append-only history of channel states.
+///
+/// The file starts with a header consisting of [`HISTORY_FILE_MAGIC`], format
+/// version and the height of the first state kept in the file (which is
+/// non-zero after [`FileHistory::compact`] was called). It is followed by the
+/// records containing strict-encoded states, each prefixed with the length of
+/// the data and followed by the first 4 bytes of the data SHA256 hash.
+///
+/// Each record is written with a single call and synced to the disk before
+/// [`History::push`] returns; a failed write is rolled back. If the process
+/// crashes during the write, the incomplete record is detected and removed
+/// when the file is opened next time.
+#[derive(Debug)]
+pub struct FileHistory<S>
+where
+    S: State,
+{
+    path: PathBuf,
+    file: File,
+    base: usize,
+    offsets: Vec<u64>,
+    _phantom: PhantomData<S>,
+}
+
+impl<S> FileHistory<S>
+where
+    S: State,
+{
+    /// Creates new empty history file, failing if the file already exists
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create_new(true)
+            .open(&path)?;
+        file.write_all(&header(0))?;
+        file.sync_all()?;
+        Ok(Self {
+            path,
+            file,
+            base: 0,
+            offsets: vec![],
+            _phantom: PhantomData,
+        })
+    }
+
+    /// Opens existing history file, checking integrity of all its records and
+    /// removing trailing incomplete record, which may be left by an
+    /// interrupted write operation. The last record is also considered
+    /// incomplete if its checksum does not match because the record data and
+    /// everything following them are zero bytes, which some file systems
+    /// leave after a crash. Any other checksum mismatch is reported as
+    /// [`Error::Checksum`].
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut file =
+            OpenOptions::new().read(true).append(true).open(&path)?;
+
+        let mut buf = [0u8; HEADER_LEN as usize];
+        file.read_exact(&mut buf).map_err(|_| Error::FileFormat)?;
+        if buf[..4] != HISTORY_FILE_MAGIC
+            || u16::from_le_bytes([buf[4], buf[5]]) != HISTORY_FILE_VERSION
+        {
+            return Err(Error::FileFormat);
+        }
+        let mut base = [0u8; 8];
+        base.copy_from_slice(&buf[6..]);
+        let base = u64::from_le_bytes(base) as usize;
+
+        let len = file.metadata()?.len();
+        let mut offsets = vec![];
+        let mut offset = HEADER_LEN;
+        loop {
+            match read_record(&file, offset, len) {
+                Ok(Some(data)) => {
+                    let next = offset + 4 + data.len() as u64;
+                    if checksum(&data[..data.len() - CHECKSUM_LEN])
+                        == data[data.len() - CHECKSUM_LEN..]
+                    {
+                        offsets.push(offset);
+                        offset = next;
+                    } else if data.iter().all(|byte| *byte == 0)
+                        && is_zero_tail(&file, next, len)?
+                    {
+                        file.set_len(offset)?;
+                        file.sync_all()?;
+                        break;
+                    } else {
+                        return Err(Error::Checksum(base + offsets.len()));
+                    }
+                }
+                // Clean end of file
+                Ok(None) if offset == len => break,
+                // Incomplete record left by an interrupted write
+                Ok(None) => {
+                    file.set_len(offset)?;
+                    file.sync_all()?;
+                    break;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(Self {
+            path,
+            file,
+            base,
+            offsets,
+            _phantom: PhantomData,
+        })
+    }
+
+    /// Returns path to the history file
+    #[inline]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the height of the oldest state kept in the history file
+    #[inline]
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    /// Removes all states below the given height from the history. Heights
+    /// of the remaining states are not changed.
+    ///
+    /// The compacted history is written to a temporary file, which replaces
+    /// the original one only once all of its data are synced to the disk, so
+    /// the operation is safe against crashes.
+    pub fn compact(&mut self, height: usize) -> Result<(), Error> {
+        if height <= self.base {
+            return Ok(());
+        }
+        if height > self.height() {
+            return Err(Error::OutOfRange(height, self.height()));
+        }
+
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".compact");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let len = self.file.metadata()?.len();
+        let mut tmp = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        tmp.write_all(&header(height))?;
+        let mut offsets = Vec::with_capacity(self.height() - height);
+        let mut offset = HEADER_LEN;
+        for record_offset in &self.offsets[height - self.base..] {
+            let data = read_record(&self.file, *record_offset, len)?
+                .ok_or(Error::Checksum(height + offsets.len()))?;
+            tmp.write_all(&(data.len() as u32).to_le_bytes())?;
+            tmp.write_all(&data)?;
+            offsets.push(offset);
+            offset += 4 + data.len() as u64;
+        }
+        tmp.sync_all()?;
+        drop(tmp);
+
+        fs::rename(&tmp_path, &self.path)?;
+        // Rename is persisted only once the directory entry is synced
+        #[cfg(unix)]
+        {
+            let dir = match self.path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            File::open(dir)?.sync_all()?;
+        }
+        self.file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+        self.base = height;
+        self.offsets = offsets;
+        Ok(())
+    }
+}
+
+impl<S> History for FileHistory<S>
+where
+    S: State,
+{
+    type State = S;
+    type Error = Error;
+
+    #[inline]
+    fn height(&self) -> usize {
+        self.base + self.offsets.len()
+    }
+
+    fn get(&self, height: usize) -> Result<S, Error> {
+        if height < self.base {
+            return Err(Error::Pruned(height));
+        }
+        let offset = *self
+            .offsets
+            .get(height - self.base)
+            .ok_or(Error::OutOfRange(height, self.height()))?;
+        let len = self.file.metadata()?.len();
+        let data = read_record(&self.file, offset, len)?
+            .ok_or(Error::Checksum(height))?;
+        let (data, check) = data.split_at(data.len() - CHECKSUM_LEN);
+        if checksum(data) != check {
+            return Err(Error::Checksum(height));
+        }
+        Ok(strict_deserialize(data)?)
+    }
+
+    fn top(&self) -> Result<S, Error> {
+        match self.height() {
+            0 => Err(Error::Empty),
+            height => self.get(height - 1),
+        }
+    }
+
+    fn bottom(&self) -> Result<S, Error> {
+        if self.offsets.is_empty() {
+            return Err(Error::Empty);
+        }
+        self.get(self.base)
+    }
+
+    /// Returns the state preceding the top one, i.e. the state of the last
+    /// revoked commitment
+    fn dig(&self) -> Result<S, Error> {
+        match self.height() {
+            0 => Err(Error::Empty),
+            height => self.get(
+                height.checked_sub(2).ok_or(Error::OutOfRange(0, height))?,
+            ),
+        }
+    }
+
+    fn push(&mut self, state: S) -> Result<&mut Self, Error> {
+        let data = strict_serialize(&state)?;
+        let mut record = Vec::with_capacity(4 + data.len() + CHECKSUM_LEN);
+        record.extend(&(data.len() as u32 + CHECKSUM_LEN as u32).to_le_bytes());
+        record.extend(&data);
+        record.extend(&checksum(&data));
+
+        let offset = self.file.seek(SeekFrom::End(0))?;
+        if let Err(err) = self
+            .file
+            .write_all(&record)
+            .and_then(|_| self.file.sync_data())
+        {
+            // Partially written record would prevent the following pushes
+            // from being read back
+            let _ = self.file.set_len(offset);
+            return Err(err.into());
+        }
+        self.offsets.push(offset);
+        Ok(self)
+    }
+}
+
+fn header(base: usize) -> Vec<u8> {
+    let mut header = Vec::with_capacity(HEADER_LEN as usize);
+    header.extend(&HISTORY_FILE_MAGIC);
+    header.extend(&HISTORY_FILE_VERSION.to_le_bytes());
+    header.extend(&(base as u64).to_le_bytes());
+    header
+}
+
+fn checksum(data: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let mut checksum = [0u8; CHECKSUM_LEN];
+    checksum.copy_from_slice(&sha256::Hash::hash(data)[..CHECKSUM_LEN]);
+    checksum
+}
+
+/// Reads record data (including checksum) at a given offset. Returns `None`
+/// if the record is incomplete, i.e. extends beyond the end of file
+fn read_record(
+    mut file: &File,
+    offset: u64,
+    file_len: u64,
+) -> Result<Option<Vec<u8>>, Error> {
+    if offset + 4 > file_len {
+        return Ok(None);
+    }
+    file.seek(SeekFrom::Start(offset))?;
+    let mut len = [0u8; 4];
+    file.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as u64;
+    if len < CHECKSUM_LEN as u64 || offset + 4 + len > file_len {
+        return Ok(None);
+    }
+    let mut data = vec![0u8; len as usize];
+    file.read_exact(&mut data)?;
+    Ok(Some(data))
+}
+
+/// Checks whether the file contains only zero bytes starting from the given
+/// offset
+fn is_zero_tail(
+    mut file: &File,
+    offset: u64,
+    file_len: u64,
+) -> Result<bool, Error> {
+    if offset >= file_len {
+        return Ok(true);
+    }
+    file.seek(SeekFrom::Start(offset))?;
+    let mut tail = vec![];
+    file.take(file_len - offset).read_to_end(&mut tail)?;
+    Ok(tail.iter().all(|byte| *byte == 0))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use strict_encoding::{StrictDecode, StrictEncode};
+
+    #[derive(Clone, PartialEq, Eq, Debug, StrictEncode, StrictDecode)]
+    struct TestState {
+        commitment_number: u64,
+        balance: u64,
+    }
+
+    impl State for TestState {}
+
+    fn state(commitment_number: u64) -> TestState {
+        TestState {
+            commitment_number,
+            balance: 1000 * commitment_number,
+        }
+    }
+
+    fn tmp_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!(
+            "lnp-history-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn check_history<H>(history: &mut H)
+    where
+        H: History<State = TestState, Error = Error>,
+    {
+        assert_eq!(history.height(), 0);
+        assert_eq!(history.top(), Err(Error::Empty));
+        assert_eq!(history.bottom(), Err(Error::Empty));
+        assert_eq!(history.dig(), Err(Error::Empty));
+
+        history.push(state(0)).unwrap();
+        assert_eq!(history.top(), Ok(state(0)));
+        assert_eq!(history.dig(), Err(Error::OutOfRange(0, 1)));
+
+        for no in 1..10 {
+            history.push(state(no)).unwrap();
+        }
+        assert_eq!(history.height(), 10);
+        assert_eq!(history.bottom(), Ok(state(0)));
+        assert_eq!(history.top(), Ok(state(9)));
+        assert_eq!(history.dig(), Ok(state(8)));
+        assert_eq!(history.get(4), Ok(state(4)));
+        assert_eq!(history.get(10), Err(Error::OutOfRange(10, 10)));
+    }
+
+    #[test]
+    fn vec_history() {
+        check_history(&mut VecHistory::new());
+    }
+
+    #[test]
+    fn file_history() {
+        let path = tmp_path("basic");
+        check_history(&mut FileHistory::create(&path).unwrap());
+
+        let history = FileHistory::<TestState>::open(&path).unwrap();
+        assert_eq!(history.height(), 10);
+        assert_eq!(history.get(7), Ok(state(7)));
+        assert!(FileHistory::<TestState>::create(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_history_torn_write() {
+        let path = tmp_path("torn");
+        let mut history = FileHistory::create(&path).unwrap();
+        history.push(state(0)).unwrap().push(state(1)).unwrap();
+        let len = fs::metadata(&path).unwrap().len();
+        drop(history);
+
+        // Simulating a crash in the middle of the record write
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[40u8, 0, 0, 0, 1, 2, 3]).unwrap();
+        drop(file);
+
+        let mut history = FileHistory::<TestState>::open(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        assert_eq!(history.height(), 2);
+        history.push(state(2)).unwrap();
+        assert_eq!(history.top(), Ok(state(2)));
+
+        let history = FileHistory::<TestState>::open(&path).unwrap();
+        assert_eq!(history.height(), 3);
+        assert_eq!(history.get(1), Ok(state(1)));
+        let len = fs::metadata(&path).unwrap().len();
+        drop(history);
+
+        // Record length reached the disk, but its data were zero-filled
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[20u8, 0, 0, 0]).unwrap();
+        file.write_all(&[0u8; 32]).unwrap();
+        drop(file);
+
+        let history = FileHistory::<TestState>::open(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        assert_eq!(history.height(), 3);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_history_corruption() {
+        let path = tmp_path("corrupted");
+        let mut history = FileHistory::create(&path).unwrap();
+        history.push(state(0)).unwrap().push(state(1)).unwrap();
+        drop(history);
+
+        let mut data = fs::read(&path).unwrap();
+        data[HEADER_LEN as usize + 4] ^= 0xFF;
+        fs::write(&path, data).unwrap();
+        assert_eq!(
+            FileHistory::<TestState>::open(&path).err(),
+            Some(Error::Checksum(0))
+        );
+
+        // Fully written last record is corrupted, not torn
+        let mut data = fs::read(&path).unwrap();
+        data[HEADER_LEN as usize + 4] ^= 0xFF;
+        let last = data.len() - CHECKSUM_LEN - 1;
+        data[last] ^= 0xFF;
+        fs::write(&path, &data).unwrap();
+        assert_eq!(
+            FileHistory::<TestState>::open(&path).err(),
+            Some(Error::Checksum(1))
+        );
+        assert_eq!(fs::read(&path).unwrap(), data);
+
+        fs::write(&path, b"not a history file").unwrap();
+        assert_eq!(
+            FileHistory::<TestState>::open(&path).err(),
+            Some(Error::FileFormat)
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_history_compaction() {
+        let path = tmp_path("compaction");
+        let mut history = FileHistory::create(&path).unwrap();
+        for no in 0..10 {
+            history.push(state(no)).unwrap();
+        }
+        let len = fs::metadata(&path).unwrap().len();
+
+        history.compact(6).unwrap();
+        assert!(fs::metadata(&path).unwrap().len() < len);
+        assert_eq!(history.base(), 6);
+        assert_eq!(history.height(), 10);
+        assert_eq!(history.bottom(), Ok(state(6)));
+        assert_eq!(history.get(5), Err(Error::Pruned(5)));
+        assert_eq!(history.get(8), Ok(state(8)));
+        history.push(state(10)).unwrap();
+        assert_eq!(history.compact(12), Err(Error::OutOfRange(12, 11)));
+
+        let history = FileHistory::<TestState>::open(&path).unwrap();
+        assert_eq!(history.base(), 6);
+        assert_eq!(history.height(), 11);
+        assert_eq!(history.bottom(), Ok(state(6)));
+        assert_eq!(history.top(), Ok(state(10)));
+        fs::remove_file(&path).unwrap();
+    }
+}
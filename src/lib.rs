@@ -45,13 +45,15 @@ pub mod channel;
 pub mod extension;
 pub mod factories;
 pub mod features;
+pub mod history;
 pub mod message;
 pub mod payment;
 pub mod prometheus;
 pub mod storm;
 
 pub use extension::{
-    ChannelExtension, Extension, GossipExtension, RoutingExtension,
+    ChannelExtension, Extension, ExtensionFactory, ExtensionKind,
+    GossipExtension, RoutingExtension,
 };
 pub use features::{
     Bolt11Context, ChannelAnnouncementContext, Feature, FeatureContext,
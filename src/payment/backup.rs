@@ -0,0 +1,327 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Static channel backups: the minimal channel data which does not change
+//! with the channel updates, allowing a node which has lost its state to
+//! reconnect to the peer and ask it to force-close the channel using
+//! `option_data_loss_protect`, sweeping the funds afterwards.
+
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::rand::{thread_rng, RngCore};
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::util::bip32::DerivationPath;
+use bitcoin::OutPoint;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use strict_encoding::{strict_deserialize, strict_serialize};
+
+use crate::message::ChannelReestablish;
+use crate::payment::channel::Params;
+use crate::payment::keys::LocalKeyset;
+use crate::payment::{AddressList, ChannelId};
+
+/// Current version of the static channel backup format
+pub const SCB_VERSION: u8 = 0;
+
+/// Length of the ChaCha20-Poly1305 nonce following the version byte
+pub const NONCE_LEN: usize = 12;
+
+/// Errors encrypting, decrypting and restoring static channel backups
+#[derive(Clone, PartialEq, Eq, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum BackupError {
+    /// static channel backup version {0} is not supported
+    UnsupportedVersion(u8),
+
+    /// static channel backup can't be decrypted with the provided seed
+    Decryption,
+
+    /// static channel backup can't be encoded: {0}
+    Encoding(String),
+
+    /// channel_reestablish message channel id {0} does not match the
+    /// restored channel id {1}
+    ChannelIdMismatch(ChannelId, ChannelId),
+
+    /// channel_reestablish message contains invalid per-commitment secret
+    /// for the local commitment number {0}
+    InvalidSecret(u64),
+}
+
+impl From<strict_encoding::Error> for BackupError {
+    fn from(err: strict_encoding::Error) -> Self {
+        BackupError::Encoding(err.to_string())
+    }
+}
+
+/// Channel data required to recover funds after the loss of the channel
+/// state
+#[derive(Clone, PartialEq, Eq, Debug, StrictEncode, StrictDecode)]
+pub struct StaticChannelBackup {
+    pub channel_id: ChannelId,
+    /// Node id of the remote peer
+    pub remote_node: PublicKey,
+    /// Addresses at which the remote peer may be reached
+    pub remote_addrs: AddressList,
+    pub funding_outpoint: OutPoint,
+    /// Derivation path of the local channel keys
+    pub derivation_path: DerivationPath,
+    /// Channel parameters negotiated with the remote peer
+    pub params: Params,
+}
+
+impl StaticChannelBackup {
+    /// Encrypts the backup with the key derived from the node seed. The
+    /// result is made of the format version, random nonce and the
+    /// ciphertext, which authenticates the version as well.
+    pub fn encrypt(&self, seed: [u8; 32]) -> Result<Vec<u8>, BackupError> {
+        let mut nonce = [0u8; NONCE_LEN];
+        thread_rng().fill_bytes(&mut nonce);
+        let plaintext = strict_serialize(self)?;
+        let mut encrypted = vec![SCB_VERSION];
+        encrypted.extend(&nonce);
+        encrypted.extend(
+            cipher(seed)
+                .encrypt(
+                    Nonce::from_slice(&nonce),
+                    Payload {
+                        msg: &plaintext,
+                        aad: &[SCB_VERSION],
+                    },
+                )
+                .expect("encryption of in-memory data does not fail"),
+        );
+        Ok(encrypted)
+    }
+
+    /// Decrypts the backup produced by [`StaticChannelBackup::encrypt`]
+    pub fn decrypt(data: &[u8], seed: [u8; 32]) -> Result<Self, BackupError> {
+        let (version, data) =
+            data.split_first().ok_or(BackupError::Decryption)?;
+        if *version != SCB_VERSION {
+            return Err(BackupError::UnsupportedVersion(*version));
+        }
+        if data.len() < NONCE_LEN {
+            return Err(BackupError::Decryption);
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let plaintext = cipher(seed)
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &[*version],
+                },
+            )
+            .map_err(|_| BackupError::Decryption)?;
+        Ok(strict_deserialize(&plaintext)?)
+    }
+
+    /// Restores recovery-only channel with the local keys derived by the
+    /// node using the backup derivation path
+    pub fn restore(self, local_keys: LocalKeyset) -> RecoveryChannel {
+        RecoveryChannel {
+            backup: self,
+            local_keys,
+            remote_per_commitment_point: None,
+        }
+    }
+}
+
+fn cipher(seed: [u8; 32]) -> ChaCha20Poly1305 {
+    let mut engine = sha256::Hash::engine();
+    engine.input(&seed);
+    engine.input(b"static_channel_backup");
+    ChaCha20Poly1305::new(Key::from_slice(
+        &sha256::Hash::from_engine(engine).into_inner(),
+    ))
+}
+
+/// Channel restored from the static backup, which can't be used for
+/// payments. It only asks the remote peer to force-close the channel and
+/// learns the per-commitment point required to sweep the funds from the
+/// remote commitment.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RecoveryChannel {
+    backup: StaticChannelBackup,
+    local_keys: LocalKeyset,
+    remote_per_commitment_point: Option<PublicKey>,
+}
+
+impl RecoveryChannel {
+    /// Returns static backup from which the channel was restored
+    #[inline]
+    pub fn backup(&self) -> &StaticChannelBackup {
+        &self.backup
+    }
+
+    /// Returns per-commitment point of the latest remote commitment, once it
+    /// is provided by the remote peer in its `channel_reestablish` message
+    #[inline]
+    pub fn remote_per_commitment_point(&self) -> Option<PublicKey> {
+        self.remote_per_commitment_point
+    }
+
+    /// Constructs `channel_reestablish` message with commitment numbers
+    /// reset and no revealed secret, which proves to the remote peer that
+    /// the local node has lost its state (`option_data_loss_protect`)
+    pub fn channel_reestablish(&self) -> ChannelReestablish {
+        ChannelReestablish {
+            channel_id: self.backup.channel_id,
+            next_commitment_number: 1,
+            next_revocation_number: 0,
+            your_last_per_commitment_secret: [0u8; 32],
+            my_current_per_commitment_point: self
+                .local_keys
+                .per_commitment_point(0),
+        }
+    }
+
+    /// Processes `channel_reestablish` received from the remote peer,
+    /// returning per-commitment point of its latest commitment. The point is
+    /// trusted only if the peer proves that it knows the state by revealing
+    /// the last local per-commitment secret.
+    pub fn reestablish(
+        &mut self,
+        message: &ChannelReestablish,
+    ) -> Result<PublicKey, BackupError> {
+        if message.channel_id != self.backup.channel_id {
+            return Err(BackupError::ChannelIdMismatch(
+                message.channel_id,
+                self.backup.channel_id,
+            ));
+        }
+        let revocation_number = message.next_revocation_number;
+        if revocation_number > 0 {
+            let secret =
+                self.local_keys.per_commitment_secret(revocation_number - 1);
+            if *secret.as_ref() != message.your_last_per_commitment_secret {
+                return Err(BackupError::InvalidSecret(revocation_number - 1));
+            }
+        }
+        self.remote_per_commitment_point =
+            Some(message.my_current_per_commitment_point);
+        Ok(message.my_current_per_commitment_point)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use amplify::DumbDefault;
+    use std::str::FromStr;
+    use wallet::SECP256K1_PUBKEY_DUMB;
+
+    use crate::payment::AnnouncedNodeAddr;
+
+    fn backup() -> StaticChannelBackup {
+        StaticChannelBackup {
+            channel_id: ChannelId::with(OutPoint::new(none!(), 1)),
+            remote_node: *SECP256K1_PUBKEY_DUMB,
+            remote_addrs: AddressList::from(vec![AnnouncedNodeAddr::IpV4 {
+                addr: [127, 0, 0, 1],
+                port: 9735,
+            }]),
+            funding_outpoint: OutPoint::new(none!(), 1),
+            derivation_path: DerivationPath::from_str("m/1017'/0'/1'").unwrap(),
+            params: Params {
+                funding_satoshis: 100_000,
+                to_self_delay: 144,
+                ..Params::default()
+            },
+        }
+    }
+
+    #[test]
+    fn encryption() {
+        let backup = backup();
+        let encrypted = backup.encrypt([1u8; 32]).unwrap();
+        assert_eq!(encrypted[0], SCB_VERSION);
+        assert_eq!(
+            StaticChannelBackup::decrypt(&encrypted, [1u8; 32]),
+            Ok(backup.clone())
+        );
+        // Nonce is random, so the same backup is encrypted differently
+        assert_ne!(backup.encrypt([1u8; 32]).unwrap(), encrypted);
+
+        assert_eq!(
+            StaticChannelBackup::decrypt(&encrypted, [2u8; 32]),
+            Err(BackupError::Decryption)
+        );
+        let mut tampered = encrypted.clone();
+        tampered[0] = SCB_VERSION + 1;
+        assert_eq!(
+            StaticChannelBackup::decrypt(&tampered, [1u8; 32]),
+            Err(BackupError::UnsupportedVersion(SCB_VERSION + 1))
+        );
+        let mut tampered = encrypted.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            StaticChannelBackup::decrypt(&tampered, [1u8; 32]),
+            Err(BackupError::Decryption)
+        );
+        assert_eq!(
+            StaticChannelBackup::decrypt(&encrypted[..NONCE_LEN], [1u8; 32]),
+            Err(BackupError::Decryption)
+        );
+    }
+
+    #[test]
+    fn restore() {
+        let local_keys = LocalKeyset::dumb_default();
+        let mut channel = backup().restore(local_keys);
+        let channel_id = channel.backup().channel_id;
+
+        let reestablish = channel.channel_reestablish();
+        assert_eq!(reestablish.channel_id, channel_id);
+        assert_eq!(reestablish.next_commitment_number, 1);
+        assert_eq!(reestablish.next_revocation_number, 0);
+        assert_eq!(reestablish.your_last_per_commitment_secret, [0u8; 32]);
+        assert_eq!(
+            reestablish.my_current_per_commitment_point,
+            local_keys.per_commitment_point(0)
+        );
+        assert_eq!(channel.remote_per_commitment_point(), None);
+
+        let point = local_keys.per_commitment_point(5);
+        let mut remote = ChannelReestablish {
+            channel_id: ChannelId::default(),
+            next_commitment_number: 6,
+            next_revocation_number: 5,
+            your_last_per_commitment_secret: [0u8; 32],
+            my_current_per_commitment_point: point,
+        };
+        assert_eq!(
+            channel.reestablish(&remote),
+            Err(BackupError::ChannelIdMismatch(
+                ChannelId::default(),
+                channel_id
+            ))
+        );
+        remote.channel_id = channel_id;
+        assert_eq!(
+            channel.reestablish(&remote),
+            Err(BackupError::InvalidSecret(4))
+        );
+        assert_eq!(channel.remote_per_commitment_point(), None);
+        remote.your_last_per_commitment_secret = [1u8; 32];
+        assert_eq!(
+            channel.reestablish(&remote),
+            Err(BackupError::InvalidSecret(4))
+        );
+        remote.your_last_per_commitment_secret =
+            *local_keys.per_commitment_secret(4).as_ref();
+        assert_eq!(channel.reestablish(&remote), Ok(point));
+        assert_eq!(channel.remote_per_commitment_point(), Some(point));
+    }
+}
@@ -13,7 +13,7 @@
 
 use amplify::DumbDefault;
 use bitcoin::blockdata::{opcodes::all::*, script};
-use bitcoin::secp256k1::PublicKey;
+use bitcoin::secp256k1::{PublicKey, SecretKey};
 use bitcoin::{OutPoint, Transaction, TxIn, TxOut};
 use wallet::LexOrder;
 use wallet::{
@@ -21,36 +21,196 @@ use wallet::{
     SECP256K1_PUBKEY_DUMB,
 };
 
-use crate::payment::ExtensionId;
-use crate::{channel, ChannelExtension, Extension, Messages};
+use crate::channel::CommitmentOwner;
+use crate::message::{ChannelReestablish, Shutdown, UpdateFee};
+use crate::payment::fee::{CommitmentFee, ANCHOR_OUTPUT_VALUE};
+use crate::payment::htlc::HtlcState;
+use crate::payment::keys::{Basepoints, CommitmentKeys, SECP256K1};
+use crate::payment::reestablish::{
+    ChannelSync, ReestablishAction, ReestablishError,
+};
+use crate::payment::shachain::{ShachainStore, SHACHAIN_MAX_INDEX};
+use crate::payment::signer::{ChannelSigner, SignerError};
+use crate::payment::{ExtensionId, HtlcError, Operation};
+use crate::{
+    channel, ChannelExtension, ChannelId, Extension, InitFeatures, Messages,
+};
+
+/// Default dust limit for the outputs of the local commitment transaction
+pub const DEFAULT_DUST_LIMIT: u64 = 546;
+
+/// Errors of the BOLT-3 channel constructor
+#[derive(
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Debug,
+    Display,
+    Error,
+    StrictEncode,
+    StrictDecode,
+)]
+#[display(doc_comments)]
+pub enum Bolt3Error {
+    /// per-commitment secret revoking remote commitment #{0} is not a valid
+    /// private key
+    InvalidSecret(u64),
+
+    /// per-commitment secret does not match the per-commitment point of the
+    /// revoked remote commitment #{0}
+    SecretMismatch(u64),
+
+    /// per-commitment secret revoking remote commitment #{0} is inconsistent
+    /// with the previously revealed secrets
+    InconsistentSecret(u64),
+
+    /// HTLC of {0} msat exceeds the balance of {1} msat of the party
+    /// offering it
+    InsufficientBalance(u64, u64),
 
-#[derive(Copy, Clone, PartialEq, Eq, Debug, StrictEncode, StrictDecode)]
-struct Keyset {
-    pub revocation_basepoint: PublicKey,
-    pub payment_basepoint: PublicKey,
-    pub delayed_payment_basepoint: PublicKey,
+    /// commitment number can't be increased above {0}
+    CommitmentNumberOverflow(u64),
 }
 
-impl DumbDefault for Keyset {
-    fn dumb_default() -> Self {
-        Self {
-            revocation_basepoint: *SECP256K1_PUBKEY_DUMB,
-            payment_basepoint: *SECP256K1_PUBKEY_DUMB,
-            delayed_payment_basepoint: *SECP256K1_PUBKEY_DUMB,
+/// Form of the commitment transaction defined by the features negotiated
+/// between the channel parties
+#[derive(
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+#[display(Debug)]
+pub enum CommitmentType {
+    /// `to_remote` output pays to the key tweaked with per-commitment point
+    Legacy,
+
+    /// `option_static_remotekey`: `to_remote` output pays directly to the
+    /// counterparty payment basepoint
+    StaticRemotekey,
+
+    /// `option_anchor_outputs`: static `to_remote` key, which is also
+    /// encumbered with a one block CSV lock
+    Anchors,
+
+    /// `option_anchors_zero_fee_htlc_tx`: same as [`CommitmentType::Anchors`]
+    /// with second-stage HTLC transactions signed with zero fee
+    AnchorsZeroFeeHtlcTx,
+}
+
+impl Default for CommitmentType {
+    fn default() -> Self {
+        CommitmentType::Legacy
+    }
+}
+
+impl CommitmentType {
+    /// Detects commitment type from the features supported by both peers
+    pub fn negotiate(local: &InitFeatures, remote: &InitFeatures) -> Self {
+        let both = |local: Option<bool>, remote: Option<bool>| {
+            local.is_some() && remote.is_some()
+        };
+        if both(
+            local.option_anchors_zero_fee_htlc_tx,
+            remote.option_anchors_zero_fee_htlc_tx,
+        ) {
+            CommitmentType::AnchorsZeroFeeHtlcTx
+        } else if both(
+            local.option_anchor_outputs,
+            remote.option_anchor_outputs,
+        ) {
+            CommitmentType::Anchors
+        } else if both(
+            local.option_static_remotekey,
+            remote.option_static_remotekey,
+        ) {
+            CommitmentType::StaticRemotekey
+        } else {
+            CommitmentType::Legacy
         }
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Debug, StrictEncode, StrictDecode)]
+/// HTLC which amount is deducted from the balance of the party offering it
+/// until the HTLC removal is irrevocably committed
+#[derive(Clone, Copy, PartialEq, Eq, Debug, StrictEncode, StrictDecode)]
+struct PendingHtlc {
+    /// Whether the HTLC is offered by the local node
+    offered: bool,
+    id: u64,
+    amount_msat: u64,
+    /// Whether the HTLC is removed by fulfillment, paying the amount to the
+    /// receiving party, or by failure, returning it to the offering party
+    fulfilled: bool,
+    state: HtlcState,
+}
+
+impl PendingHtlc {
+    /// Detects whether the HTLC amount goes to the local balance of the given
+    /// commitment, returning `None` if the HTLC has its own output there
+    fn paid_locally(self, owner: CommitmentOwner) -> Option<bool> {
+        if self.state.in_commitment(owner) {
+            return None;
+        }
+        // Outside of the commitment the HTLC is either not yet added, and
+        // belongs to the offering party, or is already removed
+        let to_offerer = !self.state.is_removing() || !self.fulfilled;
+        Some(self.offered == to_offerer)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, StrictEncode, StrictDecode)]
 pub struct Bolt3 {
-    local_amount: u64,
-    remote_amount: u64,
+    channel_id: ChannelId,
+    local_amount_msat: u64,
+    remote_amount_msat: u64,
+    /// HTLCs which removal is not yet irrevocably committed
+    pending_htlcs: Vec<PendingHtlc>,
+    /// Number of the current local commitment
     commitment_number: u64,
+    /// Number of the remote commitment which is signed next, matching
+    /// `remote_per_commitment_point`
+    remote_commitment_number: u64,
     to_self_delay: u16,
+    remote_to_self_delay: u16,
+    feerate_per_kw: u32,
+    local_dust_limit: u64,
+    remote_dust_limit: u64,
     obscuring_factor: u64,
+    funding_outpoint: OutPoint,
+
+    local_keys: Basepoints,
+    local_per_commitment_point: PublicKey,
+    /// Per-commitment point for the next local commitment, which was sent to
+    /// the remote peer in `funding_locked` or `revoke_and_ack`
+    local_next_per_commitment_point: PublicKey,
+    remote_keys: Basepoints,
+    /// Per-commitment point of the oldest unrevoked remote commitment, which
+    /// is revoked with the next `revoke_and_ack`
+    remote_current_per_commitment_point: PublicKey,
+    remote_per_commitment_point: PublicKey,
+    remote_secrets: ShachainStore,
+    sync: ChannelSync,
 
-    local_keys: Keyset,
-    remote_keys: Keyset,
+    local_features: InitFeatures,
+    remote_features: InitFeatures,
+    commitment_type: CommitmentType,
 
     is_originator: bool,
 }
@@ -62,27 +222,334 @@ impl Bolt3 {
         remote_amount: u64,
         to_self_delay: u16,
     ) -> Self {
-        let dumb_keys = Keyset::dumb_default();
+        Bolt3::with_keys(
+            is_originator,
+            local_amount,
+            remote_amount,
+            to_self_delay,
+            DEFAULT_DUST_LIMIT,
+            Basepoints::dumb_default(),
+            *SECP256K1_PUBKEY_DUMB,
+        )
+    }
+
+    /// Constructs channel with the local basepoints and the per-commitment
+    /// point of the first local commitment provided by the signer
+    pub fn with_signer(
+        is_originator: bool,
+        local_amount: u64,
+        remote_amount: u64,
+        to_self_delay: u16,
+        dust_limit_satoshis: u64,
+        signer: &dyn ChannelSigner,
+    ) -> Result<Self, SignerError> {
+        Ok(Bolt3::with_keys(
+            is_originator,
+            local_amount,
+            remote_amount,
+            to_self_delay,
+            dust_limit_satoshis,
+            signer.basepoints()?,
+            signer.per_commitment_point(0)?,
+        ))
+    }
+
+    /// Constructs channel with the local basepoints and the per-commitment
+    /// point of the first local commitment. Points of the following
+    /// commitments are taken from `funding_locked` and `revoke_and_ack`
+    /// messages sent to the remote peer, so the channel never keeps local
+    /// secrets.
+    pub fn with_keys(
+        is_originator: bool,
+        local_amount: u64,
+        remote_amount: u64,
+        to_self_delay: u16,
+        dust_limit_satoshis: u64,
+        local_keys: Basepoints,
+        first_per_commitment_point: PublicKey,
+    ) -> Self {
+        let remote_keys = Basepoints::dumb_default();
         let obscuring_factor = compute_obscuring_factor(
             is_originator,
-            dumb_keys.payment_basepoint,
-            dumb_keys.payment_basepoint,
+            local_keys.payment_basepoint,
+            remote_keys.payment_basepoint,
         );
         Bolt3 {
-            local_amount,
-            remote_amount,
+            channel_id: ChannelId::default(),
+            local_amount_msat: local_amount * 1000,
+            remote_amount_msat: remote_amount * 1000,
+            pending_htlcs: empty!(),
             commitment_number: 0,
+            remote_commitment_number: 0,
             to_self_delay,
+            remote_to_self_delay: 0,
+            feerate_per_kw: 0,
+            local_dust_limit: dust_limit_satoshis,
+            remote_dust_limit: 0,
             obscuring_factor,
-            local_keys: dumb_keys,
-            remote_keys: dumb_keys,
+            funding_outpoint: OutPoint::default(),
+            local_per_commitment_point: first_per_commitment_point,
+            local_next_per_commitment_point: *SECP256K1_PUBKEY_DUMB,
+            local_keys,
+            remote_keys,
+            remote_current_per_commitment_point: *SECP256K1_PUBKEY_DUMB,
+            remote_per_commitment_point: *SECP256K1_PUBKEY_DUMB,
+            remote_secrets: ShachainStore::new(),
+            sync: ChannelSync::new(),
+            local_features: none!(),
+            remote_features: none!(),
+            commitment_type: CommitmentType::Legacy,
             is_originator,
         }
     }
+
+    /// Returns balance of the local node not including pending HTLCs offered
+    /// by it or paid to it, in milli-satoshis
+    #[inline]
+    pub fn local_amount_msat(&self) -> u64 {
+        self.local_amount_msat
+    }
+
+    /// Returns balance of the remote peer not including pending HTLCs offered
+    /// by it or paid to it, in milli-satoshis
+    #[inline]
+    pub fn remote_amount_msat(&self) -> u64 {
+        self.remote_amount_msat
+    }
+
+    /// Returns number of the current local commitment
+    #[inline]
+    pub fn commitment_number(&self) -> u64 {
+        self.commitment_number
+    }
+
+    /// Returns per-commitment point for the current local commitment
+    #[inline]
+    pub fn local_per_commitment_point(&self) -> PublicKey {
+        self.local_per_commitment_point
+    }
+
+    /// Returns keys for the outputs of the current local commitment
+    /// transaction
+    #[inline]
+    pub fn local_commitment_keys(&self) -> CommitmentKeys {
+        CommitmentKeys::derive(
+            &self.local_keys,
+            &self.remote_keys,
+            self.local_per_commitment_point,
+        )
+    }
+
+    /// Returns keys for the outputs of the current remote commitment
+    /// transaction
+    #[inline]
+    pub fn remote_commitment_keys(&self) -> CommitmentKeys {
+        CommitmentKeys::derive(
+            &self.remote_keys,
+            &self.local_keys,
+            self.remote_per_commitment_point,
+        )
+    }
+
+    /// Returns storage of the per-commitment secrets revealed by the remote
+    /// peer for its revoked commitment transactions
+    #[inline]
+    pub fn remote_secrets(&self) -> &ShachainStore {
+        &self.remote_secrets
+    }
+
+    /// Returns factor obscuring commitment numbers in the commitment
+    /// transactions
+    #[inline]
+    pub fn obscuring_factor(&self) -> u64 {
+        self.obscuring_factor
+    }
+
+    /// Sets funding outpoint for the channel funded by the local node, which
+    /// does not learn it from the `funding_created` message
+    pub fn set_funding_outpoint(&mut self, funding_outpoint: OutPoint) {
+        self.funding_outpoint = funding_outpoint;
+        self.channel_id = ChannelId::with(funding_outpoint);
+    }
+
+    /// Returns commitment transaction type negotiated with the remote peer
+    /// when the channel was opened
+    #[inline]
+    pub fn commitment_type(&self) -> CommitmentType {
+        self.commitment_type
+    }
+
+    /// Sets features supported by the local node, which are used together
+    /// with the features from the remote peer `init` message to select the
+    /// commitment transaction type once `open_channel` or `accept_channel`
+    /// is received
+    #[inline]
+    pub fn set_local_features(&mut self, features: InitFeatures) {
+        self.local_features = features;
+    }
+
+    /// Constructs `channel_reestablish` message which has to be sent to the
+    /// remote peer on reconnection
+    #[inline]
+    pub fn channel_reestablish(
+        &self,
+        signer: &dyn ChannelSigner,
+    ) -> Result<ChannelReestablish, ReestablishError> {
+        self.sync.channel_reestablish(
+            self.channel_id,
+            signer,
+            &self.remote_secrets,
+        )
+    }
+
+    fn balance_mut(&mut self, local: bool) -> &mut u64 {
+        if local {
+            &mut self.local_amount_msat
+        } else {
+            &mut self.remote_amount_msat
+        }
+    }
+
+    /// Deducts the amount of the added HTLC from the balance of the party
+    /// offering it
+    fn add_htlc(
+        &mut self,
+        offered: bool,
+        htlc_id: u64,
+        amount_msat: u64,
+    ) -> Result<(), Bolt3Error> {
+        let balance = self.balance_mut(offered);
+        let available = *balance;
+        *balance = available
+            .checked_sub(amount_msat)
+            .ok_or(Bolt3Error::InsufficientBalance(amount_msat, available))?;
+        self.pending_htlcs.push(PendingHtlc {
+            offered,
+            id: htlc_id,
+            amount_msat,
+            fulfilled: false,
+            state: if offered {
+                HtlcState::SentAddHtlc
+            } else {
+                HtlcState::RcvdAddHtlc
+            },
+        });
+        Ok(())
+    }
+
+    /// Checks that the HTLC update received from the remote peer belongs to
+    /// the channel
+    fn check_channel_id(&self, channel_id: ChannelId) -> Result<(), HtlcError> {
+        if channel_id != self.channel_id {
+            return Err(HtlcError::ChannelIdMismatch(
+                channel_id,
+                self.channel_id,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns HTLC which removal can be started, i.e. the one which is
+    /// irrevocably committed
+    fn removable_htlc(
+        &mut self,
+        offered: bool,
+        htlc_id: u64,
+    ) -> Result<&mut PendingHtlc, HtlcError> {
+        let htlc = self
+            .pending_htlcs
+            .iter_mut()
+            .find(|htlc| htlc.offered == offered && htlc.id == htlc_id)
+            .ok_or(HtlcError::UnknownId(htlc_id))?;
+        if !htlc.state.is_committed() {
+            return Err(HtlcError::NotCommitted(htlc_id));
+        }
+        Ok(htlc)
+    }
+
+    /// Starts removal of the irrevocably committed HTLC
+    fn remove_htlc(
+        &mut self,
+        offered: bool,
+        htlc_id: u64,
+        fulfilled: bool,
+    ) -> Result<(), HtlcError> {
+        let htlc = self.removable_htlc(offered, htlc_id)?;
+        htlc.fulfilled = fulfilled;
+        htlc.state = if offered {
+            HtlcState::RcvdRemoveHtlc
+        } else {
+            HtlcState::SentRemoveHtlc
+        };
+        Ok(())
+    }
+
+    /// Applies commitment update transition to all pending HTLCs, moving
+    /// amounts of the HTLCs which removal is irrevocably committed to the
+    /// party balances
+    fn update_states(&mut self, transition: fn(HtlcState) -> HtlcState) {
+        for htlc in &mut self.pending_htlcs {
+            htlc.state = transition(htlc.state);
+        }
+        let (removed, pending): (Vec<_>, Vec<_>) = self
+            .pending_htlcs
+            .drain(..)
+            .partition(|htlc| htlc.state.is_removed());
+        self.pending_htlcs = pending;
+        for htlc in removed {
+            *self.balance_mut(htlc.offered != htlc.fulfilled) +=
+                htlc.amount_msat;
+        }
+    }
+
+    /// Forgets HTLC updates not covered by the commitment signatures, the
+    /// same way as the HTLC extender does on channel reestablishment
+    fn forget_uncommitted(&mut self) {
+        let (forgotten, pending): (Vec<_>, Vec<_>) =
+            self.pending_htlcs.drain(..).partition(|htlc| {
+                matches!(
+                    htlc.state,
+                    HtlcState::SentAddHtlc | HtlcState::RcvdAddHtlc
+                )
+            });
+        self.pending_htlcs = pending;
+        for htlc in forgotten {
+            *self.balance_mut(htlc.offered) += htlc.amount_msat;
+        }
+        for htlc in &mut self.pending_htlcs {
+            match htlc.state {
+                HtlcState::RcvdRemoveHtlc => {
+                    htlc.state = HtlcState::SentAddAckRevocation
+                }
+                HtlcState::SentRemoveHtlc => {
+                    htlc.state = HtlcState::RcvdAddAckRevocation
+                }
+                _ => continue,
+            }
+            htlc.fulfilled = false;
+        }
+    }
+
+    fn set_remote_keys(&mut self, remote_keys: Basepoints) {
+        self.remote_keys = remote_keys;
+        self.obscuring_factor = compute_obscuring_factor(
+            self.is_originator,
+            self.local_keys.payment_basepoint,
+            remote_keys.payment_basepoint,
+        );
+    }
 }
 
 impl channel::State for Bolt3 {}
 
+/// Returns number of the commitment following the given one
+fn next_commitment(commitment_number: u64) -> Result<u64, Bolt3Error> {
+    commitment_number
+        .checked_add(1)
+        .filter(|next| *next <= SHACHAIN_MAX_INDEX)
+        .ok_or(Bolt3Error::CommitmentNumberOverflow(commitment_number))
+}
+
 impl Extension for Bolt3 {
     type Identity = ExtensionId;
 
@@ -95,79 +562,454 @@ impl Extension for Bolt3 {
         message: &Messages,
     ) -> Result<(), channel::Error> {
         match message {
+            Messages::Init(init) => {
+                // Commitment type is fixed for the channel lifetime, so the
+                // features from `init` sent on reconnection do not change it
+                self.remote_features = init.local_features.clone();
+            }
             Messages::OpenChannel(open_channel) => {
-                self.remote_keys.payment_basepoint = open_channel.payment_point;
-                self.remote_keys.revocation_basepoint =
-                    open_channel.revocation_basepoint;
-                self.remote_keys.delayed_payment_basepoint =
-                    open_channel.delayed_payment_basepoint;
+                self.commitment_type = CommitmentType::negotiate(
+                    &self.local_features,
+                    &self.remote_features,
+                );
+                self.set_remote_keys(Basepoints::from(open_channel));
+                self.remote_current_per_commitment_point =
+                    open_channel.first_per_commitment_point;
+                self.remote_per_commitment_point =
+                    open_channel.first_per_commitment_point;
+                self.remote_to_self_delay = open_channel.to_self_delay;
+                self.remote_dust_limit = open_channel.dust_limit_satoshis;
+                self.feerate_per_kw = open_channel.feerate_per_kw;
             }
             Messages::AcceptChannel(accept_channel) => {
-                self.remote_keys.payment_basepoint =
-                    accept_channel.payment_point;
-                self.remote_keys.revocation_basepoint =
-                    accept_channel.revocation_basepoint;
-                self.remote_keys.delayed_payment_basepoint =
-                    accept_channel.delayed_payment_basepoint;
-            }
-            Messages::FundingCreated(_) => {}
-            Messages::FundingSigned(_) => {}
-            Messages::FundingLocked(_) => {}
+                self.commitment_type = CommitmentType::negotiate(
+                    &self.local_features,
+                    &self.remote_features,
+                );
+                self.set_remote_keys(Basepoints::from(accept_channel));
+                self.remote_current_per_commitment_point =
+                    accept_channel.first_per_commitment_point;
+                self.remote_per_commitment_point =
+                    accept_channel.first_per_commitment_point;
+                self.remote_to_self_delay = accept_channel.to_self_delay;
+                self.remote_dust_limit = accept_channel.dust_limit_satoshis;
+            }
+            Messages::FundingCreated(funding_created) => {
+                self.set_funding_outpoint(OutPoint::new(
+                    funding_created.funding_txid,
+                    funding_created.funding_output_index as u32,
+                ));
+            }
+            Messages::FundingSigned(funding_signed) => {
+                self.channel_id = funding_signed.channel_id;
+            }
+            Messages::FundingLocked(funding_locked) => {
+                // Retransmitted `funding_locked` must not reset the remote
+                // commitment number
+                if self.remote_commitment_number == 0 {
+                    self.remote_per_commitment_point =
+                        funding_locked.next_per_commitment_point;
+                    self.remote_commitment_number = 1;
+                }
+            }
             Messages::Shutdown(_) => {}
             Messages::ClosingSigned(_) => {}
-            Messages::UpdateAddHtlc(_) => {}
-            Messages::UpdateFulfillHtlc(_) => {}
-            Messages::UpdateFailHtlc(_) => {}
-            Messages::UpdateFailMalformedHtlc(_) => {}
-            Messages::CommitmentSigned(_) => {}
-            Messages::RevokeAndAck(_) => {}
-            Messages::ChannelReestablish(_) => {}
+            // HTLC updates are only checked here and applied to the balances
+            // in `message_received`, once the HTLC extender accepts them
+            Messages::UpdateAddHtlc(message) => {
+                self.check_channel_id(message.channel_id)?;
+                if message.amount_msat > self.remote_amount_msat {
+                    return Err(Bolt3Error::InsufficientBalance(
+                        message.amount_msat,
+                        self.remote_amount_msat,
+                    )
+                    .into());
+                }
+            }
+            Messages::UpdateFulfillHtlc(message) => {
+                self.check_channel_id(message.channel_id)?;
+                self.removable_htlc(true, message.htlc_id)?;
+            }
+            Messages::UpdateFailHtlc(message) => {
+                self.check_channel_id(message.channel_id)?;
+                self.removable_htlc(true, message.htlc_id)?;
+            }
+            Messages::UpdateFailMalformedHtlc(message) => {
+                self.check_channel_id(message.channel_id)?;
+                self.removable_htlc(true, message.htlc_id)?;
+            }
+            Messages::CommitmentSigned(_) => {
+                self.sync.message_received(message);
+                self.commitment_number =
+                    next_commitment(self.commitment_number)?;
+                self.local_per_commitment_point =
+                    self.local_next_per_commitment_point;
+                self.update_states(HtlcState::on_commitment_received);
+            }
+            Messages::RevokeAndAck(revoke_and_ack) => {
+                // When all secrets are already received, the store will
+                // return an error for zero index
+                let index = self.remote_secrets.next_index().unwrap_or(0);
+                let revoked_number = SHACHAIN_MAX_INDEX - index;
+                let secret = SecretKey::from_slice(
+                    &revoke_and_ack.per_commitment_secret,
+                )
+                .map_err(|_| Bolt3Error::InvalidSecret(revoked_number))?;
+                if PublicKey::from_secret_key(&SECP256K1, &secret)
+                    != self.remote_current_per_commitment_point
+                {
+                    return Err(
+                        Bolt3Error::SecretMismatch(revoked_number).into()
+                    );
+                }
+                self.remote_secrets
+                    .insert(index, revoke_and_ack.per_commitment_secret)
+                    .map_err(|_| {
+                        Bolt3Error::InconsistentSecret(revoked_number)
+                    })?;
+
+                self.sync.message_received(message);
+                self.remote_commitment_number =
+                    next_commitment(self.remote_commitment_number)?;
+                self.update_states(HtlcState::on_revocation_received);
+                self.remote_current_per_commitment_point =
+                    self.remote_per_commitment_point;
+                self.remote_per_commitment_point =
+                    revoke_and_ack.next_per_commitment_point;
+            }
+            Messages::UpdateFee(update_fee) => {
+                // Only the party paying the commitment fee may update it
+                if self.is_originator {
+                    return Err(channel::Error::Extension(s!(
+                        "update_fee received from the peer which does not \
+                         pay the channel fees"
+                    )));
+                }
+                self.feerate_per_kw = update_fee.feerate_per_kw;
+            }
+            Messages::ChannelReestablish(_) => {
+                // Otherwise the messages to retransmit would be lost
+                return Err(channel::Error::Extension(s!(
+                    "channel_reestablish must be processed with \
+                     `Channel::reestablish`"
+                )));
+            }
             _ => {}
         }
         Ok(())
     }
 
-    fn extension_state(&self) -> Box<dyn channel::State> {
-        Box::new(*self)
+    fn update_from_local(
+        &mut self,
+        operation: &Operation,
+    ) -> Result<Vec<Messages>, channel::Error> {
+        let messages = match operation {
+            Operation::UpdateFee { feerate_per_kw } => {
+                if !self.is_originator {
+                    return Err(channel::Error::Extension(s!(
+                        "only the channel funder may update channel fees"
+                    )));
+                }
+                self.feerate_per_kw = *feerate_per_kw;
+                vec![Messages::UpdateFee(UpdateFee {
+                    channel_id: self.channel_id,
+                    feerate_per_kw: *feerate_per_kw,
+                })]
+            }
+            Operation::Shutdown { scriptpubkey } => {
+                vec![Messages::Shutdown(Shutdown {
+                    channel_id: self.channel_id,
+                    scriptpubkey: scriptpubkey.clone(),
+                })]
+            }
+            Operation::AddHtlc { amount_msat, .. } => {
+                // Checked before the HTLC extender accepts the HTLC; the
+                // balance is debited once the HTLC id is known from the
+                // produced `update_add_htlc`
+                if *amount_msat > self.local_amount_msat {
+                    return Err(Bolt3Error::InsufficientBalance(
+                        *amount_msat,
+                        self.local_amount_msat,
+                    )
+                    .into());
+                }
+                vec![]
+            }
+            _ => vec![],
+        };
+        Ok(messages)
+    }
+
+    fn extension_state(&self) -> Vec<u8> {
+        channel::State::to_state_data(self)
     }
 }
 
 impl ChannelExtension for Bolt3 {
-    fn channel_state(&self) -> Box<dyn channel::State> {
-        Box::new(*self)
+    fn channel_state(&self) -> Vec<u8> {
+        channel::State::to_state_data(self)
+    }
+
+    fn message_received(
+        &mut self,
+        message: &Messages,
+    ) -> Result<(), channel::Error> {
+        match message {
+            Messages::UpdateAddHtlc(message) => {
+                self.add_htlc(false, message.htlc_id, message.amount_msat)?
+            }
+            Messages::UpdateFulfillHtlc(message) => {
+                self.remove_htlc(true, message.htlc_id, true)?
+            }
+            Messages::UpdateFailHtlc(message) => {
+                self.remove_htlc(true, message.htlc_id, false)?
+            }
+            Messages::UpdateFailMalformedHtlc(message) => {
+                self.remove_htlc(true, message.htlc_id, false)?
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn operation_accepted(
+        &mut self,
+        operation: &Operation,
+    ) -> Result<(), channel::Error> {
+        match operation {
+            Operation::SignCommitment => {
+                self.update_states(HtlcState::on_commitment_sent)
+            }
+            Operation::RevokeCommitment => {
+                self.update_states(HtlcState::on_revocation_sent)
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Also registers the messages which may need to be retransmitted on
+    /// channel reestablishment
+    fn messages_sent(
+        &mut self,
+        messages: &[Messages],
+    ) -> Result<(), channel::Error> {
+        for message in messages {
+            match message {
+                Messages::UpdateAddHtlc(message) => {
+                    self.add_htlc(true, message.htlc_id, message.amount_msat)?
+                }
+                Messages::UpdateFulfillHtlc(message) => {
+                    self.remove_htlc(false, message.htlc_id, true)?
+                }
+                Messages::UpdateFailHtlc(message) => {
+                    self.remove_htlc(false, message.htlc_id, false)?
+                }
+                Messages::UpdateFailMalformedHtlc(message) => {
+                    self.remove_htlc(false, message.htlc_id, false)?
+                }
+                // Retransmitted `funding_locked` must not replace the point
+                // sent in `revoke_and_ack`
+                Messages::FundingLocked(funding_locked)
+                    if self.commitment_number == 0 =>
+                {
+                    self.local_next_per_commitment_point =
+                        funding_locked.next_per_commitment_point
+                }
+                Messages::RevokeAndAck(revoke_and_ack) => {
+                    self.local_next_per_commitment_point =
+                        revoke_and_ack.next_per_commitment_point
+                }
+                _ => {}
+            }
+            self.sync.message_sent(message);
+        }
+        Ok(())
+    }
+
+    /// If the remote peer proves that the local state was lost, its current
+    /// per-commitment point is remembered, allowing to claim funds once the
+    /// peer closes the channel.
+    fn reestablish(
+        &mut self,
+        message: &ChannelReestablish,
+        signer: &dyn ChannelSigner,
+    ) -> Result<ReestablishAction, channel::Error> {
+        let action = self.sync.reestablish(message, self.channel_id, signer)?;
+        if let ReestablishAction::AwaitForceClose(point) = action {
+            self.remote_per_commitment_point = point;
+        }
+        self.forget_uncommitted();
+        Ok(action)
     }
 
     fn apply(
         &mut self,
         tx_graph: &mut channel::TxGraph,
     ) -> Result<(), channel::Error> {
-        // The 48-bit commitment number is obscured by XOR with the lower
-        // 48 bits of `obscuring_factor`
-        let obscured_commitment = (self.commitment_number & 0xFFFFFF)
-            ^ (self.obscuring_factor & 0xFFFFFF);
-        let obscured_commitment = obscured_commitment as u32;
-        let lock_time = (0x20u32 << 24) | obscured_commitment;
-        let sequence = (0x80u32 << 24) | obscured_commitment;
+        // HTLCs present in the commitment get their own outputs from the HTLC
+        // extender; amounts of the rest are returned to the party balances
+        let owner = tx_graph.cmt_owner;
+        let (mut local_msat, mut remote_msat) =
+            (self.local_amount_msat, self.remote_amount_msat);
+        for htlc in &self.pending_htlcs {
+            match htlc.paid_locally(owner) {
+                Some(true) => local_msat += htlc.amount_msat,
+                Some(false) => remote_msat += htlc.amount_msat,
+                None => {}
+            }
+        }
+
+        // Each of the parties is the "local" one in its own commitment
+        // transaction; the delay is imposed on the owner by its counterparty
+        let (
+            commitment_number,
+            keys,
+            to_local_msat,
+            to_remote_msat,
+            to_self_delay,
+            dust_limit,
+            basepoint,
+        ) = match owner {
+            CommitmentOwner::Local => (
+                self.commitment_number,
+                self.local_commitment_keys(),
+                local_msat,
+                remote_msat,
+                self.remote_to_self_delay,
+                self.local_dust_limit,
+                self.remote_keys.payment_basepoint,
+            ),
+            CommitmentOwner::Remote => (
+                self.remote_commitment_number,
+                self.remote_commitment_keys(),
+                remote_msat,
+                local_msat,
+                self.to_self_delay,
+                self.remote_dust_limit,
+                self.local_keys.payment_basepoint,
+            ),
+        };
+        let (lock_time, sequence) =
+            obscured_commitment(commitment_number, self.obscuring_factor);
+        // Sub-satoshi amounts go to the commitment fee
+        let to_local = to_local_msat / 1000;
+        let to_remote = to_remote_msat / 1000;
+        let to_remote_output = match self.commitment_type {
+            CommitmentType::Legacy => {
+                TxOut::ln_to_remote_v1(to_remote, keys.remotepubkey)
+            }
+            CommitmentType::StaticRemotekey => {
+                TxOut::ln_to_remote_v1(to_remote, basepoint)
+            }
+            CommitmentType::Anchors | CommitmentType::AnchorsZeroFeeHtlcTx => {
+                TxOut::ln_to_remote_v2(to_remote, basepoint)
+            }
+        };
 
+        tx_graph.set_funding_outpoint(self.funding_outpoint);
         tx_graph.cmt_version = 2;
         tx_graph.cmt_locktime = lock_time;
         tx_graph.cmt_sequence = sequence;
-        // We are doing counterparty's transaction!
+        tx_graph.cmt_fee = CommitmentFee::with(
+            self.feerate_per_kw,
+            self.commitment_type == CommitmentType::Anchors,
+            self.commitment_type == CommitmentType::AnchorsZeroFeeHtlcTx,
+        );
+        tx_graph.cmt_anchors = none!();
+        tx_graph.cmt_dust_limit = dust_limit;
+        tx_graph.cmt_to_self_delay = to_self_delay;
+        tx_graph.cmt_per_commitment_point = keys.per_commitment_point;
+        // `to_local` and `to_remote` outputs always go first, so we can find
+        // them in `finalize` after extenders have added their outputs
         tx_graph.cmt_outs = vec![
             TxOut::ln_to_local(
-                self.remote_amount,
-                self.local_keys.revocation_basepoint,
-                self.remote_keys.delayed_payment_basepoint,
-                self.to_self_delay,
-            ),
-            TxOut::ln_to_remote_v1(
-                self.local_amount,
-                self.local_keys.payment_basepoint,
+                to_local,
+                keys.revocationpubkey,
+                keys.local_delayedpubkey,
+                to_self_delay,
             ),
+            to_remote_output,
         ];
 
         Ok(())
     }
+
+    fn finalize(
+        &mut self,
+        tx_graph: &mut channel::TxGraph,
+    ) -> Result<(), channel::Error> {
+        // Extenders have accounted their HTLCs in the commitment fee
+        let mut fee = tx_graph.cmt_fee.compute();
+
+        // Anchor outputs, if provided by an extender, are paid by the funder
+        // as well
+        let anchors = std::mem::take(&mut tx_graph.cmt_anchors);
+        if !anchors.is_empty() {
+            tx_graph.cmt_fee.anchors_amount = 2 * ANCHOR_OUTPUT_VALUE;
+            fee += tx_graph.cmt_fee.anchors_amount;
+        }
+
+        // Commitment fee is paid by the channel funder
+        let funder_index = match (tx_graph.cmt_owner, self.is_originator) {
+            (CommitmentOwner::Local, true)
+            | (CommitmentOwner::Remote, false) => 0,
+            _ => 1,
+        };
+        let funder_output = &mut tx_graph.cmt_outs[funder_index];
+        funder_output.value = funder_output.value.saturating_sub(fee);
+
+        // Removing the second output first keeps the index of the first one
+        let dust_limit = tx_graph.cmt_dust_limit;
+        let mut present = [true; 2];
+        for index in [1usize, 0].iter() {
+            if tx_graph.cmt_outs[*index].value < dust_limit {
+                let output = tx_graph.remove_cmt_out(*index);
+                tx_graph.cmt_fee.trimmed_output_amount += output.value;
+                present[*index] = false;
+            }
+        }
+
+        // Anchor is omitted for a party without `to_local`/`to_remote`
+        // output unless there are untrimmed HTLCs in the transaction
+        let has_htlcs = tx_graph.cmt_fee.htlc_count > 0;
+        for (anchor, present) in anchors.into_iter().zip(present.iter()) {
+            if *present || has_htlcs {
+                tx_graph.cmt_outs.push(anchor);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Computes commitment transaction `lock_time` and input `sequence` encoding
+/// 48-bit commitment number obscured by XOR with the lower 48 bits of the
+/// `obscuring_factor`
+fn obscured_commitment(
+    commitment_number: u64,
+    obscuring_factor: u64,
+) -> (u32, u32) {
+    let obscured = (commitment_number ^ obscuring_factor) & 0xFFFF_FFFF_FFFF;
+    let lock_time = (0x20u32 << 24) | (obscured & 0xFF_FFFF) as u32;
+    let sequence = (0x80u32 << 24) | (obscured >> 24) as u32;
+    (lock_time, sequence)
+}
+
+/// Recovers commitment number from the commitment transaction `lock_time`
+/// and input `sequence`. Returns `None` if they do not have the upper bytes
+/// marking commitment transactions, i.e. the transaction is not a commitment.
+pub fn commitment_number(
+    lock_time: u32,
+    sequence: u32,
+    obscuring_factor: u64,
+) -> Option<u64> {
+    if lock_time >> 24 != 0x20 || sequence >> 24 != 0x80 {
+        return None;
+    }
+    let obscured =
+        ((sequence & 0xFF_FFFF) as u64) << 24 | (lock_time & 0xFF_FFFF) as u64;
+    Some((obscured ^ obscuring_factor) & 0xFFFF_FFFF_FFFF)
 }
 
 pub trait ScriptGenerators {
@@ -404,13 +1246,8 @@ impl TxGenerators for Transaction {
         local_delayedpubkey: PublicKey,
         to_self_delay: u16,
     ) -> Self {
-        // The 48-bit commitment number is obscured by XOR with the lower
-        // 48 bits of `obscuring_factor`
-        let obscured_commitment =
-            (commitment_number & 0xFFFFFF) ^ (obscuring_factor & 0xFFFFFF);
-        let obscured_commitment = obscured_commitment as u32;
-        let lock_time = (0x20u32 << 24) | obscured_commitment;
-        let sequence = (0x80u32 << 24) | obscured_commitment;
+        let (lock_time, sequence) =
+            obscured_commitment(commitment_number, obscuring_factor);
         let tx = Transaction {
             version: 2,
             lock_time,
@@ -479,3 +1316,1140 @@ impl TxGenerators for Psbt {
             .expect("Tx has empty sigs so PSBT creation does not faile")
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use amplify::Wrapper;
+    use bitcoin::consensus::serialize;
+    use bitcoin::hashes::hex::{FromHex, ToHex};
+    use bitcoin::secp256k1::{SecretKey, Signature};
+    use bitcoin::Txid;
+    use std::collections::BTreeMap;
+    use strict_encoding::strict_deserialize;
+    use wallet::{HashLock, HashPreimage};
+
+    use crate::channel::SignatureError;
+    use crate::channel::{Channel, TxGraph};
+    use crate::message::{
+        AcceptChannel, CommitmentSigned, FundingLocked, FundingSigned, Init,
+        RevokeAndAck, UpdateAddHtlc, UpdateFulfillHtlc,
+    };
+    use crate::payment::anchor_out::ScriptGenerators as _;
+    use crate::payment::channel::Params;
+    use crate::payment::htlc::TxFinalizers;
+    use crate::payment::keys::{
+        derive_privkey, derive_pubkey, LocalKeyset, SECP256K1,
+    };
+    use crate::payment::reestablish::ReestablishError;
+    use crate::payment::signer::InMemorySigner;
+    use crate::payment::{AnchorOut, Bolt3Ordering, Htlc, TxType};
+    use crate::OnionPacket;
+
+    // Keys and HTLCs from BOLT-3 Appendix C: Commitment and HTLC
+    // Transaction Test Vectors
+    fn pubkey(secret: [u8; 32]) -> PublicKey {
+        PublicKey::from_secret_key(
+            &SECP256K1,
+            &SecretKey::from_slice(&secret).unwrap(),
+        )
+    }
+
+    fn local_basepoints() -> Basepoints {
+        Basepoints {
+            revocation_basepoint: pubkey([0x11; 32]),
+            payment_basepoint: pubkey([0x11; 32]),
+            delayed_payment_basepoint: pubkey([0x33; 32]),
+            htlc_basepoint: pubkey([0x11; 32]),
+        }
+    }
+
+    fn local_keys() -> LocalKeyset {
+        LocalKeyset {
+            basepoints: local_basepoints(),
+            per_commitment_seed: [0u8; 32],
+        }
+    }
+
+    fn funding_pubkey(hex: &str) -> PublicKey {
+        PublicKey::from_slice(&Vec::<u8>::from_hex(hex).unwrap()).unwrap()
+    }
+
+    fn local_funding_pubkey() -> PublicKey {
+        funding_pubkey(
+            "023da092f6980e58d2c037173180e9a465476026ee50f96695963e8efe436f54eb",
+        )
+    }
+
+    fn remote_funding_pubkey() -> PublicKey {
+        funding_pubkey(
+            "030e9f7b623d2ccc7c9bd44d66d5ce21ce504c0acf6385a132cec6d3c39fa711c1",
+        )
+    }
+
+    fn accept_channel() -> Messages {
+        Messages::AcceptChannel(AcceptChannel {
+            temporary_channel_id: DumbDefault::dumb_default(),
+            dust_limit_satoshis: DEFAULT_DUST_LIMIT,
+            max_htlc_value_in_flight_msat: 10_000_000_000,
+            channel_reserve_satoshis: 0,
+            htlc_minimum_msat: 0,
+            minimum_depth: 0,
+            to_self_delay: 144,
+            max_accepted_htlcs: 483,
+            funding_pubkey: remote_funding_pubkey(),
+            revocation_basepoint: pubkey([0x22; 32]),
+            payment_point: pubkey([0x44; 32]),
+            delayed_payment_basepoint: pubkey([0x44; 32]),
+            htlc_basepoint: pubkey([0x44; 32]),
+            first_per_commitment_point: remote_keys().per_commitment_point(0),
+        })
+    }
+
+    fn funding_locked(channel_id: ChannelId) -> Messages {
+        Messages::FundingLocked(FundingLocked {
+            channel_id,
+            next_per_commitment_point: remote_keys().per_commitment_point(1),
+        })
+    }
+
+    /// Exchanges `funding_locked` messages with the remote peer
+    fn lock_funding(channel: &mut Channel<ExtensionId>, channel_id: ChannelId) {
+        channel
+            .update_from_peer(&funding_locked(channel_id))
+            .unwrap();
+        channel
+            .message_sent(&Messages::FundingLocked(FundingLocked {
+                channel_id,
+                next_per_commitment_point: local_keys().per_commitment_point(1),
+            }))
+            .unwrap();
+    }
+
+    fn hashlock(byte: u8) -> HashLock {
+        HashLock::from(HashPreimage::from_inner(amplify::Slice32::from_inner(
+            [byte; 32],
+        )))
+    }
+
+    // (amount, cltv_expiry, preimage byte)
+    type HtlcVector = (u64, u32, u8);
+
+    const RECEIVED_HTLCS: [HtlcVector; 3] =
+        [(1000, 500, 0), (2000, 501, 1), (4000, 504, 4)];
+    const OFFERED_HTLCS: [HtlcVector; 2] = [(2000, 502, 2), (3000, 503, 3)];
+
+    fn remote_keys() -> LocalKeyset {
+        LocalKeyset {
+            basepoints: Basepoints::dumb_default(),
+            per_commitment_seed: [0x99; 32],
+        }
+    }
+
+    fn bolt3_state(channel: &Channel<ExtensionId>) -> Bolt3 {
+        strict_deserialize(&channel.integral_state()[&ExtensionId::Bolt3])
+            .unwrap()
+    }
+
+    /// Signs the remote commitment and receives signature for the local
+    /// one, with revocation of the previous commitments by both peers. Each
+    /// of the peers reveals secret for `commitment_number`, sending point for
+    /// the commitment following the one which was just signed.
+    fn commitment_round(
+        channel: &mut Channel<ExtensionId>,
+        channel_id: ChannelId,
+        commitment_number: u64,
+    ) {
+        let remote_keys = remote_keys();
+        let mut per_commitment_secret = [0u8; 32];
+        per_commitment_secret.copy_from_slice(
+            &remote_keys.per_commitment_secret(commitment_number)[..],
+        );
+        channel
+            .update_from_local(&Operation::SignCommitment)
+            .unwrap();
+        channel
+            .update_from_peer(&Messages::RevokeAndAck(RevokeAndAck {
+                channel_id,
+                per_commitment_secret,
+                next_per_commitment_point: remote_keys
+                    .per_commitment_point(commitment_number + 2),
+            }))
+            .unwrap();
+        channel
+            .update_from_peer(&Messages::CommitmentSigned(CommitmentSigned {
+                channel_id,
+                signature: Signature::from_compact(&[1u8; 64]).unwrap(),
+                htlc_signatures: vec![],
+            }))
+            .unwrap();
+        channel
+            .update_from_local(&Operation::RevokeCommitment)
+            .unwrap();
+        per_commitment_secret.copy_from_slice(
+            &local_keys().per_commitment_secret(commitment_number)[..],
+        );
+        channel
+            .message_sent(&Messages::RevokeAndAck(RevokeAndAck {
+                channel_id,
+                per_commitment_secret,
+                next_per_commitment_point: local_keys()
+                    .per_commitment_point(commitment_number + 2),
+            }))
+            .unwrap();
+    }
+
+    fn commitment_graph(
+        to_local: u64,
+        feerate_per_kw: u32,
+        received: &[HtlcVector],
+        offered: &[HtlcVector],
+    ) -> TxGraph {
+        commitment_graph_with_features(
+            InitFeatures::default(),
+            to_local,
+            feerate_per_kw,
+            received,
+            offered,
+        )
+    }
+
+    fn commitment_graph_with_features(
+        features: InitFeatures,
+        to_local: u64,
+        feerate_per_kw: u32,
+        received: &[HtlcVector],
+        offered: &[HtlcVector],
+    ) -> TxGraph {
+        // Channel balances are reduced by the HTLC amounts once the HTLCs are
+        // added
+        let htlc_sum = |htlcs: &[HtlcVector]| -> u64 {
+            htlcs.iter().map(|(amount, ..)| amount).sum()
+        };
+        let mut bolt3 = Bolt3::with_keys(
+            true,
+            to_local + htlc_sum(offered),
+            3_000_000 + htlc_sum(received),
+            144,
+            DEFAULT_DUST_LIMIT,
+            local_basepoints(),
+            local_keys().per_commitment_point(0),
+        );
+        let funding_outpoint = OutPoint::new(
+            Txid::from_hex(
+                "8984484a580b825b9972d7adb15050b3ab624ccd731946b3eeddb92f4e7ef6be",
+            )
+            .unwrap(),
+            0,
+        );
+        let channel_id = ChannelId::with(funding_outpoint);
+        bolt3.set_funding_outpoint(funding_outpoint);
+        bolt3.set_local_features(features.clone());
+        bolt3.feerate_per_kw = feerate_per_kw;
+
+        let params = Params {
+            max_htlc_value_in_flight_msat: 10_000_000_000,
+            max_accepted_htlcs: 483,
+            ..Params::default()
+        };
+        let mut channel = Channel::with(
+            bolt3,
+            vec![Htlc::with(local_basepoints(), &params)],
+            vec![Bolt3Ordering],
+        );
+        channel.add_extender(AnchorOut::with(local_funding_pubkey()));
+        channel
+            .update_from_peer(&Messages::Init(Init {
+                global_features: none!(),
+                local_features: features,
+                assets: none!(),
+                unknown_tlvs: none!(),
+            }))
+            .unwrap();
+        channel.update_from_peer(&accept_channel()).unwrap();
+        channel
+            .update_from_peer(&Messages::FundingSigned(FundingSigned {
+                channel_id,
+                signature: Signature::from_compact(&[1u8; 64]).unwrap(),
+            }))
+            .unwrap();
+        lock_funding(&mut channel, channel_id);
+
+        for (htlc_id, (amount, cltv_expiry, preimage)) in
+            received.iter().enumerate()
+        {
+            channel
+                .update_from_peer(&Messages::UpdateAddHtlc(UpdateAddHtlc {
+                    channel_id,
+                    htlc_id: htlc_id as u64,
+                    amount_msat: amount * 1000,
+                    payment_hash: hashlock(*preimage),
+                    cltv_expiry: *cltv_expiry,
+                    onion_routing_packet: OnionPacket::dumb_default(),
+                    asset_id: None,
+                }))
+                .unwrap();
+        }
+        for (amount, cltv_expiry, preimage) in offered {
+            channel
+                .update_from_local(&Operation::AddHtlc {
+                    amount_msat: amount * 1000,
+                    payment_hash: hashlock(*preimage),
+                    cltv_expiry: *cltv_expiry,
+                    onion_routing_packet: OnionPacket::dumb_default(),
+                    asset_id: None,
+                })
+                .unwrap();
+        }
+
+        // Two commitment rounds lock in the HTLCs added by both peers
+        for commitment_number in 0..2 {
+            commitment_round(&mut channel, channel_id, commitment_number);
+        }
+
+        // Test vectors use commitment number and per-commitment point which
+        // can't be reached with the commitment rounds above
+        let mut state = channel.integral_state();
+        let mut bolt3 = bolt3_state(&channel);
+        assert_eq!(bolt3.local_amount_msat, to_local * 1000);
+        assert_eq!(bolt3.remote_amount_msat, 3_000_000_000);
+        assert_eq!(bolt3.commitment_number, 2);
+        bolt3.commitment_number = 42;
+        bolt3.local_per_commitment_point = PublicKey::from_secret_key(
+            &SECP256K1,
+            &SecretKey::from_slice(
+                &Vec::<u8>::from_hex(
+                    "1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100",
+                )
+                .unwrap(),
+            )
+            .unwrap(),
+        );
+        state.insert(ExtensionId::Bolt3, channel::State::to_state_data(&bolt3));
+        let channel = Channel::<ExtensionId>::restore(state).unwrap();
+
+        let mut tx_graph = TxGraph::with_owner(CommitmentOwner::Local);
+        channel.apply(&mut tx_graph).unwrap();
+        tx_graph
+    }
+
+    fn commitment_tx(
+        to_local: u64,
+        feerate_per_kw: u32,
+        received: &[HtlcVector],
+        offered: &[HtlcVector],
+    ) -> String {
+        let tx_graph =
+            commitment_graph(to_local, feerate_per_kw, received, offered);
+        serialize(&tx_graph.render_cmt().global.unsigned_tx).to_hex()
+    }
+
+    #[test]
+    fn obscured_commitment_number() {
+        assert_eq!(
+            obscured_commitment(42, 0x2bb038521914),
+            (0x2052_193e, 0x802b_b038)
+        );
+        assert_eq!(
+            commitment_number(0x2052_193e, 0x802b_b038, 0x2bb038521914),
+            Some(42)
+        );
+        assert_eq!(
+            commitment_number(0x2052_193e, 0xffff_ffff, 0x2bb038521914),
+            None
+        );
+    }
+
+    #[test]
+    fn simple_commitment_tx() {
+        assert_eq!(
+            commitment_tx(7_000_000, 15000, &[], &[]),
+            "0200000001bef67e4e2fb9ddeeb3461973cd4c62abb35050b1add772995b820b584a488489000000000038b02b8002c0c62d0000000000160014ccf1af2f2aabee14bb40fa3851ab2301de84311054a56a00000000002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d857accc862d6b7dd80e3e195220"
+        );
+    }
+
+    #[test]
+    fn commitment_tx_with_htlcs() {
+        let vectors = [
+            (0, "0200000001bef67e4e2fb9ddeeb3461973cd4c62abb35050b1add772995b820b584a488489000000000038b02b8007e80300000000000022002052bfef0479d7b293c27e0f1eb294bea154c63a3294ef092c19af51409bce0e2ad007000000000000220020403d394747cae42e98ff01734ad5c08f82ba123d3d9a620abda88989651e2ab5d007000000000000220020748eba944fedc8827f6b06bc44678f93c0f9e6078b35c6331ed31e75f8ce0c2db80b000000000000220020c20b5d1f8584fd90443e7b7b720136174fa4b9333c261d04dbbd012635c0f419a00f0000000000002200208c48d15160397c9731df9bc3b236656efb6665fbfe92b4a6878e88a499f741c4c0c62d0000000000160014ccf1af2f2aabee14bb40fa3851ab2301de843110e0a06a00000000002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d857accc862d6b7dd80e3e195220"),
+            (647, "0200000001bef67e4e2fb9ddeeb3461973cd4c62abb35050b1add772995b820b584a488489000000000038b02b8007e80300000000000022002052bfef0479d7b293c27e0f1eb294bea154c63a3294ef092c19af51409bce0e2ad007000000000000220020403d394747cae42e98ff01734ad5c08f82ba123d3d9a620abda88989651e2ab5d007000000000000220020748eba944fedc8827f6b06bc44678f93c0f9e6078b35c6331ed31e75f8ce0c2db80b000000000000220020c20b5d1f8584fd90443e7b7b720136174fa4b9333c261d04dbbd012635c0f419a00f0000000000002200208c48d15160397c9731df9bc3b236656efb6665fbfe92b4a6878e88a499f741c4c0c62d0000000000160014ccf1af2f2aabee14bb40fa3851ab2301de843110e09c6a00000000002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d857accc862d6b7dd80e3e195220"),
+            (648, "0200000001bef67e4e2fb9ddeeb3461973cd4c62abb35050b1add772995b820b584a488489000000000038b02b8006d007000000000000220020403d394747cae42e98ff01734ad5c08f82ba123d3d9a620abda88989651e2ab5d007000000000000220020748eba944fedc8827f6b06bc44678f93c0f9e6078b35c6331ed31e75f8ce0c2db80b000000000000220020c20b5d1f8584fd90443e7b7b720136174fa4b9333c261d04dbbd012635c0f419a00f0000000000002200208c48d15160397c9731df9bc3b236656efb6665fbfe92b4a6878e88a499f741c4c0c62d0000000000160014ccf1af2f2aabee14bb40fa3851ab2301de8431104e9d6a00000000002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d857accc862d6b7dd80e3e195220"),
+            (2069, "0200000001bef67e4e2fb9ddeeb3461973cd4c62abb35050b1add772995b820b584a488489000000000038b02b8006d007000000000000220020403d394747cae42e98ff01734ad5c08f82ba123d3d9a620abda88989651e2ab5d007000000000000220020748eba944fedc8827f6b06bc44678f93c0f9e6078b35c6331ed31e75f8ce0c2db80b000000000000220020c20b5d1f8584fd90443e7b7b720136174fa4b9333c261d04dbbd012635c0f419a00f0000000000002200208c48d15160397c9731df9bc3b236656efb6665fbfe92b4a6878e88a499f741c4c0c62d0000000000160014ccf1af2f2aabee14bb40fa3851ab2301de84311077956a00000000002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d857accc862d6b7dd80e3e195220"),
+            (2070, "0200000001bef67e4e2fb9ddeeb3461973cd4c62abb35050b1add772995b820b584a488489000000000038b02b8005d007000000000000220020403d394747cae42e98ff01734ad5c08f82ba123d3d9a620abda88989651e2ab5b80b000000000000220020c20b5d1f8584fd90443e7b7b720136174fa4b9333c261d04dbbd012635c0f419a00f0000000000002200208c48d15160397c9731df9bc3b236656efb6665fbfe92b4a6878e88a499f741c4c0c62d0000000000160014ccf1af2f2aabee14bb40fa3851ab2301de843110da966a00000000002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d857accc862d6b7dd80e3e195220"),
+            (2194, "0200000001bef67e4e2fb9ddeeb3461973cd4c62abb35050b1add772995b820b584a488489000000000038b02b8005d007000000000000220020403d394747cae42e98ff01734ad5c08f82ba123d3d9a620abda88989651e2ab5b80b000000000000220020c20b5d1f8584fd90443e7b7b720136174fa4b9333c261d04dbbd012635c0f419a00f0000000000002200208c48d15160397c9731df9bc3b236656efb6665fbfe92b4a6878e88a499f741c4c0c62d0000000000160014ccf1af2f2aabee14bb40fa3851ab2301de84311040966a00000000002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d857accc862d6b7dd80e3e195220"),
+            (2195, "0200000001bef67e4e2fb9ddeeb3461973cd4c62abb35050b1add772995b820b584a488489000000000038b02b8004b80b000000000000220020c20b5d1f8584fd90443e7b7b720136174fa4b9333c261d04dbbd012635c0f419a00f0000000000002200208c48d15160397c9731df9bc3b236656efb6665fbfe92b4a6878e88a499f741c4c0c62d0000000000160014ccf1af2f2aabee14bb40fa3851ab2301de843110b8976a00000000002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d857accc862d6b7dd80e3e195220"),
+            (3702, "0200000001bef67e4e2fb9ddeeb3461973cd4c62abb35050b1add772995b820b584a488489000000000038b02b8004b80b000000000000220020c20b5d1f8584fd90443e7b7b720136174fa4b9333c261d04dbbd012635c0f419a00f0000000000002200208c48d15160397c9731df9bc3b236656efb6665fbfe92b4a6878e88a499f741c4c0c62d0000000000160014ccf1af2f2aabee14bb40fa3851ab2301de8431106f916a00000000002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d857accc862d6b7dd80e3e195220"),
+            (3703, "0200000001bef67e4e2fb9ddeeb3461973cd4c62abb35050b1add772995b820b584a488489000000000038b02b8003a00f0000000000002200208c48d15160397c9731df9bc3b236656efb6665fbfe92b4a6878e88a499f741c4c0c62d0000000000160014ccf1af2f2aabee14bb40fa3851ab2301de843110eb936a00000000002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d857accc862d6b7dd80e3e195220"),
+            (4914, "0200000001bef67e4e2fb9ddeeb3461973cd4c62abb35050b1add772995b820b584a488489000000000038b02b8003a00f0000000000002200208c48d15160397c9731df9bc3b236656efb6665fbfe92b4a6878e88a499f741c4c0c62d0000000000160014ccf1af2f2aabee14bb40fa3851ab2301de843110ae8f6a00000000002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d857accc862d6b7dd80e3e195220"),
+            (4915, "0200000001bef67e4e2fb9ddeeb3461973cd4c62abb35050b1add772995b820b584a488489000000000038b02b8002c0c62d0000000000160014ccf1af2f2aabee14bb40fa3851ab2301de843110fa926a00000000002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d857accc862d6b7dd80e3e195220"),
+            (9651180, "0200000001bef67e4e2fb9ddeeb3461973cd4c62abb35050b1add772995b820b584a488489000000000038b02b800222020000000000002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d857accc862d6b7dd80ec0c62d0000000000160014ccf1af2f2aabee14bb40fa3851ab2301de8431103e195220"),
+            (9651181, "0200000001bef67e4e2fb9ddeeb3461973cd4c62abb35050b1add772995b820b584a488489000000000038b02b8001c0c62d0000000000160014ccf1af2f2aabee14bb40fa3851ab2301de8431103e195220"),
+            (9651936, "0200000001bef67e4e2fb9ddeeb3461973cd4c62abb35050b1add772995b820b584a488489000000000038b02b8001c0c62d0000000000160014ccf1af2f2aabee14bb40fa3851ab2301de8431103e195220"),
+        ];
+        for (feerate_per_kw, tx) in vectors.iter() {
+            assert_eq!(
+                &commitment_tx(
+                    6_988_000,
+                    *feerate_per_kw,
+                    &RECEIVED_HTLCS,
+                    &OFFERED_HTLCS
+                ),
+                tx,
+                "commitment tx mismatch for feerate_per_kw {}",
+                feerate_per_kw
+            );
+        }
+    }
+
+    #[test]
+    fn commitment_fee_breakdown() {
+        let fee =
+            commitment_graph(6_988_000, 2070, &RECEIVED_HTLCS, &OFFERED_HTLCS)
+                .cmt_fee;
+        assert_eq!(fee.htlc_count, 3);
+        assert_eq!(fee.trimmed_htlc_count, 2);
+        assert_eq!(fee.trimmed_htlc_amount, 3000);
+        assert_eq!(fee.weight, 1240);
+        assert_eq!(fee.fee, 2566);
+
+        let fee = commitment_graph(
+            6_988_000,
+            9651181,
+            &RECEIVED_HTLCS,
+            &OFFERED_HTLCS,
+        )
+        .cmt_fee;
+        assert_eq!(fee.htlc_count, 0);
+        assert_eq!(fee.trimmed_htlc_count, 5);
+        assert_eq!(fee.fee, 6_987_455);
+        assert_eq!(fee.trimmed_output_amount, 545);
+        assert_eq!(fee.total(), 6_988_000 + 12_000);
+    }
+
+    #[test]
+    fn same_amount_htlcs_ordering() {
+        // Two offered HTLCs with the same amount and payment hash, added in
+        // the reverse order of their CLTV expiry
+        let tx_graph = commitment_graph(
+            6_987_999,
+            253,
+            &[(2000, 501, 1)],
+            &[(5000, 506, 5), (5000, 505, 5)],
+        );
+        assert_eq!(
+            serialize(&tx_graph.render_cmt().global.unsigned_tx).to_hex(),
+            "0200000001bef67e4e2fb9ddeeb3461973cd4c62abb35050b1add772995b820b584a488489000000000038b02b8005d007000000000000220020748eba944fedc8827f6b06bc44678f93c0f9e6078b35c6331ed31e75f8ce0c2d8813000000000000220020305c12e1a0bc21e283c131cea1c66d68857d28b7b2fce0a6fbc40c164852121b8813000000000000220020305c12e1a0bc21e283c131cea1c66d68857d28b7b2fce0a6fbc40c164852121bc0c62d0000000000160014ccf1af2f2aabee14bb40fa3851ab2301de843110a69f6a00000000002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d857accc862d6b7dd80e3e195220"
+        );
+
+        let mut expected = BTreeMap::new();
+        expected.insert(0, 501);
+        expected.insert(1, 505);
+        expected.insert(2, 506);
+        assert_eq!(tx_graph.cmt_cltv_expiry, expected);
+
+        // Second-stage transactions must follow their outputs
+        let spent_vout = |ty: TxType, index: u64| {
+            let tx = &tx_graph.tx(ty, index).unwrap().global.unsigned_tx;
+            (tx.lock_time, tx.input[0].previous_output.vout)
+        };
+        assert_eq!(spent_vout(TxType::HtlcSuccess, 0), (0, 0));
+        assert_eq!(spent_vout(TxType::HtlcTimeout, 0), (506, 2));
+        assert_eq!(spent_vout(TxType::HtlcTimeout, 1), (505, 1));
+    }
+
+    #[test]
+    fn commitment_type_negotiation() {
+        let legacy = InitFeatures::default();
+        let static_remotekey = InitFeatures {
+            option_static_remotekey: Some(false),
+            ..InitFeatures::default()
+        };
+        let anchors = InitFeatures {
+            option_static_remotekey: Some(true),
+            option_anchor_outputs: Some(false),
+            ..InitFeatures::default()
+        };
+        assert_eq!(
+            CommitmentType::negotiate(&legacy, &anchors),
+            CommitmentType::Legacy
+        );
+        assert_eq!(
+            CommitmentType::negotiate(&static_remotekey, &anchors),
+            CommitmentType::StaticRemotekey
+        );
+        assert_eq!(
+            CommitmentType::negotiate(&anchors, &anchors),
+            CommitmentType::Anchors
+        );
+
+        let zero_fee = InitFeatures {
+            option_anchors_zero_fee_htlc_tx: Some(false),
+            ..anchors.clone()
+        };
+        assert_eq!(
+            CommitmentType::negotiate(&anchors, &zero_fee),
+            CommitmentType::Anchors
+        );
+        assert_eq!(
+            CommitmentType::negotiate(&zero_fee, &zero_fee),
+            CommitmentType::AnchorsZeroFeeHtlcTx
+        );
+    }
+
+    #[test]
+    fn commitment_type_fixed() {
+        let anchors = InitFeatures {
+            option_static_remotekey: Some(true),
+            option_anchor_outputs: Some(false),
+            ..InitFeatures::default()
+        };
+        let init = |features: &InitFeatures| {
+            Messages::Init(Init {
+                global_features: none!(),
+                local_features: features.clone(),
+                assets: none!(),
+                unknown_tlvs: none!(),
+            })
+        };
+        let mut bolt3 = Bolt3::with_keys(
+            true,
+            7_000_000,
+            3_000_000,
+            144,
+            DEFAULT_DUST_LIMIT,
+            local_basepoints(),
+            local_keys().per_commitment_point(0),
+        );
+        bolt3.set_local_features(anchors.clone());
+        bolt3.update_from_peer(&init(&anchors)).unwrap();
+        assert_eq!(bolt3.commitment_type(), CommitmentType::Legacy);
+        bolt3.update_from_peer(&accept_channel()).unwrap();
+        assert_eq!(bolt3.commitment_type(), CommitmentType::Anchors);
+
+        // Features of the reconnected peers do not affect open channel
+        bolt3
+            .update_from_peer(&init(&InitFeatures::default()))
+            .unwrap();
+        bolt3.set_local_features(InitFeatures::default());
+        assert_eq!(bolt3.commitment_type(), CommitmentType::Anchors);
+    }
+
+    #[test]
+    fn to_remote_key() {
+        // Payment basepoint of the remote peer is used without tweaking
+        let remote_payment_basepoint = pubkey([0x44; 32]);
+
+        let features = InitFeatures {
+            option_static_remotekey: Some(false),
+            ..InitFeatures::default()
+        };
+        let tx_graph = commitment_graph_with_features(
+            features,
+            7_000_000,
+            15000,
+            &[],
+            &[],
+        );
+        assert_eq!(
+            tx_graph.cmt_outs[0],
+            TxOut::ln_to_remote_v1(3_000_000, remote_payment_basepoint)
+        );
+        assert_eq!(tx_graph.cmt_fee.weight, 724);
+
+        let features = InitFeatures {
+            option_static_remotekey: Some(false),
+            option_anchor_outputs: Some(false),
+            ..InitFeatures::default()
+        };
+        let tx_graph = commitment_graph_with_features(
+            features,
+            7_000_000,
+            15000,
+            &[],
+            &[],
+        );
+        assert_eq!(
+            tx_graph.cmt_outs[0],
+            TxOut::ln_to_remote_v2(3_000_000, remote_payment_basepoint)
+        );
+        assert_eq!(tx_graph.cmt_fee.weight, 1124);
+    }
+
+    fn anchors_features(zero_fee_htlc: bool) -> InitFeatures {
+        InitFeatures {
+            option_static_remotekey: Some(false),
+            option_anchor_outputs: Some(false),
+            option_anchors_zero_fee_htlc_tx: if zero_fee_htlc {
+                Some(false)
+            } else {
+                None
+            },
+            ..InitFeatures::default()
+        }
+    }
+
+    #[test]
+    fn anchors_commitment_tx() {
+        let vectors = [
+            (false, 7_000_000, 15000, false, "0200000001bef67e4e2fb9ddeeb3461973cd4c62abb35050b1add772995b820b584a488489000000000038b02b80044a010000000000002200202b1b5854183c12d3316565972c4668929d314d81c5dcdbb21cb45fe8a9a8114f4a01000000000000220020e9e86e4823faa62e222ebc858a226636856158f07e69898da3b0d1af0ddb3994c0c62d0000000000220020f3394e1e619b0eca1f91be2fb5ab4dfc59ba5b84ebe014ad1d43a564d012994a508b6a00000000002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d857accc862d6b7dd80e3e195220"),
+            (false, 6_988_000, 3702, true, "0200000001bef67e4e2fb9ddeeb3461973cd4c62abb35050b1add772995b820b584a488489000000000038b02b80054a010000000000002200202b1b5854183c12d3316565972c4668929d314d81c5dcdbb21cb45fe8a9a8114f4a01000000000000220020e9e86e4823faa62e222ebc858a226636856158f07e69898da3b0d1af0ddb3994a00f000000000000220020ce6e751274836ff59622a0d1e07f8831d80bd6730bd48581398bfadd2bb8da9ac0c62d0000000000220020f3394e1e619b0eca1f91be2fb5ab4dfc59ba5b84ebe014ad1d43a564d012994a8f8b6a00000000002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d857accc862d6b7dd80e3e195220"),
+            (true, 6_988_000, 3702, true, "0200000001bef67e4e2fb9ddeeb3461973cd4c62abb35050b1add772995b820b584a488489000000000038b02b80094a010000000000002200202b1b5854183c12d3316565972c4668929d314d81c5dcdbb21cb45fe8a9a8114f4a01000000000000220020e9e86e4823faa62e222ebc858a226636856158f07e69898da3b0d1af0ddb3994e80300000000000022002010f88bf09e56f14fb4543fd26e47b0db50ea5de9cf3fc46434792471082621aed0070000000000002200203e68115ae0b15b8de75b6c6bc9af5ac9f01391544e0870dae443a1e8fe7837ead007000000000000220020fe0598d74fee2205cc3672e6e6647706b4f3099713b4661b62482c3addd04a5eb80b000000000000220020f96d0334feb64a4f40eb272031d07afcb038db56aa57446d60308c9f8ccadef9a00f000000000000220020ce6e751274836ff59622a0d1e07f8831d80bd6730bd48581398bfadd2bb8da9ac0c62d0000000000220020f3394e1e619b0eca1f91be2fb5ab4dfc59ba5b84ebe014ad1d43a564d012994a9c816a00000000002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d857accc862d6b7dd80e3e195220"),
+        ];
+        for (zero_fee_htlc, to_local, feerate_per_kw, htlcs, tx) in
+            vectors.iter()
+        {
+            let (received, offered): (&[HtlcVector], &[HtlcVector]) = if *htlcs
+            {
+                (&RECEIVED_HTLCS, &OFFERED_HTLCS)
+            } else {
+                (&[], &[])
+            };
+            let tx_graph = commitment_graph_with_features(
+                anchors_features(*zero_fee_htlc),
+                *to_local,
+                *feerate_per_kw,
+                received,
+                offered,
+            );
+            assert_eq!(
+                &serialize(&tx_graph.render_cmt().global.unsigned_tx).to_hex(),
+                tx,
+                "anchor commitment tx mismatch for feerate_per_kw {}",
+                feerate_per_kw
+            );
+            assert_eq!(tx_graph.cmt_fee.anchors_amount, 660);
+        }
+    }
+
+    #[test]
+    fn anchor_omission() {
+        let local_anchor = TxOut::ln_anchor(330, local_funding_pubkey());
+        let remote_anchor = TxOut::ln_anchor(330, remote_funding_pubkey());
+
+        // `to_local` output goes below dust after paying for the anchors
+        let tx_graph = commitment_graph_with_features(
+            anchors_features(true),
+            1000,
+            0,
+            &[],
+            &[],
+        );
+        assert_eq!(tx_graph.cmt_outs.len(), 2);
+        assert_eq!(tx_graph.cmt_outs[0], remote_anchor);
+        assert_eq!(tx_graph.cmt_outs[1].value, 3_000_000);
+        assert_eq!(tx_graph.cmt_fee.trimmed_output_amount, 340);
+
+        // Both anchors are kept while there are untrimmed HTLCs
+        let tx_graph = commitment_graph_with_features(
+            anchors_features(true),
+            1000,
+            0,
+            &[],
+            &[(5000, 500, 5)],
+        );
+        assert_eq!(tx_graph.cmt_outs.len(), 4);
+        assert!(tx_graph.cmt_outs.contains(&local_anchor));
+        assert!(tx_graph.cmt_outs.contains(&remote_anchor));
+    }
+
+    #[test]
+    fn anchors_htlc_tx() {
+        for zero_fee_htlc in &[false, true] {
+            let tx_graph = commitment_graph_with_features(
+                anchors_features(*zero_fee_htlc),
+                6_988_000,
+                253,
+                &[(2000, 501, 1)],
+                &[(5000, 506, 5)],
+            );
+            for ty in &[TxType::HtlcSuccess, TxType::HtlcTimeout] {
+                let psbt = tx_graph.tx(*ty, 0u64).unwrap();
+                assert_eq!(psbt.global.unsigned_tx.input[0].sequence, 1);
+                assert_eq!(
+                    psbt.inputs[0].sighash_type,
+                    Some(bitcoin::SigHashType::SinglePlusAnyoneCanPay)
+                );
+            }
+            let timeout = &tx_graph
+                .tx(TxType::HtlcTimeout, 0u64)
+                .unwrap()
+                .global
+                .unsigned_tx;
+            let fee = if *zero_fee_htlc { 0 } else { 253 * 666 / 1000 };
+            assert_eq!(timeout.output[0].value, 5000 - fee);
+        }
+    }
+
+    fn signature(hex: &str) -> Signature {
+        let mut der = Vec::<u8>::from_hex(hex).unwrap();
+        // Drop sighash type byte
+        der.pop();
+        Signature::from_der(&der).unwrap()
+    }
+
+    #[test]
+    fn htlc_txs() {
+        // BOLT-3 "commitment tx with all five HTLCs untrimmed (minimum
+        // feerate)": HTLC #0 success and HTLC #1 timeout transactions
+        let tx_graph =
+            commitment_graph(6_988_000, 0, &RECEIVED_HTLCS, &OFFERED_HTLCS);
+
+        let mut success =
+            tx_graph.tx(TxType::HtlcSuccess, 0u64).unwrap().clone();
+        assert_eq!(
+            success.inputs[0].witness_utxo,
+            Some(tx_graph.cmt_outs[0].clone())
+        );
+        assert_eq!(
+            success.inputs[0].sighash_type,
+            Some(bitcoin::SigHashType::All)
+        );
+        success
+            .ln_finalize_htlc_success(
+                signature("304402206a6e59f18764a5bf8d4fa45eebc591566689441229c918b480fb2af8cc6a4aeb02205248f273be447684b33e3c8d1d85a8e0ca9fa0bae9ae33f0527ada9c162919a601"),
+                signature("304402207cb324fa0de88f452ffa9389678127ebcf4cabe1dd848b8e076c1a1962bf34720220116ed922b12311bd602d67e60d2529917f21c5b82f25ff6506c0f87886b4dfd501"),
+                HashPreimage::from_inner(amplify::Slice32::from_inner([0; 32])),
+            )
+            .unwrap();
+        assert_eq!(
+            serialize(&success.extract_tx()).to_hex(),
+            "020000000001018154ecccf11a5fb56c39654c4deb4d2296f83c69268280b94d021370c94e219700000000000000000001e8030000000000002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d857accc862d6b7dd80e050047304402206a6e59f18764a5bf8d4fa45eebc591566689441229c918b480fb2af8cc6a4aeb02205248f273be447684b33e3c8d1d85a8e0ca9fa0bae9ae33f0527ada9c162919a60147304402207cb324fa0de88f452ffa9389678127ebcf4cabe1dd848b8e076c1a1962bf34720220116ed922b12311bd602d67e60d2529917f21c5b82f25ff6506c0f87886b4dfd5012000000000000000000000000000000000000000000000000000000000000000008a76a91414011f7254d96b819c76986c277d115efce6f7b58763ac67210394854aa6eab5b2a8122cc726e9dded053a2184d88256816826d6231c068d4a5b7c8201208763a914b8bcb07f6344b42ab04250c86a6e8b75d3fdbbc688527c21030d417a46946384f88d5f3337267c5e579765875dc4daca813e21734b140639e752ae677502f401b175ac686800000000"
+        );
+
+        let mut timeout =
+            tx_graph.tx(TxType::HtlcTimeout, 0u64).unwrap().clone();
+        timeout
+            .ln_finalize_htlc_timeout(
+                signature("3045022100d5275b3619953cb0c3b5aa577f04bc512380e60fa551762ce3d7a1bb7401cff9022037237ab0dac3fe100cde094e82e2bed9ba0ed1bb40154b48e56aa70f259e608b01"),
+                signature("3045022100c89172099507ff50f4c925e6c5150e871fb6e83dd73ff9fbb72f6ce829a9633f02203a63821d9162e99f9be712a68f9e589483994feae2661e4546cd5b6cec007be501"),
+            )
+            .unwrap();
+        assert_eq!(
+            serialize(&timeout.extract_tx()).to_hex(),
+            "020000000001018154ecccf11a5fb56c39654c4deb4d2296f83c69268280b94d021370c94e219701000000000000000001d0070000000000002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d857accc862d6b7dd80e0500483045022100d5275b3619953cb0c3b5aa577f04bc512380e60fa551762ce3d7a1bb7401cff9022037237ab0dac3fe100cde094e82e2bed9ba0ed1bb40154b48e56aa70f259e608b01483045022100c89172099507ff50f4c925e6c5150e871fb6e83dd73ff9fbb72f6ce829a9633f02203a63821d9162e99f9be712a68f9e589483994feae2661e4546cd5b6cec007be501008576a91414011f7254d96b819c76986c277d115efce6f7b58763ac67210394854aa6eab5b2a8122cc726e9dded053a2184d88256816826d6231c068d4a5b7c820120876475527c21030d417a46946384f88d5f3337267c5e579765875dc4daca813e21734b140639e752ae67a914b43e1b38138a41b37f7cd9a1d274bc63e3a9b5d188ac6868f6010000"
+        );
+    }
+
+    #[test]
+    fn commitment_signatures() {
+        // BOLT-3 "commitment tx with all five HTLCs untrimmed (minimum
+        // feerate)": signatures of both peers
+        let tx_graph =
+            commitment_graph(6_988_000, 0, &RECEIVED_HTLCS, &OFFERED_HTLCS);
+        let funding_script = WitnessScript::ln_funding(
+            10_000_000,
+            local_funding_pubkey(),
+            remote_funding_pubkey(),
+        );
+        let per_commitment_point = tx_graph.cmt_per_commitment_point;
+
+        let (signature, htlc_signatures) = tx_graph
+            .sign(
+                &funding_script,
+                10_000_000,
+                &SecretKey::from_slice(
+                    &Vec::<u8>::from_hex(
+                        "30ff4956bbdd3222d44cc5e8a1261dab1e07957bdac5ae88fe3261ef321f3749",
+                    )
+                    .unwrap(),
+                )
+                .unwrap(),
+                &derive_privkey(
+                    SecretKey::from_slice(&[0x11; 32]).unwrap(),
+                    per_commitment_point,
+                ),
+            )
+            .unwrap();
+        assert_eq!(
+            signature.serialize_der().to_hex(),
+            "30440220275b0c325a5e9355650dc30c0eccfbc7efb23987c24b556b9dfdd40effca18d202206caceb2c067836c51f296740c7ae807ffcbfbf1dd3a0d56b6de9a5b247985f06"
+        );
+        assert_eq!(
+            htlc_signatures
+                .iter()
+                .map(|sig| sig.serialize_der().to_hex())
+                .collect::<Vec<_>>(),
+            vec![
+                "304402207cb324fa0de88f452ffa9389678127ebcf4cabe1dd848b8e076c1a1962bf34720220116ed922b12311bd602d67e60d2529917f21c5b82f25ff6506c0f87886b4dfd5",
+                "3045022100c89172099507ff50f4c925e6c5150e871fb6e83dd73ff9fbb72f6ce829a9633f02203a63821d9162e99f9be712a68f9e589483994feae2661e4546cd5b6cec007be5",
+                "3045022100def389deab09cee69eaa1ec14d9428770e45bcbe9feb46468ecf481371165c2f022015d2e3c46600b2ebba8dcc899768874cc6851fd1ecb3fffd15db1cc3de7e10da",
+                "30440220643aacb19bbb72bd2b635bc3f7375481f5981bace78cdd8319b2988ffcc6704202203d27784ec8ad51ed3bd517a05525a5139bb0b755dd719e0054332d186ac08727",
+                "30440220549e80b4496803cbc4a1d09d46df50109f546d43fbbf86cd90b174b1484acd5402205f12a4f995cb9bded597eabfee195a285986aa6d93ae5bb72507ebc6a4e2349e",
+            ]
+        );
+
+        let der = |hex: &str| {
+            Signature::from_der(&Vec::<u8>::from_hex(hex).unwrap()).unwrap()
+        };
+        let mut commitment_signed = CommitmentSigned {
+            channel_id: ChannelId::default(),
+            signature: der("304402204fd4928835db1ccdfc40f5c78ce9bd65249b16348df81f0c44328dcdefc97d630220194d3869c38bc732dd87d13d2958015e2fc16829e74cd4377f84d215c0b70606"),
+            htlc_signatures: vec![
+                der("304402206a6e59f18764a5bf8d4fa45eebc591566689441229c918b480fb2af8cc6a4aeb02205248f273be447684b33e3c8d1d85a8e0ca9fa0bae9ae33f0527ada9c162919a6"),
+                der("3045022100d5275b3619953cb0c3b5aa577f04bc512380e60fa551762ce3d7a1bb7401cff9022037237ab0dac3fe100cde094e82e2bed9ba0ed1bb40154b48e56aa70f259e608b"),
+                der("304402201b63ec807771baf4fdff523c644080de17f1da478989308ad13a58b51db91d360220568939d38c9ce295adba15665fa68f51d967e8ed14a007b751540a80b325f202"),
+                der("3045022100daee1808f9861b6c3ecd14f7b707eca02dd6bdfc714ba2f33bc8cdba507bb182022026654bf8863af77d74f51f4e0b62d461a019561bb12acb120d3f7195d148a554"),
+                der("304402207e0410e45454b0978a623f36a10626ef17b27d9ad44e2760f98cfa3efb37924f0220220bd8acd43ecaa916a80bd4f919c495a2c58982ce7c8625153f8596692a801d"),
+            ],
+        };
+        let remote_htlcpubkey =
+            derive_pubkey(pubkey([0x44; 32]), per_commitment_point);
+        let verify = |commitment_signed: &CommitmentSigned| {
+            tx_graph.verify(
+                commitment_signed,
+                &funding_script,
+                10_000_000,
+                remote_funding_pubkey(),
+                remote_htlcpubkey,
+            )
+        };
+        verify(&commitment_signed).unwrap();
+
+        // Both of the swapped signatures are reported
+        commitment_signed.htlc_signatures.swap(1, 3);
+        assert_eq!(
+            verify(&commitment_signed),
+            Err(SignatureError::Htlc(vec![1, 3]).into())
+        );
+        commitment_signed.htlc_signatures.pop();
+        assert_eq!(
+            verify(&commitment_signed),
+            Err(SignatureError::HtlcCount(4, 5).into())
+        );
+        commitment_signed.signature = signature;
+        assert_eq!(
+            verify(&commitment_signed),
+            Err(SignatureError::Commitment.into())
+        );
+    }
+
+    #[test]
+    fn htlc_balances() {
+        let mut bolt3 = Bolt3::with_keys(
+            true,
+            7_000_000,
+            3_000_000,
+            144,
+            DEFAULT_DUST_LIMIT,
+            local_basepoints(),
+            local_keys().per_commitment_point(0),
+        );
+        let funding_outpoint = OutPoint::new(Txid::default(), 0);
+        let channel_id = ChannelId::with(funding_outpoint);
+        bolt3.set_funding_outpoint(funding_outpoint);
+        bolt3.feerate_per_kw = 0;
+        let params = Params {
+            max_htlc_value_in_flight_msat: 10_000_000_000,
+            max_accepted_htlcs: 483,
+            ..Params::default()
+        };
+        let mut channel = Channel::with(
+            bolt3,
+            vec![Htlc::with(local_basepoints(), &params)],
+            vec![Bolt3Ordering],
+        );
+        channel.update_from_peer(&accept_channel()).unwrap();
+        channel
+            .update_from_peer(&Messages::FundingSigned(FundingSigned {
+                channel_id,
+                signature: Signature::from_compact(&[1u8; 64]).unwrap(),
+            }))
+            .unwrap();
+        lock_funding(&mut channel, channel_id);
+        let outputs = |channel: &mut Channel<ExtensionId>| {
+            let mut tx_graph = TxGraph::with_owner(CommitmentOwner::Local);
+            channel.apply(&mut tx_graph).unwrap();
+            let mut values = tx_graph
+                .render_cmt()
+                .global
+                .unsigned_tx
+                .output
+                .iter()
+                .map(|txout| txout.value)
+                .collect::<Vec<_>>();
+            values.sort_unstable();
+            values
+        };
+
+        let preimage =
+            HashPreimage::from_inner(amplify::Slice32::from_inner([7u8; 32]));
+        channel
+            .update_from_local(&Operation::AddHtlc {
+                amount_msat: 5_000_000,
+                payment_hash: HashLock::from(preimage),
+                cltv_expiry: 500,
+                onion_routing_packet: OnionPacket::dumb_default(),
+                asset_id: None,
+            })
+            .unwrap();
+        assert_eq!(bolt3_state(&channel).local_amount_msat, 6_995_000_000);
+        // The HTLC is not yet in the local commitment
+        assert_eq!(outputs(&mut channel), vec![3_000_000, 7_000_000]);
+
+        commitment_round(&mut channel, channel_id, 0);
+        let bolt3 = bolt3_state(&channel);
+        assert_eq!(bolt3.commitment_number, 1);
+        assert_eq!(bolt3.remote_commitment_number, 2);
+        assert_eq!(
+            bolt3.local_per_commitment_point,
+            local_keys().per_commitment_point(1)
+        );
+        assert_eq!(outputs(&mut channel), vec![5_000, 3_000_000, 6_995_000]);
+
+        // Update rejected by the HTLC extender does not change the balances
+        let wrong_preimage =
+            HashPreimage::from_inner(amplify::Slice32::from_inner([8u8; 32]));
+        assert_eq!(
+            channel.update_from_peer(&Messages::UpdateFulfillHtlc(
+                UpdateFulfillHtlc {
+                    channel_id,
+                    htlc_id: 0,
+                    payment_preimage: wrong_preimage,
+                }
+            )),
+            Err(HtlcError::PreimageMismatch(0).into())
+        );
+        assert_eq!(bolt3_state(&channel), bolt3);
+        let foreign_id = ChannelId::with(OutPoint::new(Txid::default(), 1));
+        assert_eq!(
+            channel.update_from_peer(&Messages::UpdateFulfillHtlc(
+                UpdateFulfillHtlc {
+                    channel_id: foreign_id,
+                    htlc_id: 0,
+                    payment_preimage: preimage,
+                }
+            )),
+            Err(HtlcError::ChannelIdMismatch(foreign_id, channel_id).into())
+        );
+        assert_eq!(bolt3_state(&channel), bolt3);
+
+        channel
+            .update_from_peer(&Messages::UpdateFulfillHtlc(UpdateFulfillHtlc {
+                channel_id,
+                htlc_id: 0,
+                payment_preimage: preimage,
+            }))
+            .unwrap();
+        channel
+            .update_from_peer(&Messages::CommitmentSigned(CommitmentSigned {
+                channel_id,
+                signature: Signature::from_compact(&[1u8; 64]).unwrap(),
+                htlc_signatures: vec![],
+            }))
+            .unwrap();
+        let bolt3 = bolt3_state(&channel);
+        assert_eq!(bolt3.commitment_number, 2);
+        assert_eq!(
+            bolt3.local_per_commitment_point,
+            local_keys().per_commitment_point(2)
+        );
+        assert_eq!(outputs(&mut channel), vec![3_005_000, 6_995_000]);
+
+        // Fulfilled HTLC is paid out once its removal is irrevocable
+        channel
+            .update_from_local(&Operation::RevokeCommitment)
+            .unwrap();
+        channel
+            .update_from_local(&Operation::SignCommitment)
+            .unwrap();
+        assert_eq!(bolt3_state(&channel).remote_amount_msat, 3_000_000_000);
+        let remote_keys = remote_keys();
+        let mut per_commitment_secret = [0u8; 32];
+        per_commitment_secret
+            .copy_from_slice(&remote_keys.per_commitment_secret(1)[..]);
+        channel
+            .update_from_peer(&Messages::RevokeAndAck(RevokeAndAck {
+                channel_id,
+                per_commitment_secret,
+                next_per_commitment_point: remote_keys.per_commitment_point(3),
+            }))
+            .unwrap();
+        let bolt3 = bolt3_state(&channel);
+        assert_eq!(bolt3.remote_amount_msat, 3_005_000_000);
+        assert_eq!(bolt3.remote_commitment_number, 3);
+        assert!(bolt3.pending_htlcs.is_empty());
+        assert_eq!(outputs(&mut channel), vec![3_005_000, 6_995_000]);
+    }
+
+    #[test]
+    fn revocation_secret_mismatch() {
+        let mut bolt3 = Bolt3::with_keys(
+            true,
+            7_000_000,
+            3_000_000,
+            144,
+            DEFAULT_DUST_LIMIT,
+            local_basepoints(),
+            local_keys().per_commitment_point(0),
+        );
+        let funding_outpoint = OutPoint::new(Txid::default(), 0);
+        let channel_id = ChannelId::with(funding_outpoint);
+        bolt3.set_funding_outpoint(funding_outpoint);
+        let mut channel = Channel::with(
+            bolt3,
+            Vec::<Bolt3Ordering>::new(),
+            vec![Bolt3Ordering],
+        );
+        channel.update_from_peer(&accept_channel()).unwrap();
+        channel
+            .update_from_peer(&Messages::FundingSigned(FundingSigned {
+                channel_id,
+                signature: Signature::from_compact(&[1u8; 64]).unwrap(),
+            }))
+            .unwrap();
+        lock_funding(&mut channel, channel_id);
+        channel
+            .update_from_local(&Operation::SignCommitment)
+            .unwrap();
+
+        let remote_keys = remote_keys();
+        let revoke_and_ack = |commitment_number: u64| {
+            let mut per_commitment_secret = [0u8; 32];
+            per_commitment_secret.copy_from_slice(
+                &remote_keys.per_commitment_secret(commitment_number)[..],
+            );
+            Messages::RevokeAndAck(RevokeAndAck {
+                channel_id,
+                per_commitment_secret,
+                next_per_commitment_point: remote_keys.per_commitment_point(2),
+            })
+        };
+        assert_eq!(
+            channel.update_from_peer(&revoke_and_ack(1)),
+            Err(Bolt3Error::SecretMismatch(0).into())
+        );
+        let bolt3 = bolt3_state(&channel);
+        assert_eq!(bolt3.remote_commitment_number, 1);
+        assert!(bolt3.remote_secrets.is_empty());
+
+        channel.update_from_peer(&revoke_and_ack(0)).unwrap();
+        let bolt3 = bolt3_state(&channel);
+        assert_eq!(bolt3.remote_commitment_number, 2);
+        assert_eq!(
+            bolt3.remote_per_commitment_point,
+            remote_keys.per_commitment_point(2)
+        );
+    }
+
+    #[test]
+    fn reestablish_retransmits_sent_messages() {
+        let signer = InMemorySigner::with_seed([0u8; 32]);
+        let mut bolt3 = Bolt3::with_signer(
+            true,
+            7_000_000,
+            3_000_000,
+            144,
+            DEFAULT_DUST_LIMIT,
+            &signer,
+        )
+        .unwrap();
+        assert_eq!(
+            bolt3.local_per_commitment_point,
+            signer.per_commitment_point(0).unwrap()
+        );
+        let funding_outpoint = OutPoint::new(Txid::default(), 0);
+        let channel_id = ChannelId::with(funding_outpoint);
+        bolt3.set_funding_outpoint(funding_outpoint);
+        let params = Params {
+            max_htlc_value_in_flight_msat: 10_000_000_000,
+            max_accepted_htlcs: 483,
+            ..Params::default()
+        };
+        let mut channel = Channel::with(
+            bolt3,
+            vec![Htlc::with(local_basepoints(), &params)],
+            vec![Bolt3Ordering],
+        );
+        channel.update_from_peer(&accept_channel()).unwrap();
+        channel
+            .update_from_peer(&Messages::FundingSigned(FundingSigned {
+                channel_id,
+                signature: Signature::from_compact(&[1u8; 64]).unwrap(),
+            }))
+            .unwrap();
+        channel
+            .update_from_peer(&funding_locked(channel_id))
+            .unwrap();
+        channel
+            .message_sent(&Messages::FundingLocked(FundingLocked {
+                channel_id,
+                next_per_commitment_point: signer
+                    .per_commitment_point(1)
+                    .unwrap(),
+            }))
+            .unwrap();
+
+        let preimage =
+            HashPreimage::from_inner(amplify::Slice32::from_inner([7u8; 32]));
+        channel
+            .update_from_local(&Operation::AddHtlc {
+                amount_msat: 5_000_000,
+                payment_hash: HashLock::from(preimage),
+                cltv_expiry: 500,
+                onion_routing_packet: OnionPacket::dumb_default(),
+                asset_id: None,
+            })
+            .unwrap();
+        channel
+            .update_from_local(&Operation::SignCommitment)
+            .unwrap();
+        channel
+            .message_sent(&Messages::CommitmentSigned(CommitmentSigned {
+                channel_id,
+                signature: Signature::from_compact(&[1u8; 64]).unwrap(),
+                htlc_signatures: vec![],
+            }))
+            .unwrap();
+
+        let reestablish = ChannelReestablish {
+            channel_id,
+            next_commitment_number: 1,
+            next_revocation_number: 0,
+            your_last_per_commitment_secret: [0u8; 32],
+            my_current_per_commitment_point: remote_keys()
+                .per_commitment_point(0),
+        };
+        assert!(channel
+            .update_from_peer(&Messages::ChannelReestablish(
+                reestablish.clone()
+            ))
+            .is_err());
+        let messages = match channel.reestablish(&reestablish, &signer).unwrap()
+        {
+            ReestablishAction::Retransmit(messages) => messages,
+            ReestablishAction::AwaitForceClose(_) => {
+                panic!("local state is not lost")
+            }
+        };
+        assert_eq!(messages.len(), 3);
+        assert_eq!(
+            messages[0],
+            Messages::FundingLocked(FundingLocked {
+                channel_id,
+                next_per_commitment_point: signer
+                    .per_commitment_point(1)
+                    .unwrap(),
+            })
+        );
+        assert!(matches!(
+            messages[1],
+            Messages::UpdateAddHtlc(UpdateAddHtlc { htlc_id: 0, .. })
+        ));
+        assert!(matches!(messages[2], Messages::CommitmentSigned(_)));
+        // Signed HTLC is not forgotten
+        assert_eq!(bolt3_state(&channel).local_amount_msat, 6_995_000_000);
+
+        let foreign = ChannelReestablish {
+            channel_id: ChannelId::default(),
+            ..reestablish
+        };
+        assert_eq!(
+            channel.reestablish(&foreign, &signer).unwrap_err(),
+            ReestablishError::ChannelIdMismatch(
+                ChannelId::default(),
+                channel_id
+            )
+            .into()
+        );
+    }
+}
@@ -10,3 +10,858 @@
 // You should have received a copy of the MIT License
 // along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
+
+//! Eltoo channel constructor (Decker, Russell & Osuntokun "eltoo: A Simple
+//! Layer2 Protocol for Bitcoin").
+//!
+//! Each channel state is represented by the update transaction, which
+//! `nLockTime` encodes the state number, and the settlement transaction
+//! distributing channel funds after the CSV delay. The output of the update
+//! transaction may be spent by any update transaction of a later state, so
+//! the latest state always wins and no revocation is required. Update
+//! transactions are rebound to the previous updates with
+//! `SIGHASH_ANYPREVOUTANYSCRIPT` signatures and settlement transactions with
+//! `SIGHASH_ANYPREVOUT` (BIP-118); since these sighash types are not
+//! supported by the bitcoin consensus rules yet, the channel works only with
+//! a script interpreter supporting them (like a local regtest node).
+
+use amplify::Wrapper;
+use bitcoin::blockdata::{opcodes::all::*, script};
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::util::psbt::{self, raw};
+use bitcoin::{OutPoint, Transaction, TxIn, TxOut, Txid};
+use wallet::{IntoPk, LexOrder, LockScript, Psbt, PubkeyScript, WitnessScript};
+
+use crate::payment::bolt3::ScriptGenerators;
+use crate::payment::fee::{fee_for_weight, CommitmentFee};
+use crate::payment::{ExtensionId, Operation, TxType};
+use crate::{channel, ChannelExtension, ChannelId, Extension, Messages};
+
+/// Lock time of the update transaction for the channel state 0. Lock times
+/// of the later states follow it; values above 500 000 000 are interpreted
+/// as timestamps, which are in the past up to [`MAX_STATE_NUMBER`], so
+/// update transactions are always final.
+pub const UPDATE_LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+/// Maximal channel state number. The update transaction of this state has
+/// lock time of 1 500 000 000 (July 2017), which is below the median time
+/// past of any block mined since then; later lock times would make update
+/// transactions non-final.
+pub const MAX_STATE_NUMBER: u32 = 1_000_000_000;
+
+/// Sequence of the update transaction input, enabling its lock time
+pub const SEQUENCE_LOCKTIME: u32 = 0xFFFF_FFFE;
+
+/// BIP-118 sighash type of the settlement transactions:
+/// `SIGHASH_ALL | SIGHASH_ANYPREVOUT`
+pub const SIGHASH_ANYPREVOUT: u8 = 0x41;
+
+/// BIP-118 sighash type of the update transactions:
+/// `SIGHASH_ALL | SIGHASH_ANYPREVOUTANYSCRIPT`
+pub const SIGHASH_ANYPREVOUTANYSCRIPT: u8 = 0xC1;
+
+/// Identifier of the LNP proprietary PSBT keys
+pub const PSBT_LNP_PROPRIETARY_PREFIX: &[u8] = b"LNP";
+
+/// Subtype of the proprietary PSBT input key holding BIP-118 sighash type,
+/// which can't be represented by the standard `PSBT_IN_SIGHASH_TYPE` key
+pub const PSBT_IN_LNP_ANYPREVOUT_SIGHASH: u8 = 0x01;
+
+/// Estimated weight of the update transaction
+pub const UPDATE_TX_WEIGHT: u64 = 686;
+
+/// Estimated weight of the settlement transaction with both balance outputs
+pub const SETTLEMENT_TX_WEIGHT: u64 = 762;
+
+/// Errors updating eltoo channel and binding its update transactions
+#[derive(
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Debug,
+    Display,
+    Error,
+    StrictEncode,
+    StrictDecode,
+)]
+#[display(doc_comments)]
+pub enum EltooError {
+    /// sum of the balances {0} does not match the channel funding amount {1}
+    BalanceMismatch(u64, u64),
+
+    /// channel state number can't be increased anymore
+    StateOverflow,
+
+    /// transaction {0} is not an update transaction of the channel
+    NotUpdate(Txid),
+
+    /// update transaction for the state {0} can't be spent by the update for
+    /// the current state {1}
+    StaleUpdate(u32, u32),
+
+    /// channel funder balance {0} does not cover update and settlement
+    /// transaction fees {1}
+    InsufficientFunderBalance(u64, u64),
+
+    /// sum of the balances exceeds the maximum bitcoin amount
+    AmountOverflow,
+}
+
+impl From<EltooError> for channel::Error {
+    fn from(err: EltooError) -> Self {
+        channel::Error::Extension(err.to_string())
+    }
+}
+
+/// Keys of the channel party used in eltoo transactions
+#[derive(
+    Clone, Copy, PartialEq, Eq, Hash, Debug, StrictEncode, StrictDecode,
+)]
+pub struct EltooKeys {
+    /// Key signing update transactions, also used in the funding output
+    pub update_pubkey: PublicKey,
+    /// Key signing settlement transactions
+    pub settlement_pubkey: PublicKey,
+    /// Key receiving party balance in the settlement transaction
+    pub payment_pubkey: PublicKey,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, StrictEncode, StrictDecode)]
+pub struct Eltoo {
+    channel_id: ChannelId,
+    funding_outpoint: OutPoint,
+    local_amount: u64,
+    remote_amount: u64,
+    state_number: u32,
+    settlement_delay: u16,
+    feerate_per_kw: u32,
+    dust_limit: u64,
+    local_keys: EltooKeys,
+    remote_keys: EltooKeys,
+    is_originator: bool,
+}
+
+impl Eltoo {
+    /// Constructs channel with zero fees; use [`Eltoo::set_fee_params`] to
+    /// set fee rate. Fails if the balances overflow.
+    pub fn with(
+        is_originator: bool,
+        funding_outpoint: OutPoint,
+        local_amount: u64,
+        remote_amount: u64,
+        settlement_delay: u16,
+        local_keys: EltooKeys,
+        remote_keys: EltooKeys,
+    ) -> Result<Self, EltooError> {
+        local_amount
+            .checked_add(remote_amount)
+            .ok_or(EltooError::AmountOverflow)?;
+        let eltoo = Eltoo {
+            channel_id: ChannelId::with(funding_outpoint),
+            funding_outpoint,
+            local_amount,
+            remote_amount,
+            state_number: 0,
+            settlement_delay,
+            feerate_per_kw: 0,
+            dust_limit: 0,
+            local_keys,
+            remote_keys,
+            is_originator,
+        };
+        eltoo.check_fees(local_amount, remote_amount, eltoo.feerate_per_kw)?;
+        Ok(eltoo)
+    }
+
+    #[inline]
+    pub fn channel_id(&self) -> ChannelId {
+        self.channel_id
+    }
+
+    /// Returns number of the current channel state
+    #[inline]
+    pub fn state_number(&self) -> u32 {
+        self.state_number
+    }
+
+    #[inline]
+    pub fn funding_amount(&self) -> u64 {
+        self.local_amount + self.remote_amount
+    }
+
+    /// Sets fee rate and dust limit used by the update and settlement
+    /// transactions. Fails if the channel funder balance does not cover the
+    /// fees at the new fee rate.
+    pub fn set_fee_params(
+        &mut self,
+        feerate_per_kw: u32,
+        dust_limit: u64,
+    ) -> Result<(), EltooError> {
+        self.check_fees(self.local_amount, self.remote_amount, feerate_per_kw)?;
+        self.feerate_per_kw = feerate_per_kw;
+        self.dust_limit = dust_limit;
+        Ok(())
+    }
+
+    /// Checks that the balance of the channel funder pays both update and
+    /// settlement transaction fees, so settlement outputs never exceed the
+    /// update output
+    fn check_fees(
+        &self,
+        local_amount: u64,
+        remote_amount: u64,
+        feerate_per_kw: u32,
+    ) -> Result<(), EltooError> {
+        let fee = fee_for_weight(feerate_per_kw, UPDATE_TX_WEIGHT)
+            + fee_for_weight(feerate_per_kw, SETTLEMENT_TX_WEIGHT);
+        let funder_amount = if self.is_originator {
+            local_amount
+        } else {
+            remote_amount
+        };
+        if funder_amount < fee {
+            return Err(EltooError::InsufficientFunderBalance(
+                funder_amount,
+                fee,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns 2-of-2 script of the update keys locking the funding output
+    pub fn funding_script(&self) -> WitnessScript {
+        WitnessScript::ln_funding(
+            self.funding_amount(),
+            self.local_keys.update_pubkey,
+            self.remote_keys.update_pubkey,
+        )
+    }
+
+    /// Returns script of the update transaction output for the given state
+    pub fn update_script(&self, state_number: u32) -> WitnessScript {
+        WitnessScript::ln_eltoo_update(
+            state_number,
+            [
+                self.local_keys.update_pubkey,
+                self.remote_keys.update_pubkey,
+            ],
+            [
+                self.local_keys.settlement_pubkey,
+                self.remote_keys.settlement_pubkey,
+            ],
+            self.settlement_delay,
+        )
+    }
+
+    /// Moves channel to the next state with the given balances, returning
+    /// the new state number
+    pub fn update_state(
+        &mut self,
+        local_amount: u64,
+        remote_amount: u64,
+    ) -> Result<u32, EltooError> {
+        let total = local_amount
+            .checked_add(remote_amount)
+            .ok_or(EltooError::AmountOverflow)?;
+        if total != self.funding_amount() {
+            return Err(EltooError::BalanceMismatch(
+                total,
+                self.funding_amount(),
+            ));
+        }
+        self.check_fees(local_amount, remote_amount, self.feerate_per_kw)?;
+        let state_number = self
+            .state_number
+            .checked_add(1)
+            .filter(|state| *state <= MAX_STATE_NUMBER)
+            .ok_or(EltooError::StateOverflow)?;
+        self.state_number = state_number;
+        self.local_amount = local_amount;
+        self.remote_amount = remote_amount;
+        Ok(state_number)
+    }
+
+    /// Constructs update transaction for the current state spending the
+    /// funding output
+    pub fn update_psbt(&self) -> Psbt {
+        self.bound_update_psbt(
+            self.funding_outpoint,
+            TxOut {
+                value: self.funding_amount(),
+                script_pubkey: self.funding_script().to_p2wsh().into(),
+            },
+            self.funding_script(),
+        )
+    }
+
+    /// Binds update transaction for the current state to the update
+    /// transaction of a previous state published on-chain, placing it into
+    /// the transaction graph. Since the update output value is the same for
+    /// all states, the rebound transaction pays no fee and has to be bumped
+    /// with additional inputs.
+    pub fn bind_update(
+        &self,
+        tx_graph: &mut channel::TxGraph,
+        prev_update: &Transaction,
+    ) -> Result<(), EltooError> {
+        let txid = prev_update.txid();
+        let state_number = prev_update
+            .lock_time
+            .checked_sub(UPDATE_LOCKTIME_THRESHOLD)
+            .ok_or(EltooError::NotUpdate(txid))?;
+        if state_number >= self.state_number {
+            return Err(EltooError::StaleUpdate(
+                state_number,
+                self.state_number,
+            ));
+        }
+        let update_script = self.update_script(state_number);
+        let txout = prev_update
+            .output
+            .get(0)
+            .filter(|txout| {
+                txout.script_pubkey == update_script.to_p2wsh().into_inner()
+            })
+            .ok_or(EltooError::NotUpdate(txid))?;
+
+        let psbt = self.bound_update_psbt(
+            OutPoint::new(txid, 0),
+            txout.clone(),
+            update_script,
+        );
+        tx_graph.insert_tx(TxType::EltooUpdate, state_number as u64, psbt);
+        Ok(())
+    }
+
+    fn bound_update_psbt(
+        &self,
+        prevout: OutPoint,
+        spent: TxOut,
+        witness_script: WitnessScript,
+    ) -> Psbt {
+        let tx = Transaction {
+            version: 2,
+            lock_time: UPDATE_LOCKTIME_THRESHOLD + self.state_number,
+            input: vec![TxIn {
+                previous_output: prevout,
+                script_sig: empty!(),
+                sequence: SEQUENCE_LOCKTIME,
+                witness: empty!(),
+            }],
+            output: vec![self.update_output()],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx).expect(
+            "PSBT construction fails only if script_sig and witness are not \
+             empty; which is not the case here",
+        );
+        psbt.inputs[0].witness_script = Some(witness_script.into_inner());
+        psbt.inputs[0].witness_utxo = Some(spent);
+        psbt.inputs[0].set_anyprevout_sighash(SIGHASH_ANYPREVOUTANYSCRIPT);
+        psbt
+    }
+
+    fn update_fee(&self) -> u64 {
+        fee_for_weight(self.feerate_per_kw, UPDATE_TX_WEIGHT)
+    }
+
+    fn settlement_fee(&self) -> u64 {
+        fee_for_weight(self.feerate_per_kw, SETTLEMENT_TX_WEIGHT)
+    }
+
+    fn update_output(&self) -> TxOut {
+        TxOut {
+            value: self.funding_amount().saturating_sub(self.update_fee()),
+            script_pubkey: self
+                .update_script(self.state_number)
+                .to_p2wsh()
+                .into(),
+        }
+    }
+
+    /// Settlement transaction outputs are the same for both parties: the
+    /// output of the channel funder, which pays all fees, goes first.
+    /// Outputs below the dust limit are trimmed.
+    fn settlement_outputs(&self) -> Vec<TxOut> {
+        let fee = self.update_fee() + self.settlement_fee();
+        let local = TxOut::ln_to_remote_v1(
+            if self.is_originator {
+                self.local_amount.saturating_sub(fee)
+            } else {
+                self.local_amount
+            },
+            self.local_keys.payment_pubkey,
+        );
+        let remote = TxOut::ln_to_remote_v1(
+            if self.is_originator {
+                self.remote_amount
+            } else {
+                self.remote_amount.saturating_sub(fee)
+            },
+            self.remote_keys.payment_pubkey,
+        );
+        let outputs = if self.is_originator {
+            vec![local, remote]
+        } else {
+            vec![remote, local]
+        };
+        outputs
+            .into_iter()
+            .filter(|txout| txout.value >= self.dust_limit)
+            .collect()
+    }
+}
+
+impl channel::State for Eltoo {}
+
+impl Extension for Eltoo {
+    type Identity = ExtensionId;
+
+    fn identity(&self) -> Self::Identity {
+        ExtensionId::Eltoo
+    }
+
+    fn update_from_peer(&mut self, _: &Messages) -> Result<(), channel::Error> {
+        // There are no eltoo channel messages in BOLTs yet
+        Ok(())
+    }
+
+    fn update_from_local(
+        &mut self,
+        _: &Operation,
+    ) -> Result<Vec<Messages>, channel::Error> {
+        Ok(vec![])
+    }
+
+    fn extension_state(&self) -> Vec<u8> {
+        channel::State::to_state_data(self)
+    }
+}
+
+impl ChannelExtension for Eltoo {
+    fn channel_state(&self) -> Vec<u8> {
+        channel::State::to_state_data(self)
+    }
+
+    /// Update transaction spending the funding output is placed into the
+    /// graph as the commitment transaction, followed by the settlement
+    /// transaction spending it. Both transactions are the same for the
+    /// channel parties, so the commitment owner is ignored.
+    fn apply(
+        &mut self,
+        tx_graph: &mut channel::TxGraph,
+    ) -> Result<(), channel::Error> {
+        tx_graph.set_funding_outpoint(self.funding_outpoint);
+        tx_graph.cmt_version = 2;
+        tx_graph.cmt_locktime = UPDATE_LOCKTIME_THRESHOLD + self.state_number;
+        tx_graph.cmt_sequence = SEQUENCE_LOCKTIME;
+        tx_graph.cmt_fee =
+            CommitmentFee::with(self.feerate_per_kw, false, false);
+        tx_graph.cmt_fee.weight = UPDATE_TX_WEIGHT;
+        tx_graph.cmt_fee.fee = self.update_fee();
+        tx_graph.cmt_anchors = none!();
+        tx_graph.cmt_dust_limit = self.dust_limit;
+        tx_graph.cmt_to_self_delay = self.settlement_delay;
+        tx_graph.cmt_outs = vec![self.update_output()];
+
+        let settlement_tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: tx_graph.cmt_outpoint(0),
+                script_sig: empty!(),
+                sequence: self.settlement_delay as u32,
+                witness: empty!(),
+            }],
+            output: self.settlement_outputs(),
+        };
+        let mut psbt = Psbt::from_unsigned_tx(settlement_tx).expect(
+            "PSBT construction fails only if script_sig and witness are not \
+             empty; which is not the case here",
+        );
+        psbt.inputs[0].witness_script =
+            Some(self.update_script(self.state_number).into_inner());
+        psbt.inputs[0].witness_utxo = Some(self.update_output());
+        psbt.inputs[0].set_anyprevout_sighash(SIGHASH_ANYPREVOUT);
+        tx_graph.insert_tx(TxType::EltooSettlement, 0u64, psbt);
+        Ok(())
+    }
+}
+
+/// Access to BIP-118 sighash type stored in the proprietary PSBT input key
+pub trait AnyprevoutSighash {
+    fn anyprevout_sighash(&self) -> Option<u8>;
+
+    fn set_anyprevout_sighash(&mut self, sighash: u8);
+}
+
+impl AnyprevoutSighash for psbt::Input {
+    fn anyprevout_sighash(&self) -> Option<u8> {
+        self.unknown
+            .get(&anyprevout_sighash_key())
+            .and_then(|value| value.first().copied())
+    }
+
+    fn set_anyprevout_sighash(&mut self, sighash: u8) {
+        self.unknown.insert(anyprevout_sighash_key(), vec![sighash]);
+    }
+}
+
+/// Proprietary PSBT key: compact size of the identifier, the identifier and
+/// the key subtype
+fn anyprevout_sighash_key() -> raw::Key {
+    let mut key = vec![PSBT_LNP_PROPRIETARY_PREFIX.len() as u8];
+    key.extend(PSBT_LNP_PROPRIETARY_PREFIX);
+    key.push(PSBT_IN_LNP_ANYPREVOUT_SIGHASH);
+    raw::Key {
+        type_value: 0xFC,
+        key,
+    }
+}
+
+pub trait EltooScripts {
+    fn ln_eltoo_update(
+        state_number: u32,
+        update_pubkeys: [PublicKey; 2],
+        settlement_pubkeys: [PublicKey; 2],
+        settlement_delay: u16,
+    ) -> Self;
+}
+
+impl EltooScripts for LockScript {
+    fn ln_eltoo_update(
+        state_number: u32,
+        update_pubkeys: [PublicKey; 2],
+        settlement_pubkeys: [PublicKey; 2],
+        settlement_delay: u16,
+    ) -> Self {
+        let update = update_pubkeys
+            .iter()
+            .map(|pubkey| pubkey.into_pk())
+            .collect::<Vec<_>>()
+            .lex_ordered();
+        let settlement = settlement_pubkeys
+            .iter()
+            .map(|pubkey| pubkey.into_pk())
+            .collect::<Vec<_>>()
+            .lex_ordered();
+
+        script::Builder::new()
+            .push_opcode(OP_IF)
+            .push_int(
+                (UPDATE_LOCKTIME_THRESHOLD as i64) + state_number as i64 + 1,
+            )
+            .push_opcode(OP_CLTV)
+            .push_opcode(OP_DROP)
+            .push_int(2)
+            .push_key(&update[0])
+            .push_key(&update[1])
+            .push_int(2)
+            .push_opcode(OP_CHECKMULTISIG)
+            .push_opcode(OP_ELSE)
+            .push_int(settlement_delay as i64)
+            .push_opcode(OP_CSV)
+            .push_opcode(OP_DROP)
+            .push_int(2)
+            .push_key(&settlement[0])
+            .push_key(&settlement[1])
+            .push_int(2)
+            .push_opcode(OP_CHECKMULTISIG)
+            .push_opcode(OP_ENDIF)
+            .into_script()
+            .into()
+    }
+}
+
+impl EltooScripts for WitnessScript {
+    #[inline]
+    fn ln_eltoo_update(
+        state_number: u32,
+        update_pubkeys: [PublicKey; 2],
+        settlement_pubkeys: [PublicKey; 2],
+        settlement_delay: u16,
+    ) -> Self {
+        LockScript::ln_eltoo_update(
+            state_number,
+            update_pubkeys,
+            settlement_pubkeys,
+            settlement_delay,
+        )
+        .into()
+    }
+}
+
+impl EltooScripts for PubkeyScript {
+    #[inline]
+    fn ln_eltoo_update(
+        state_number: u32,
+        update_pubkeys: [PublicKey; 2],
+        settlement_pubkeys: [PublicKey; 2],
+        settlement_delay: u16,
+    ) -> Self {
+        WitnessScript::ln_eltoo_update(
+            state_number,
+            update_pubkeys,
+            settlement_pubkeys,
+            settlement_delay,
+        )
+        .to_p2wsh()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::blockdata::script::Instruction;
+    use bitcoin::Script;
+
+    use crate::channel::TxGraph;
+    use crate::payment::keys::test::pubkey;
+
+    fn keys(byte: u8) -> EltooKeys {
+        EltooKeys {
+            update_pubkey: pubkey(byte),
+            settlement_pubkey: pubkey(byte + 1),
+            payment_pubkey: pubkey(byte + 2),
+        }
+    }
+
+    fn eltoo(is_originator: bool) -> Eltoo {
+        let (local, remote) = if is_originator { (1, 4) } else { (4, 1) };
+        let (local_amount, remote_amount) = if is_originator {
+            (700_000, 300_000)
+        } else {
+            (300_000, 700_000)
+        };
+        let mut eltoo = Eltoo::with(
+            is_originator,
+            OutPoint::new(Txid::default(), 1),
+            local_amount,
+            remote_amount,
+            144,
+            keys(local),
+            keys(remote),
+        )
+        .unwrap();
+        eltoo.set_fee_params(1000, 546).unwrap();
+        eltoo
+    }
+
+    fn graph(eltoo: &mut Eltoo) -> TxGraph {
+        let mut tx_graph = TxGraph::default();
+        eltoo.apply(&mut tx_graph).unwrap();
+        tx_graph.link_cmt_txid();
+        tx_graph
+    }
+
+    #[test]
+    fn update_script() {
+        let script =
+            Eltoo::with(true, OutPoint::default(), 0, 0, 144, keys(1), keys(4))
+                .unwrap()
+                .update_script(5)
+                .into_inner();
+        let instructions = script
+            .instructions()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(instructions[0], Instruction::Op(OP_IF));
+        assert_eq!(
+            script::read_scriptint(match instructions[1] {
+                Instruction::PushBytes(bytes) => bytes,
+                _ => panic!("state lock time must be pushed"),
+            })
+            .unwrap(),
+            UPDATE_LOCKTIME_THRESHOLD as i64 + 6
+        );
+        assert_eq!(instructions[2], Instruction::Op(OP_CLTV));
+        assert_eq!(instructions[9], Instruction::Op(OP_ELSE));
+        assert_eq!(
+            script::read_scriptint(match instructions[10] {
+                Instruction::PushBytes(bytes) => bytes,
+                _ => panic!("settlement delay must be pushed"),
+            })
+            .unwrap(),
+            144
+        );
+        assert_eq!(instructions[11], Instruction::Op(OP_CSV));
+        assert_eq!(instructions.last(), Some(&Instruction::Op(OP_ENDIF)));
+    }
+
+    #[test]
+    fn symmetric_transactions() {
+        let mut local = eltoo(true);
+        let mut remote = eltoo(false);
+        assert_eq!(local.channel_id(), remote.channel_id());
+        assert_eq!(local.funding_script(), remote.funding_script());
+        assert_eq!(local.update_script(3), remote.update_script(3));
+
+        let local_graph = graph(&mut local);
+        let remote_graph = graph(&mut remote);
+        assert_eq!(local_graph.render(), remote_graph.render());
+        assert_eq!(local.update_psbt(), remote.update_psbt());
+    }
+
+    #[test]
+    fn settlement() {
+        let mut eltoo = eltoo(true);
+        let tx_graph = graph(&mut eltoo);
+
+        let update_tx = tx_graph.render_cmt().global.unsigned_tx;
+        assert_eq!(update_tx, eltoo.update_psbt().global.unsigned_tx);
+        assert_eq!(update_tx.lock_time, UPDATE_LOCKTIME_THRESHOLD);
+        assert_eq!(update_tx.input[0].sequence, SEQUENCE_LOCKTIME);
+        assert_eq!(
+            update_tx.input[0].previous_output,
+            OutPoint::new(Txid::default(), 1)
+        );
+        assert_eq!(update_tx.output[0].value, 1_000_000 - 686);
+        assert_eq!(
+            eltoo.update_psbt().inputs[0].anyprevout_sighash(),
+            Some(SIGHASH_ANYPREVOUTANYSCRIPT)
+        );
+
+        let psbt = tx_graph.tx(TxType::EltooSettlement, 0u64).unwrap();
+        let settlement_tx = &psbt.global.unsigned_tx;
+        assert_eq!(
+            settlement_tx.input[0].previous_output,
+            OutPoint::new(update_tx.txid(), 0)
+        );
+        assert_eq!(settlement_tx.input[0].sequence, 144);
+        assert_eq!(
+            settlement_tx
+                .output
+                .iter()
+                .map(|txout| txout.value)
+                .collect::<Vec<_>>(),
+            vec![700_000 - 686 - 762, 300_000]
+        );
+        assert_eq!(
+            psbt.inputs[0].anyprevout_sighash(),
+            Some(SIGHASH_ANYPREVOUT)
+        );
+        assert_eq!(
+            psbt.inputs[0].witness_script,
+            Some(eltoo.update_script(0).into_inner())
+        );
+
+        // Dust balance is trimmed
+        eltoo.update_state(999_800, 200).unwrap();
+        let tx_graph = graph(&mut eltoo);
+        let psbt = tx_graph.tx(TxType::EltooSettlement, 0u64).unwrap();
+        assert_eq!(psbt.global.unsigned_tx.output.len(), 1);
+    }
+
+    #[test]
+    fn update_chain() {
+        let mut eltoo = eltoo(true);
+        assert_eq!(
+            eltoo.update_state(700_000, 400_000),
+            Err(EltooError::BalanceMismatch(1_100_000, 1_000_000))
+        );
+        assert_eq!(
+            eltoo.update_state(u64::MAX, 1),
+            Err(EltooError::AmountOverflow)
+        );
+        // Funder must be able to pay 686 + 762 sats of fees
+        assert_eq!(
+            eltoo.update_state(1_000, 999_000),
+            Err(EltooError::InsufficientFunderBalance(1_000, 1_448))
+        );
+        assert_eq!(eltoo.state_number(), 0);
+        assert_eq!(
+            eltoo.set_fee_params(1_000_000, 546),
+            Err(EltooError::InsufficientFunderBalance(700_000, 1_448_000))
+        );
+        assert_eq!(
+            Eltoo::with(
+                true,
+                OutPoint::default(),
+                u64::MAX,
+                1,
+                144,
+                keys(1),
+                keys(4)
+            ),
+            Err(EltooError::AmountOverflow)
+        );
+
+        let mut updates = vec![eltoo.update_psbt().global.unsigned_tx];
+        for state in 1..=3u32 {
+            assert_eq!(
+                eltoo.update_state(
+                    700_000 - state as u64 * 10_000,
+                    300_000 + state as u64 * 10_000
+                ),
+                Ok(state)
+            );
+            let update_tx = eltoo.update_psbt().global.unsigned_tx;
+            assert_eq!(update_tx.lock_time, UPDATE_LOCKTIME_THRESHOLD + state);
+            // Each update output can be spent only by the later updates
+            assert_eq!(
+                update_tx.output[0].script_pubkey,
+                Script::from(eltoo.update_script(state).to_p2wsh())
+            );
+            updates.push(update_tx);
+        }
+
+        let mut limited = eltoo.clone();
+        limited.state_number = MAX_STATE_NUMBER - 1;
+        assert_eq!(
+            limited.update_state(600_000, 400_000),
+            Ok(MAX_STATE_NUMBER)
+        );
+        assert_eq!(
+            limited.update_psbt().global.unsigned_tx.lock_time,
+            1_500_000_000
+        );
+        assert_eq!(
+            limited.update_state(600_000, 400_000),
+            Err(EltooError::StateOverflow)
+        );
+        assert_eq!(limited.state_number(), MAX_STATE_NUMBER);
+
+        let mut tx_graph = graph(&mut eltoo);
+        for (state, prev_update) in updates[..3].iter().enumerate() {
+            eltoo.bind_update(&mut tx_graph, prev_update).unwrap();
+            let psbt = tx_graph.tx(TxType::EltooUpdate, state as u64).unwrap();
+            let bound_tx = &psbt.global.unsigned_tx;
+            assert_eq!(
+                bound_tx.input[0].previous_output,
+                OutPoint::new(prev_update.txid(), 0)
+            );
+            // Lock time satisfies CLTV of the previous update output
+            assert!(bound_tx.lock_time > prev_update.lock_time);
+            assert_eq!(bound_tx.output, updates[3].output);
+            assert_eq!(
+                psbt.inputs[0].witness_script,
+                Some(eltoo.update_script(state as u32).into_inner())
+            );
+            assert_eq!(
+                psbt.inputs[0].anyprevout_sighash(),
+                Some(SIGHASH_ANYPREVOUTANYSCRIPT)
+            );
+        }
+        assert_eq!(tx_graph.last_index(TxType::EltooUpdate), 3);
+
+        assert_eq!(
+            eltoo.bind_update(&mut tx_graph, &updates[3]),
+            Err(EltooError::StaleUpdate(3, 3))
+        );
+        let mut forged = updates[1].clone();
+        forged.output[0].script_pubkey = Script::new();
+        assert_eq!(
+            eltoo.bind_update(&mut tx_graph, &forged),
+            Err(EltooError::NotUpdate(forged.txid()))
+        );
+        let mut unrelated = updates[1].clone();
+        unrelated.lock_time = 0;
+        assert_eq!(
+            eltoo.bind_update(&mut tx_graph, &unrelated),
+            Err(EltooError::NotUpdate(unrelated.txid()))
+        );
+    }
+}
@@ -13,6 +13,9 @@
 
 pub mod bolt3;
 pub mod eltoo;
+pub mod multiparty;
 pub mod taproot;
 
-pub use bolt3::Bolt3;
+pub use bolt3::{Bolt3, Bolt3Error};
+pub use eltoo::Eltoo;
+pub use multiparty::MultiParty;
@@ -0,0 +1,691 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Constructor for the channels funded by more than two parties, locking
+//! funds in N-of-M funding output. The funding output uses
+//! `OP_CHECKMULTISIG`; it will be replaced with a MuSig key once taproot
+//! becomes available. Commitment transaction pays each party its balance
+//! without a revocation mechanism, which requires a channel protocol which
+//! does not depend on the number of parties (like eltoo).
+//!
+//! Since there is no way to invalidate a signed commitment, the channel has
+//! a single state: balances can be changed only until the local node signs
+//! the commitment transaction, after which they are fixed until the channel
+//! is closed.
+
+use std::collections::BTreeMap;
+
+use amplify::Wrapper;
+use bitcoin::blockdata::{opcodes::all::*, script};
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::{OutPoint, Transaction, TxIn, TxOut};
+use wallet::{IntoPk, LexOrder, LockScript, Psbt, PubkeyScript, WitnessScript};
+
+use crate::payment::bolt3::ScriptGenerators;
+use crate::payment::fee::{
+    fee_for_weight, CommitmentFee, COMMITMENT_BASE_WEIGHT,
+};
+use crate::payment::{ExtensionId, Operation};
+use crate::{channel, ChannelExtension, ChannelId, Extension, Messages};
+
+/// Maximum number of the public keys in `OP_CHECKMULTISIG` funding script
+/// standard for P2WSH outputs
+pub const MAX_FUNDING_PARTIES: usize = 20;
+
+/// Weight of each commitment balance output paying to P2WPKH
+pub const BALANCE_OUTPUT_WEIGHT: u64 = 124;
+
+/// Weight of the BOLT-3 P2WSH `to_local` output, which is included into the
+/// two-party commitment weight but is absent in multi-party commitment
+pub const TO_LOCAL_OUTPUT_WEIGHT: u64 = 172;
+
+/// Weight added to the funding input witness by each public key in the
+/// funding script
+pub const FUNDING_KEY_WEIGHT: u64 = 34;
+
+/// Weight added to the funding input witness by each signature
+pub const FUNDING_SIGNATURE_WEIGHT: u64 = 73;
+
+/// Errors constructing multi-party channel
+#[derive(
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Debug,
+    Display,
+    Error,
+    StrictEncode,
+    StrictDecode,
+)]
+#[display(doc_comments)]
+pub enum MultiPartyError {
+    /// multi-party channel requires from 2 to 20 funding parties, while {0}
+    /// parties are provided
+    PartyCount(usize),
+
+    /// funding threshold {0} is invalid for {1} funding parties
+    Threshold(u8, usize),
+
+    /// inputs of the funding party #{0} do not cover its contribution and
+    /// change
+    InsufficientInputs(usize),
+
+    /// {0} balances are provided for {1} funding parties
+    BalanceCount(usize, usize),
+
+    /// sum of the balances {0} does not match the channel funding amount {1}
+    BalanceMismatch(u64, u64),
+
+    /// balances of the parties are short of {0} sats to pay the commitment
+    /// transaction fee
+    FeeShortfall(u64),
+
+    /// balances can't be updated after the commitment transaction is signed
+    StateSigned,
+}
+
+impl From<MultiPartyError> for channel::Error {
+    fn from(err: MultiPartyError) -> Self {
+        channel::Error::Extension(err.to_string())
+    }
+}
+
+/// Party providing funds to the multi-party channel
+#[derive(Clone, PartialEq, Eq, Debug, StrictEncode, StrictDecode)]
+pub struct FundingParty {
+    /// Key used by the party in the funding output script
+    pub funding_pubkey: PublicKey,
+    /// Key receiving the party balance in the commitment transaction
+    pub payment_pubkey: PublicKey,
+    /// Outputs spent by the party in the funding transaction
+    pub prevouts: BTreeMap<OutPoint, TxOut>,
+    /// Amount contributed by the party to the funding output
+    pub contribution: u64,
+    /// Change output of the party in the funding transaction
+    pub change: Option<TxOut>,
+}
+
+impl FundingParty {
+    /// Amount left by the party to pay the funding transaction fee
+    fn funding_fee(&self) -> Option<u64> {
+        let spent: u64 = self.prevouts.values().map(|txout| txout.value).sum();
+        let change = self.change.as_ref().map(|txout| txout.value);
+        self.contribution
+            .checked_add(change.unwrap_or_default())
+            .and_then(|total| spent.checked_sub(total))
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, StrictEncode, StrictDecode)]
+pub struct MultiParty {
+    channel_id: ChannelId,
+    threshold: u8,
+    parties: Vec<FundingParty>,
+    balances: Vec<u64>,
+    feerate_per_kw: u32,
+    dust_limit: u64,
+    signed: bool,
+}
+
+impl MultiParty {
+    /// Constructs channel funded by the given parties, `threshold` of which
+    /// must sign channel transactions. Initial balances of the parties are
+    /// equal to their contributions.
+    pub fn with(
+        threshold: u8,
+        parties: Vec<FundingParty>,
+        feerate_per_kw: u32,
+        dust_limit: u64,
+    ) -> Result<Self, MultiPartyError> {
+        let count = parties.len();
+        if count < 2 || count > MAX_FUNDING_PARTIES {
+            return Err(MultiPartyError::PartyCount(count));
+        }
+        if threshold == 0 || threshold as usize > count {
+            return Err(MultiPartyError::Threshold(threshold, count));
+        }
+        if let Some(index) = parties
+            .iter()
+            .position(|party| party.funding_fee().is_none())
+        {
+            return Err(MultiPartyError::InsufficientInputs(index));
+        }
+
+        let balances = parties.iter().map(|party| party.contribution).collect();
+        let mut channel = MultiParty {
+            channel_id: ChannelId::default(),
+            threshold,
+            parties,
+            balances,
+            feerate_per_kw,
+            dust_limit,
+            signed: false,
+        };
+        channel.channel_id = ChannelId::with(channel.funding_outpoint());
+        Ok(channel)
+    }
+
+    #[inline]
+    pub fn channel_id(&self) -> ChannelId {
+        self.channel_id
+    }
+
+    #[inline]
+    pub fn threshold(&self) -> u8 {
+        self.threshold
+    }
+
+    #[inline]
+    pub fn parties(&self) -> &[FundingParty] {
+        &self.parties
+    }
+
+    /// Returns balances of the parties, in the order of the parties
+    #[inline]
+    pub fn balances(&self) -> &[u64] {
+        &self.balances
+    }
+
+    /// Returns whether the commitment transaction was signed, fixing the
+    /// channel state
+    #[inline]
+    pub fn is_signed(&self) -> bool {
+        self.signed
+    }
+
+    /// Returns amount locked in the funding output
+    pub fn funding_amount(&self) -> u64 {
+        self.parties.iter().map(|party| party.contribution).sum()
+    }
+
+    /// Returns N-of-M multisig script locking the funding output
+    pub fn funding_script(&self) -> WitnessScript {
+        let pubkeys = self
+            .parties
+            .iter()
+            .map(|party| party.funding_pubkey)
+            .collect::<Vec<_>>();
+        LockScript::ln_multisig_funding(self.threshold, &pubkeys).into()
+    }
+
+    /// Constructs funding transaction spending inputs of all parties. The
+    /// funding output goes first, followed by the change outputs in the
+    /// order of the parties.
+    pub fn funding_psbt(&self) -> Psbt {
+        let prevouts = self
+            .parties
+            .iter()
+            .flat_map(|party| party.prevouts.iter())
+            .collect::<Vec<_>>();
+        let funding_output = TxOut {
+            value: self.funding_amount(),
+            script_pubkey: self.funding_script().to_p2wsh().into(),
+        };
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: prevouts
+                .iter()
+                .map(|(outpoint, _)| TxIn {
+                    previous_output: **outpoint,
+                    script_sig: empty!(),
+                    sequence: core::u32::MAX,
+                    witness: empty!(),
+                })
+                .collect(),
+            output: Some(funding_output)
+                .into_iter()
+                .chain(
+                    self.parties
+                        .iter()
+                        .filter_map(|party| party.change.clone()),
+                )
+                .collect(),
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx).expect(
+            "PSBT construction fails only if script_sig and witness are not \
+             empty; which is not the case here",
+        );
+        for (input, (_, txout)) in psbt.inputs.iter_mut().zip(prevouts) {
+            input.witness_utxo = Some(txout.clone());
+        }
+        psbt.outputs[0].witness_script =
+            Some(self.funding_script().into_inner());
+        psbt
+    }
+
+    /// Returns funding outpoint spent by the commitment transaction
+    pub fn funding_outpoint(&self) -> OutPoint {
+        OutPoint::new(self.funding_psbt().global.unsigned_tx.txid(), 0)
+    }
+
+    /// Updates balances of the parties, which must sum up to the funding
+    /// amount. Fails once the commitment transaction is signed.
+    pub fn set_balances(
+        &mut self,
+        balances: Vec<u64>,
+    ) -> Result<(), MultiPartyError> {
+        if self.signed {
+            return Err(MultiPartyError::StateSigned);
+        }
+        if balances.len() != self.parties.len() {
+            return Err(MultiPartyError::BalanceCount(
+                balances.len(),
+                self.parties.len(),
+            ));
+        }
+        let total = balances.iter().sum();
+        if total != self.funding_amount() {
+            return Err(MultiPartyError::BalanceMismatch(
+                total,
+                self.funding_amount(),
+            ));
+        }
+        self.balances = balances;
+        Ok(())
+    }
+
+    /// Weight of the commitment transaction without HTLC outputs. Derived
+    /// from the two-party BOLT-3 commitment weight by replacing its
+    /// `to_local` and `to_remote` outputs with a balance output per party
+    /// and its 2-of-2 funding witness with the N-of-M one.
+    fn base_weight(&self) -> u64 {
+        let parties = self.parties.len() as u64;
+        let threshold = self.threshold as u64;
+        COMMITMENT_BASE_WEIGHT
+            - TO_LOCAL_OUTPUT_WEIGHT
+            - BALANCE_OUTPUT_WEIGHT
+            - 2 * (FUNDING_KEY_WEIGHT + FUNDING_SIGNATURE_WEIGHT)
+            + parties * (BALANCE_OUTPUT_WEIGHT + FUNDING_KEY_WEIGHT)
+            + threshold * FUNDING_SIGNATURE_WEIGHT
+    }
+}
+
+impl channel::State for MultiParty {}
+
+impl Extension for MultiParty {
+    type Identity = ExtensionId;
+
+    fn identity(&self) -> Self::Identity {
+        ExtensionId::MultiParty
+    }
+
+    fn update_from_peer(&mut self, _: &Messages) -> Result<(), channel::Error> {
+        // There are no multi-party channel messages in BOLTs yet
+        Ok(())
+    }
+
+    fn update_from_local(
+        &mut self,
+        operation: &Operation,
+    ) -> Result<Vec<Messages>, channel::Error> {
+        if let Operation::SignCommitment = operation {
+            self.signed = true;
+        }
+        Ok(vec![])
+    }
+
+    fn extension_state(&self) -> Vec<u8> {
+        channel::State::to_state_data(self)
+    }
+}
+
+impl ChannelExtension for MultiParty {
+    fn channel_state(&self) -> Vec<u8> {
+        channel::State::to_state_data(self)
+    }
+
+    /// All parties share the same commitment transaction, so its owner is
+    /// ignored
+    fn apply(
+        &mut self,
+        tx_graph: &mut channel::TxGraph,
+    ) -> Result<(), channel::Error> {
+        let funding_psbt = self.funding_psbt();
+        tx_graph.set_funding_outpoint(OutPoint::new(
+            funding_psbt.global.unsigned_tx.txid(),
+            0,
+        ));
+        tx_graph.set_funding_tx(
+            self.parties.len() as u8,
+            self.threshold,
+            funding_psbt,
+        );
+        tx_graph.cmt_version = 2;
+        tx_graph.cmt_locktime = 0;
+        tx_graph.cmt_sequence = core::u32::MAX;
+        tx_graph.cmt_fee =
+            CommitmentFee::with(self.feerate_per_kw, false, false);
+        tx_graph.cmt_anchors = none!();
+        tx_graph.cmt_dust_limit = self.dust_limit;
+        // Balance outputs go first, so we can find them in `finalize` after
+        // extenders have added their outputs
+        tx_graph.cmt_outs = self
+            .parties
+            .iter()
+            .zip(&self.balances)
+            .map(|(party, balance)| {
+                TxOut::ln_to_remote_v1(*balance, party.payment_pubkey)
+            })
+            .collect();
+        Ok(())
+    }
+
+    fn finalize(
+        &mut self,
+        tx_graph: &mut channel::TxGraph,
+    ) -> Result<(), channel::Error> {
+        tx_graph.cmt_fee.compute();
+        tx_graph.cmt_fee.weight = tx_graph.cmt_fee.weight
+            - COMMITMENT_BASE_WEIGHT
+            + self.base_weight();
+        tx_graph.cmt_fee.fee =
+            fee_for_weight(self.feerate_per_kw, tx_graph.cmt_fee.weight);
+
+        // Fee is split equally between the parties, with the remainder paid
+        // by the first one. Shares which parties can't afford are charged to
+        // the others, starting from the first party.
+        let count = self.parties.len() as u64;
+        let fee = tx_graph.cmt_fee.fee;
+        let mut shortfall = 0u64;
+        for (index, output) in tx_graph
+            .cmt_outs
+            .iter_mut()
+            .take(count as usize)
+            .enumerate()
+        {
+            let share = fee / count + if index == 0 { fee % count } else { 0 };
+            let paid = output.value.min(share);
+            output.value -= paid;
+            shortfall += share - paid;
+        }
+        for output in tx_graph.cmt_outs.iter_mut().take(count as usize) {
+            let paid = output.value.min(shortfall);
+            output.value -= paid;
+            shortfall -= paid;
+        }
+        if shortfall > 0 {
+            return Err(MultiPartyError::FeeShortfall(shortfall).into());
+        }
+
+        // Removing outputs from the last one keeps the indexes of the rest
+        let dust_limit = tx_graph.cmt_dust_limit;
+        for index in (0..count as usize).rev() {
+            if tx_graph.cmt_outs[index].value < dust_limit {
+                let output = tx_graph.remove_cmt_out(index);
+                tx_graph.cmt_fee.trimmed_output_amount += output.value;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub trait MultisigGenerators {
+    fn ln_multisig_funding(threshold: u8, pubkeys: &[PublicKey]) -> Self;
+}
+
+impl MultisigGenerators for LockScript {
+    fn ln_multisig_funding(threshold: u8, pubkeys: &[PublicKey]) -> Self {
+        let pk = pubkeys
+            .iter()
+            .map(|pubkey| pubkey.into_pk())
+            .collect::<Vec<_>>()
+            .lex_ordered();
+
+        pk.iter()
+            .fold(
+                script::Builder::new().push_int(threshold as i64),
+                |builder, pk| builder.push_key(pk),
+            )
+            .push_int(pk.len() as i64)
+            .push_opcode(OP_CHECKMULTISIG)
+            .into_script()
+            .into()
+    }
+}
+
+impl MultisigGenerators for WitnessScript {
+    #[inline]
+    fn ln_multisig_funding(threshold: u8, pubkeys: &[PublicKey]) -> Self {
+        LockScript::ln_multisig_funding(threshold, pubkeys).into()
+    }
+}
+
+impl MultisigGenerators for PubkeyScript {
+    #[inline]
+    fn ln_multisig_funding(threshold: u8, pubkeys: &[PublicKey]) -> Self {
+        WitnessScript::ln_multisig_funding(threshold, pubkeys).to_p2wsh()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use amplify::DumbDefault;
+    use bitcoin::{Script, Txid};
+
+    use crate::channel::{Channel, TxGraph};
+    use crate::payment::bip96::Bip96;
+    use crate::payment::keys::test::pubkey;
+    use crate::payment::Htlc;
+
+    fn party(byte: u8, contribution: u64) -> FundingParty {
+        let mut prevouts = BTreeMap::new();
+        prevouts.insert(
+            OutPoint::new(Txid::default(), byte as u32),
+            TxOut {
+                value: contribution + 10_000,
+                script_pubkey: Script::new(),
+            },
+        );
+        FundingParty {
+            funding_pubkey: pubkey(byte),
+            payment_pubkey: pubkey(byte + 0x10),
+            prevouts,
+            contribution,
+            change: Some(TxOut {
+                value: 9_000,
+                script_pubkey: Script::new(),
+            }),
+        }
+    }
+
+    fn channel() -> MultiParty {
+        MultiParty::with(
+            2,
+            vec![party(1, 100_000), party(2, 200_000), party(3, 300_000)],
+            1000,
+            546,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn construction_errors() {
+        assert_eq!(
+            MultiParty::with(1, vec![party(1, 100_000)], 1000, 546),
+            Err(MultiPartyError::PartyCount(1))
+        );
+        assert_eq!(
+            MultiParty::with(
+                3,
+                vec![party(1, 100_000), party(2, 100_000)],
+                1000,
+                546
+            ),
+            Err(MultiPartyError::Threshold(3, 2))
+        );
+        assert_eq!(
+            MultiParty::with(
+                0,
+                vec![party(1, 100_000), party(2, 100_000)],
+                1000,
+                546
+            ),
+            Err(MultiPartyError::Threshold(0, 2))
+        );
+
+        let mut poor = party(2, 100_000);
+        poor.change.as_mut().unwrap().value = 20_000;
+        assert_eq!(
+            MultiParty::with(2, vec![party(1, 100_000), poor], 1000, 546),
+            Err(MultiPartyError::InsufficientInputs(1))
+        );
+    }
+
+    #[test]
+    fn funding() {
+        let channel = channel();
+        assert_eq!(channel.funding_amount(), 600_000);
+
+        let mut pk = (1..=3)
+            .map(|byte| pubkey(byte).into_pk())
+            .collect::<Vec<_>>();
+        pk.sort_by_key(|pk| pk.to_bytes());
+        let script = script::Builder::new()
+            .push_int(2)
+            .push_key(&pk[0])
+            .push_key(&pk[1])
+            .push_key(&pk[2])
+            .push_int(3)
+            .push_opcode(OP_CHECKMULTISIG)
+            .into_script();
+        assert_eq!(channel.funding_script().into_inner(), script);
+
+        let psbt = channel.funding_psbt();
+        let tx = &psbt.global.unsigned_tx;
+        assert_eq!(tx.input.len(), 3);
+        assert_eq!(tx.output.len(), 4);
+        assert_eq!(tx.output[0].value, 600_000);
+        assert_eq!(
+            tx.output[0].script_pubkey,
+            PubkeyScript::ln_multisig_funding(
+                2,
+                &[pubkey(1), pubkey(2), pubkey(3)]
+            )
+            .into_inner()
+        );
+        assert!(psbt.inputs.iter().all(|input| input.witness_utxo.is_some()));
+        assert_eq!(
+            channel.channel_id(),
+            ChannelId::with(OutPoint::new(tx.txid(), 0))
+        );
+    }
+
+    #[test]
+    fn commitment() {
+        let mut channel = channel();
+        assert_eq!(
+            channel.set_balances(vec![100_000, 500_000]),
+            Err(MultiPartyError::BalanceCount(2, 3))
+        );
+        assert_eq!(
+            channel.set_balances(vec![100_000, 100_000, 100_000]),
+            Err(MultiPartyError::BalanceMismatch(300_000, 600_000))
+        );
+        channel
+            .set_balances(vec![150_000, 150_000, 300_000])
+            .unwrap();
+
+        let mut tx_graph = TxGraph::default();
+        channel.apply(&mut tx_graph).unwrap();
+        channel.finalize(&mut tx_graph).unwrap();
+        assert_eq!(*tx_graph.funding_parties(), 3);
+        assert_eq!(*tx_graph.funding_threshold(), 2);
+        assert_eq!(
+            tx_graph.funding_tx().global.unsigned_tx.txid(),
+            channel.funding_outpoint().txid
+        );
+
+        // 724 - 172 - 124 - 2 * (34 + 73) + 3 * (124 + 34) + 2 * 73 weight
+        // units
+        let fee = 834;
+        assert_eq!(tx_graph.cmt_fee.fee, fee);
+        let cmt_tx = tx_graph.render_cmt().global.unsigned_tx;
+        assert_eq!(cmt_tx.input[0].previous_output, channel.funding_outpoint());
+        assert_eq!(
+            cmt_tx
+                .output
+                .iter()
+                .map(|txout| txout.value)
+                .collect::<Vec<_>>(),
+            vec![150_000 - 278, 150_000 - 278, 300_000 - 278]
+        );
+        assert_eq!(
+            cmt_tx.output[2].script_pubkey,
+            PubkeyScript::ln_to_remote_v1(0, pubkey(0x13)).into_inner()
+        );
+
+        // Dust balances are trimmed, and the fee share of the party without
+        // funds is paid by the first one
+        channel.set_balances(vec![599_500, 500, 0]).unwrap();
+        let mut tx_graph = TxGraph::default();
+        channel.apply(&mut tx_graph).unwrap();
+        channel.finalize(&mut tx_graph).unwrap();
+        assert_eq!(tx_graph.cmt_outs.len(), 1);
+        assert_eq!(tx_graph.cmt_outs[0].value, 599_500 - 2 * 278);
+        assert_eq!(tx_graph.cmt_fee.trimmed_output_amount, 500 - 278);
+    }
+
+    #[test]
+    fn fee_shortfall() {
+        let mut channel = MultiParty::with(
+            2,
+            vec![party(1, 300), party(2, 200), party(3, 100)],
+            1000,
+            546,
+        )
+        .unwrap();
+        let mut tx_graph = TxGraph::default();
+        channel.apply(&mut tx_graph).unwrap();
+        assert_eq!(
+            channel.finalize(&mut tx_graph),
+            Err(MultiPartyError::FeeShortfall(834 - 600).into())
+        );
+    }
+
+    #[test]
+    fn single_state() {
+        let mut channel = channel();
+        channel
+            .set_balances(vec![150_000, 150_000, 300_000])
+            .unwrap();
+        assert!(!channel.is_signed());
+        channel
+            .update_from_local(&Operation::SignCommitment)
+            .unwrap();
+        assert!(channel.is_signed());
+        assert_eq!(
+            channel.set_balances(vec![100_000, 200_000, 300_000]),
+            Err(MultiPartyError::StateSigned)
+        );
+        assert_eq!(channel.balances(), &[150_000, 150_000, 300_000]);
+    }
+
+    #[test]
+    fn channel_layout() {
+        let mut channel =
+            Channel::with(channel(), vec![Htlc::dumb_default()], vec![Bip96]);
+        let mut tx_graph = TxGraph::default();
+        channel.apply(&mut tx_graph).unwrap();
+        assert_eq!(tx_graph.cmt_outs.len(), 3);
+
+        let restored =
+            Channel::<ExtensionId>::restore(channel.integral_state()).unwrap();
+        assert_eq!(restored.integral_state(), channel.integral_state());
+    }
+}
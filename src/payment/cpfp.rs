@@ -0,0 +1,473 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Child-pays-for-parent fee bumping of the commitment transactions with
+//! anchor outputs and sweeping of the anchors left unspent after 16 blocks
+//! (see BOLT-3 "`to_local_anchor` and `to_remote_anchor` Output" section).
+
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::util::psbt::PartiallySignedTransaction as Psbt;
+use bitcoin::{OutPoint, Script, SigHashType, Transaction, TxIn, TxOut, Txid};
+use wallet::{PubkeyScript, WitnessScript};
+
+use crate::payment::anchor_out::ScriptGenerators;
+use crate::payment::bolt3::DEFAULT_DUST_LIMIT;
+use crate::payment::fee::{fee_for_weight, CommitmentFee, ANCHOR_OUTPUT_VALUE};
+
+/// Weight of the transaction fields not related to inputs and outputs:
+/// version, lock time, input and output counts and segwit marker with flag
+pub const TX_BASE_WEIGHT: u64 = 42;
+
+/// Weight of a transaction input without its witness
+pub const TXIN_BASE_WEIGHT: u64 = 164;
+
+/// Maximal length of DER-encoded signature with the sighash type byte
+pub const MAX_SIGNATURE_LEN: u64 = 73;
+
+/// Maximal weight of the witness spending P2WPKH output
+pub const P2WPKH_SATISFACTION_WEIGHT: u64 = 109;
+
+/// Maximal weight of the witness spending anchor output with the signature
+/// of the funding key
+pub const ANCHOR_SATISFACTION_WEIGHT: u64 = 116;
+
+/// Weight of the witness spending anchor output by anyone after 16 blocks
+pub const ANCHOR_SWEEP_SATISFACTION_WEIGHT: u64 = 43;
+
+/// Relative lock time after which anchor output can be spent by anyone
+pub const ANCHOR_SWEEP_DELAY: u32 = 16;
+
+/// Input sequence signalling replaceability, so the fee bump can be bumped
+/// again
+pub(crate) const SEQUENCE_RBF: u32 = 0xFFFF_FFFD;
+
+/// Errors constructing CPFP or anchor sweeping transactions
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum CpfpError {
+    /// commitment transaction {0} has no anchor output for the provided
+    /// funding public key
+    NoAnchor(Txid),
+
+    /// wallet funds are insufficient: {required} sats are required for the
+    /// fee and change, while only {available} sats are available
+    InsufficientFunds { required: u64, available: u64 },
+}
+
+/// Wallet output which can be used to fund fee bumping transaction
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct WalletUtxo {
+    pub outpoint: OutPoint,
+    pub txout: TxOut,
+    /// Witness script, if the output is P2WSH
+    pub witness_script: Option<WitnessScript>,
+    /// Maximal weight of the witness satisfying the output
+    pub satisfaction_weight: u64,
+}
+
+impl WalletUtxo {
+    /// Constructs P2WPKH wallet output
+    pub fn p2wpkh(outpoint: OutPoint, txout: TxOut) -> Self {
+        WalletUtxo {
+            outpoint,
+            txout,
+            witness_script: None,
+            satisfaction_weight: P2WPKH_SATISFACTION_WEIGHT,
+        }
+    }
+}
+
+/// Input of the constructed transaction with the data required for PSBT
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub(crate) struct SpendInput {
+    pub outpoint: OutPoint,
+    pub txout: TxOut,
+    pub witness_script: Option<Script>,
+    pub sequence: u32,
+    pub satisfaction_weight: u64,
+    pub sighash_type: Option<SigHashType>,
+    pub final_witness: Option<Vec<Vec<u8>>>,
+}
+
+impl From<&WalletUtxo> for SpendInput {
+    fn from(utxo: &WalletUtxo) -> Self {
+        SpendInput {
+            outpoint: utxo.outpoint,
+            txout: utxo.txout.clone(),
+            witness_script: utxo.witness_script.clone().map(Script::from),
+            sequence: SEQUENCE_RBF,
+            satisfaction_weight: utxo.satisfaction_weight,
+            sighash_type: None,
+            final_witness: None,
+        }
+    }
+}
+
+/// Builder of the child transaction bumping fee of the unconfirmed
+/// commitment transaction by spending its anchor output belonging to the
+/// local node together with the wallet outputs.
+///
+/// The child transaction pays enough fee for the package of the commitment
+/// and the child to reach the target fee rate, sending the rest of the funds
+/// to the change output.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct CpfpBuilder {
+    anchor: SpendInput,
+    commitment_weight: u64,
+    commitment_fee: u64,
+    feerate_per_kw: u32,
+    change_script: PubkeyScript,
+    utxos: Vec<WalletUtxo>,
+}
+
+impl CpfpBuilder {
+    /// Starts construction of CPFP transaction for the commitment
+    /// transaction and its fee breakdown, locating the anchor output for the
+    /// local `funding_pubkey`
+    pub fn with(
+        commitment: &Transaction,
+        commitment_fee: &CommitmentFee,
+        funding_pubkey: PublicKey,
+        feerate_per_kw: u32,
+        change_script: PubkeyScript,
+    ) -> Result<Self, CpfpError> {
+        let txid = commitment.txid();
+        let anchor = TxOut::ln_anchor(ANCHOR_OUTPUT_VALUE, funding_pubkey);
+        let vout = commitment
+            .output
+            .iter()
+            .position(|txout| *txout == anchor)
+            .ok_or(CpfpError::NoAnchor(txid))?;
+        Ok(CpfpBuilder {
+            anchor: SpendInput {
+                outpoint: OutPoint::new(txid, vout as u32),
+                txout: anchor,
+                witness_script: Some(
+                    WitnessScript::ln_anchor(
+                        ANCHOR_OUTPUT_VALUE,
+                        funding_pubkey,
+                    )
+                    .into(),
+                ),
+                sequence: SEQUENCE_RBF,
+                satisfaction_weight: ANCHOR_SATISFACTION_WEIGHT,
+                sighash_type: None,
+                final_witness: None,
+            },
+            commitment_weight: commitment_fee.weight,
+            commitment_fee: commitment_fee.fee
+                + commitment_fee.trimmed_htlc_amount
+                + commitment_fee.trimmed_output_amount,
+            feerate_per_kw,
+            change_script,
+            utxos: vec![],
+        })
+    }
+
+    /// Returns outpoint of the anchor output spent by the CPFP transaction
+    #[inline]
+    pub fn anchor_outpoint(&self) -> OutPoint {
+        self.anchor.outpoint
+    }
+
+    /// Adds wallet output which may be used to fund the CPFP transaction
+    pub fn add_utxo(&mut self, utxo: WalletUtxo) -> &mut Self {
+        self.utxos.push(utxo);
+        self
+    }
+
+    /// Constructs CPFP transaction, selecting the wallet outputs starting from
+    /// the largest ones. The anchor is always the first input.
+    pub fn build(&self) -> Result<Psbt, CpfpError> {
+        let package_weight = self.commitment_weight;
+        let package_fee = self.commitment_fee;
+        let feerate_per_kw = self.feerate_per_kw;
+        fund(
+            vec![self.anchor.clone()],
+            &self.utxos,
+            &self.change_script,
+            |weight| {
+                // Child must pay at least for itself even if the parent
+                // already pays enough
+                let package =
+                    fee_for_weight(feerate_per_kw, package_weight + weight)
+                        .saturating_sub(package_fee);
+                package.max(fee_for_weight(feerate_per_kw, weight))
+            },
+        )
+    }
+}
+
+/// Constructs transaction sweeping anchor outputs, which may be spent by
+/// anyone after 16 blocks since the commitment confirmation. Anchors are
+/// given by their outpoints and the funding public key of the party owning
+/// the anchor. Since anchor value usually does not cover the fee, the
+/// wallet outputs are added to the transaction if required.
+///
+/// Anchor inputs do not require signatures, so they are finalized in the
+/// returned PSBT.
+pub fn sweep_anchors(
+    anchors: &[(OutPoint, PublicKey)],
+    utxos: &[WalletUtxo],
+    feerate_per_kw: u32,
+    change_script: &PubkeyScript,
+) -> Result<Psbt, CpfpError> {
+    let inputs = anchors
+        .iter()
+        .map(|(outpoint, funding_pubkey)| {
+            let witness_script = Script::from(WitnessScript::ln_anchor(
+                ANCHOR_OUTPUT_VALUE,
+                *funding_pubkey,
+            ));
+            SpendInput {
+                outpoint: *outpoint,
+                txout: TxOut::ln_anchor(ANCHOR_OUTPUT_VALUE, *funding_pubkey),
+                final_witness: Some(vec![vec![], witness_script.to_bytes()]),
+                witness_script: Some(witness_script),
+                sequence: ANCHOR_SWEEP_DELAY,
+                satisfaction_weight: ANCHOR_SWEEP_SATISFACTION_WEIGHT,
+                sighash_type: None,
+            }
+        })
+        .collect();
+    fund(inputs, utxos, change_script, |weight| {
+        fee_for_weight(feerate_per_kw, weight)
+    })
+}
+
+/// Computes weight of the transaction spending `inputs` to the single output
+/// with the given script
+pub(crate) fn tx_weight(
+    inputs: &[SpendInput],
+    change_script: &PubkeyScript,
+) -> u64 {
+    let script_len = Script::from(change_script.clone()).len() as u64;
+    let output_weight = (8 + 1 + script_len) * 4;
+    TX_BASE_WEIGHT
+        + output_weight
+        + inputs
+            .iter()
+            .map(|input| TXIN_BASE_WEIGHT + input.satisfaction_weight)
+            .sum::<u64>()
+}
+
+/// Adds wallet outputs, starting from the largest, to the mandatory inputs
+/// until they cover the fee computed by `required_fee` from the transaction
+/// weight and leave non-dust change
+fn fund(
+    mut inputs: Vec<SpendInput>,
+    utxos: &[WalletUtxo],
+    change_script: &PubkeyScript,
+    required_fee: impl Fn(u64) -> u64,
+) -> Result<Psbt, CpfpError> {
+    let mut utxos = utxos.iter().collect::<Vec<_>>();
+    utxos.sort_by(|a, b| b.txout.value.cmp(&a.txout.value));
+    let mut utxos = utxos.into_iter();
+
+    loop {
+        let available =
+            inputs.iter().map(|input| input.txout.value).sum::<u64>();
+        let fee = required_fee(tx_weight(&inputs, change_script));
+        if available >= fee + DEFAULT_DUST_LIMIT {
+            return Ok(spending_psbt(
+                inputs,
+                0,
+                available - fee,
+                change_script,
+            ));
+        }
+        match utxos.next() {
+            Some(utxo) => inputs.push(utxo.into()),
+            None => {
+                return Err(CpfpError::InsufficientFunds {
+                    required: fee + DEFAULT_DUST_LIMIT,
+                    available,
+                })
+            }
+        }
+    }
+}
+
+/// Constructs PSBT spending `inputs` to the single output with the given
+/// value and script
+pub(crate) fn spending_psbt(
+    inputs: Vec<SpendInput>,
+    lock_time: u32,
+    change: u64,
+    change_script: &PubkeyScript,
+) -> Psbt {
+    let tx = Transaction {
+        version: 2,
+        lock_time,
+        input: inputs
+            .iter()
+            .map(|input| TxIn {
+                previous_output: input.outpoint,
+                script_sig: empty!(),
+                sequence: input.sequence,
+                witness: empty!(),
+            })
+            .collect(),
+        output: vec![TxOut {
+            value: change,
+            script_pubkey: change_script.clone().into(),
+        }],
+    };
+    let mut psbt = Psbt::from_unsigned_tx(tx)
+        .expect("Tx has empty sigs so PSBT creation does not fail");
+    for (psbt_input, input) in psbt.inputs.iter_mut().zip(inputs) {
+        psbt_input.witness_utxo = Some(input.txout);
+        psbt_input.witness_script = input.witness_script;
+        psbt_input.sighash_type = input.sighash_type;
+        psbt_input.final_script_witness = input.final_witness;
+    }
+    psbt
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::hashes::hex::FromHex;
+    use bitcoin::hashes::Hash;
+
+    fn funding_pubkey() -> PublicKey {
+        PublicKey::from_slice(
+            &Vec::<u8>::from_hex(
+                "023da092f6980e58d2c037173180e9a465476026ee50f96695963e8efe436f54eb",
+            )
+            .unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn change_script() -> PubkeyScript {
+        Script::new_v0_wpkh(&bitcoin::WPubkeyHash::hash(&[0u8; 33])).into()
+    }
+
+    fn utxo(index: u8, value: u64) -> WalletUtxo {
+        WalletUtxo::p2wpkh(
+            OutPoint::new(Txid::from_inner([index; 32]), 0),
+            TxOut {
+                value,
+                script_pubkey: change_script().into(),
+            },
+        )
+    }
+
+    fn commitment() -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![
+                TxOut {
+                    value: 3_000_000,
+                    script_pubkey: change_script().into(),
+                },
+                TxOut::ln_anchor(ANCHOR_OUTPUT_VALUE, funding_pubkey()),
+            ],
+        }
+    }
+
+    #[test]
+    fn cpfp() {
+        let mut fee = CommitmentFee::with(253, true, false);
+        fee.compute();
+        let mut builder = CpfpBuilder::with(
+            &commitment(),
+            &fee,
+            funding_pubkey(),
+            5000,
+            change_script(),
+        )
+        .unwrap();
+        assert_eq!(builder.anchor_outpoint().vout, 1);
+        assert_eq!(
+            builder.build(),
+            Err(CpfpError::InsufficientFunds {
+                required: 5000 * (1124 + 42 + 124 + 164 + 116) / 1000 - 284
+                    + 546,
+                available: 330
+            })
+        );
+
+        builder.add_utxo(utxo(1, 2000)).add_utxo(utxo(2, 100_000));
+        let psbt = builder.build().unwrap();
+        let tx = &psbt.global.unsigned_tx;
+        // Only the largest wallet output is used
+        assert_eq!(tx.input.len(), 2);
+        assert_eq!(tx.input[0].previous_output, builder.anchor_outpoint());
+        assert_eq!(tx.input[1].previous_output, utxo(2, 0).outpoint);
+        let child_weight = 42 + 124 + 2 * 164 + 116 + 109;
+        let child_fee = 5000 * (1124 + child_weight) / 1000 - 284;
+        assert_eq!(tx.output[0].value, 100_330 - child_fee);
+        assert_eq!(
+            psbt.inputs[0].witness_script,
+            Some(WitnessScript::ln_anchor(330, funding_pubkey()).into())
+        );
+        assert_eq!(
+            psbt.inputs[0].witness_utxo,
+            Some(TxOut::ln_anchor(330, funding_pubkey()))
+        );
+        assert_eq!(psbt.inputs[1].witness_utxo, Some(utxo(2, 100_000).txout));
+
+        assert_eq!(
+            CpfpBuilder::with(
+                &commitment(),
+                &fee,
+                *wallet::SECP256K1_PUBKEY_DUMB,
+                5000,
+                change_script(),
+            ),
+            Err(CpfpError::NoAnchor(commitment().txid()))
+        );
+    }
+
+    #[test]
+    fn anchor_sweep() {
+        let anchors = (0u8..4)
+            .map(|index| {
+                (
+                    OutPoint::new(Txid::from_inner([index; 32]), 0),
+                    funding_pubkey(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let psbt = sweep_anchors(&anchors, &[], 253, &change_script()).unwrap();
+        let tx = &psbt.global.unsigned_tx;
+        assert_eq!(tx.input.len(), 4);
+        assert!(tx.input.iter().all(|txin| txin.sequence == 16));
+        let weight = 42 + 124 + 4 * (164 + 43);
+        assert_eq!(tx.output[0].value, 4 * 330 - 253 * weight / 1000);
+        let witness_script =
+            Script::from(WitnessScript::ln_anchor(330, funding_pubkey()));
+        assert_eq!(
+            psbt.inputs[0].final_script_witness,
+            Some(vec![vec![], witness_script.to_bytes()])
+        );
+
+        // Single anchor can't cover the fee and change
+        assert!(
+            sweep_anchors(&anchors[..1], &[], 253, &change_script()).is_err()
+        );
+        let psbt = sweep_anchors(
+            &anchors[..1],
+            &[utxo(5, 10_000)],
+            253,
+            &change_script(),
+        )
+        .unwrap();
+        assert_eq!(psbt.global.unsigned_tx.input.len(), 2);
+        assert_eq!(psbt.global.unsigned_tx.input[1].sequence, SEQUENCE_RBF);
+    }
+}
@@ -10,3 +10,212 @@
 // You should have received a copy of the MIT License
 // along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
+
+use amplify::DumbDefault;
+use bitcoin::blockdata::{opcodes::all::*, script};
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::{SigHashType, TxOut};
+use wallet::{
+    IntoPk, LockScript, PubkeyScript, WitnessScript, SECP256K1_PUBKEY_DUMB,
+};
+
+use crate::channel::CommitmentOwner;
+use crate::payment::fee::ANCHOR_OUTPUT_VALUE;
+use crate::payment::{ExtensionId, Operation, TxType};
+use crate::{channel, ChannelExtension, Extension, Messages};
+
+/// Anchor outputs extension (`option_anchor_outputs` and
+/// `option_anchors_zero_fee_htlc_tx`) adding to the commitment transaction
+/// a pair of small outputs which can be spent by the channel parties to bump
+/// commitment transaction fee with CPFP.
+///
+/// The extension is active only when the constructor has negotiated
+/// commitment type with anchors, i.e. sets [`CommitmentFee::anchors`]
+/// flag in the transaction graph.
+///
+/// [`CommitmentFee::anchors`]: crate::payment::fee::CommitmentFee::anchors
+#[derive(Clone, PartialEq, Eq, Debug, StrictEncode, StrictDecode)]
+pub struct AnchorOut {
+    local_funding_pubkey: PublicKey,
+    remote_funding_pubkey: PublicKey,
+}
+
+impl AnchorOut {
+    /// Constructs anchor extension with the funding public key of the local
+    /// node, which is used in the anchor output of the local party
+    pub fn with(local_funding_pubkey: PublicKey) -> Self {
+        AnchorOut {
+            local_funding_pubkey,
+            remote_funding_pubkey: *SECP256K1_PUBKEY_DUMB,
+        }
+    }
+}
+
+impl DumbDefault for AnchorOut {
+    fn dumb_default() -> Self {
+        AnchorOut::with(*SECP256K1_PUBKEY_DUMB)
+    }
+}
+
+impl channel::State for AnchorOut {}
+
+impl Extension for AnchorOut {
+    type Identity = ExtensionId;
+
+    fn identity(&self) -> Self::Identity {
+        ExtensionId::AnchorOut
+    }
+
+    fn update_from_peer(
+        &mut self,
+        message: &Messages,
+    ) -> Result<(), channel::Error> {
+        match message {
+            Messages::OpenChannel(open_channel) => {
+                self.remote_funding_pubkey = open_channel.funding_pubkey;
+            }
+            Messages::AcceptChannel(accept_channel) => {
+                self.remote_funding_pubkey = accept_channel.funding_pubkey;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn update_from_local(
+        &mut self,
+        _: &Operation,
+    ) -> Result<Vec<Messages>, channel::Error> {
+        // Anchor outputs do not require any interaction with the remote peer
+        Ok(vec![])
+    }
+
+    fn extension_state(&self) -> Vec<u8> {
+        channel::State::to_state_data(self)
+    }
+}
+
+impl ChannelExtension for AnchorOut {
+    fn channel_state(&self) -> Vec<u8> {
+        channel::State::to_state_data(self)
+    }
+
+    fn apply(
+        &mut self,
+        tx_graph: &mut channel::TxGraph,
+    ) -> Result<(), channel::Error> {
+        if !tx_graph.cmt_fee.anchors {
+            return Ok(());
+        }
+
+        let (to_local_pubkey, to_remote_pubkey) = match tx_graph.cmt_owner {
+            CommitmentOwner::Local => {
+                (self.local_funding_pubkey, self.remote_funding_pubkey)
+            }
+            CommitmentOwner::Remote => {
+                (self.remote_funding_pubkey, self.local_funding_pubkey)
+            }
+        };
+        // Anchors are placed by the constructor, since an anchor must be
+        // omitted when the matching `to_local` or `to_remote` output is
+        // trimmed
+        tx_graph.cmt_anchors = vec![
+            TxOut::ln_anchor(ANCHOR_OUTPUT_VALUE, to_local_pubkey),
+            TxOut::ln_anchor(ANCHOR_OUTPUT_VALUE, to_remote_pubkey),
+        ];
+
+        // Second-stage HTLC transactions are signed by the remote peer with
+        // `SIGHASH_SINGLE|SIGHASH_ANYONECANPAY`, so the local node can attach
+        // more inputs and outputs to pay the fee; their inputs must satisfy
+        // one block CSV lock of the HTLC outputs
+        let success: u16 = TxType::HtlcSuccess.into();
+        let timeout: u16 = TxType::HtlcTimeout.into();
+        tx_graph
+            .vec_mut()
+            .into_iter()
+            .filter(|(role, _, _)| *role == success || *role == timeout)
+            .for_each(|(_, _, psbt)| {
+                psbt.global.unsigned_tx.input[0].sequence = 1;
+                psbt.inputs[0].sighash_type =
+                    Some(SigHashType::SinglePlusAnyoneCanPay);
+            });
+
+        Ok(())
+    }
+}
+
+pub trait ScriptGenerators {
+    fn ln_anchor(amount: u64, funding_pubkey: PublicKey) -> Self;
+}
+
+impl ScriptGenerators for LockScript {
+    fn ln_anchor(_: u64, funding_pubkey: PublicKey) -> Self {
+        script::Builder::new()
+            .push_key(&funding_pubkey.into_pk())
+            .push_opcode(OP_CHECKSIG)
+            .push_opcode(OP_IFDUP)
+            .push_opcode(OP_NOTIF)
+            .push_int(16)
+            .push_opcode(OP_CSV)
+            .push_opcode(OP_ENDIF)
+            .into_script()
+            .into()
+    }
+}
+
+impl ScriptGenerators for WitnessScript {
+    #[inline]
+    fn ln_anchor(amount: u64, funding_pubkey: PublicKey) -> Self {
+        LockScript::ln_anchor(amount, funding_pubkey).into()
+    }
+}
+
+impl ScriptGenerators for PubkeyScript {
+    #[inline]
+    fn ln_anchor(amount: u64, funding_pubkey: PublicKey) -> Self {
+        WitnessScript::ln_anchor(amount, funding_pubkey).to_p2wsh()
+    }
+}
+
+impl ScriptGenerators for TxOut {
+    #[inline]
+    fn ln_anchor(amount: u64, funding_pubkey: PublicKey) -> Self {
+        TxOut {
+            value: amount,
+            script_pubkey: PubkeyScript::ln_anchor(amount, funding_pubkey)
+                .into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::hashes::hex::{FromHex, ToHex};
+    use bitcoin::Script;
+
+    #[test]
+    fn anchor_script() {
+        // Funding pubkey from BOLT-3 appendix B
+        let funding_pubkey = PublicKey::from_slice(
+            &Vec::<u8>::from_hex(
+                "023da092f6980e58d2c037173180e9a465476026ee50f96695963e8efe436f54eb",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let script = Script::from(LockScript::ln_anchor(330, funding_pubkey));
+        assert_eq!(
+            script.to_hex(),
+            "21023da092f6980e58d2c037173180e9a465476026ee50f96695963e8efe43\
+             6f54ebac736460b268"
+        );
+        let txout = TxOut::ln_anchor(330, funding_pubkey);
+        assert_eq!(txout.value, 330);
+        assert_eq!(
+            txout.script_pubkey.to_hex(),
+            "00202b1b5854183c12d3316565972c4668929d314d81c5dcdbb21cb45fe8a9a8\
+             114f"
+        );
+    }
+}
@@ -11,19 +11,306 @@
 // along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
+use amplify::DumbDefault;
 use bitcoin::blockdata::{opcodes::all::*, script};
-use bitcoin::secp256k1::PublicKey;
+use bitcoin::hashes::{ripemd160, Hash};
+use bitcoin::secp256k1::{PublicKey, Signature};
 use bitcoin::util::psbt::PartiallySignedTransaction as Psbt;
-use bitcoin::{OutPoint, Transaction, TxIn, TxOut};
+use bitcoin::{OutPoint, SigHashType, Transaction, TxIn, TxOut};
 use lnpbp::chain::AssetId;
 use wallet::{
     HashLock, HashPreimage, IntoPk, LockScript, PubkeyScript, WitnessScript,
+    SECP256K1_PUBKEY_DUMB,
 };
 
-use crate::payment::{ExtensionId, TxType};
+use crate::channel::CommitmentOwner;
+use crate::message::{
+    UpdateAddHtlc, UpdateFailHtlc, UpdateFailMalformedHtlc, UpdateFulfillHtlc,
+};
+use crate::payment::channel::Params;
+use crate::payment::keys::{Basepoints, CommitmentKeys};
+use crate::payment::{ExtensionId, Operation, TxType};
 use crate::{channel, ChannelExtension, ChannelId, Extension, Messages};
 
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+/// State of HTLC in the commitment update process defined by BOLT-2. Each
+/// update goes through the commitment of the peer which has received the
+/// update, revocation of its previous commitment and then the same two steps
+/// for the commitment of the peer which has sent the update. State names
+/// follow the last event which has happened to the HTLC.
+#[derive(
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+)]
+#[display(Debug)]
+pub enum HtlcState {
+    // HTLC offered by the local node
+    SentAddHtlc,
+    SentAddCommit,
+    RcvdAddRevocation,
+    RcvdAddAckCommit,
+    SentAddAckRevocation,
+    RcvdRemoveHtlc,
+    RcvdRemoveCommit,
+    SentRemoveRevocation,
+    SentRemoveAckCommit,
+    RcvdRemoveAckRevocation,
+
+    // HTLC offered by the remote peer
+    RcvdAddHtlc,
+    RcvdAddCommit,
+    SentAddRevocation,
+    SentAddAckCommit,
+    RcvdAddAckRevocation,
+    SentRemoveHtlc,
+    SentRemoveCommit,
+    RcvdRemoveRevocation,
+    RcvdRemoveAckCommit,
+    SentRemoveAckRevocation,
+}
+
+impl HtlcState {
+    /// Transition on sending `commitment_signed` to the remote peer
+    pub fn on_commitment_sent(self) -> Self {
+        match self {
+            HtlcState::SentAddHtlc => HtlcState::SentAddCommit,
+            HtlcState::SentRemoveHtlc => HtlcState::SentRemoveCommit,
+            HtlcState::SentAddRevocation => HtlcState::SentAddAckCommit,
+            HtlcState::SentRemoveRevocation => HtlcState::SentRemoveAckCommit,
+            state => state,
+        }
+    }
+
+    /// Transition on receiving `commitment_signed` from the remote peer
+    pub fn on_commitment_received(self) -> Self {
+        match self {
+            HtlcState::RcvdAddHtlc => HtlcState::RcvdAddCommit,
+            HtlcState::RcvdRemoveHtlc => HtlcState::RcvdRemoveCommit,
+            HtlcState::RcvdAddRevocation => HtlcState::RcvdAddAckCommit,
+            HtlcState::RcvdRemoveRevocation => HtlcState::RcvdRemoveAckCommit,
+            state => state,
+        }
+    }
+
+    /// Transition on sending `revoke_and_ack` to the remote peer
+    pub fn on_revocation_sent(self) -> Self {
+        match self {
+            HtlcState::RcvdAddCommit => HtlcState::SentAddRevocation,
+            HtlcState::RcvdRemoveCommit => HtlcState::SentRemoveRevocation,
+            HtlcState::RcvdAddAckCommit => HtlcState::SentAddAckRevocation,
+            HtlcState::RcvdRemoveAckCommit => {
+                HtlcState::SentRemoveAckRevocation
+            }
+            state => state,
+        }
+    }
+
+    /// Transition on receiving `revoke_and_ack` from the remote peer
+    pub fn on_revocation_received(self) -> Self {
+        match self {
+            HtlcState::SentAddCommit => HtlcState::RcvdAddRevocation,
+            HtlcState::SentRemoveCommit => HtlcState::RcvdRemoveRevocation,
+            HtlcState::SentAddAckCommit => HtlcState::RcvdAddAckRevocation,
+            HtlcState::SentRemoveAckCommit => {
+                HtlcState::RcvdRemoveAckRevocation
+            }
+            state => state,
+        }
+    }
+
+    /// Detects whether HTLC is irrevocably committed by both peers, so it
+    /// can be fulfilled or failed
+    #[inline]
+    pub fn is_committed(self) -> bool {
+        matches!(
+            self,
+            HtlcState::SentAddAckRevocation | HtlcState::RcvdAddAckRevocation
+        )
+    }
+
+    /// Detects whether HTLC removal is irrevocably committed by both peers,
+    /// so the HTLC can be forgotten
+    #[inline]
+    pub fn is_removed(self) -> bool {
+        matches!(
+            self,
+            HtlcState::RcvdRemoveAckRevocation
+                | HtlcState::SentRemoveAckRevocation
+        )
+    }
+
+    /// Detects whether HTLC removal has been started by one of the peers
+    #[inline]
+    pub fn is_removing(self) -> bool {
+        matches!(
+            self,
+            HtlcState::RcvdRemoveHtlc
+                | HtlcState::RcvdRemoveCommit
+                | HtlcState::SentRemoveRevocation
+                | HtlcState::SentRemoveAckCommit
+                | HtlcState::RcvdRemoveAckRevocation
+                | HtlcState::SentRemoveHtlc
+                | HtlcState::SentRemoveCommit
+                | HtlcState::RcvdRemoveRevocation
+                | HtlcState::RcvdRemoveAckCommit
+                | HtlcState::SentRemoveAckRevocation
+        )
+    }
+
+    /// Detects whether HTLC is present in the latest commitment transaction
+    /// of the given channel party
+    pub fn in_commitment(self, owner: CommitmentOwner) -> bool {
+        match owner {
+            CommitmentOwner::Local => matches!(
+                self,
+                HtlcState::RcvdAddAckCommit
+                    | HtlcState::SentAddAckRevocation
+                    | HtlcState::RcvdRemoveHtlc
+                    | HtlcState::RcvdAddCommit
+                    | HtlcState::SentAddRevocation
+                    | HtlcState::SentAddAckCommit
+                    | HtlcState::RcvdAddAckRevocation
+                    | HtlcState::SentRemoveHtlc
+                    | HtlcState::SentRemoveCommit
+                    | HtlcState::RcvdRemoveRevocation
+            ),
+            CommitmentOwner::Remote => matches!(
+                self,
+                HtlcState::SentAddCommit
+                    | HtlcState::RcvdAddRevocation
+                    | HtlcState::RcvdAddAckCommit
+                    | HtlcState::SentAddAckRevocation
+                    | HtlcState::RcvdRemoveHtlc
+                    | HtlcState::RcvdRemoveCommit
+                    | HtlcState::SentRemoveRevocation
+                    | HtlcState::SentAddAckCommit
+                    | HtlcState::RcvdAddAckRevocation
+                    | HtlcState::SentRemoveHtlc
+            ),
+        }
+    }
+}
+
+/// BOLT-4 failure code flag for the failures which are permanent
+pub const FAILURE_FLAG_PERM: u16 = 0x4000;
+/// BOLT-4 failure code flag for the failures which must be accompanied by the
+/// `channel_update` message
+pub const FAILURE_FLAG_UPDATE: u16 = 0x1000;
+
+/// BOLT-4 `temporary_channel_failure` failure code
+pub const TEMPORARY_CHANNEL_FAILURE: u16 = FAILURE_FLAG_UPDATE | 7;
+/// BOLT-4 `amount_below_minimum` failure code
+pub const AMOUNT_BELOW_MINIMUM: u16 = FAILURE_FLAG_UPDATE | 11;
+/// BOLT-4 `incorrect_or_unknown_payment_details` failure code
+pub const INCORRECT_OR_UNKNOWN_PAYMENT_DETAILS: u16 = FAILURE_FLAG_PERM | 15;
+/// BOLT-4 `expiry_too_far` failure code
+pub const EXPIRY_TOO_FAR: u16 = 21;
+
+/// Errors of the HTLC extension
+#[derive(
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Debug,
+    Display,
+    Error,
+    StrictEncode,
+    StrictDecode,
+)]
+#[display(doc_comments)]
+pub enum HtlcError {
+    /// HTLC amount {0} msat is below the channel minimum of {1} msat
+    AmountBelowMinimum(u64, u64),
+
+    /// HTLC amount {0} msat exceeds the amount which can exist in bitcoin
+    /// network
+    AmountOverflow(u64),
+
+    /// number of the accepted HTLCs has reached the channel limit of {0}
+    TooManyHtlcs(u16),
+
+    /// HTLC of {0} msat exceeds the limit of {1} msat for the value of HTLCs
+    /// in flight
+    InFlightExceeded(u64, u64),
+
+    /// HTLC CLTV expiry {0} is not a block height
+    InvalidCltvExpiry(u32),
+
+    /// HTLC id {0} is used out of order; the next HTLC id must be {1}
+    IdReuse(u64, u64),
+
+    /// HTLC #{0} is not known
+    UnknownId(u64),
+
+    /// payment preimage does not match the hash lock of HTLC #{0}
+    PreimageMismatch(u64),
+
+    /// HTLC message for channel {0} is received by the channel {1}
+    ChannelIdMismatch(ChannelId, ChannelId),
+
+    /// HTLC #{0} is not irrevocably committed by both peers yet
+    NotCommitted(u64),
+
+    /// HTLC transaction has no witness script
+    NoWitnessScript,
+
+    /// HTLC transaction does not specify the commitment output it spends
+    NoSpentOutput,
+}
+
+impl HtlcError {
+    /// Returns BOLT-4 failure code for the errors caused by the HTLC which can
+    /// be failed back upstream. Other errors are violations of the protocol
+    /// by the remote peer, so `None` is returned and the channel has to be
+    /// failed with an `error` message.
+    ///
+    /// NB: codes having [`FAILURE_FLAG_UPDATE`] set must be accompanied by the
+    /// latest `channel_update` in the onion failure message
+    pub fn failure_code(self) -> Option<u16> {
+        match self {
+            HtlcError::AmountBelowMinimum(..) => Some(AMOUNT_BELOW_MINIMUM),
+            HtlcError::TooManyHtlcs(_) | HtlcError::InFlightExceeded(..) => {
+                Some(TEMPORARY_CHANNEL_FAILURE)
+            }
+            HtlcError::InvalidCltvExpiry(_) => Some(EXPIRY_TOO_FAR),
+            HtlcError::PreimageMismatch(_) => {
+                Some(INCORRECT_OR_UNKNOWN_PAYMENT_DETAILS)
+            }
+            HtlcError::AmountOverflow(_)
+            | HtlcError::IdReuse(..)
+            | HtlcError::UnknownId(_)
+            | HtlcError::ChannelIdMismatch(..)
+            | HtlcError::NotCommitted(_)
+            | HtlcError::NoWitnessScript
+            | HtlcError::NoSpentOutput => None,
+        }
+    }
+}
+
+#[derive(
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Debug,
+    StrictEncode,
+    StrictDecode,
+)]
 pub struct HtlcKnown {
     pub amount: u64,
     pub preimage: HashPreimage,
@@ -32,16 +319,38 @@ pub struct HtlcKnown {
     pub asset_id: Option<AssetId>,
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[derive(
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Debug,
+    StrictEncode,
+    StrictDecode,
+)]
 pub struct HtlcSecret {
     pub amount: u64,
     pub hashlock: HashLock,
     pub id: u64,
     pub cltv_expiry: u32,
     pub asset_id: Option<AssetId>,
+    pub state: HtlcState,
 }
 
-#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[derive(
+    Clone,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Debug,
+    StrictEncode,
+    StrictDecode,
+)]
 pub struct Htlc {
     // Sets of HTLC informations
     offered_htlcs: Vec<HtlcSecret>,
@@ -49,15 +358,12 @@ pub struct Htlc {
     resolved_htlcs: Vec<HtlcKnown>,
 
     // Commitment round specific information
-    to_self_delay: u16,
-    revocation_pubkey: PublicKey,
-    local_htlc_pubkey: PublicKey,
-    remote_htlc_pubkey: PublicKey,
-    local_delayed_pubkey: PublicKey,
+    local_keys: Basepoints,
+    remote_keys: Basepoints,
+    remote_per_commitment_point: PublicKey,
 
     // Channel specific information
     channel_id: ChannelId,
-    commitment_outpoint: OutPoint,
     htlc_minimum_msat: u64,
     max_htlc_value_in_flight_msat: u64,
     total_htlc_value_in_flight_msat: u64,
@@ -65,6 +371,161 @@ pub struct Htlc {
     total_accepted_htlcs: u16,
     last_recieved_htlc_id: u64,
     last_offered_htlc_id: u64,
+
+    // Limits for the HTLCs offered by the local node, as specified by the
+    // remote peer in its `open_channel` or `accept_channel` message
+    remote_htlc_minimum_msat: u64,
+    remote_max_htlc_value_in_flight_msat: u64,
+    remote_max_accepted_htlcs: u16,
+}
+
+impl Htlc {
+    /// Constructs HTLC extension with the local basepoints and the limits for
+    /// the HTLCs offered by the remote peer, as specified by the local node
+    /// in its `open_channel` or `accept_channel` message
+    pub fn with(local_keys: Basepoints, local_params: &Params) -> Self {
+        Htlc {
+            local_keys,
+            htlc_minimum_msat: local_params.htlc_minimum_msat,
+            max_htlc_value_in_flight_msat: local_params
+                .max_htlc_value_in_flight_msat,
+            max_accepted_htlcs: local_params.max_accepted_htlcs,
+            ..Htlc::dumb_default()
+        }
+    }
+
+    /// Returns keys for the HTLC outputs of the current remote commitment
+    /// transaction
+    #[inline]
+    pub fn remote_commitment_keys(&self) -> CommitmentKeys {
+        CommitmentKeys::derive(
+            &self.remote_keys,
+            &self.local_keys,
+            self.remote_per_commitment_point,
+        )
+    }
+
+    fn received_htlc_index(
+        &self,
+        htlc_id: u64,
+    ) -> Result<usize, channel::Error> {
+        self.received_htlcs
+            .iter()
+            .position(|htlc| htlc.id == htlc_id)
+            .ok_or(HtlcError::UnknownId(htlc_id).into())
+    }
+
+    fn offered_htlc_index(
+        &self,
+        htlc_id: u64,
+    ) -> Result<usize, channel::Error> {
+        self.offered_htlcs
+            .iter()
+            .position(|htlc| htlc.id == htlc_id)
+            .ok_or(HtlcError::UnknownId(htlc_id).into())
+    }
+
+    /// Starts removal of the HTLC, which is allowed only once the HTLC is
+    /// irrevocably committed by both peers
+    fn start_removal(
+        htlc: &mut HtlcSecret,
+        state: HtlcState,
+    ) -> Result<(), channel::Error> {
+        if !htlc.state.is_committed() {
+            return Err(HtlcError::NotCommitted(htlc.id).into());
+        }
+        htlc.state = state;
+        Ok(())
+    }
+
+    /// Applies commitment update transition to all HTLCs, forgetting the ones
+    /// which removal is irrevocably committed
+    fn update_states(&mut self, transition: fn(HtlcState) -> HtlcState) {
+        self.offered_htlcs
+            .iter_mut()
+            .chain(self.received_htlcs.iter_mut())
+            .for_each(|htlc| htlc.state = transition(htlc.state));
+
+        self.offered_htlcs.retain(|htlc| !htlc.state.is_removed());
+        let (removed, received): (Vec<_>, Vec<_>) = self
+            .received_htlcs
+            .iter()
+            .partition(|htlc| htlc.state.is_removed());
+        self.received_htlcs = received;
+        for htlc in removed {
+            self.total_accepted_htlcs =
+                self.total_accepted_htlcs.saturating_sub(1);
+            self.total_htlc_value_in_flight_msat = self
+                .total_htlc_value_in_flight_msat
+                .saturating_sub(htlc.amount);
+        }
+    }
+
+    /// Forgets all updates which were not yet covered by a commitment
+    /// signature on channel reestablishment, as required by BOLT-2. Forgotten
+    /// HTLC ids are reused by the updates re-sent after the reconnection.
+    fn forget_uncommitted(&mut self) {
+        if let Some(htlc) = self
+            .offered_htlcs
+            .iter()
+            .find(|htlc| htlc.state == HtlcState::SentAddHtlc)
+        {
+            self.last_offered_htlc_id = htlc.id;
+        }
+        self.offered_htlcs
+            .retain(|htlc| htlc.state != HtlcState::SentAddHtlc);
+
+        let (forgotten, received): (Vec<_>, Vec<_>) = self
+            .received_htlcs
+            .iter()
+            .partition(|htlc| htlc.state == HtlcState::RcvdAddHtlc);
+        self.received_htlcs = received;
+        if let Some(htlc) = forgotten.first() {
+            self.last_recieved_htlc_id = htlc.id;
+        }
+        for htlc in forgotten {
+            self.total_accepted_htlcs =
+                self.total_accepted_htlcs.saturating_sub(1);
+            self.total_htlc_value_in_flight_msat = self
+                .total_htlc_value_in_flight_msat
+                .saturating_sub(htlc.amount);
+        }
+
+        for htlc in &mut self.offered_htlcs {
+            if htlc.state == HtlcState::RcvdRemoveHtlc {
+                htlc.state = HtlcState::SentAddAckRevocation;
+            }
+        }
+        for htlc in &mut self.received_htlcs {
+            if htlc.state == HtlcState::SentRemoveHtlc {
+                htlc.state = HtlcState::RcvdAddAckRevocation;
+            }
+        }
+    }
+}
+
+impl DumbDefault for Htlc {
+    fn dumb_default() -> Self {
+        Self {
+            offered_htlcs: empty!(),
+            received_htlcs: empty!(),
+            resolved_htlcs: empty!(),
+            local_keys: Basepoints::dumb_default(),
+            remote_keys: Basepoints::dumb_default(),
+            remote_per_commitment_point: *SECP256K1_PUBKEY_DUMB,
+            channel_id: Default::default(),
+            htlc_minimum_msat: 0,
+            max_htlc_value_in_flight_msat: 0,
+            total_htlc_value_in_flight_msat: 0,
+            max_accepted_htlcs: 0,
+            total_accepted_htlcs: 0,
+            last_recieved_htlc_id: 0,
+            last_offered_htlc_id: 0,
+            remote_htlc_minimum_msat: 0,
+            remote_max_htlc_value_in_flight_msat: 0,
+            remote_max_accepted_htlcs: 0,
+        }
+    }
 }
 
 impl channel::State for Htlc {}
@@ -81,6 +542,36 @@ impl Extension for Htlc {
         message: &Messages,
     ) -> Result<(), channel::Error> {
         match message {
+            Messages::OpenChannel(open_channel) => {
+                self.remote_keys = Basepoints::from(open_channel);
+                self.remote_per_commitment_point =
+                    open_channel.first_per_commitment_point;
+                self.remote_htlc_minimum_msat = open_channel.htlc_minimum_msat;
+                self.remote_max_htlc_value_in_flight_msat =
+                    open_channel.max_htlc_value_in_flight_msat;
+                self.remote_max_accepted_htlcs =
+                    open_channel.max_accepted_htlcs;
+            }
+            Messages::AcceptChannel(accept_channel) => {
+                self.remote_keys = Basepoints::from(accept_channel);
+                self.remote_per_commitment_point =
+                    accept_channel.first_per_commitment_point;
+                self.remote_htlc_minimum_msat =
+                    accept_channel.htlc_minimum_msat;
+                self.remote_max_htlc_value_in_flight_msat =
+                    accept_channel.max_htlc_value_in_flight_msat;
+                self.remote_max_accepted_htlcs =
+                    accept_channel.max_accepted_htlcs;
+            }
+            Messages::FundingCreated(funding_created) => {
+                self.channel_id = ChannelId::with(OutPoint::new(
+                    funding_created.funding_txid,
+                    funding_created.funding_output_index as u32,
+                ));
+            }
+            Messages::FundingSigned(funding_signed) => {
+                self.channel_id = funding_signed.channel_id;
+            }
             Messages::UpdateAddHtlc(message) => {
                 if message.channel_id == self.channel_id {
                     // Checks
@@ -91,36 +582,47 @@ impl Extension for Htlc {
                     if message.amount_msat == 0
                         || message.amount_msat < self.htlc_minimum_msat
                     {
-                        return Err(channel::Error::HTLC(
-                            "amount_msat has to be greaterthan 0".to_string(),
-                        ));
+                        return Err(HtlcError::AmountBelowMinimum(
+                            message.amount_msat,
+                            self.htlc_minimum_msat,
+                        )
+                        .into());
                     } else if self.total_accepted_htlcs
                         == self.max_accepted_htlcs
                     {
-                        return Err(channel::Error::HTLC(
-                            "max no. of HTLC limit exceeded".to_string(),
-                        ));
-                    } else if message.amount_msat
-                        + self.total_htlc_value_in_flight_msat
+                        return Err(HtlcError::TooManyHtlcs(
+                            self.max_accepted_htlcs,
+                        )
+                        .into());
+                    } else if message
+                        .amount_msat
+                        .checked_add(self.total_htlc_value_in_flight_msat)
+                        .ok_or(HtlcError::AmountOverflow(message.amount_msat))?
                         > self.max_htlc_value_in_flight_msat
                     {
-                        return Err(channel::Error::HTLC(
-                            "max HTLC inflight amount limit exceeded"
-                                .to_string(),
-                        ));
-                    } else if message.cltv_expiry > 500000000 {
-                        return Err(channel::Error::HTLC(
-                            "cltv_expiry limit exceeded".to_string(),
-                        ));
+                        return Err(HtlcError::InFlightExceeded(
+                            message.amount_msat,
+                            self.max_htlc_value_in_flight_msat,
+                        )
+                        .into());
+                    } else if message.cltv_expiry >= 500000000 {
+                        return Err(HtlcError::InvalidCltvExpiry(
+                            message.cltv_expiry,
+                        )
+                        .into());
                     } else if message.amount_msat.leading_zeros() < 32 {
-                        return Err(channel::Error::HTLC(
-                            "Leading zeros not satisfied for Bitcoin network"
-                                .to_string(),
-                        ));
-                    } else if message.htlc_id <= self.last_recieved_htlc_id {
-                        return Err(channel::Error::HTLC(
-                            "HTLC id violation occured".to_string(),
-                        )); // TODO handle reconnection
+                        return Err(HtlcError::AmountOverflow(
+                            message.amount_msat,
+                        )
+                        .into());
+                    } else if message.htlc_id != self.last_recieved_htlc_id {
+                        // HTLC ids are assigned sequentially starting from
+                        // zero
+                        return Err(HtlcError::IdReuse(
+                            message.htlc_id,
+                            self.last_recieved_htlc_id,
+                        )
+                        .into());
                     } else {
                         let htlc = HtlcSecret {
                             amount: message.amount_msat,
@@ -131,35 +633,37 @@ impl Extension for Htlc {
                             asset_id: message.asset_id,
                             #[cfg(not(feature = "rgb"))]
                             asset_id: None,
+                            state: HtlcState::RcvdAddHtlc,
                         };
                         self.received_htlcs.push(htlc);
 
                         self.last_recieved_htlc_id += 1;
+                        self.total_accepted_htlcs += 1;
+                        self.total_htlc_value_in_flight_msat +=
+                            message.amount_msat;
                     }
                 } else {
-                    return Err(channel::Error::HTLC(
-                        "Missmatched channel_id, bad remote node".to_string(),
-                    ));
+                    return Err(HtlcError::ChannelIdMismatch(
+                        message.channel_id,
+                        self.channel_id,
+                    )
+                    .into());
                 }
             }
             Messages::UpdateFulfillHtlc(message) => {
                 if message.channel_id == self.channel_id {
                     // Get the corresponding offered htlc
-                    let (index, offered_htlc) = self
-                        .offered_htlcs
-                        .iter()
-                        .enumerate()
-                        .filter(|(_, htlc)| htlc.id == message.htlc_id)
-                        .next()
-                        .ok_or(channel::Error::HTLC(
-                            "HTLC id didn't match".to_string(),
-                        ))?;
+                    let index = self.offered_htlc_index(message.htlc_id)?;
+                    let offered_htlc = self.offered_htlcs[index];
 
                     // Check for correct hash preimage in the message
                     if offered_htlc.hashlock
                         == HashLock::from(message.payment_preimage)
                     {
-                        self.received_htlcs.remove(index);
+                        Self::start_removal(
+                            &mut self.offered_htlcs[index],
+                            HtlcState::RcvdRemoveHtlc,
+                        )?;
                         let resolved_htlc = HtlcKnown {
                             amount: offered_htlc.amount,
                             preimage: message.payment_preimage,
@@ -169,114 +673,359 @@ impl Extension for Htlc {
                         };
 
                         self.resolved_htlcs.push(resolved_htlc);
+                    } else {
+                        return Err(HtlcError::PreimageMismatch(
+                            message.htlc_id,
+                        )
+                        .into());
                     }
                 } else {
-                    return Err(channel::Error::HTLC(
-                        "Missmatched channel_id, bad remote node".to_string(),
-                    ));
+                    return Err(HtlcError::ChannelIdMismatch(
+                        message.channel_id,
+                        self.channel_id,
+                    )
+                    .into());
                 }
             }
             Messages::UpdateFailHtlc(message) => {
                 if message.channel_id == self.channel_id {
                     // get the offered HTLC to fail
-                    let (index, _) = self
-                        .offered_htlcs
-                        .iter()
-                        .enumerate()
-                        .filter(|(_, htlc)| htlc.id == message.htlc_id)
-                        .next()
-                        .ok_or(channel::Error::HTLC(
-                            "HTLC id didn't match".to_string(),
-                        ))?;
-
-                    self.offered_htlcs.remove(index);
+                    let index = self.offered_htlc_index(message.htlc_id)?;
+                    Self::start_removal(
+                        &mut self.offered_htlcs[index],
+                        HtlcState::RcvdRemoveHtlc,
+                    )?;
 
                     // TODO the failure reason should be handled here
+                } else {
+                    return Err(HtlcError::ChannelIdMismatch(
+                        message.channel_id,
+                        self.channel_id,
+                    )
+                    .into());
+                }
+            }
+            Messages::UpdateFailMalformedHtlc(message) => {
+                if message.channel_id == self.channel_id {
+                    let index = self.offered_htlc_index(message.htlc_id)?;
+                    Self::start_removal(
+                        &mut self.offered_htlcs[index],
+                        HtlcState::RcvdRemoveHtlc,
+                    )?;
+                } else {
+                    return Err(HtlcError::ChannelIdMismatch(
+                        message.channel_id,
+                        self.channel_id,
+                    )
+                    .into());
                 }
             }
-            Messages::UpdateFailMalformedHtlc(_) => {}
-            Messages::CommitmentSigned(_) => {}
-            Messages::RevokeAndAck(_) => {}
-            Messages::ChannelReestablish(_) => {}
+            Messages::CommitmentSigned(_) => {
+                self.update_states(HtlcState::on_commitment_received);
+            }
+            Messages::RevokeAndAck(revoke_and_ack) => {
+                self.remote_per_commitment_point =
+                    revoke_and_ack.next_per_commitment_point;
+                self.update_states(HtlcState::on_revocation_received);
+            }
+            Messages::ChannelReestablish(message) => {
+                if message.channel_id != self.channel_id {
+                    return Err(HtlcError::ChannelIdMismatch(
+                        message.channel_id,
+                        self.channel_id,
+                    )
+                    .into());
+                }
+                self.forget_uncommitted();
+            }
             _ => {}
         }
         Ok(())
     }
 
-    fn extension_state(&self) -> Box<dyn channel::State> {
-        Box::new(self.clone())
+    fn update_from_local(
+        &mut self,
+        operation: &Operation,
+    ) -> Result<Vec<Messages>, channel::Error> {
+        let message = match operation {
+            Operation::AddHtlc {
+                amount_msat,
+                payment_hash,
+                cltv_expiry,
+                onion_routing_packet,
+                asset_id,
+            } => {
+                // The remote peer would fail the channel on receiving HTLC
+                // violating its limits
+                let in_flight_msat = self
+                    .offered_htlcs
+                    .iter()
+                    .try_fold(*amount_msat, |sum, htlc| {
+                        sum.checked_add(htlc.amount)
+                    })
+                    .ok_or(HtlcError::AmountOverflow(*amount_msat))?;
+                if *amount_msat == 0
+                    || *amount_msat < self.remote_htlc_minimum_msat
+                {
+                    return Err(HtlcError::AmountBelowMinimum(
+                        *amount_msat,
+                        self.remote_htlc_minimum_msat.max(1),
+                    )
+                    .into());
+                } else if self.offered_htlcs.len()
+                    >= self.remote_max_accepted_htlcs as usize
+                {
+                    return Err(HtlcError::TooManyHtlcs(
+                        self.remote_max_accepted_htlcs,
+                    )
+                    .into());
+                } else if in_flight_msat
+                    > self.remote_max_htlc_value_in_flight_msat
+                {
+                    return Err(HtlcError::InFlightExceeded(
+                        *amount_msat,
+                        self.remote_max_htlc_value_in_flight_msat,
+                    )
+                    .into());
+                } else if *cltv_expiry >= 500000000 {
+                    return Err(
+                        HtlcError::InvalidCltvExpiry(*cltv_expiry).into()
+                    );
+                }
+
+                // HTLC ids are assigned sequentially starting from zero
+                let htlc_id = self.last_offered_htlc_id;
+                self.last_offered_htlc_id += 1;
+
+                self.offered_htlcs.push(HtlcSecret {
+                    amount: *amount_msat,
+                    hashlock: *payment_hash,
+                    id: htlc_id,
+                    cltv_expiry: *cltv_expiry,
+                    asset_id: *asset_id,
+                    state: HtlcState::SentAddHtlc,
+                });
+
+                Messages::UpdateAddHtlc(UpdateAddHtlc {
+                    channel_id: self.channel_id,
+                    htlc_id,
+                    amount_msat: *amount_msat,
+                    payment_hash: *payment_hash,
+                    cltv_expiry: *cltv_expiry,
+                    onion_routing_packet: onion_routing_packet.clone(),
+                    asset_id: *asset_id,
+                })
+            }
+            Operation::FulfillHtlc {
+                htlc_id,
+                payment_preimage,
+            } => {
+                let index = self.received_htlc_index(*htlc_id)?;
+                let received_htlc = self.received_htlcs[index];
+                if received_htlc.hashlock != HashLock::from(*payment_preimage) {
+                    return Err(HtlcError::PreimageMismatch(*htlc_id).into());
+                }
+                Self::start_removal(
+                    &mut self.received_htlcs[index],
+                    HtlcState::SentRemoveHtlc,
+                )?;
+                self.resolved_htlcs.push(HtlcKnown {
+                    amount: received_htlc.amount,
+                    preimage: *payment_preimage,
+                    id: *htlc_id,
+                    cltv_expiry: received_htlc.cltv_expiry,
+                    asset_id: received_htlc.asset_id,
+                });
+
+                Messages::UpdateFulfillHtlc(UpdateFulfillHtlc {
+                    channel_id: self.channel_id,
+                    htlc_id: *htlc_id,
+                    payment_preimage: *payment_preimage,
+                })
+            }
+            Operation::FailHtlc { htlc_id, reason } => {
+                let index = self.received_htlc_index(*htlc_id)?;
+                Self::start_removal(
+                    &mut self.received_htlcs[index],
+                    HtlcState::SentRemoveHtlc,
+                )?;
+
+                Messages::UpdateFailHtlc(UpdateFailHtlc {
+                    channel_id: self.channel_id,
+                    htlc_id: *htlc_id,
+                    reason: reason.clone(),
+                })
+            }
+            Operation::FailMalformedHtlc {
+                htlc_id,
+                sha256_of_onion,
+                failure_code,
+            } => {
+                let index = self.received_htlc_index(*htlc_id)?;
+                Self::start_removal(
+                    &mut self.received_htlcs[index],
+                    HtlcState::SentRemoveHtlc,
+                )?;
+
+                Messages::UpdateFailMalformedHtlc(UpdateFailMalformedHtlc {
+                    channel_id: self.channel_id,
+                    htlc_id: *htlc_id,
+                    sha256_of_onion: *sha256_of_onion,
+                    failure_code: *failure_code,
+                })
+            }
+            Operation::SignCommitment => {
+                self.update_states(HtlcState::on_commitment_sent);
+                return Ok(vec![]);
+            }
+            Operation::RevokeCommitment => {
+                self.update_states(HtlcState::on_revocation_sent);
+                return Ok(vec![]);
+            }
+            _ => return Ok(vec![]),
+        };
+        Ok(vec![message])
+    }
+
+    fn extension_state(&self) -> Vec<u8> {
+        channel::State::to_state_data(self)
     }
 }
 
 impl ChannelExtension for Htlc {
-    fn channel_state(&self) -> Box<dyn channel::State> {
-        Box::new(self.clone())
+    fn channel_state(&self) -> Vec<u8> {
+        channel::State::to_state_data(self)
     }
 
     fn apply(
         &mut self,
         tx_graph: &mut channel::TxGraph,
     ) -> Result<(), channel::Error> {
+        // HTLCs offered by us are received by the remote peer in its own
+        // commitment transaction, and vice versa
+        let owner = tx_graph.cmt_owner;
+        let (keys, offered_htlcs, received_htlcs) = match owner {
+            CommitmentOwner::Local => (
+                CommitmentKeys::derive(
+                    &self.local_keys,
+                    &self.remote_keys,
+                    tx_graph.cmt_per_commitment_point,
+                ),
+                &self.offered_htlcs,
+                &self.received_htlcs,
+            ),
+            CommitmentOwner::Remote => (
+                CommitmentKeys::derive(
+                    &self.remote_keys,
+                    &self.local_keys,
+                    tx_graph.cmt_per_commitment_point,
+                ),
+                &self.received_htlcs,
+                &self.offered_htlcs,
+            ),
+        };
+        let anchors = tx_graph.cmt_fee.anchors;
+        let dust_limit = tx_graph.cmt_dust_limit;
+        let to_self_delay = tx_graph.cmt_to_self_delay;
+
         // Process offered HTLCs
-        for (index, offered) in self.offered_htlcs.iter().enumerate() {
-            let htlc_output = TxOut::ln_offered_htlc(
-                offered.amount,
-                self.revocation_pubkey,
-                self.local_htlc_pubkey,
-                self.remote_htlc_pubkey,
+        let timeout_fee = tx_graph.cmt_fee.htlc_tx_fee(true);
+        // Only HTLCs locked in the owner commitment are included
+        for offered in offered_htlcs
+            .iter()
+            .filter(|htlc| htlc.state.in_commitment(owner))
+        {
+            let amount = offered.amount / 1000;
+            // HTLC is trimmed if its second-stage transaction output would be
+            // below dust limit
+            if !tx_graph.cmt_fee.add_htlc(amount, true, dust_limit) {
+                continue;
+            }
+            let witness_script = WitnessScript::ln_offered_htlc(
+                amount,
+                keys.revocationpubkey,
+                keys.local_htlcpubkey,
+                keys.remote_htlcpubkey,
                 offered.hashlock,
+                anchors,
             );
-            tx_graph.cmt_outs.push(htlc_output); // Should htlc outputs be inside graph.cmt?
+            let htlc_output = TxOut {
+                value: amount,
+                script_pubkey: witness_script.to_p2wsh().into(),
+            };
+            let vout = tx_graph.cmt_outs.len() as u32;
+            tx_graph.cmt_outs.push(htlc_output.clone());
+            tx_graph.cmt_cltv_expiry.insert(vout, offered.cltv_expiry);
 
-            let htlc_tx = Psbt::ln_htlc(
-                offered.amount,
-                self.commitment_outpoint,
+            let mut htlc_tx = Psbt::ln_htlc(
+                amount - timeout_fee,
+                tx_graph.cmt_outpoint(vout),
                 offered.cltv_expiry,
-                self.revocation_pubkey,
-                self.local_delayed_pubkey,
-                self.to_self_delay,
-            );
-            // Last index of transaction in graph
-            let last_index = tx_graph.last_index(TxType::HtlcTimeout) + 1;
-            tx_graph.insert_tx(
-                TxType::HtlcTimeout,
-                (last_index + index) as u64,
-                htlc_tx,
+                keys.revocationpubkey,
+                keys.local_delayedpubkey,
+                to_self_delay,
             );
+            set_htlc_input(&mut htlc_tx, htlc_output, witness_script);
+            let index = tx_graph.last_index(TxType::HtlcTimeout);
+            tx_graph.insert_tx(TxType::HtlcTimeout, index as u64, htlc_tx);
         }
 
         // Process recieved HTLCs
-        for (index, recieved) in self.received_htlcs.iter().enumerate() {
-            let htlc_output = TxOut::ln_received_htlc(
-                recieved.amount,
-                self.revocation_pubkey,
-                self.local_htlc_pubkey,
-                self.remote_htlc_pubkey,
+        let success_fee = tx_graph.cmt_fee.htlc_tx_fee(false);
+        for recieved in received_htlcs
+            .iter()
+            .filter(|htlc| htlc.state.in_commitment(owner))
+        {
+            let amount = recieved.amount / 1000;
+            if !tx_graph.cmt_fee.add_htlc(amount, false, dust_limit) {
+                continue;
+            }
+            let witness_script = WitnessScript::ln_received_htlc(
+                amount,
+                keys.revocationpubkey,
+                keys.local_htlcpubkey,
+                keys.remote_htlcpubkey,
                 recieved.cltv_expiry,
-                recieved.hashlock.clone(),
+                recieved.hashlock,
+                anchors,
             );
-            tx_graph.cmt_outs.push(htlc_output);
+            let htlc_output = TxOut {
+                value: amount,
+                script_pubkey: witness_script.to_p2wsh().into(),
+            };
+            let vout = tx_graph.cmt_outs.len() as u32;
+            tx_graph.cmt_outs.push(htlc_output.clone());
+            tx_graph.cmt_cltv_expiry.insert(vout, recieved.cltv_expiry);
 
-            let htlc_tx = Psbt::ln_htlc(
-                recieved.amount,
-                self.commitment_outpoint,
-                recieved.cltv_expiry,
-                self.revocation_pubkey,
-                self.local_delayed_pubkey,
-                self.to_self_delay,
-            );
-            // Figure out the last index of transaction in graph
-            let last_index = tx_graph.last_index(TxType::HtlcSuccess) + 1;
-            tx_graph.insert_tx(
-                TxType::HtlcSuccess,
-                (last_index + index) as u64,
-                htlc_tx,
+            let mut htlc_tx = Psbt::ln_htlc(
+                amount - success_fee,
+                tx_graph.cmt_outpoint(vout),
+                0,
+                keys.revocationpubkey,
+                keys.local_delayedpubkey,
+                to_self_delay,
             );
+            set_htlc_input(&mut htlc_tx, htlc_output, witness_script);
+            let index = tx_graph.last_index(TxType::HtlcSuccess);
+            tx_graph.insert_tx(TxType::HtlcSuccess, index as u64, htlc_tx);
         }
         Ok(())
     }
 }
 
+/// Provides second-stage HTLC transaction PSBT with the information about
+/// the spent HTLC output required for signing and finalization
+fn set_htlc_input(
+    psbt: &mut Psbt,
+    htlc_output: TxOut,
+    witness_script: WitnessScript,
+) {
+    let input = &mut psbt.inputs[0];
+    input.witness_utxo = Some(htlc_output);
+    input.witness_script = Some(witness_script.into());
+    input.sighash_type = Some(SigHashType::All);
+}
+
 pub trait ScriptGenerators {
     fn ln_offered_htlc(
         amount: u64,
@@ -284,6 +1033,7 @@ pub trait ScriptGenerators {
         local_htlcpubkey: PublicKey,
         remote_htlcpubkey: PublicKey,
         payment_hash: HashLock,
+        anchors: bool,
     ) -> Self;
 
     fn ln_received_htlc(
@@ -293,6 +1043,7 @@ pub trait ScriptGenerators {
         remote_htlcpubkey: PublicKey,
         cltv_expiry: u32,
         payment_hash: HashLock,
+        anchors: bool,
     ) -> Self;
 
     fn ln_htlc_output(
@@ -310,8 +1061,9 @@ impl ScriptGenerators for LockScript {
         local_htlcpubkey: PublicKey,
         remote_htlcpubkey: PublicKey,
         payment_hash: HashLock,
+        anchors: bool,
     ) -> Self {
-        script::Builder::new()
+        let builder = script::Builder::new()
             .push_opcode(OP_DUP)
             .push_opcode(OP_HASH160)
             .push_slice(&revocationpubkey.into_pk().pubkey_hash())
@@ -333,10 +1085,11 @@ impl ScriptGenerators for LockScript {
             .push_opcode(OP_CHECKMULTISIG)
             .push_opcode(OP_ELSE)
             .push_opcode(OP_HASH160)
-            .push_slice(payment_hash.as_ref())
+            .push_slice(&ripemd160::Hash::hash(payment_hash.as_ref()))
             .push_opcode(OP_EQUALVERIFY)
             .push_opcode(OP_CHECKSIG)
-            .push_opcode(OP_ENDIF)
+            .push_opcode(OP_ENDIF);
+        with_anchor_csv(builder, anchors)
             .push_opcode(OP_ENDIF)
             .into_script()
             .into()
@@ -349,8 +1102,9 @@ impl ScriptGenerators for LockScript {
         remote_htlcpubkey: PublicKey,
         cltv_expiry: u32,
         payment_hash: HashLock,
+        anchors: bool,
     ) -> Self {
-        script::Builder::new()
+        let builder = script::Builder::new()
             .push_opcode(OP_DUP)
             .push_opcode(OP_HASH160)
             .push_slice(&revocationpubkey.into_pk().pubkey_hash())
@@ -365,7 +1119,7 @@ impl ScriptGenerators for LockScript {
             .push_opcode(OP_EQUAL)
             .push_opcode(OP_IF)
             .push_opcode(OP_HASH160)
-            .push_slice(payment_hash.as_ref())
+            .push_slice(&ripemd160::Hash::hash(payment_hash.as_ref()))
             .push_opcode(OP_EQUALVERIFY)
             .push_int(2)
             .push_opcode(OP_SWAP)
@@ -378,7 +1132,8 @@ impl ScriptGenerators for LockScript {
             .push_opcode(OP_CLTV)
             .push_opcode(OP_DROP)
             .push_opcode(OP_CHECKSIG)
-            .push_opcode(OP_ENDIF)
+            .push_opcode(OP_ENDIF);
+        with_anchor_csv(builder, anchors)
             .push_opcode(OP_ENDIF)
             .into_script()
             .into()
@@ -405,6 +1160,17 @@ impl ScriptGenerators for LockScript {
     }
 }
 
+/// With `option_anchor_outputs` all HTLC spending paths except revocation
+/// are encumbered with a one block CSV lock, so they can't be used to
+/// CPFP-pin the commitment transaction
+fn with_anchor_csv(builder: script::Builder, anchors: bool) -> script::Builder {
+    if anchors {
+        builder.push_int(1).push_opcode(OP_CSV).push_opcode(OP_DROP)
+    } else {
+        builder
+    }
+}
+
 impl ScriptGenerators for WitnessScript {
     #[inline]
     fn ln_offered_htlc(
@@ -413,6 +1179,7 @@ impl ScriptGenerators for WitnessScript {
         local_htlcpubkey: PublicKey,
         remote_htlcpubkey: PublicKey,
         payment_hash: HashLock,
+        anchors: bool,
     ) -> Self {
         LockScript::ln_offered_htlc(
             amount,
@@ -420,6 +1187,7 @@ impl ScriptGenerators for WitnessScript {
             local_htlcpubkey,
             remote_htlcpubkey,
             payment_hash,
+            anchors,
         )
         .into()
     }
@@ -432,6 +1200,7 @@ impl ScriptGenerators for WitnessScript {
         remote_htlcpubkey: PublicKey,
         cltv_expiry: u32,
         payment_hash: HashLock,
+        anchors: bool,
     ) -> Self {
         LockScript::ln_received_htlc(
             amount,
@@ -440,6 +1209,7 @@ impl ScriptGenerators for WitnessScript {
             remote_htlcpubkey,
             cltv_expiry,
             payment_hash,
+            anchors,
         )
         .into()
     }
@@ -469,6 +1239,7 @@ impl ScriptGenerators for PubkeyScript {
         local_htlcpubkey: PublicKey,
         remote_htlcpubkey: PublicKey,
         payment_hash: HashLock,
+        anchors: bool,
     ) -> Self {
         WitnessScript::ln_offered_htlc(
             amount,
@@ -476,6 +1247,7 @@ impl ScriptGenerators for PubkeyScript {
             local_htlcpubkey,
             remote_htlcpubkey,
             payment_hash,
+            anchors,
         )
         .to_p2wsh()
     }
@@ -488,6 +1260,7 @@ impl ScriptGenerators for PubkeyScript {
         remote_htlcpubkey: PublicKey,
         cltv_expiry: u32,
         payment_hash: HashLock,
+        anchors: bool,
     ) -> Self {
         WitnessScript::ln_received_htlc(
             amount,
@@ -496,6 +1269,7 @@ impl ScriptGenerators for PubkeyScript {
             remote_htlcpubkey,
             cltv_expiry,
             payment_hash,
+            anchors,
         )
         .to_p2wsh()
     }
@@ -525,6 +1299,7 @@ impl ScriptGenerators for TxOut {
         local_htlcpubkey: PublicKey,
         remote_htlcpubkey: PublicKey,
         payment_hash: HashLock,
+        anchors: bool,
     ) -> Self {
         TxOut {
             value: amount,
@@ -534,6 +1309,7 @@ impl ScriptGenerators for TxOut {
                 local_htlcpubkey,
                 remote_htlcpubkey,
                 payment_hash,
+                anchors,
             )
             .into(),
         }
@@ -547,6 +1323,7 @@ impl ScriptGenerators for TxOut {
         remote_htlcpubkey: PublicKey,
         cltv_expiry: u32,
         payment_hash: HashLock,
+        anchors: bool,
     ) -> Self {
         TxOut {
             value: amount,
@@ -557,6 +1334,7 @@ impl ScriptGenerators for TxOut {
                 remote_htlcpubkey,
                 cltv_expiry,
                 payment_hash,
+                anchors,
             )
             .into(),
         }
@@ -643,3 +1421,520 @@ impl TxGenerators for Psbt {
         .expect("Tx has empty sigs so PSBT creation does not faile")
     }
 }
+
+pub trait TxFinalizers {
+    /// Finalizes HTLC-timeout transaction with the signatures of the remote
+    /// and local HTLC keys
+    fn ln_finalize_htlc_timeout(
+        &mut self,
+        remote_htlc_sig: Signature,
+        local_htlc_sig: Signature,
+    ) -> Result<(), channel::Error>;
+
+    /// Finalizes HTLC-success transaction with the signatures of the remote
+    /// and local HTLC keys and the payment preimage
+    fn ln_finalize_htlc_success(
+        &mut self,
+        remote_htlc_sig: Signature,
+        local_htlc_sig: Signature,
+        payment_preimage: HashPreimage,
+    ) -> Result<(), channel::Error>;
+}
+
+impl TxFinalizers for Psbt {
+    fn ln_finalize_htlc_timeout(
+        &mut self,
+        remote_htlc_sig: Signature,
+        local_htlc_sig: Signature,
+    ) -> Result<(), channel::Error> {
+        finalize_htlc_tx(self, remote_htlc_sig, local_htlc_sig, vec![])
+    }
+
+    fn ln_finalize_htlc_success(
+        &mut self,
+        remote_htlc_sig: Signature,
+        local_htlc_sig: Signature,
+        payment_preimage: HashPreimage,
+    ) -> Result<(), channel::Error> {
+        finalize_htlc_tx(
+            self,
+            remote_htlc_sig,
+            local_htlc_sig,
+            payment_preimage.as_ref().to_vec(),
+        )
+    }
+}
+
+/// Constructs witness `0 <remotehtlcsig> <localhtlcsig> <payment_preimage>`
+/// for the second-stage HTLC transaction; the preimage is empty for
+/// HTLC-timeout. The remote signature uses sighash type from the PSBT, which
+/// is `SIGHASH_SINGLE|SIGHASH_ANYONECANPAY` for the channels with anchors.
+fn finalize_htlc_tx(
+    psbt: &mut Psbt,
+    remote_htlc_sig: Signature,
+    local_htlc_sig: Signature,
+    payment_preimage: Vec<u8>,
+) -> Result<(), channel::Error> {
+    let input = &mut psbt.inputs[0];
+    let witness_script = input
+        .witness_script
+        .as_ref()
+        .ok_or_else(|| channel::Error::from(HtlcError::NoWitnessScript))?;
+    let remote_sighash = input.sighash_type.unwrap_or(SigHashType::All);
+    let mut remote_sig = remote_htlc_sig.serialize_der().to_vec();
+    remote_sig.push(remote_sighash.as_u32() as u8);
+    let mut local_sig = local_htlc_sig.serialize_der().to_vec();
+    local_sig.push(SigHashType::All.as_u32() as u8);
+    input.final_script_witness = Some(vec![
+        vec![],
+        remote_sig,
+        local_sig,
+        payment_preimage,
+        witness_script.to_bytes(),
+    ]);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use amplify::{Slice32, Wrapper};
+
+    use crate::message::{ChannelReestablish, CommitmentSigned, RevokeAndAck};
+    use crate::OnionPacket;
+
+    use CommitmentOwner::{Local, Remote};
+    use HtlcState::*;
+
+    fn htlc() -> Htlc {
+        Htlc {
+            remote_max_htlc_value_in_flight_msat: 10_000_000_000,
+            remote_max_accepted_htlcs: 483,
+            ..Htlc::with(
+                Basepoints::dumb_default(),
+                &Params {
+                    max_htlc_value_in_flight_msat: 10_000_000_000,
+                    max_accepted_htlcs: 483,
+                    ..Params::default()
+                },
+            )
+        }
+    }
+
+    fn preimage(byte: u8) -> HashPreimage {
+        HashPreimage::from_inner(Slice32::from_inner([byte; 32]))
+    }
+
+    fn add_local(htlc: &mut Htlc, preimage_byte: u8) {
+        htlc.update_from_local(&Operation::AddHtlc {
+            amount_msat: 5_000_000,
+            payment_hash: HashLock::from(preimage(preimage_byte)),
+            cltv_expiry: 500,
+            onion_routing_packet: OnionPacket::dumb_default(),
+            asset_id: None,
+        })
+        .unwrap();
+    }
+
+    fn add_remote(htlc: &mut Htlc, htlc_id: u64, preimage_byte: u8) {
+        htlc.update_from_peer(&Messages::UpdateAddHtlc(UpdateAddHtlc {
+            channel_id: ChannelId::default(),
+            htlc_id,
+            amount_msat: 5_000_000,
+            payment_hash: HashLock::from(preimage(preimage_byte)),
+            cltv_expiry: 500,
+            onion_routing_packet: OnionPacket::dumb_default(),
+            asset_id: None,
+        }))
+        .unwrap();
+    }
+
+    fn sign(htlc: &mut Htlc) {
+        htlc.update_from_local(&Operation::SignCommitment).unwrap();
+    }
+
+    fn revoke(htlc: &mut Htlc) {
+        htlc.update_from_local(&Operation::RevokeCommitment)
+            .unwrap();
+    }
+
+    fn commitment_signed(htlc: &mut Htlc) {
+        htlc.update_from_peer(&Messages::CommitmentSigned(CommitmentSigned {
+            channel_id: ChannelId::default(),
+            signature: Signature::from_compact(&[1u8; 64]).unwrap(),
+            htlc_signatures: vec![],
+        }))
+        .unwrap();
+    }
+
+    fn revoke_and_ack(htlc: &mut Htlc) {
+        htlc.update_from_peer(&Messages::RevokeAndAck(RevokeAndAck {
+            channel_id: ChannelId::default(),
+            per_commitment_secret: [0u8; 32],
+            next_per_commitment_point: *SECP256K1_PUBKEY_DUMB,
+        }))
+        .unwrap();
+    }
+
+    fn in_commitments(htlc: &HtlcSecret) -> (bool, bool) {
+        (
+            htlc.state.in_commitment(Local),
+            htlc.state.in_commitment(Remote),
+        )
+    }
+
+    #[test]
+    fn local_add() {
+        let mut htlc = htlc();
+        add_local(&mut htlc, 1);
+        assert_eq!(htlc.offered_htlcs[0].state, SentAddHtlc);
+        assert_eq!(in_commitments(&htlc.offered_htlcs[0]), (false, false));
+
+        sign(&mut htlc);
+        assert_eq!(htlc.offered_htlcs[0].state, SentAddCommit);
+        assert_eq!(in_commitments(&htlc.offered_htlcs[0]), (false, true));
+
+        revoke_and_ack(&mut htlc);
+        assert_eq!(htlc.offered_htlcs[0].state, RcvdAddRevocation);
+
+        commitment_signed(&mut htlc);
+        assert_eq!(htlc.offered_htlcs[0].state, RcvdAddAckCommit);
+        assert_eq!(in_commitments(&htlc.offered_htlcs[0]), (true, true));
+
+        revoke(&mut htlc);
+        assert_eq!(htlc.offered_htlcs[0].state, SentAddAckRevocation);
+        assert!(htlc.offered_htlcs[0].state.is_committed());
+    }
+
+    #[test]
+    fn remote_add_and_fulfill() {
+        let mut htlc = htlc();
+        add_remote(&mut htlc, 0, 1);
+        assert_eq!(htlc.received_htlcs[0].state, RcvdAddHtlc);
+
+        // HTLC can't be removed before it is committed
+        assert!(htlc
+            .update_from_local(&Operation::FulfillHtlc {
+                htlc_id: 0,
+                payment_preimage: preimage(1),
+            })
+            .is_err());
+
+        commitment_signed(&mut htlc);
+        assert_eq!(htlc.received_htlcs[0].state, RcvdAddCommit);
+        assert_eq!(in_commitments(&htlc.received_htlcs[0]), (true, false));
+        revoke(&mut htlc);
+        assert_eq!(htlc.received_htlcs[0].state, SentAddRevocation);
+        sign(&mut htlc);
+        assert_eq!(htlc.received_htlcs[0].state, SentAddAckCommit);
+        assert_eq!(in_commitments(&htlc.received_htlcs[0]), (true, true));
+        revoke_and_ack(&mut htlc);
+        assert_eq!(htlc.received_htlcs[0].state, RcvdAddAckRevocation);
+
+        htlc.update_from_local(&Operation::FulfillHtlc {
+            htlc_id: 0,
+            payment_preimage: preimage(1),
+        })
+        .unwrap();
+        assert_eq!(htlc.received_htlcs[0].state, SentRemoveHtlc);
+        assert_eq!(htlc.resolved_htlcs.len(), 1);
+
+        sign(&mut htlc);
+        assert_eq!(in_commitments(&htlc.received_htlcs[0]), (true, false));
+        revoke_and_ack(&mut htlc);
+        assert_eq!(htlc.received_htlcs[0].state, RcvdRemoveRevocation);
+        commitment_signed(&mut htlc);
+        assert_eq!(in_commitments(&htlc.received_htlcs[0]), (false, false));
+        assert_eq!(htlc.total_accepted_htlcs, 1);
+
+        revoke(&mut htlc);
+        assert!(htlc.received_htlcs.is_empty());
+        assert_eq!(htlc.total_accepted_htlcs, 0);
+        assert_eq!(htlc.total_htlc_value_in_flight_msat, 0);
+    }
+
+    #[test]
+    fn reestablish_forgets_uncommitted() {
+        let mut htlc = htlc();
+        add_remote(&mut htlc, 0, 1);
+        commitment_signed(&mut htlc);
+        revoke(&mut htlc);
+        sign(&mut htlc);
+        revoke_and_ack(&mut htlc);
+        htlc.update_from_local(&Operation::FulfillHtlc {
+            htlc_id: 0,
+            payment_preimage: preimage(1),
+        })
+        .unwrap();
+        add_local(&mut htlc, 2);
+        add_remote(&mut htlc, 1, 3);
+        assert_eq!(htlc.total_accepted_htlcs, 2);
+
+        let reestablish = ChannelReestablish {
+            channel_id: ChannelId::default(),
+            next_commitment_number: 2,
+            next_revocation_number: 1,
+            your_last_per_commitment_secret: [0u8; 32],
+            my_current_per_commitment_point: *SECP256K1_PUBKEY_DUMB,
+        };
+        let foreign_id = ChannelId::with(OutPoint::new(Default::default(), 1));
+        assert_eq!(
+            htlc.update_from_peer(&Messages::ChannelReestablish(
+                ChannelReestablish {
+                    channel_id: foreign_id,
+                    ..reestablish
+                }
+            )),
+            Err(
+                HtlcError::ChannelIdMismatch(foreign_id, ChannelId::default())
+                    .into()
+            )
+        );
+        assert_eq!(htlc.total_accepted_htlcs, 2);
+        htlc.update_from_peer(&Messages::ChannelReestablish(reestablish))
+            .unwrap();
+        assert!(htlc.offered_htlcs.is_empty());
+        assert_eq!(htlc.received_htlcs.len(), 1);
+        assert_eq!(htlc.received_htlcs[0].state, RcvdAddAckRevocation);
+        assert_eq!(htlc.total_accepted_htlcs, 1);
+        assert_eq!(htlc.total_htlc_value_in_flight_msat, 5_000_000);
+
+        // Forgotten HTLC ids are reused by the re-sent updates
+        add_local(&mut htlc, 2);
+        assert_eq!(htlc.offered_htlcs[0].id, 0);
+        add_remote(&mut htlc, 1, 3);
+        assert_eq!(htlc.received_htlcs[1].id, 1);
+    }
+
+    #[test]
+    fn crossed_adds() {
+        // Both peers add HTLCs before either of them signs a commitment
+        let mut htlc = htlc();
+        add_local(&mut htlc, 1);
+        add_remote(&mut htlc, 0, 2);
+
+        sign(&mut htlc);
+        commitment_signed(&mut htlc);
+        assert_eq!(htlc.offered_htlcs[0].state, SentAddCommit);
+        assert_eq!(htlc.received_htlcs[0].state, RcvdAddCommit);
+
+        revoke_and_ack(&mut htlc);
+        revoke(&mut htlc);
+        assert_eq!(htlc.offered_htlcs[0].state, RcvdAddRevocation);
+        assert_eq!(htlc.received_htlcs[0].state, SentAddRevocation);
+        // Each of the HTLCs is so far committed only by the peer receiving it
+        assert_eq!(in_commitments(&htlc.offered_htlcs[0]), (false, true));
+        assert_eq!(in_commitments(&htlc.received_htlcs[0]), (true, false));
+
+        sign(&mut htlc);
+        commitment_signed(&mut htlc);
+        revoke_and_ack(&mut htlc);
+        revoke(&mut htlc);
+        assert_eq!(htlc.offered_htlcs[0].state, SentAddAckRevocation);
+        assert_eq!(htlc.received_htlcs[0].state, RcvdAddAckRevocation);
+        assert_eq!(in_commitments(&htlc.offered_htlcs[0]), (true, true));
+        assert_eq!(in_commitments(&htlc.received_htlcs[0]), (true, true));
+    }
+
+    #[test]
+    fn remote_fail() {
+        let mut htlc = htlc();
+        add_local(&mut htlc, 1);
+        sign(&mut htlc);
+        revoke_and_ack(&mut htlc);
+        commitment_signed(&mut htlc);
+        revoke(&mut htlc);
+
+        htlc.update_from_peer(&Messages::UpdateFailHtlc(UpdateFailHtlc {
+            channel_id: ChannelId::default(),
+            htlc_id: 0,
+            reason: vec![],
+        }))
+        .unwrap();
+        assert_eq!(htlc.offered_htlcs[0].state, RcvdRemoveHtlc);
+        assert_eq!(in_commitments(&htlc.offered_htlcs[0]), (true, true));
+
+        commitment_signed(&mut htlc);
+        assert_eq!(in_commitments(&htlc.offered_htlcs[0]), (false, true));
+        revoke(&mut htlc);
+        sign(&mut htlc);
+        assert_eq!(in_commitments(&htlc.offered_htlcs[0]), (false, false));
+        revoke_and_ack(&mut htlc);
+        assert!(htlc.offered_htlcs.is_empty());
+    }
+
+    #[test]
+    fn typed_errors() {
+        let mut htlc = htlc();
+        htlc.max_accepted_htlcs = 1;
+        add_remote(&mut htlc, 0, 1);
+
+        let err = htlc
+            .update_from_peer(&Messages::UpdateAddHtlc(UpdateAddHtlc {
+                channel_id: ChannelId::default(),
+                htlc_id: 1,
+                amount_msat: 5_000_000,
+                payment_hash: HashLock::from(preimage(2)),
+                cltv_expiry: 500,
+                onion_routing_packet: OnionPacket::dumb_default(),
+                asset_id: None,
+            }))
+            .unwrap_err();
+        assert_eq!(err, channel::Error::Htlc(HtlcError::TooManyHtlcs(1)));
+        assert_eq!(
+            HtlcError::TooManyHtlcs(1).failure_code(),
+            Some(TEMPORARY_CHANNEL_FAILURE)
+        );
+
+        let err = htlc
+            .update_from_local(&Operation::FulfillHtlc {
+                htlc_id: 7,
+                payment_preimage: preimage(1),
+            })
+            .unwrap_err();
+        assert_eq!(err, channel::Error::Htlc(HtlcError::UnknownId(7)));
+        assert_eq!(HtlcError::UnknownId(7).failure_code(), None);
+
+        assert_eq!(
+            HtlcError::AmountBelowMinimum(1, 1000).failure_code(),
+            Some(0x100B)
+        );
+        assert_eq!(HtlcError::PreimageMismatch(0).failure_code(), Some(0x400F));
+    }
+
+    #[test]
+    fn peer_update_errors() {
+        let mut htlc = htlc();
+        htlc.total_htlc_value_in_flight_msat = u64::MAX;
+        let err = htlc
+            .update_from_peer(&Messages::UpdateAddHtlc(UpdateAddHtlc {
+                channel_id: ChannelId::default(),
+                htlc_id: 0,
+                amount_msat: 5_000_000,
+                payment_hash: HashLock::from(preimage(1)),
+                cltv_expiry: 500,
+                onion_routing_packet: OnionPacket::dumb_default(),
+                asset_id: None,
+            }))
+            .unwrap_err();
+        assert_eq!(
+            err,
+            channel::Error::Htlc(HtlcError::AmountOverflow(5_000_000))
+        );
+
+        let mut htlc = self::htlc();
+        add_local(&mut htlc, 1);
+        let err = htlc
+            .update_from_peer(&Messages::UpdateFulfillHtlc(UpdateFulfillHtlc {
+                channel_id: ChannelId::default(),
+                htlc_id: 0,
+                payment_preimage: preimage(2),
+            }))
+            .unwrap_err();
+        assert_eq!(err, channel::Error::Htlc(HtlcError::PreimageMismatch(0)));
+        assert!(htlc.resolved_htlcs.is_empty());
+
+        let foreign_id = ChannelId::with(OutPoint::new(Default::default(), 1));
+        let err = htlc
+            .update_from_peer(&Messages::UpdateFailHtlc(UpdateFailHtlc {
+                channel_id: foreign_id,
+                htlc_id: 0,
+                reason: vec![],
+            }))
+            .unwrap_err();
+        assert_eq!(
+            err,
+            channel::Error::Htlc(HtlcError::ChannelIdMismatch(
+                foreign_id,
+                ChannelId::default()
+            ))
+        );
+        let err = htlc
+            .update_from_peer(&Messages::UpdateFailMalformedHtlc(
+                UpdateFailMalformedHtlc {
+                    channel_id: foreign_id,
+                    htlc_id: 0,
+                    sha256_of_onion: bitcoin::hashes::sha256::Hash::default(),
+                    failure_code: 0x4000 | 0x8000 | 5,
+                },
+            ))
+            .unwrap_err();
+        assert_eq!(
+            err,
+            channel::Error::Htlc(HtlcError::ChannelIdMismatch(
+                foreign_id,
+                ChannelId::default()
+            ))
+        );
+    }
+
+    #[test]
+    fn cltv_expiry_limit() {
+        let mut htlc = htlc();
+        let add = |htlc_id: u64, cltv_expiry: u32| {
+            Messages::UpdateAddHtlc(UpdateAddHtlc {
+                channel_id: ChannelId::default(),
+                htlc_id,
+                amount_msat: 5_000_000,
+                payment_hash: HashLock::from(preimage(1)),
+                cltv_expiry,
+                onion_routing_packet: OnionPacket::dumb_default(),
+                asset_id: None,
+            })
+        };
+        // Values starting from 500000000 are timestamps, not block heights
+        assert_eq!(
+            htlc.update_from_peer(&add(0, 500_000_000)),
+            Err(HtlcError::InvalidCltvExpiry(500_000_000).into())
+        );
+        assert!(htlc.received_htlcs.is_empty());
+        htlc.update_from_peer(&add(0, 499_999_999)).unwrap();
+
+        let offer = |cltv_expiry: u32| Operation::AddHtlc {
+            amount_msat: 5_000_000,
+            payment_hash: HashLock::from(preimage(1)),
+            cltv_expiry,
+            onion_routing_packet: OnionPacket::dumb_default(),
+            asset_id: None,
+        };
+        assert_eq!(
+            htlc.update_from_local(&offer(500_000_000)),
+            Err(HtlcError::InvalidCltvExpiry(500_000_000).into())
+        );
+        htlc.update_from_local(&offer(499_999_999)).unwrap();
+    }
+
+    #[test]
+    fn local_offer_limits() {
+        let mut htlc = Htlc {
+            remote_htlc_minimum_msat: 1_000,
+            remote_max_htlc_value_in_flight_msat: 6_000_000,
+            remote_max_accepted_htlcs: 1,
+            ..htlc()
+        };
+        let offer = |amount_msat: u64| Operation::AddHtlc {
+            amount_msat,
+            payment_hash: HashLock::from(preimage(1)),
+            cltv_expiry: 500,
+            onion_routing_packet: OnionPacket::dumb_default(),
+            asset_id: None,
+        };
+        assert_eq!(
+            htlc.update_from_local(&offer(500)),
+            Err(HtlcError::AmountBelowMinimum(500, 1_000).into())
+        );
+        add_local(&mut htlc, 1);
+        assert_eq!(
+            htlc.update_from_local(&offer(1_000)),
+            Err(HtlcError::TooManyHtlcs(1).into())
+        );
+        htlc.remote_max_accepted_htlcs = 2;
+        assert_eq!(
+            htlc.update_from_local(&offer(2_000_000)),
+            Err(HtlcError::InFlightExceeded(2_000_000, 6_000_000).into())
+        );
+        assert_eq!(htlc.offered_htlcs.len(), 1);
+        assert_eq!(htlc.last_offered_htlc_id, 1);
+        htlc.update_from_local(&offer(1_000_000)).unwrap();
+    }
+}
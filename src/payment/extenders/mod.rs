@@ -24,4 +24,5 @@ pub mod ptlc;
 pub mod dlc;
 pub mod lightspeed;
 
-pub use htlc::Htlc;
+pub use anchor_out::AnchorOut;
+pub use htlc::{Htlc, HtlcError};
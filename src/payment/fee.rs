@@ -0,0 +1,270 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Commitment transaction fee and weight calculation according to BOLT-3
+//! "Fee Calculation" and "Trimmed Outputs" sections.
+
+#[cfg(feature = "serde")]
+use amplify::ToYamlString;
+
+/// Weight of the commitment transaction without HTLC outputs
+pub const COMMITMENT_BASE_WEIGHT: u64 = 724;
+
+/// Weight of the commitment transaction without HTLC outputs when
+/// `option_anchor_outputs` is negotiated
+pub const COMMITMENT_BASE_WEIGHT_ANCHORS: u64 = 1124;
+
+/// Value of each anchor output, in satoshis
+pub const ANCHOR_OUTPUT_VALUE: u64 = 330;
+
+/// Weight added to the commitment transaction by each untrimmed HTLC output
+pub const HTLC_OUTPUT_WEIGHT: u64 = 172;
+
+/// Weight of HTLC-timeout transaction
+pub const HTLC_TIMEOUT_WEIGHT: u64 = 663;
+
+/// Weight of HTLC-timeout transaction when `option_anchor_outputs` is
+/// negotiated
+pub const HTLC_TIMEOUT_WEIGHT_ANCHORS: u64 = 666;
+
+/// Weight of HTLC-success transaction
+pub const HTLC_SUCCESS_WEIGHT: u64 = 703;
+
+/// Weight of HTLC-success transaction when `option_anchor_outputs` is
+/// negotiated
+pub const HTLC_SUCCESS_WEIGHT_ANCHORS: u64 = 706;
+
+/// Computes fee for a transaction of a given weight, rounding down as
+/// required by BOLT-3
+#[inline]
+pub fn fee_for_weight(feerate_per_kw: u32, weight: u64) -> u64 {
+    feerate_per_kw as u64 * weight / 1000
+}
+
+/// Returns weight of the commitment transaction with the given number of
+/// untrimmed HTLC outputs
+#[inline]
+pub fn commitment_weight(htlc_count: u16, anchors: bool) -> u64 {
+    let base = if anchors {
+        COMMITMENT_BASE_WEIGHT_ANCHORS
+    } else {
+        COMMITMENT_BASE_WEIGHT
+    };
+    base + HTLC_OUTPUT_WEIGHT * htlc_count as u64
+}
+
+/// Returns fee of the second-stage transaction spending HTLC output
+/// (HTLC-timeout for the offered and HTLC-success for the received HTLCs)
+pub fn htlc_tx_fee(feerate_per_kw: u32, offered: bool, anchors: bool) -> u64 {
+    let weight = match (offered, anchors) {
+        (true, false) => HTLC_TIMEOUT_WEIGHT,
+        (true, true) => HTLC_TIMEOUT_WEIGHT_ANCHORS,
+        (false, false) => HTLC_SUCCESS_WEIGHT,
+        (false, true) => HTLC_SUCCESS_WEIGHT_ANCHORS,
+    };
+    fee_for_weight(feerate_per_kw, weight)
+}
+
+/// Detects whether HTLC with the given amount (in satoshis) must be trimmed
+/// from the commitment transaction, i.e. whether the output of its
+/// second-stage transaction would be below the dust limit
+#[inline]
+pub fn is_htlc_trimmed(
+    amount: u64,
+    offered: bool,
+    dust_limit: u64,
+    feerate_per_kw: u32,
+    anchors: bool,
+) -> bool {
+    amount < dust_limit + htlc_tx_fee(feerate_per_kw, offered, anchors)
+}
+
+/// Breakdown of the amounts going to miners from the commitment transaction
+#[derive(
+    Clone, Copy, PartialEq, Eq, Hash, Debug, Default, StrictEncode, StrictDecode,
+)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Display, Serialize, Deserialize),
+    serde(crate = "serde_crate"),
+    display(CommitmentFee::to_yaml_string)
+)]
+pub struct CommitmentFee {
+    /// Fee rate used for the commitment transaction, in satoshi per 1000
+    /// weight units
+    pub feerate_per_kw: u32,
+
+    /// Whether the commitment transaction uses anchor outputs
+    pub anchors: bool,
+
+    /// Whether second-stage HTLC transactions are pre-signed with zero fee
+    /// (`option_anchors_zero_fee_htlc_tx`)
+    pub zero_fee_htlc: bool,
+
+    /// Number of HTLC outputs present in the commitment transaction
+    pub htlc_count: u16,
+
+    /// Number of HTLCs trimmed from the commitment transaction
+    pub trimmed_htlc_count: u16,
+
+    /// Total amount of trimmed HTLCs, in satoshis
+    pub trimmed_htlc_amount: u64,
+
+    /// Total amount of `to_local` and `to_remote` outputs dropped as dust,
+    /// in satoshis
+    pub trimmed_output_amount: u64,
+
+    /// Total amount of anchor outputs paid by the funder, in satoshis
+    pub anchors_amount: u64,
+
+    /// Weight of the commitment transaction, as defined by BOLT-3
+    pub weight: u64,
+
+    /// Commitment transaction fee paid from the funder output, in satoshis
+    pub fee: u64,
+}
+
+#[cfg(feature = "serde")]
+impl ToYamlString for CommitmentFee {}
+
+impl CommitmentFee {
+    /// Starts commitment fee computation for a given fee rate
+    pub fn with(
+        feerate_per_kw: u32,
+        anchors: bool,
+        zero_fee_htlc: bool,
+    ) -> Self {
+        Self {
+            feerate_per_kw,
+            anchors: anchors || zero_fee_htlc,
+            zero_fee_htlc,
+            ..Self::default()
+        }
+    }
+
+    /// Returns fee of the second-stage transaction spending HTLC output,
+    /// which is always zero when `option_anchors_zero_fee_htlc_tx` is used
+    #[inline]
+    pub fn htlc_tx_fee(&self, offered: bool) -> u64 {
+        if self.zero_fee_htlc {
+            0
+        } else {
+            htlc_tx_fee(self.feerate_per_kw, offered, self.anchors)
+        }
+    }
+
+    /// Accounts HTLC with the given amount (in satoshis) against the
+    /// commitment fee. Returns `false` if the HTLC must be trimmed from the
+    /// commitment transaction.
+    pub fn add_htlc(
+        &mut self,
+        amount: u64,
+        offered: bool,
+        dust_limit: u64,
+    ) -> bool {
+        if amount < dust_limit + self.htlc_tx_fee(offered) {
+            self.trimmed_htlc_count += 1;
+            self.trimmed_htlc_amount += amount;
+            false
+        } else {
+            self.htlc_count += 1;
+            true
+        }
+    }
+
+    /// Computes weight and fee of the commitment transaction from the HTLCs
+    /// accounted so far, returning the fee
+    pub fn compute(&mut self) -> u64 {
+        self.weight = commitment_weight(self.htlc_count, self.anchors);
+        self.fee = fee_for_weight(self.feerate_per_kw, self.weight);
+        self.fee
+    }
+
+    /// Total amount lost to miners by the commitment transaction, including
+    /// the fee and all trimmed outputs
+    #[inline]
+    pub fn total(&self) -> u64 {
+        self.fee
+            + self.trimmed_htlc_amount
+            + self.trimmed_output_amount
+            + self.anchors_amount
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn weight() {
+        assert_eq!(commitment_weight(0, false), 724);
+        assert_eq!(commitment_weight(5, false), 1584);
+        assert_eq!(commitment_weight(0, true), 1124);
+        assert_eq!(commitment_weight(3, true), 1640);
+    }
+
+    #[test]
+    fn htlc_trimming() {
+        // Thresholds from BOLT-3 appendix C test vectors with 546 sat dust
+        // limit
+        assert!(!is_htlc_trimmed(1000, false, 546, 647, false));
+        assert!(is_htlc_trimmed(1000, false, 546, 648, false));
+        assert!(!is_htlc_trimmed(2000, false, 546, 2069, false));
+        assert!(is_htlc_trimmed(2000, false, 546, 2070, false));
+        assert!(!is_htlc_trimmed(2000, true, 546, 2194, false));
+        assert!(is_htlc_trimmed(2000, true, 546, 2195, false));
+    }
+
+    #[test]
+    fn breakdown() {
+        let mut fee = CommitmentFee::with(2070, false, false);
+        for (amount, offered) in &[
+            (1000, false),
+            (2000, false),
+            (2000, true),
+            (3000, true),
+            (4000, false),
+        ] {
+            fee.add_htlc(*amount, *offered, 546);
+        }
+        assert_eq!(fee.htlc_count, 3);
+        assert_eq!(fee.trimmed_htlc_count, 2);
+        assert_eq!(fee.trimmed_htlc_amount, 3000);
+        assert_eq!(fee.compute(), 2566);
+        assert_eq!(fee.weight, 1240);
+        assert_eq!(fee.total(), 5566);
+    }
+
+    #[test]
+    fn zero_fee_htlc() {
+        let mut anchors = CommitmentFee::with(3702, true, false);
+        let mut zero_fee = CommitmentFee::with(3702, false, true);
+        assert!(zero_fee.anchors);
+        assert_eq!(anchors.htlc_tx_fee(true), 2465);
+        assert_eq!(zero_fee.htlc_tx_fee(true), 0);
+        for (amount, offered) in &[
+            (1000, false),
+            (2000, false),
+            (2000, true),
+            (3000, true),
+            (4000, false),
+        ] {
+            anchors.add_htlc(*amount, *offered, 546);
+            zero_fee.add_htlc(*amount, *offered, 546);
+        }
+        assert_eq!(anchors.htlc_count, 1);
+        assert_eq!(zero_fee.htlc_count, 5);
+        assert_eq!(anchors.compute(), 4797);
+        assert_eq!(zero_fee.compute(), 7344);
+    }
+}
@@ -0,0 +1,692 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Detection of the revoked commitment transactions published by the remote
+//! peer and construction of the justice (penalty) transactions sweeping all
+//! their outputs with the revocation key (see BOLT-5 "Revoked Transaction
+//! Close Handling").
+
+use bitcoin::blockdata::opcodes::all::OP_IF;
+use bitcoin::consensus::encode::VarInt;
+use bitcoin::secp256k1::{PublicKey, SecretKey, Signature};
+use bitcoin::util::psbt::PartiallySignedTransaction as Psbt;
+use bitcoin::{OutPoint, Script, SigHashType, Transaction, TxOut, Txid};
+use wallet::{PubkeyScript, WitnessScript};
+
+use crate::channel::{History, TxGraph};
+use crate::payment::bolt3::{
+    commitment_number, ScriptGenerators, DEFAULT_DUST_LIMIT,
+};
+use crate::payment::cpfp::{
+    spending_psbt, tx_weight, SpendInput, MAX_SIGNATURE_LEN, SEQUENCE_RBF,
+    TXIN_BASE_WEIGHT,
+};
+use crate::payment::fee::fee_for_weight;
+use crate::payment::keys::{
+    derive_pubkey, derive_revocation_privkey, derive_revocation_pubkey,
+    Basepoints, SECP256K1,
+};
+use crate::payment::shachain::ShachainStore;
+
+/// Maximal weight of the transaction relayed by the nodes with the default
+/// standardness policy
+pub const MAX_STANDARD_TX_WEIGHT: u64 = 400_000;
+
+/// Errors detecting revoked commitments and constructing justice transactions
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum JusticeError {
+    /// transaction {0} does not spend the channel funding output
+    NotFundingSpend(Txid),
+
+    /// transaction {0} spending the funding output is not a commitment
+    /// transaction
+    NotCommitment(Txid),
+
+    /// commitment #{0} is not revoked, so it can't be punished
+    NotRevoked(u64),
+
+    /// state of the revoked commitment #{0} is not available: {1}
+    NoState(u64, String),
+
+    /// transaction {0} does not match revoked commitment #{1} from the
+    /// channel history
+    StateMismatch(Txid, u64),
+
+    /// revoked commitment has no outputs which can be swept
+    NothingToSweep,
+
+    /// swept amount of {0} sats does not cover the fee of {1} sats with the
+    /// dust limit
+    InsufficientFunds(u64, u64),
+
+    /// {0} signatures are provided for the justice transaction with {1}
+    /// inputs
+    SignatureCount(usize, usize),
+
+    /// justice transaction input #{0} has no witness script
+    NoWitnessScript(usize),
+
+    /// per-commitment secret for commitment #{0} provided by the remote peer
+    /// is not a valid private key
+    InvalidSecret(u64),
+}
+
+/// Revoked commitment transaction published by the remote peer
+#[derive(Clone)]
+pub struct Breach {
+    /// Published revoked commitment transaction
+    pub commitment_tx: Transaction,
+
+    /// Number of the revoked commitment
+    pub commitment_number: u64,
+
+    /// Per-commitment secret revealed by the remote peer on revocation
+    pub per_commitment_secret: SecretKey,
+
+    /// State of the revoked commitment from the channel history
+    pub tx_graph: TxGraph,
+}
+
+/// Constructs justice transaction input spending revocation branch of the
+/// revoked commitment or second-stage HTLC transaction output. HTLC outputs
+/// require revocation public key in the witness, while `to_local` and
+/// second-stage HTLC outputs just select the branch
+fn penalty_input(
+    outpoint: OutPoint,
+    txout: TxOut,
+    witness_script: Script,
+    htlc: bool,
+) -> SpendInput {
+    let script_len = witness_script.len() as u64;
+    let selector_len = if htlc { 1 + 33 } else { 1 + 1 };
+    SpendInput {
+        outpoint,
+        txout,
+        witness_script: Some(witness_script),
+        sequence: SEQUENCE_RBF,
+        satisfaction_weight: 1
+            + 1
+            + MAX_SIGNATURE_LEN
+            + selector_len
+            + VarInt(script_len).len() as u64
+            + script_len,
+        sighash_type: Some(SigHashType::All),
+        final_witness: None,
+    }
+}
+
+impl Breach {
+    /// Detects whether the transaction spending the funding output is a
+    /// revoked commitment of the remote peer. The `history` must contain
+    /// transaction graphs of the remote commitments at the heights equal to
+    /// their commitment numbers.
+    pub fn detect<H>(
+        tx: &Transaction,
+        funding_outpoint: OutPoint,
+        obscuring_factor: u64,
+        remote_secrets: &ShachainStore,
+        history: &H,
+    ) -> Result<Breach, JusticeError>
+    where
+        H: History<State = TxGraph>,
+    {
+        let txid = tx.txid();
+        let txin = tx
+            .input
+            .iter()
+            .find(|txin| txin.previous_output == funding_outpoint)
+            .ok_or(JusticeError::NotFundingSpend(txid))?;
+        let commitment_number =
+            commitment_number(tx.lock_time, txin.sequence, obscuring_factor)
+                .ok_or(JusticeError::NotCommitment(txid))?;
+
+        let secret = remote_secrets
+            .commitment_secret(commitment_number)
+            .map_err(|_| JusticeError::NotRevoked(commitment_number))?;
+        let per_commitment_secret = SecretKey::from_slice(&secret)
+            .map_err(|_| JusticeError::InvalidSecret(commitment_number))?;
+
+        let tx_graph =
+            history.get(commitment_number as usize).map_err(|err| {
+                JusticeError::NoState(commitment_number, err.to_string())
+            })?;
+        if tx_graph.render_cmt().global.unsigned_tx.txid() != txid {
+            return Err(JusticeError::StateMismatch(txid, commitment_number));
+        }
+
+        Ok(Breach {
+            commitment_tx: tx.clone(),
+            commitment_number,
+            per_commitment_secret,
+            tx_graph,
+        })
+    }
+
+    /// Returns per-commitment point of the revoked commitment
+    #[inline]
+    pub fn per_commitment_point(&self) -> PublicKey {
+        PublicKey::from_secret_key(&SECP256K1, &self.per_commitment_secret)
+    }
+
+    /// Derives revocation public key used in the revoked commitment
+    #[inline]
+    pub fn revocation_pubkey(
+        &self,
+        revocation_basepoint: PublicKey,
+    ) -> PublicKey {
+        derive_revocation_pubkey(
+            revocation_basepoint,
+            self.per_commitment_point(),
+        )
+    }
+
+    /// Derives secret key spending revocation branches of all outputs of the
+    /// revoked commitment
+    #[inline]
+    pub fn revocation_privkey(
+        &self,
+        revocation_basepoint_secret: SecretKey,
+    ) -> SecretKey {
+        derive_revocation_privkey(
+            revocation_basepoint_secret,
+            self.per_commitment_secret,
+        )
+    }
+
+    /// Constructs justice transactions sweeping `to_local` and HTLC outputs
+    /// of the revoked commitment to the `destination`. HTLC outputs which
+    /// were already spent by the second-stage HTLC transactions provided in
+    /// `second_stage_txs` are swept from the outputs of those transactions.
+    /// Inputs are split into several transactions if their weight exceeds
+    /// `max_weight` (usually [`MAX_STANDARD_TX_WEIGHT`]); zero `max_weight`
+    /// sweeps each output with a separate transaction.
+    pub fn justice_txs(
+        &self,
+        local_keys: &Basepoints,
+        remote_keys: &Basepoints,
+        second_stage_txs: &[Transaction],
+        destination: &PubkeyScript,
+        feerate_per_kw: u32,
+        max_weight: u64,
+    ) -> Result<Vec<Psbt>, JusticeError> {
+        let per_commitment_point = self.per_commitment_point();
+        // Second-stage HTLC outputs have the same script as `to_local`
+        let to_local_script = WitnessScript::ln_to_local(
+            0,
+            self.revocation_pubkey(local_keys.revocation_basepoint),
+            derive_pubkey(
+                remote_keys.delayed_payment_basepoint,
+                per_commitment_point,
+            ),
+            self.tx_graph.cmt_to_self_delay,
+        );
+        let to_local_pubkey_script: Script = to_local_script.to_p2wsh().into();
+
+        let htlc_inputs = self
+            .tx_graph
+            .htlc_txs()
+            .into_iter()
+            .map(|psbt| &psbt.inputs[0])
+            .collect::<Vec<_>>();
+        let commitment_txid = self.commitment_tx.txid();
+        let mut inputs = vec![];
+        for (vout, txout) in self.commitment_tx.output.iter().enumerate() {
+            let outpoint = OutPoint::new(commitment_txid, vout as u32);
+            if txout.script_pubkey == to_local_pubkey_script {
+                inputs.push(penalty_input(
+                    outpoint,
+                    txout.clone(),
+                    to_local_script.clone().into(),
+                    false,
+                ));
+                continue;
+            }
+
+            let htlc_script = match htlc_inputs
+                .iter()
+                .find(|input| input.witness_utxo.as_ref() == Some(txout))
+                .and_then(|input| input.witness_script.clone())
+            {
+                Some(script) => script,
+                // Anchor and `to_remote` outputs are not punishable
+                None => continue,
+            };
+            let second_stage = second_stage_txs.iter().find_map(|tx| {
+                let index = tx
+                    .input
+                    .iter()
+                    .position(|txin| txin.previous_output == outpoint)?;
+                tx.output
+                    .get(index)
+                    .filter(|txout| {
+                        txout.script_pubkey == to_local_pubkey_script
+                    })
+                    .map(|txout| {
+                        (OutPoint::new(tx.txid(), index as u32), txout.clone())
+                    })
+            });
+            inputs.push(match second_stage {
+                Some((outpoint, txout)) => penalty_input(
+                    outpoint,
+                    txout,
+                    to_local_script.clone().into(),
+                    false,
+                ),
+                None => {
+                    penalty_input(outpoint, txout.clone(), htlc_script, true)
+                }
+            });
+        }
+        if inputs.is_empty() {
+            return Err(JusticeError::NothingToSweep);
+        }
+
+        let base_weight = tx_weight(&[], destination);
+        let mut txs = vec![];
+        let mut chunk = vec![];
+        let mut weight = base_weight;
+        for input in inputs {
+            let input_weight = TXIN_BASE_WEIGHT + input.satisfaction_weight;
+            if !chunk.is_empty() && weight + input_weight > max_weight {
+                txs.push(justice_psbt(
+                    chunk,
+                    weight,
+                    destination,
+                    feerate_per_kw,
+                )?);
+                chunk = vec![];
+                weight = base_weight;
+            }
+            weight += input_weight;
+            chunk.push(input);
+        }
+        txs.push(justice_psbt(chunk, weight, destination, feerate_per_kw)?);
+        Ok(txs)
+    }
+}
+
+fn justice_psbt(
+    inputs: Vec<SpendInput>,
+    weight: u64,
+    destination: &PubkeyScript,
+    feerate_per_kw: u32,
+) -> Result<Psbt, JusticeError> {
+    let amount = inputs.iter().map(|input| input.txout.value).sum::<u64>();
+    let fee = fee_for_weight(feerate_per_kw, weight);
+    if amount < fee + DEFAULT_DUST_LIMIT {
+        return Err(JusticeError::InsufficientFunds(amount, fee));
+    }
+    Ok(spending_psbt(inputs, 0, amount - fee, destination))
+}
+
+/// Finalization of the justice transactions spending outputs with the
+/// revocation key
+pub trait JusticeFinalizer {
+    /// Constructs witnesses for all inputs of the justice transaction from
+    /// the signatures made with the revocation key, in the order of inputs
+    fn ln_finalize_justice(
+        &mut self,
+        signatures: &[Signature],
+        revocationpubkey: PublicKey,
+    ) -> Result<(), JusticeError>;
+}
+
+impl JusticeFinalizer for Psbt {
+    fn ln_finalize_justice(
+        &mut self,
+        signatures: &[Signature],
+        revocationpubkey: PublicKey,
+    ) -> Result<(), JusticeError> {
+        if signatures.len() != self.inputs.len() {
+            return Err(JusticeError::SignatureCount(
+                signatures.len(),
+                self.inputs.len(),
+            ));
+        }
+        for (index, (input, signature)) in
+            self.inputs.iter_mut().zip(signatures).enumerate()
+        {
+            let witness_script = input
+                .witness_script
+                .as_ref()
+                .ok_or(JusticeError::NoWitnessScript(index))?;
+            let mut sig = signature.serialize_der().to_vec();
+            sig.push(SigHashType::All.as_u32() as u8);
+            // `to_local` script starts with revocation branch selected by
+            // `1`, while HTLC scripts check revocation key hash first
+            let selector = if witness_script.as_bytes().first()
+                == Some(&OP_IF.into_u8())
+            {
+                vec![1u8]
+            } else {
+                revocationpubkey.serialize().to_vec()
+            };
+            input.final_script_witness =
+                Some(vec![sig, selector, witness_script.to_bytes()]);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use amplify::{Slice32, Wrapper};
+    use bitcoin::TxIn;
+    use wallet::{HashLock, HashPreimage};
+
+    use crate::channel::CommitmentOwner;
+    use crate::history::VecHistory;
+    use crate::payment::cpfp::TX_BASE_WEIGHT;
+    use crate::payment::htlc::ScriptGenerators as _;
+    use crate::payment::shachain::commitment_index;
+    use crate::payment::signer::{ChannelSigner, InMemorySigner};
+    use crate::payment::TxType;
+
+    fn funding_outpoint() -> OutPoint {
+        OutPoint::new(Txid::default(), 0)
+    }
+
+    fn signers() -> (InMemorySigner, InMemorySigner) {
+        (
+            InMemorySigner::with_seed([1u8; 32]),
+            InMemorySigner::with_seed([2u8; 32]),
+        )
+    }
+
+    /// Remote commitment with `to_local`, `to_remote` and an offered HTLC
+    /// output, which commitment number is not obscured
+    fn remote_commitment(commitment_number: u64) -> TxGraph {
+        let (local, remote) = signers();
+        let local_keys = local.basepoints().unwrap();
+        let remote_keys = remote.basepoints().unwrap();
+        let per_commitment_point =
+            remote.per_commitment_point(commitment_number).unwrap();
+        let revocationpubkey = derive_revocation_pubkey(
+            local_keys.revocation_basepoint,
+            per_commitment_point,
+        );
+        let delayedpubkey = derive_pubkey(
+            remote_keys.delayed_payment_basepoint,
+            per_commitment_point,
+        );
+        let remote_htlcpubkey =
+            derive_pubkey(remote_keys.htlc_basepoint, per_commitment_point);
+        let local_htlcpubkey =
+            derive_pubkey(local_keys.htlc_basepoint, per_commitment_point);
+        let hashlock = HashLock::from(HashPreimage::from_inner(
+            Slice32::from_inner([commitment_number as u8; 32]),
+        ));
+
+        let mut tx_graph = TxGraph::with_owner(CommitmentOwner::Remote);
+        tx_graph.set_funding_outpoint(funding_outpoint());
+        tx_graph.cmt_locktime = 0x2000_0000 | commitment_number as u32;
+        tx_graph.cmt_sequence = 0x8000_0000;
+        tx_graph.cmt_to_self_delay = 144;
+        tx_graph.cmt_per_commitment_point = per_commitment_point;
+        tx_graph.cmt_outs = vec![
+            TxOut::ln_to_local(50_000, revocationpubkey, delayedpubkey, 144),
+            TxOut::ln_to_remote_v1(30_000, local_keys.payment_basepoint),
+            TxOut::ln_offered_htlc(
+                20_000,
+                revocationpubkey,
+                remote_htlcpubkey,
+                local_htlcpubkey,
+                hashlock,
+                false,
+            ),
+        ];
+
+        let mut htlc_tx = Psbt::from_unsigned_tx(Transaction {
+            version: 2,
+            lock_time: 500,
+            input: vec![TxIn {
+                previous_output: tx_graph.cmt_outpoint(2),
+                script_sig: empty!(),
+                sequence: 0,
+                witness: empty!(),
+            }],
+            output: vec![TxOut::ln_to_local(
+                19_000,
+                revocationpubkey,
+                delayedpubkey,
+                144,
+            )],
+        })
+        .unwrap();
+        htlc_tx.inputs[0].witness_utxo = Some(tx_graph.cmt_outs[2].clone());
+        htlc_tx.inputs[0].witness_script = Some(
+            WitnessScript::ln_offered_htlc(
+                20_000,
+                revocationpubkey,
+                remote_htlcpubkey,
+                local_htlcpubkey,
+                hashlock,
+                false,
+            )
+            .into(),
+        );
+        tx_graph.insert_tx(TxType::HtlcTimeout, 0u64, htlc_tx);
+        tx_graph.link_cmt_txid();
+        tx_graph
+    }
+
+    fn channel_history() -> (ShachainStore, VecHistory<TxGraph>) {
+        let (_, remote) = signers();
+        let mut secrets = ShachainStore::new();
+        let mut history = VecHistory::new();
+        for commitment_number in 0..3 {
+            history.push(remote_commitment(commitment_number)).unwrap();
+        }
+        // Commitment #2 is the current one
+        remote.advance_commitment(2).unwrap();
+        for commitment_number in 0..2 {
+            let mut secret = [0u8; 32];
+            secret.copy_from_slice(
+                &remote
+                    .release_per_commitment_secret(commitment_number)
+                    .unwrap()[..],
+            );
+            secrets
+                .insert(commitment_index(commitment_number), secret)
+                .unwrap();
+        }
+        (secrets, history)
+    }
+
+    fn commitment_tx(commitment_number: u64) -> Transaction {
+        remote_commitment(commitment_number)
+            .render_cmt()
+            .global
+            .unsigned_tx
+    }
+
+    #[test]
+    fn detect() {
+        let (secrets, history) = channel_history();
+        let detect = |tx: &Transaction| {
+            Breach::detect(tx, funding_outpoint(), 0, &secrets, &history).err()
+        };
+
+        let breach = Breach::detect(
+            &commitment_tx(1),
+            funding_outpoint(),
+            0,
+            &secrets,
+            &history,
+        )
+        .unwrap();
+        assert_eq!(breach.commitment_number, 1);
+        assert!(breach.tx_graph == remote_commitment(1));
+        assert_eq!(
+            breach.per_commitment_point(),
+            signers().1.per_commitment_point(1).unwrap()
+        );
+
+        let tx = commitment_tx(2);
+        assert_eq!(detect(&tx), Some(JusticeError::NotRevoked(2)));
+
+        let mut tx = commitment_tx(0);
+        tx.lock_time = 0;
+        assert_eq!(detect(&tx), Some(JusticeError::NotCommitment(tx.txid())));
+
+        tx.input[0].previous_output.vout = 1;
+        assert_eq!(detect(&tx), Some(JusticeError::NotFundingSpend(tx.txid())));
+
+        // Commitment number does not match commitment from the history
+        let mut tx = commitment_tx(0);
+        tx.lock_time = 0x2000_0001;
+        assert_eq!(
+            detect(&tx),
+            Some(JusticeError::StateMismatch(tx.txid(), 1))
+        );
+
+        // Secrets are not validated by the shachain storage when the first
+        // one is inserted
+        let mut secrets = ShachainStore::new();
+        secrets.insert(commitment_index(0), [0xFF; 32]).unwrap();
+        assert_eq!(
+            Breach::detect(
+                &commitment_tx(0),
+                funding_outpoint(),
+                0,
+                &secrets,
+                &history,
+            )
+            .err(),
+            Some(JusticeError::InvalidSecret(0))
+        );
+    }
+
+    #[test]
+    fn justice_txs() {
+        let (local, remote) = signers();
+        let (secrets, history) = channel_history();
+        let local_keys = local.basepoints().unwrap();
+        let remote_keys = remote.basepoints().unwrap();
+        let destination = PubkeyScript::from(Script::new_v0_wpkh(
+            &bitcoin::WPubkeyHash::default(),
+        ));
+        let breach = Breach::detect(
+            &commitment_tx(0),
+            funding_outpoint(),
+            0,
+            &secrets,
+            &history,
+        )
+        .unwrap();
+        let commitment_txid = breach.commitment_tx.txid();
+
+        let mut txs = breach
+            .justice_txs(
+                &local_keys,
+                &remote_keys,
+                &[],
+                &destination,
+                253,
+                MAX_STANDARD_TX_WEIGHT,
+            )
+            .unwrap();
+        assert_eq!(txs.len(), 1);
+        let tx = &txs[0].global.unsigned_tx;
+        assert_eq!(
+            tx.input
+                .iter()
+                .map(|txin| txin.previous_output)
+                .collect::<Vec<_>>(),
+            vec![
+                OutPoint::new(commitment_txid, 0),
+                OutPoint::new(commitment_txid, 2)
+            ]
+        );
+        let weight = TX_BASE_WEIGHT
+            + 4 * (8 + 1 + 22)
+            + txs[0]
+                .inputs
+                .iter()
+                .map(|input| {
+                    penalty_input(
+                        OutPoint::default(),
+                        input.witness_utxo.clone().unwrap(),
+                        input.witness_script.clone().unwrap(),
+                        input.witness_script.as_ref().unwrap().as_bytes()[0]
+                            != OP_IF.into_u8(),
+                    )
+                    .satisfaction_weight
+                        + TXIN_BASE_WEIGHT
+                })
+                .sum::<u64>();
+        assert_eq!(tx.output[0].value, 70_000 - fee_for_weight(253, weight));
+
+        // Signing with the revocation key
+        let signatures = local
+            .sign_justice(&txs[0], breach.per_commitment_secret)
+            .unwrap();
+        let revocationpubkey =
+            breach.revocation_pubkey(local_keys.revocation_basepoint);
+        txs[0]
+            .ln_finalize_justice(&signatures, revocationpubkey)
+            .unwrap();
+        let witness = txs[0].inputs[0].final_script_witness.clone().unwrap();
+        assert_eq!(witness[1], vec![1u8]);
+        let witness = txs[0].inputs[1].final_script_witness.clone().unwrap();
+        assert_eq!(witness[1], revocationpubkey.serialize().to_vec());
+        assert_eq!(
+            txs[0].ln_finalize_justice(&signatures[..1], revocationpubkey),
+            Err(JusticeError::SignatureCount(1, 2))
+        );
+
+        // HTLC output spent by the remote peer is swept from the second-stage
+        // transaction
+        let second_stage = breach
+            .tx_graph
+            .tx(TxType::HtlcTimeout, 0u64)
+            .unwrap()
+            .global
+            .unsigned_tx
+            .clone();
+        let txs = breach
+            .justice_txs(
+                &local_keys,
+                &remote_keys,
+                &[second_stage.clone()],
+                &destination,
+                253,
+                MAX_STANDARD_TX_WEIGHT,
+            )
+            .unwrap();
+        assert_eq!(
+            txs[0].global.unsigned_tx.input[1].previous_output,
+            OutPoint::new(second_stage.txid(), 0)
+        );
+
+        // Splitting into several transactions
+        let txs = breach
+            .justice_txs(&local_keys, &remote_keys, &[], &destination, 253, 800)
+            .unwrap();
+        assert_eq!(txs.len(), 2);
+
+        assert!(matches!(
+            breach.justice_txs(
+                &local_keys,
+                &remote_keys,
+                &[],
+                &destination,
+                1_000_000,
+                MAX_STANDARD_TX_WEIGHT,
+            ),
+            Err(JusticeError::InsufficientFunds(70_000, _))
+        ));
+    }
+}
@@ -0,0 +1,394 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Derivation of per-commitment keys from channel basepoints according to
+//! BOLT-3 "Key Derivation" section.
+
+use std::fmt::{self, Debug, Formatter};
+
+use amplify::DumbDefault;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::{self, PublicKey, Secp256k1, SecretKey};
+use wallet::SECP256K1_PUBKEY_DUMB;
+
+use super::shachain::{commitment_index, generate_from_seed};
+use crate::message::{AcceptChannel, OpenChannel};
+
+lazy_static! {
+    /// Global secp256k1 context used for key derivation and signing
+    pub static ref SECP256K1: Secp256k1<secp256k1::All> = Secp256k1::new();
+}
+
+fn tweak(first: PublicKey, second: PublicKey) -> sha256::Hash {
+    let mut engine = sha256::Hash::engine();
+    engine.input(&first.serialize());
+    engine.input(&second.serialize());
+    sha256::Hash::from_engine(engine)
+}
+
+/// Derives per-commitment public key (`localpubkey`, `remotepubkey`,
+/// `local_htlcpubkey`, `remote_htlcpubkey`, `local_delayedpubkey` or
+/// `remote_delayedpubkey`) from the basepoint:
+///
+/// `pubkey = basepoint + SHA256(per_commitment_point || basepoint) * G`
+pub fn derive_pubkey(
+    basepoint: PublicKey,
+    per_commitment_point: PublicKey,
+) -> PublicKey {
+    let mut pubkey = basepoint;
+    pubkey
+        .add_exp_assign(&SECP256K1, &tweak(per_commitment_point, basepoint)[..])
+        .expect("negligible probability of hash value exceeding curve order");
+    pubkey
+}
+
+/// Derives per-commitment secret key matching [`derive_pubkey`]:
+///
+/// `privkey = basepoint_secret + SHA256(per_commitment_point || basepoint)`
+pub fn derive_privkey(
+    basepoint_secret: SecretKey,
+    per_commitment_point: PublicKey,
+) -> SecretKey {
+    let basepoint = PublicKey::from_secret_key(&SECP256K1, &basepoint_secret);
+    let mut privkey = basepoint_secret;
+    privkey
+        .add_assign(&tweak(per_commitment_point, basepoint)[..])
+        .expect("negligible probability of hash value exceeding curve order");
+    privkey
+}
+
+/// Derives `revocationpubkey` from the revocation basepoint of the
+/// counterparty and per-commitment point of the commitment transaction
+/// owner:
+///
+/// `revocationpubkey = revocation_basepoint * SHA256(revocation_basepoint ||
+/// per_commitment_point) + per_commitment_point *
+/// SHA256(per_commitment_point || revocation_basepoint)`
+pub fn derive_revocation_pubkey(
+    revocation_basepoint: PublicKey,
+    per_commitment_point: PublicKey,
+) -> PublicKey {
+    let mut basepoint_part = revocation_basepoint;
+    basepoint_part
+        .mul_assign(
+            &SECP256K1,
+            &tweak(revocation_basepoint, per_commitment_point)[..],
+        )
+        .expect("negligible probability of hash value exceeding curve order");
+    let mut commitment_part = per_commitment_point;
+    commitment_part
+        .mul_assign(
+            &SECP256K1,
+            &tweak(per_commitment_point, revocation_basepoint)[..],
+        )
+        .expect("negligible probability of hash value exceeding curve order");
+    basepoint_part
+        .combine(&commitment_part)
+        .expect("negligible probability of point at infinity")
+}
+
+/// Derives secret key for the `revocationpubkey`, which becomes possible
+/// once the per-commitment secret is revealed by the counterparty:
+///
+/// `revocationprivkey = revocation_basepoint_secret *
+/// SHA256(revocation_basepoint || per_commitment_point) +
+/// per_commitment_secret * SHA256(per_commitment_point ||
+/// revocation_basepoint)`
+pub fn derive_revocation_privkey(
+    revocation_basepoint_secret: SecretKey,
+    per_commitment_secret: SecretKey,
+) -> SecretKey {
+    let revocation_basepoint =
+        PublicKey::from_secret_key(&SECP256K1, &revocation_basepoint_secret);
+    let per_commitment_point =
+        PublicKey::from_secret_key(&SECP256K1, &per_commitment_secret);
+
+    let mut basepoint_part = revocation_basepoint_secret;
+    basepoint_part
+        .mul_assign(&tweak(revocation_basepoint, per_commitment_point)[..])
+        .expect("negligible probability of hash value exceeding curve order");
+    let mut commitment_part = per_commitment_secret;
+    commitment_part
+        .mul_assign(&tweak(per_commitment_point, revocation_basepoint)[..])
+        .expect("negligible probability of hash value exceeding curve order");
+    basepoint_part
+        .add_assign(&commitment_part[..])
+        .expect("negligible probability of zero key");
+    basepoint_part
+}
+
+/// Set of channel basepoints of one of the channel parties, as they are
+/// announced in `open_channel` and `accept_channel` messages
+#[derive(
+    Copy,
+    Clone,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Debug,
+    StrictEncode,
+    StrictDecode,
+)]
+pub struct Basepoints {
+    pub revocation_basepoint: PublicKey,
+    pub payment_basepoint: PublicKey,
+    pub delayed_payment_basepoint: PublicKey,
+    pub htlc_basepoint: PublicKey,
+}
+
+impl DumbDefault for Basepoints {
+    fn dumb_default() -> Self {
+        Self {
+            revocation_basepoint: *SECP256K1_PUBKEY_DUMB,
+            payment_basepoint: *SECP256K1_PUBKEY_DUMB,
+            delayed_payment_basepoint: *SECP256K1_PUBKEY_DUMB,
+            htlc_basepoint: *SECP256K1_PUBKEY_DUMB,
+        }
+    }
+}
+
+impl From<&OpenChannel> for Basepoints {
+    fn from(msg: &OpenChannel) -> Self {
+        Self {
+            revocation_basepoint: msg.revocation_basepoint,
+            payment_basepoint: msg.payment_point,
+            delayed_payment_basepoint: msg.delayed_payment_basepoint,
+            htlc_basepoint: msg.htlc_basepoint,
+        }
+    }
+}
+
+impl From<&AcceptChannel> for Basepoints {
+    fn from(msg: &AcceptChannel) -> Self {
+        Self {
+            revocation_basepoint: msg.revocation_basepoint,
+            payment_basepoint: msg.payment_point,
+            delayed_payment_basepoint: msg.delayed_payment_basepoint,
+            htlc_basepoint: msg.htlc_basepoint,
+        }
+    }
+}
+
+/// Keys of the local channel party: basepoints and the seed from which
+/// per-commitment secrets are generated with shachain
+#[derive(Copy, Clone, PartialEq, Eq, StrictEncode, StrictDecode)]
+pub struct LocalKeyset {
+    pub basepoints: Basepoints,
+    pub per_commitment_seed: [u8; 32],
+}
+
+// Debug output must not leak the per-commitment seed
+impl Debug for LocalKeyset {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LocalKeyset")
+            .field("basepoints", &self.basepoints)
+            .finish()
+    }
+}
+
+impl DumbDefault for LocalKeyset {
+    fn dumb_default() -> Self {
+        Self {
+            basepoints: Basepoints::dumb_default(),
+            per_commitment_seed: [0u8; 32],
+        }
+    }
+}
+
+impl LocalKeyset {
+    /// Returns per-commitment secret for the commitment with a given number
+    pub fn per_commitment_secret(&self, commitment_number: u64) -> SecretKey {
+        SecretKey::from_slice(&generate_from_seed(
+            self.per_commitment_seed,
+            commitment_index(commitment_number),
+        ))
+        .expect("negligible probability of hash value exceeding curve order")
+    }
+
+    /// Returns per-commitment point for the commitment with a given number
+    pub fn per_commitment_point(&self, commitment_number: u64) -> PublicKey {
+        PublicKey::from_secret_key(
+            &SECP256K1,
+            &self.per_commitment_secret(commitment_number),
+        )
+    }
+}
+
+/// Keys used in the outputs of a commitment transaction. The naming follows
+/// BOLT-3, where `local` stands for the party owning (i.e. able to publish)
+/// the commitment transaction and `remote` for its counterparty.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, StrictEncode, StrictDecode)]
+pub struct CommitmentKeys {
+    pub per_commitment_point: PublicKey,
+    pub revocationpubkey: PublicKey,
+    pub local_delayedpubkey: PublicKey,
+    pub local_htlcpubkey: PublicKey,
+    pub remote_htlcpubkey: PublicKey,
+    pub remotepubkey: PublicKey,
+}
+
+impl CommitmentKeys {
+    /// Derives keys for the commitment transaction owned by the party with
+    /// `local` basepoints and the given per-commitment point
+    pub fn derive(
+        local: &Basepoints,
+        remote: &Basepoints,
+        per_commitment_point: PublicKey,
+    ) -> Self {
+        CommitmentKeys {
+            per_commitment_point,
+            revocationpubkey: derive_revocation_pubkey(
+                remote.revocation_basepoint,
+                per_commitment_point,
+            ),
+            local_delayedpubkey: derive_pubkey(
+                local.delayed_payment_basepoint,
+                per_commitment_point,
+            ),
+            local_htlcpubkey: derive_pubkey(
+                local.htlc_basepoint,
+                per_commitment_point,
+            ),
+            remote_htlcpubkey: derive_pubkey(
+                remote.htlc_basepoint,
+                per_commitment_point,
+            ),
+            remotepubkey: derive_pubkey(
+                remote.payment_basepoint,
+                per_commitment_point,
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use bitcoin::hashes::hex::FromHex;
+    use std::str::FromStr;
+
+    /// Public key for the secret key made of the repeated byte, used by the
+    /// test fixtures across the crate
+    pub(crate) fn pubkey(byte: u8) -> PublicKey {
+        PublicKey::from_secret_key(
+            &SECP256K1,
+            &SecretKey::from_slice(&[byte; 32]).unwrap(),
+        )
+    }
+
+    fn secret_key(hex: &str) -> SecretKey {
+        SecretKey::from_slice(&Vec::<u8>::from_hex(hex).unwrap()).unwrap()
+    }
+
+    fn public_key(hex: &str) -> PublicKey {
+        PublicKey::from_str(hex).unwrap()
+    }
+
+    fn vector_keys() -> (SecretKey, SecretKey) {
+        (
+            secret_key(
+                "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+            ),
+            secret_key(
+                "1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100",
+            ),
+        )
+    }
+
+    #[test]
+    fn bolt3_derivation_points() {
+        let (base_secret, per_commitment_secret) = vector_keys();
+        assert_eq!(
+            PublicKey::from_secret_key(&SECP256K1, &base_secret),
+            public_key("036d6caac248af96f6afa7f904f550253a0f3ef3f5aa2fe6838a95b216691468e2")
+        );
+        assert_eq!(
+            PublicKey::from_secret_key(&SECP256K1, &per_commitment_secret),
+            public_key("025f7117a78150fe2ef97db7cfc83bd57b2e2c0d0dd25eaf467a4a1c2a45ce1486")
+        );
+    }
+
+    #[test]
+    fn bolt3_derive_pubkey() {
+        let (base_secret, per_commitment_secret) = vector_keys();
+        let basepoint = PublicKey::from_secret_key(&SECP256K1, &base_secret);
+        let per_commitment_point =
+            PublicKey::from_secret_key(&SECP256K1, &per_commitment_secret);
+
+        assert_eq!(
+            derive_pubkey(basepoint, per_commitment_point),
+            public_key("0235f2dbfaa89b57ec7b055afe29849ef7ddfeb1cefdb9ebdc43f5494984db29e5")
+        );
+        let privkey = derive_privkey(base_secret, per_commitment_point);
+        assert_eq!(
+            privkey,
+            secret_key("cbced912d3b21bf196a766651e436aff192362621ce317704ea2f75d87e7be0f")
+        );
+        assert_eq!(
+            PublicKey::from_secret_key(&SECP256K1, &privkey),
+            derive_pubkey(basepoint, per_commitment_point)
+        );
+    }
+
+    #[test]
+    fn bolt3_derive_revocation_key() {
+        let (base_secret, per_commitment_secret) = vector_keys();
+        let basepoint = PublicKey::from_secret_key(&SECP256K1, &base_secret);
+        let per_commitment_point =
+            PublicKey::from_secret_key(&SECP256K1, &per_commitment_secret);
+
+        assert_eq!(
+            derive_revocation_pubkey(basepoint, per_commitment_point),
+            public_key("02916e326636d19c33f13e8c0c3a03dd157f332f3e99c317c141dd865eb01f8ff0")
+        );
+        let privkey =
+            derive_revocation_privkey(base_secret, per_commitment_secret);
+        assert_eq!(
+            privkey,
+            secret_key("d09ffff62ddb2297ab000cc85bcb4283fdeb6aa052affbc9dddcf33b61078110")
+        );
+        assert_eq!(
+            PublicKey::from_secret_key(&SECP256K1, &privkey),
+            derive_revocation_pubkey(basepoint, per_commitment_point)
+        );
+    }
+
+    #[test]
+    fn local_keyset_points() {
+        let keyset = LocalKeyset {
+            basepoints: Basepoints::dumb_default(),
+            per_commitment_seed: [0xFF; 32],
+        };
+        assert_eq!(
+            keyset.per_commitment_secret(0),
+            secret_key("7cc854b54e3e0dcdb010d7a3fee464a9687be6e8db3be6854c475621e007a5dc")
+        );
+        assert_eq!(
+            keyset.per_commitment_point(1),
+            PublicKey::from_secret_key(
+                &SECP256K1,
+                &keyset.per_commitment_secret(1)
+            )
+        );
+        assert_ne!(
+            keyset.per_commitment_point(0),
+            keyset.per_commitment_point(1)
+        );
+
+        let debug = format!("{:?}", keyset);
+        assert!(debug.contains("basepoints"));
+        assert!(!debug.contains("per_commitment_seed"));
+    }
+}
@@ -0,0 +1,649 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use super::Lifecycle;
+use crate::Messages;
+
+/// Direction in which a message is passed between the channel peers
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+#[derive(
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+)]
+pub enum Direction {
+    /// Message received from the remote peer
+    #[display("received")]
+    Inbound,
+
+    /// Message sent by the local node to the remote peer
+    #[display("sent")]
+    Outbound,
+}
+
+/// On-chain events affecting channel lifecycle
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Display)]
+#[display(doc_comments)]
+pub enum ChainEvent {
+    /// funding transaction was published to the network
+    FundingPublished,
+
+    /// funding transaction has reached the required minimum depth
+    FundingConfirmed,
+
+    /// funding output was spent by a mined transaction
+    FundingSpent,
+}
+
+/// Events driving channel lifecycle state machine
+#[derive(Clone, Copy, Debug, Display)]
+pub enum LifecycleEvent<'msg> {
+    /// Channel message passed between the peers
+    #[display("{1} {0}")]
+    Message(Direction, &'msg Messages),
+
+    /// Blockchain event related to the channel funding output
+    #[display(inner)]
+    Chain(ChainEvent),
+}
+
+/// Errors of the channel lifecycle state machine
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum LifecycleError {
+    /// `{message}` message can't be {direction} while the channel is in
+    /// {state} state
+    UnexpectedMessage {
+        state: Lifecycle,
+        direction: Direction,
+        message: &'static str,
+    },
+
+    /// chain event "{event}" is not expected while the channel is in {state}
+    /// state
+    UnexpectedChainEvent { state: Lifecycle, event: ChainEvent },
+
+    /// number of `closing_signed` negotiation rounds exceeds the maximum of
+    /// {0}
+    ClosingRoundOverflow(usize),
+}
+
+impl Lifecycle {
+    /// Returns whether the channel has reached one of its terminal states,
+    /// after which no further state transitions are possible
+    #[inline]
+    pub fn is_final(self) -> bool {
+        matches!(self, Lifecycle::Closed | Lifecycle::Aborted)
+    }
+
+    /// Returns whether the funding transaction is already signed by both
+    /// peers, i.e. the channel can be closed on-chain only by spending the
+    /// funding output
+    #[inline]
+    pub fn is_funded(self) -> bool {
+        !matches!(
+            self,
+            Lifecycle::Initial
+                | Lifecycle::Proposed
+                | Lifecycle::Accepted
+                | Lifecycle::Funding
+        ) && !self.is_final()
+    }
+
+    /// Computes next channel lifecycle state basing on the provided event,
+    /// according to the channel state transitions defined in BOLT-2.
+    ///
+    /// Messages not related to the channel operations (like `init`, `ping`
+    /// and gossip messages) never change the channel state. Returns error if
+    /// the event is not allowed in the current state of the channel; in this
+    /// case the channel state should be kept unchanged.
+    ///
+    /// # Specification
+    /// <https://github.com/lightningnetwork/lightning-rfc/blob/master/02-peer-protocol.md>
+    pub fn next(self, event: LifecycleEvent) -> Result<Self, LifecycleError> {
+        match event {
+            LifecycleEvent::Message(direction, message) => {
+                self.next_on_message(direction, message)
+            }
+            LifecycleEvent::Chain(event) => self.next_on_chain(event),
+        }
+    }
+
+    /// Updates lifecycle state in place basing on the provided event. If the
+    /// event is not allowed in the current state, the state is kept unchanged
+    /// and an error is returned.
+    #[inline]
+    pub fn apply(
+        &mut self,
+        event: LifecycleEvent,
+    ) -> Result<Self, LifecycleError> {
+        *self = self.next(event)?;
+        Ok(*self)
+    }
+
+    fn next_on_message(
+        self,
+        direction: Direction,
+        message: &Messages,
+    ) -> Result<Self, LifecycleError> {
+        use Lifecycle::*;
+
+        let next = match (self, message) {
+            // Messages which are not the part of the channel protocol
+            (
+                state,
+                Messages::Init(_)
+                | Messages::Ping(_)
+                | Messages::Pong(_)
+                | Messages::AnnouncementSignatures(_)
+                | Messages::ChannelAnnouncements(_)
+                | Messages::NodeAnnouncements(_)
+                | Messages::ChannelUpdate(_)
+                | Messages::QueryShortChannelIds(_)
+                | Messages::ReplyShortChannelIdsEnd(_)
+                | Messages::QueryChannelRange(_)
+                | Messages::ReplyChannelRange(_)
+                | Messages::GossipTimestampFilter(_),
+            ) => state,
+
+            // Channel failure
+            (state, Messages::Error(_)) if !state.is_final() => Aborted,
+
+            // Channel establishment
+            (Initial, Messages::OpenChannel(_)) => Proposed,
+            (Proposed, Messages::AcceptChannel(_)) => Accepted,
+            (Accepted, Messages::FundingCreated(_)) => Funding,
+            (Funding, Messages::FundingSigned(_)) => Signed,
+            (Signed, Messages::FundingLocked(_))
+            | (Funded, Messages::FundingLocked(_)) => Locked { by: direction },
+            // Repeated `funding_locked` from the same peer does not confirm
+            // the lock by the other peer
+            (Locked { by }, Messages::FundingLocked(_)) if by == direction => {
+                Locked { by }
+            }
+            (Locked { .. }, Messages::FundingLocked(_)) => Active,
+
+            // Normal operations
+            (
+                Active,
+                Messages::UpdateAddHtlc(_)
+                | Messages::UpdateFulfillHtlc(_)
+                | Messages::UpdateFailHtlc(_)
+                | Messages::UpdateFailMalformedHtlc(_)
+                | Messages::CommitmentSigned(_)
+                | Messages::RevokeAndAck(_)
+                | Messages::UpdateFee(_),
+            ) => Active,
+
+            // Once shutdown is initiated, no new HTLCs may be added, but the
+            // existing ones must be resolved
+            (
+                state @ Shutdown { .. },
+                Messages::UpdateFulfillHtlc(_)
+                | Messages::UpdateFailHtlc(_)
+                | Messages::UpdateFailMalformedHtlc(_)
+                | Messages::CommitmentSigned(_)
+                | Messages::RevokeAndAck(_)
+                | Messages::UpdateFee(_),
+            ) => state,
+
+            // Reconnection
+            (Active, Messages::ChannelReestablish(_)) => {
+                Reestablishing { by: direction }
+            }
+            (Reestablishing { by }, Messages::ChannelReestablish(_))
+                if by == direction =>
+            {
+                Reestablishing { by }
+            }
+            (Reestablishing { .. }, Messages::ChannelReestablish(_)) => Active,
+            (
+                state @ Signed
+                | state @ Funded
+                | state @ Locked { .. }
+                | state @ Shutdown { .. }
+                | state @ Closing { .. },
+                Messages::ChannelReestablish(_),
+            ) => state,
+
+            // Channel closing
+            (Signed, Messages::Shutdown(_))
+            | (Funded, Messages::Shutdown(_))
+            | (Locked { .. }, Messages::Shutdown(_))
+            | (Active, Messages::Shutdown(_)) => Shutdown {
+                by: direction,
+                agreed: false,
+            },
+            // Shutdown is agreed only once the other peer replies with its
+            // own `shutdown` message
+            (Shutdown { by, agreed }, Messages::Shutdown(_)) => Shutdown {
+                by,
+                agreed: agreed || by != direction,
+            },
+            (Shutdown { agreed: true, .. }, Messages::ClosingSigned(_)) => {
+                Closing {
+                    round: 1,
+                    by: direction,
+                }
+            }
+            // Peers take turns in proposing closing fee
+            (Closing { round, by }, Messages::ClosingSigned(_))
+                if by != direction =>
+            {
+                Closing {
+                    round: round
+                        .checked_add(1)
+                        .ok_or(LifecycleError::ClosingRoundOverflow(round))?,
+                    by: direction,
+                }
+            }
+
+            (state, message) => {
+                return Err(LifecycleError::UnexpectedMessage {
+                    state,
+                    direction,
+                    message: message_name(message),
+                })
+            }
+        };
+        Ok(next)
+    }
+
+    fn next_on_chain(self, event: ChainEvent) -> Result<Self, LifecycleError> {
+        use Lifecycle::*;
+
+        let next = match (self, event) {
+            (Signed, ChainEvent::FundingPublished) => Funded,
+            (
+                state @ Signed | state @ Funded | state @ Locked { .. },
+                ChainEvent::FundingConfirmed,
+            ) => state,
+            (Closing { .. }, ChainEvent::FundingSpent) => Closed,
+            (state, ChainEvent::FundingSpent) if state.is_funded() => Aborted,
+            (state, event) => {
+                return Err(LifecycleError::UnexpectedChainEvent {
+                    state,
+                    event,
+                })
+            }
+        };
+        Ok(next)
+    }
+}
+
+/// Returns BOLT name of the message type
+pub(crate) fn message_name(message: &Messages) -> &'static str {
+    match message {
+        Messages::Init(_) => "init",
+        Messages::Error(_) => "error",
+        Messages::Ping(_) => "ping",
+        Messages::Pong(_) => "pong",
+        Messages::OpenChannel(_) => "open_channel",
+        Messages::AcceptChannel(_) => "accept_channel",
+        Messages::FundingCreated(_) => "funding_created",
+        Messages::FundingSigned(_) => "funding_signed",
+        Messages::FundingLocked(_) => "funding_locked",
+        Messages::Shutdown(_) => "shutdown",
+        Messages::ClosingSigned(_) => "closing_signed",
+        Messages::UpdateAddHtlc(_) => "update_add_htlc",
+        Messages::UpdateFulfillHtlc(_) => "update_fulfill_htlc",
+        Messages::UpdateFailHtlc(_) => "update_fail_htlc",
+        Messages::UpdateFailMalformedHtlc(_) => "update_fail_malformed_htlc",
+        Messages::CommitmentSigned(_) => "commitment_signed",
+        Messages::RevokeAndAck(_) => "revoke_and_ack",
+        Messages::UpdateFee(_) => "update_fee",
+        Messages::ChannelReestablish(_) => "channel_reestablish",
+        Messages::AnnouncementSignatures(_) => "announcement_signatures",
+        Messages::ChannelAnnouncements(_) => "channel_announcement",
+        Messages::NodeAnnouncements(_) => "node_announcement",
+        Messages::ChannelUpdate(_) => "channel_update",
+        Messages::QueryShortChannelIds(_) => "query_short_channel_ids",
+        Messages::ReplyShortChannelIdsEnd(_) => "reply_short_channel_ids_end",
+        Messages::QueryChannelRange(_) => "query_channel_range",
+        Messages::ReplyChannelRange(_) => "reply_channel_range",
+        Messages::GossipTimestampFilter(_) => "gossip_timestamp_filter",
+        #[cfg(feature = "rgb")]
+        Messages::AssignFunds(_) => "assign_funds",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use amplify::DumbDefault;
+    use bitcoin::hashes::Hash;
+    use bitcoin::secp256k1::Signature;
+    use bitcoin::{Script, Txid};
+    use wallet::SECP256K1_PUBKEY_DUMB;
+
+    use super::*;
+    use crate::message::{
+        AcceptChannel, ClosingSigned, FundingCreated, FundingLocked,
+        FundingSigned, OpenChannel, RevokeAndAck, Shutdown, UpdateFee,
+    };
+    use crate::ChannelId;
+
+    fn signature() -> Signature {
+        Signature::from_compact(&[1u8; 64]).unwrap()
+    }
+
+    fn open_channel() -> Messages {
+        Messages::OpenChannel(OpenChannel::dumb_default())
+    }
+
+    fn accept_channel() -> Messages {
+        Messages::AcceptChannel(AcceptChannel {
+            temporary_channel_id: DumbDefault::dumb_default(),
+            dust_limit_satoshis: 0,
+            max_htlc_value_in_flight_msat: 0,
+            channel_reserve_satoshis: 0,
+            htlc_minimum_msat: 0,
+            minimum_depth: 0,
+            to_self_delay: 0,
+            max_accepted_htlcs: 0,
+            funding_pubkey: *SECP256K1_PUBKEY_DUMB,
+            revocation_basepoint: *SECP256K1_PUBKEY_DUMB,
+            payment_point: *SECP256K1_PUBKEY_DUMB,
+            delayed_payment_basepoint: *SECP256K1_PUBKEY_DUMB,
+            htlc_basepoint: *SECP256K1_PUBKEY_DUMB,
+            first_per_commitment_point: *SECP256K1_PUBKEY_DUMB,
+        })
+    }
+
+    fn funding_created() -> Messages {
+        Messages::FundingCreated(FundingCreated {
+            temporary_channel_id: DumbDefault::dumb_default(),
+            funding_txid: Txid::from_inner([0u8; 32]),
+            funding_output_index: 0,
+            signature: signature(),
+        })
+    }
+
+    fn funding_signed() -> Messages {
+        Messages::FundingSigned(FundingSigned {
+            channel_id: ChannelId::default(),
+            signature: signature(),
+        })
+    }
+
+    fn funding_locked() -> Messages {
+        Messages::FundingLocked(FundingLocked {
+            channel_id: ChannelId::default(),
+            next_per_commitment_point: *SECP256K1_PUBKEY_DUMB,
+        })
+    }
+
+    fn update_fee() -> Messages {
+        Messages::UpdateFee(UpdateFee {
+            channel_id: ChannelId::default(),
+            feerate_per_kw: 253,
+        })
+    }
+
+    fn revoke_and_ack() -> Messages {
+        Messages::RevokeAndAck(RevokeAndAck {
+            channel_id: ChannelId::default(),
+            per_commitment_secret: [0u8; 32],
+            next_per_commitment_point: *SECP256K1_PUBKEY_DUMB,
+        })
+    }
+
+    fn shutdown() -> Messages {
+        Messages::Shutdown(Shutdown {
+            channel_id: ChannelId::default(),
+            scriptpubkey: Script::default(),
+        })
+    }
+
+    fn closing_signed() -> Messages {
+        Messages::ClosingSigned(ClosingSigned {
+            channel_id: ChannelId::default(),
+            fee_satoshis: 1000,
+            signature: signature(),
+        })
+    }
+
+    fn sent(message: &Messages) -> LifecycleEvent {
+        LifecycleEvent::Message(Direction::Outbound, message)
+    }
+
+    fn received(message: &Messages) -> LifecycleEvent {
+        LifecycleEvent::Message(Direction::Inbound, message)
+    }
+
+    #[test]
+    fn full_lifecycle() {
+        let mut state = Lifecycle::default();
+        assert_eq!(state.apply(sent(&open_channel())), Ok(Lifecycle::Proposed));
+        assert_eq!(
+            state.apply(received(&accept_channel())),
+            Ok(Lifecycle::Accepted)
+        );
+        assert_eq!(
+            state.apply(sent(&funding_created())),
+            Ok(Lifecycle::Funding)
+        );
+        assert_eq!(
+            state.apply(received(&funding_signed())),
+            Ok(Lifecycle::Signed)
+        );
+        assert_eq!(
+            state.apply(LifecycleEvent::Chain(ChainEvent::FundingPublished)),
+            Ok(Lifecycle::Funded)
+        );
+        assert_eq!(
+            state.apply(LifecycleEvent::Chain(ChainEvent::FundingConfirmed)),
+            Ok(Lifecycle::Funded)
+        );
+        assert_eq!(
+            state.apply(sent(&funding_locked())),
+            Ok(Lifecycle::Locked {
+                by: Direction::Outbound
+            })
+        );
+        assert_eq!(
+            state.apply(received(&funding_locked())),
+            Ok(Lifecycle::Active)
+        );
+        assert_eq!(state.apply(sent(&update_fee())), Ok(Lifecycle::Active));
+        assert_eq!(
+            state.apply(received(&revoke_and_ack())),
+            Ok(Lifecycle::Active)
+        );
+        assert_eq!(
+            state.apply(sent(&shutdown())),
+            Ok(Lifecycle::Shutdown {
+                by: Direction::Outbound,
+                agreed: false
+            })
+        );
+        assert_eq!(
+            state.apply(received(&shutdown())),
+            Ok(Lifecycle::Shutdown {
+                by: Direction::Outbound,
+                agreed: true
+            })
+        );
+        assert_eq!(
+            state.apply(sent(&closing_signed())),
+            Ok(Lifecycle::Closing {
+                round: 1,
+                by: Direction::Outbound
+            })
+        );
+        assert_eq!(
+            state.apply(received(&closing_signed())),
+            Ok(Lifecycle::Closing {
+                round: 2,
+                by: Direction::Inbound
+            })
+        );
+        assert_eq!(
+            state.apply(LifecycleEvent::Chain(ChainEvent::FundingSpent)),
+            Ok(Lifecycle::Closed)
+        );
+        assert!(state.is_final());
+    }
+
+    #[test]
+    fn unilateral_close() {
+        assert_eq!(
+            Lifecycle::Active
+                .next(LifecycleEvent::Chain(ChainEvent::FundingSpent)),
+            Ok(Lifecycle::Aborted)
+        );
+        assert_eq!(
+            Lifecycle::Shutdown {
+                by: Direction::Inbound,
+                agreed: false
+            }
+            .next(LifecycleEvent::Chain(ChainEvent::FundingSpent)),
+            Ok(Lifecycle::Aborted)
+        );
+    }
+
+    #[test]
+    fn illegal_messages() {
+        assert_eq!(
+            Lifecycle::Initial.next(received(&accept_channel())),
+            Err(LifecycleError::UnexpectedMessage {
+                state: Lifecycle::Initial,
+                direction: Direction::Inbound,
+                message: "accept_channel"
+            })
+        );
+        assert_eq!(
+            Lifecycle::Proposed.next(sent(&funding_created())),
+            Err(LifecycleError::UnexpectedMessage {
+                state: Lifecycle::Proposed,
+                direction: Direction::Outbound,
+                message: "funding_created"
+            })
+        );
+        assert!(Lifecycle::Active.next(received(&open_channel())).is_err());
+        assert!(Lifecycle::Locked {
+            by: Direction::Outbound
+        }
+        .next(sent(&update_fee()))
+        .is_err());
+        assert!(Lifecycle::Funding.next(received(&shutdown())).is_err());
+        assert!(Lifecycle::Active.next(sent(&closing_signed())).is_err());
+        assert!(Lifecycle::Closed.next(received(&funding_locked())).is_err());
+        assert!(Lifecycle::Aborted.next(sent(&update_fee())).is_err());
+    }
+
+    #[test]
+    fn illegal_chain_events() {
+        assert_eq!(
+            Lifecycle::Accepted
+                .next(LifecycleEvent::Chain(ChainEvent::FundingPublished)),
+            Err(LifecycleError::UnexpectedChainEvent {
+                state: Lifecycle::Accepted,
+                event: ChainEvent::FundingPublished
+            })
+        );
+        assert!(Lifecycle::Proposed
+            .next(LifecycleEvent::Chain(ChainEvent::FundingSpent))
+            .is_err());
+        assert!(Lifecycle::Active
+            .next(LifecycleEvent::Chain(ChainEvent::FundingPublished))
+            .is_err());
+        assert!(Lifecycle::Closed
+            .next(LifecycleEvent::Chain(ChainEvent::FundingSpent))
+            .is_err());
+    }
+
+    #[test]
+    fn failed_state_is_kept() {
+        let mut state = Lifecycle::Proposed;
+        assert!(state.apply(sent(&funding_signed())).is_err());
+        assert_eq!(state, Lifecycle::Proposed);
+    }
+
+    #[test]
+    fn reestablish() {
+        use crate::message::ChannelReestablish;
+        let reestablish = Messages::ChannelReestablish(ChannelReestablish {
+            channel_id: ChannelId::default(),
+            next_commitment_number: 1,
+            next_revocation_number: 0,
+            your_last_per_commitment_secret: [0u8; 32],
+            my_current_per_commitment_point: *SECP256K1_PUBKEY_DUMB,
+        });
+        let mut state = Lifecycle::Active;
+        let reestablishing = Lifecycle::Reestablishing {
+            by: Direction::Outbound,
+        };
+        assert_eq!(state.apply(sent(&reestablish)), Ok(reestablishing));
+        assert!(state.apply(sent(&update_fee())).is_err());
+        assert_eq!(state.apply(sent(&reestablish)), Ok(reestablishing));
+        assert_eq!(state.apply(received(&reestablish)), Ok(Lifecycle::Active));
+        let agreed = Lifecycle::Shutdown {
+            by: Direction::Inbound,
+            agreed: true,
+        };
+        assert_eq!(agreed.next(received(&reestablish)), Ok(agreed));
+    }
+
+    #[test]
+    fn repeated_messages() {
+        let locked = Lifecycle::Locked {
+            by: Direction::Outbound,
+        };
+        assert_eq!(locked.next(sent(&funding_locked())), Ok(locked));
+        assert_eq!(
+            locked.next(received(&funding_locked())),
+            Ok(Lifecycle::Active)
+        );
+
+        let proposed = Lifecycle::Shutdown {
+            by: Direction::Inbound,
+            agreed: false,
+        };
+        assert_eq!(proposed.next(received(&shutdown())), Ok(proposed));
+        assert!(proposed.next(sent(&closing_signed())).is_err());
+        assert!(proposed.next(received(&closing_signed())).is_err());
+
+        let closing = Lifecycle::Closing {
+            round: 1,
+            by: Direction::Outbound,
+        };
+        assert_eq!(
+            closing.next(sent(&closing_signed())),
+            Err(LifecycleError::UnexpectedMessage {
+                state: closing,
+                direction: Direction::Outbound,
+                message: "closing_signed"
+            })
+        );
+        assert_eq!(
+            Lifecycle::Closing {
+                round: usize::MAX,
+                by: Direction::Outbound
+            }
+            .next(received(&closing_signed())),
+            Err(LifecycleError::ClosingRoundOverflow(usize::MAX))
+        );
+    }
+}
@@ -11,20 +11,37 @@
 // along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
+pub mod backup;
 pub mod channel;
+pub mod cpfp;
+pub mod fee;
+pub mod justice;
+pub mod keys;
+mod lifecycle;
+mod operation;
+pub mod reestablish;
+pub mod shachain;
+pub mod signer;
+pub mod sweep;
 mod types;
+pub mod watchtower;
 
 mod constructors;
 mod extenders;
 mod modifiers;
 
+pub use lifecycle::{ChainEvent, Direction, LifecycleError, LifecycleEvent};
+pub use operation::Operation;
 pub use types::{
     AddressList, Alias, AnnouncedNodeAddr, AssetsBalance, ChannelId,
     ExtensionId, Lifecycle, NodeColor, ShortChannelId, TempChannelId, TxType,
 };
 
-pub use constructors::{bolt3, eltoo, taproot, Bolt3};
+pub use constructors::{
+    bolt3, eltoo, multiparty, taproot, Bolt3, Bolt3Error, Eltoo, MultiParty,
+};
 pub use extenders::{
-    anchor_out, dlc, htlc, lightspeed, ptlc, shutdown_script, Htlc,
+    anchor_out, dlc, htlc, lightspeed, ptlc, shutdown_script, AnchorOut, Htlc,
+    HtlcError,
 };
-pub use modifiers::{bip96, rgb};
+pub use modifiers::{bip96, bolt3_ordering, rgb, Bolt3Ordering};
@@ -13,7 +13,7 @@
 
 use wallet::LexOrder;
 
-use crate::payment::ExtensionId;
+use crate::payment::{ExtensionId, Operation};
 use crate::{channel, ChannelExtension, Extension, Messages};
 
 pub struct Bip96;
@@ -35,15 +35,25 @@ impl Extension for Bip96 {
     }
 
     #[inline]
-    fn extension_state(&self) -> Box<dyn channel::State> {
-        Box::new(())
+    fn update_from_local(
+        &mut self,
+        _: &Operation,
+    ) -> Result<Vec<Messages>, channel::Error> {
+        // Output ordering does not require any interaction with the remote
+        // peer
+        Ok(vec![])
+    }
+
+    #[inline]
+    fn extension_state(&self) -> Vec<u8> {
+        channel::State::to_state_data(&())
     }
 }
 
 impl ChannelExtension for Bip96 {
     #[inline]
-    fn channel_state(&self) -> Box<dyn channel::State> {
-        Box::new(())
+    fn channel_state(&self) -> Vec<u8> {
+        channel::State::to_state_data(&())
     }
 
     #[inline]
@@ -0,0 +1,143 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::collections::BTreeMap;
+
+use bitcoin::TxOut;
+
+use crate::payment::{ExtensionId, Operation};
+use crate::{channel, ChannelExtension, Extension, Messages};
+
+/// Commitment transaction output ordering according to BOLT-3 "Transaction
+/// Output Ordering" section: lexicographic ordering as in BIP-69, with the
+/// outputs having the same amount and `scriptPubkey` (like HTLCs with the
+/// same payment hash) ordered by increasing `cltv_expiry`
+pub struct Bolt3Ordering;
+
+impl Bolt3Ordering {
+    /// Computes permutation map for the commitment outputs, where the value
+    /// at each index is the new index for the output at the same position in
+    /// the original ordering
+    pub fn permutation(
+        outputs: &[TxOut],
+        cltv_expiry: &BTreeMap<u32, u32>,
+    ) -> Vec<u32> {
+        let mut order = (0..outputs.len() as u32).collect::<Vec<_>>();
+        order.sort_by(|a, b| {
+            let (out_a, out_b) = (&outputs[*a as usize], &outputs[*b as usize]);
+            out_a
+                .value
+                .cmp(&out_b.value)
+                .then_with(|| {
+                    out_a
+                        .script_pubkey
+                        .as_bytes()
+                        .cmp(out_b.script_pubkey.as_bytes())
+                })
+                .then_with(|| cltv_expiry.get(a).cmp(&cltv_expiry.get(b)))
+        });
+
+        let mut permutation = vec![0u32; outputs.len()];
+        for (new, old) in order.into_iter().enumerate() {
+            permutation[old as usize] = new as u32;
+        }
+        permutation
+    }
+}
+
+impl Extension for Bolt3Ordering {
+    type Identity = ExtensionId;
+
+    #[inline]
+    fn identity(&self) -> Self::Identity {
+        ExtensionId::Bolt3Ordering
+    }
+
+    #[inline]
+    fn update_from_peer(&mut self, _: &Messages) -> Result<(), channel::Error> {
+        // Output ordering is stateless and does not depend on peer messages
+        Ok(())
+    }
+
+    #[inline]
+    fn update_from_local(
+        &mut self,
+        _: &Operation,
+    ) -> Result<Vec<Messages>, channel::Error> {
+        // Output ordering does not require any interaction with the remote
+        // peer
+        Ok(vec![])
+    }
+
+    #[inline]
+    fn extension_state(&self) -> Vec<u8> {
+        channel::State::to_state_data(&())
+    }
+}
+
+impl ChannelExtension for Bolt3Ordering {
+    #[inline]
+    fn channel_state(&self) -> Vec<u8> {
+        channel::State::to_state_data(&())
+    }
+
+    fn apply(
+        &mut self,
+        tx_graph: &mut channel::TxGraph,
+    ) -> Result<(), channel::Error> {
+        let permutation = Bolt3Ordering::permutation(
+            &tx_graph.cmt_outs,
+            &tx_graph.cmt_cltv_expiry,
+        );
+        tx_graph.permute_cmt_outs(&permutation);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::Script;
+
+    fn txout(value: u64, script: &[u8]) -> TxOut {
+        TxOut {
+            value,
+            script_pubkey: Script::from(script.to_vec()),
+        }
+    }
+
+    #[test]
+    fn permutation() {
+        let outputs = vec![
+            txout(5000, &[0x01]),
+            txout(2000, &[0x02]),
+            txout(5000, &[0x01]),
+            txout(2000, &[0x01]),
+            txout(5000, &[0x00]),
+        ];
+        let mut cltv_expiry = BTreeMap::new();
+        cltv_expiry.insert(0, 506);
+        cltv_expiry.insert(2, 505);
+        assert_eq!(
+            Bolt3Ordering::permutation(&outputs, &cltv_expiry),
+            vec![4, 1, 3, 0, 2]
+        );
+
+        // Without CLTV expiry the original ordering of the identical
+        // outputs is preserved
+        assert_eq!(
+            Bolt3Ordering::permutation(&outputs, &BTreeMap::new()),
+            vec![3, 1, 4, 0, 2]
+        );
+    }
+}
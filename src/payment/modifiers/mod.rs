@@ -12,4 +12,7 @@
 // If not, see <https://opensource.org/licenses/MIT>.
 
 pub mod bip96; // Lexicographic ordering
+pub mod bolt3_ordering; // BOLT-3 output ordering
 pub mod rgb; // RGB itself!
+
+pub use bolt3_ordering::Bolt3Ordering;
@@ -0,0 +1,95 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use bitcoin::hashes::sha256;
+use bitcoin::Script;
+use lnpbp::chain::AssetId;
+use wallet::{HashLock, HashPreimage};
+
+use crate::OnionPacket;
+
+/// Operations on the channel which may be initiated by the local node. Each
+/// of the channel extensions processes the operation, updates its state and
+/// returns messages which has to be sent to the remote peer.
+#[derive(Clone, PartialEq, Eq, Debug, Display)]
+#[display(doc_comments)]
+#[non_exhaustive]
+pub enum Operation {
+    /// offer a new HTLC for {amount_msat} msat locked with {payment_hash}
+    AddHtlc {
+        /// The HTLC value in milli-satoshi
+        amount_msat: u64,
+
+        /// The payment hash, the pre-image of which controls HTLC redemption
+        payment_hash: HashLock,
+
+        /// The expiry height of the HTLC
+        cltv_expiry: u32,
+
+        /// Onion packet for the next hop
+        onion_routing_packet: OnionPacket,
+
+        /// RGB Extension: asset transferred by the HTLC
+        asset_id: Option<AssetId>,
+    },
+
+    /// fulfill HTLC #{htlc_id} offered by the remote peer
+    FulfillHtlc {
+        /// The HTLC ID
+        htlc_id: u64,
+
+        /// The pre-image of the payment hash, allowing HTLC redemption
+        payment_preimage: HashPreimage,
+    },
+
+    /// fail HTLC #{htlc_id} offered by the remote peer
+    FailHtlc {
+        /// The HTLC ID
+        htlc_id: u64,
+
+        /// Encrypted failure reason for the HTLC originator
+        reason: Vec<u8>,
+    },
+
+    /// fail HTLC #{htlc_id} offered by the remote peer with unparsable onion
+    FailMalformedHtlc {
+        /// The HTLC ID
+        htlc_id: u64,
+
+        /// SHA256 hash of onion data
+        sha256_of_onion: sha256::Hash,
+
+        /// The failure code
+        failure_code: u16,
+    },
+
+    /// sign the next remote commitment transaction including all pending
+    /// updates
+    SignCommitment,
+
+    /// revoke the previous local commitment transaction once the next one is
+    /// signed by the remote peer
+    RevokeCommitment,
+
+    /// update commitment transaction fee rate to {feerate_per_kw} sat per kw
+    UpdateFee {
+        /// Fee rate per 1000-weight of the transaction
+        feerate_per_kw: u32,
+    },
+
+    /// initiate cooperative channel closing paying to {scriptpubkey}
+    Shutdown {
+        /// The destination of our funds on closing
+        scriptpubkey: Script,
+    },
+}
@@ -0,0 +1,591 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Channel reestablishment after reconnection: tracking of the commitment
+//! numbers and of the messages which may need retransmission, and
+//! processing of the `channel_reestablish` message with
+//! `option_data_loss_protect` checks (see BOLT-2 "Message Retransmission").
+
+use bitcoin::secp256k1::{PublicKey, SecretKey};
+
+use crate::message::{
+    ChannelReestablish, CommitmentSigned, FundingLocked, RevokeAndAck,
+    UpdateAddHtlc, UpdateFailHtlc, UpdateFailMalformedHtlc, UpdateFee,
+    UpdateFulfillHtlc,
+};
+use crate::payment::keys::SECP256K1;
+use crate::payment::shachain::ShachainStore;
+use crate::payment::signer::ChannelSigner;
+use crate::{ChannelId, Messages};
+
+/// Errors processing `channel_reestablish` message, requiring the channel to
+/// be failed
+#[derive(
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Debug,
+    Display,
+    Error,
+    StrictEncode,
+    StrictDecode,
+)]
+#[display(doc_comments)]
+pub enum ReestablishError {
+    /// `channel_reestablish` for channel {0} is received by the channel {1}
+    ChannelIdMismatch(ChannelId, ChannelId),
+
+    /// remote peer has provided wrong per-commitment secret for the local
+    /// commitment #{0}
+    InvalidSecret(u64),
+
+    /// remote peer expects revocation of the local commitment #{0}, while the
+    /// latest local commitment is #{1}
+    UnexpectedRevocationNumber(u64, u64),
+
+    /// remote peer expects signature for its commitment #{0}, while the
+    /// latest signed remote commitment is #{1}
+    UnexpectedCommitmentNumber(u64, u64),
+
+    /// signer has failed to provide the key for the local commitment #{0}
+    Signer(u64),
+}
+
+/// Channel update message sent to the remote peer, which has to be
+/// retransmitted if the following `commitment_signed` was not received
+#[derive(Clone, PartialEq, Eq, Debug, From, StrictEncode, StrictDecode)]
+pub enum PendingUpdate {
+    #[from]
+    AddHtlc(UpdateAddHtlc),
+
+    #[from]
+    FulfillHtlc(UpdateFulfillHtlc),
+
+    #[from]
+    FailHtlc(UpdateFailHtlc),
+
+    #[from]
+    FailMalformedHtlc(UpdateFailMalformedHtlc),
+
+    #[from]
+    Fee(UpdateFee),
+}
+
+impl PendingUpdate {
+    /// Extracts update from the message, if the message is a channel update
+    pub fn with(message: &Messages) -> Option<Self> {
+        Some(match message {
+            Messages::UpdateAddHtlc(msg) => msg.clone().into(),
+            Messages::UpdateFulfillHtlc(msg) => msg.clone().into(),
+            Messages::UpdateFailHtlc(msg) => msg.clone().into(),
+            Messages::UpdateFailMalformedHtlc(msg) => msg.clone().into(),
+            Messages::UpdateFee(msg) => msg.clone().into(),
+            _ => return None,
+        })
+    }
+}
+
+impl From<PendingUpdate> for Messages {
+    fn from(update: PendingUpdate) -> Self {
+        match update {
+            PendingUpdate::AddHtlc(msg) => Messages::UpdateAddHtlc(msg),
+            PendingUpdate::FulfillHtlc(msg) => Messages::UpdateFulfillHtlc(msg),
+            PendingUpdate::FailHtlc(msg) => Messages::UpdateFailHtlc(msg),
+            PendingUpdate::FailMalformedHtlc(msg) => {
+                Messages::UpdateFailMalformedHtlc(msg)
+            }
+            PendingUpdate::Fee(msg) => Messages::UpdateFee(msg),
+        }
+    }
+}
+
+/// Result of the `channel_reestablish` processing
+#[derive(Clone, Debug)]
+pub enum ReestablishAction {
+    /// Channel is synchronized once the messages (if any) are retransmitted
+    /// in the given order
+    Retransmit(Vec<Messages>),
+
+    /// Remote peer has proven that the local node has lost its channel state
+    /// (`option_data_loss_protect`). Local node must not publish its
+    /// commitment transaction and should wait for the remote peer to close
+    /// the channel, sweeping funds from its commitment with the provided
+    /// remote per-commitment point.
+    AwaitForceClose(PublicKey),
+}
+
+/// Commitment numbers and messages tracked for the channel reestablishment
+#[derive(Clone, PartialEq, Eq, Debug, Default, StrictEncode, StrictDecode)]
+pub struct ChannelSync {
+    /// Number of the latest local commitment signed by the remote peer
+    local_commitment_number: u64,
+
+    /// Number of the latest remote commitment signed by the local node
+    remote_commitment_number: u64,
+
+    /// Updates sent since the latest `commitment_signed`
+    uncommitted_updates: Vec<PendingUpdate>,
+
+    /// Updates covered by the latest `commitment_signed` which revocation
+    /// was not received yet, together with the `commitment_signed` message
+    unrevoked_commitment: Option<(Vec<PendingUpdate>, CommitmentSigned)>,
+
+    /// Whether `revoke_and_ack` was sent after the latest
+    /// `commitment_signed`
+    revocation_sent_last: bool,
+}
+
+impl ChannelSync {
+    /// Constructs tracker for the newly funded channel
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns number of the latest local commitment signed by the remote
+    /// peer
+    #[inline]
+    pub fn local_commitment_number(&self) -> u64 {
+        self.local_commitment_number
+    }
+
+    /// Returns number of the latest remote commitment signed by the local
+    /// node
+    #[inline]
+    pub fn remote_commitment_number(&self) -> u64 {
+        self.remote_commitment_number
+    }
+
+    /// Registers message sent to the remote peer
+    pub fn message_sent(&mut self, message: &Messages) {
+        if let Some(update) = PendingUpdate::with(message) {
+            self.uncommitted_updates.push(update);
+            return;
+        }
+        match message {
+            Messages::CommitmentSigned(commitment_signed) => {
+                self.unrevoked_commitment = Some((
+                    std::mem::take(&mut self.uncommitted_updates),
+                    commitment_signed.clone(),
+                ));
+                self.remote_commitment_number += 1;
+                self.revocation_sent_last = false;
+            }
+            Messages::RevokeAndAck(_) => self.revocation_sent_last = true,
+            _ => {}
+        }
+    }
+
+    /// Registers message received from the remote peer
+    pub fn message_received(&mut self, message: &Messages) {
+        match message {
+            Messages::CommitmentSigned(_) => self.local_commitment_number += 1,
+            Messages::RevokeAndAck(_) => self.unrevoked_commitment = None,
+            _ => {}
+        }
+    }
+
+    /// Number of the remote commitment which revocation is expected next
+    fn next_revocation_number(&self) -> u64 {
+        match self.unrevoked_commitment {
+            Some(_) => self.remote_commitment_number - 1,
+            None => self.remote_commitment_number,
+        }
+    }
+
+    /// Constructs `channel_reestablish` message which has to be sent to the
+    /// remote peer on reconnection
+    pub fn channel_reestablish(
+        &self,
+        channel_id: ChannelId,
+        signer: &dyn ChannelSigner,
+        remote_secrets: &ShachainStore,
+    ) -> Result<ChannelReestablish, ReestablishError> {
+        let next_revocation_number = self.next_revocation_number();
+        let your_last_per_commitment_secret = match next_revocation_number {
+            0 => [0u8; 32],
+            n => remote_secrets.commitment_secret(n - 1).unwrap_or([0u8; 32]),
+        };
+        Ok(ChannelReestablish {
+            channel_id,
+            next_commitment_number: self.local_commitment_number + 1,
+            next_revocation_number,
+            your_last_per_commitment_secret,
+            my_current_per_commitment_point: per_commitment_point(
+                signer,
+                self.local_commitment_number,
+            )?,
+        })
+    }
+
+    /// Processes `channel_reestablish` received from the remote peer,
+    /// returning messages which have to be retransmitted. Updates sent
+    /// without `commitment_signed` are forgotten, as required by BOLT-2.
+    pub fn reestablish(
+        &mut self,
+        message: &ChannelReestablish,
+        channel_id: ChannelId,
+        signer: &dyn ChannelSigner,
+    ) -> Result<ReestablishAction, ReestablishError> {
+        if message.channel_id != channel_id {
+            return Err(ReestablishError::ChannelIdMismatch(
+                message.channel_id,
+                channel_id,
+            ));
+        }
+        self.uncommitted_updates.clear();
+
+        // The secret is checked against the point, since the signer refuses
+        // to release secrets of the commitments which were not revoked
+        let revocation_number = message.next_revocation_number;
+        if revocation_number > 0 {
+            let revoked = revocation_number - 1;
+            let point = per_commitment_point(signer, revoked)?;
+            match SecretKey::from_slice(
+                &message.your_last_per_commitment_secret,
+            ) {
+                Ok(secret)
+                    if PublicKey::from_secret_key(&SECP256K1, &secret)
+                        == point => {}
+                _ => return Err(ReestablishError::InvalidSecret(revoked)),
+            }
+        }
+        // The peer knows our secret for the commitment which we have not
+        // revoked yet: we have lost our state
+        if revocation_number > self.local_commitment_number {
+            return Ok(ReestablishAction::AwaitForceClose(
+                message.my_current_per_commitment_point,
+            ));
+        }
+
+        let mut messages = vec![];
+        let commitment_number = message.next_commitment_number;
+        if commitment_number == 1 && self.local_commitment_number == 0 {
+            messages.push(Messages::FundingLocked(FundingLocked {
+                channel_id,
+                next_per_commitment_point: per_commitment_point(signer, 1)?,
+            }));
+        }
+
+        let revoke_and_ack =
+            if revocation_number == self.local_commitment_number {
+                None
+            } else if revocation_number + 1 == self.local_commitment_number {
+                let secret = signer
+                    .release_per_commitment_secret(revocation_number)
+                    .map_err(|_| ReestablishError::Signer(revocation_number))?;
+                Some(Messages::RevokeAndAck(RevokeAndAck {
+                    channel_id,
+                    per_commitment_secret: *secret.as_ref(),
+                    next_per_commitment_point: per_commitment_point(
+                        signer,
+                        self.local_commitment_number + 1,
+                    )?,
+                }))
+            } else {
+                return Err(ReestablishError::UnexpectedRevocationNumber(
+                    revocation_number,
+                    self.local_commitment_number,
+                ));
+            };
+
+        let commitment = match (commitment_number, &self.unrevoked_commitment) {
+            (n, _) if n == self.remote_commitment_number + 1 => vec![],
+            (n, Some((updates, commitment_signed)))
+                if n == self.remote_commitment_number =>
+            {
+                updates
+                    .iter()
+                    .cloned()
+                    .map(Messages::from)
+                    .chain(Some(Messages::CommitmentSigned(
+                        commitment_signed.clone(),
+                    )))
+                    .collect()
+            }
+            (n, _) => {
+                return Err(ReestablishError::UnexpectedCommitmentNumber(
+                    n,
+                    self.remote_commitment_number,
+                ))
+            }
+        };
+
+        // Messages must be retransmitted in the order they were sent
+        if self.revocation_sent_last {
+            messages.extend(commitment);
+            messages.extend(revoke_and_ack);
+        } else {
+            messages.extend(revoke_and_ack);
+            messages.extend(commitment);
+        }
+        Ok(ReestablishAction::Retransmit(messages))
+    }
+}
+
+fn per_commitment_point(
+    signer: &dyn ChannelSigner,
+    commitment_number: u64,
+) -> Result<PublicKey, ReestablishError> {
+    signer
+        .per_commitment_point(commitment_number)
+        .map_err(|_| ReestablishError::Signer(commitment_number))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::secp256k1::Signature;
+    use bitcoin::OutPoint;
+
+    use crate::payment::signer::InMemorySigner;
+
+    fn signers() -> (InMemorySigner, InMemorySigner) {
+        (
+            InMemorySigner::with_seed([1u8; 32]),
+            InMemorySigner::with_seed([2u8; 32]),
+        )
+    }
+
+    fn commitment_signed() -> Messages {
+        Messages::CommitmentSigned(CommitmentSigned {
+            channel_id: ChannelId::default(),
+            signature: Signature::from_compact(&[1u8; 64]).unwrap(),
+            htlc_signatures: vec![],
+        })
+    }
+
+    fn revoke_and_ack(signer: &InMemorySigner, revoked: u64) -> Messages {
+        Messages::RevokeAndAck(RevokeAndAck {
+            channel_id: ChannelId::default(),
+            per_commitment_secret: *signer
+                .release_per_commitment_secret(revoked)
+                .unwrap()
+                .as_ref(),
+            next_per_commitment_point: signer
+                .per_commitment_point(revoked + 2)
+                .unwrap(),
+        })
+    }
+
+    /// Receives `commitment_signed`, which makes the signed commitment the
+    /// current one for the signer as well
+    fn commitment_received(sync: &mut ChannelSync, signer: &InMemorySigner) {
+        sync.message_received(&commitment_signed());
+        signer
+            .advance_commitment(sync.local_commitment_number())
+            .unwrap();
+    }
+
+    fn update_fee() -> Messages {
+        Messages::UpdateFee(UpdateFee {
+            channel_id: ChannelId::default(),
+            feerate_per_kw: 1000,
+        })
+    }
+
+    /// Runs a full commitment round, where the local node offers updates
+    fn round(
+        local: &mut ChannelSync,
+        remote: &mut ChannelSync,
+        local_signer: &InMemorySigner,
+        remote_signer: &InMemorySigner,
+    ) {
+        let n = local.remote_commitment_number;
+        local.message_sent(&update_fee());
+        remote.message_received(&update_fee());
+        local.message_sent(&commitment_signed());
+        commitment_received(remote, remote_signer);
+        let revocation = revoke_and_ack(remote_signer, n);
+        remote.message_sent(&revocation);
+        local.message_received(&revocation);
+        remote.message_sent(&commitment_signed());
+        commitment_received(local, local_signer);
+        let revocation = revoke_and_ack(local_signer, n);
+        local.message_sent(&revocation);
+        remote.message_received(&revocation);
+    }
+
+    fn names(action: ReestablishAction) -> Vec<String> {
+        match action {
+            ReestablishAction::Retransmit(messages) => messages
+                .iter()
+                .map(|msg| {
+                    msg.to_string().split('(').next().unwrap().to_owned()
+                })
+                .collect(),
+            ReestablishAction::AwaitForceClose(_) => vec![s!("force_close")],
+        }
+    }
+
+    fn secrets(signer: &InMemorySigner, count: u64) -> ShachainStore {
+        let mut store = ShachainStore::new();
+        for n in 0..count {
+            store
+                .insert(
+                    store.next_index().unwrap(),
+                    *signer.release_per_commitment_secret(n).unwrap().as_ref(),
+                )
+                .unwrap();
+        }
+        store
+    }
+
+    #[test]
+    fn in_sync() {
+        let (local_signer, remote_signer) = signers();
+        let (mut local, mut remote) = (ChannelSync::new(), ChannelSync::new());
+        let id = ChannelId::default();
+
+        // Freshly funded channel retransmits `funding_locked`
+        let msg = remote
+            .channel_reestablish(id, &remote_signer, &none!())
+            .unwrap();
+        assert_eq!(
+            names(local.reestablish(&msg, id, &local_signer).unwrap()),
+            vec![s!("funding_locked")]
+        );
+
+        round(&mut local, &mut remote, &local_signer, &remote_signer);
+        round(&mut local, &mut remote, &local_signer, &remote_signer);
+        assert_eq!(local.local_commitment_number(), 2);
+        assert_eq!(local.remote_commitment_number(), 2);
+
+        let msg = remote
+            .channel_reestablish(id, &remote_signer, &secrets(&local_signer, 2))
+            .unwrap();
+        assert_eq!(msg.next_commitment_number, 3);
+        assert_eq!(msg.next_revocation_number, 2);
+        assert!(names(local.reestablish(&msg, id, &local_signer).unwrap())
+            .is_empty());
+
+        // Uncommitted updates are forgotten
+        local.message_sent(&update_fee());
+        let msg = local
+            .channel_reestablish(id, &local_signer, &secrets(&remote_signer, 2))
+            .unwrap();
+        assert!(names(remote.reestablish(&msg, id, &remote_signer).unwrap())
+            .is_empty());
+        assert_eq!(local.uncommitted_updates.len(), 1);
+        let msg = remote
+            .channel_reestablish(id, &remote_signer, &secrets(&local_signer, 2))
+            .unwrap();
+        local.reestablish(&msg, id, &local_signer).unwrap();
+        assert!(local.uncommitted_updates.is_empty());
+    }
+
+    #[test]
+    fn retransmission() {
+        let (local_signer, remote_signer) = signers();
+        let (mut local, mut remote) = (ChannelSync::new(), ChannelSync::new());
+        let id = ChannelId::default();
+        round(&mut local, &mut remote, &local_signer, &remote_signer);
+
+        // `commitment_signed` with updates is lost
+        local.message_sent(&update_fee());
+        local.message_sent(&commitment_signed());
+        let msg = remote
+            .channel_reestablish(id, &remote_signer, &secrets(&local_signer, 1))
+            .unwrap();
+        assert_eq!(
+            names(local.reestablish(&msg, id, &local_signer).unwrap()),
+            vec![s!("update_fee"), s!("commitment_signed")]
+        );
+
+        // Remote peer has received the commitment, but `revoke_and_ack` is
+        // lost
+        remote.message_received(&update_fee());
+        commitment_received(&mut remote, &remote_signer);
+        remote.message_sent(&revoke_and_ack(&remote_signer, 1));
+        let msg = local
+            .channel_reestablish(id, &local_signer, &secrets(&remote_signer, 1))
+            .unwrap();
+        assert_eq!(msg.next_revocation_number, 1);
+        assert_eq!(
+            names(remote.reestablish(&msg, id, &remote_signer).unwrap()),
+            vec![s!("revoke_and_ack")]
+        );
+        // Signer does not release secret of the current commitment
+        let stale_signer = InMemorySigner::with_seed([2u8; 32]);
+        stale_signer.advance_commitment(1).unwrap();
+        assert_eq!(
+            remote.reestablish(&msg, id, &stale_signer).unwrap_err(),
+            ReestablishError::Signer(1)
+        );
+
+        // Remote peer has sent `revoke_and_ack` followed by
+        // `commitment_signed`, which both are lost
+        remote.message_sent(&commitment_signed());
+        assert_eq!(
+            names(remote.reestablish(&msg, id, &remote_signer).unwrap()),
+            vec![s!("revoke_and_ack"), s!("commitment_signed")]
+        );
+    }
+
+    #[test]
+    fn data_loss() {
+        let (local_signer, remote_signer) = signers();
+        let (mut local, mut remote) = (ChannelSync::new(), ChannelSync::new());
+        let id = ChannelId::default();
+        let stale = local.clone();
+        round(&mut local, &mut remote, &local_signer, &remote_signer);
+        round(&mut local, &mut remote, &local_signer, &remote_signer);
+
+        // Local node restored from the stale backup
+        let mut local = stale;
+        let msg = remote
+            .channel_reestablish(id, &remote_signer, &secrets(&local_signer, 2))
+            .unwrap();
+        match local.reestablish(&msg, id, &local_signer).unwrap() {
+            ReestablishAction::AwaitForceClose(point) => {
+                assert_eq!(
+                    point,
+                    remote_signer.per_commitment_point(2).unwrap()
+                )
+            }
+            _ => panic!("data loss is not detected"),
+        }
+
+        // Remote peer can't prove its claims
+        let mut msg = msg;
+        msg.your_last_per_commitment_secret = [0u8; 32];
+        assert_eq!(
+            local.reestablish(&msg, id, &local_signer).unwrap_err(),
+            ReestablishError::InvalidSecret(1)
+        );
+
+        // Remote peer has lost its state
+        let msg = ChannelSync::new()
+            .channel_reestablish(id, &remote_signer, &none!())
+            .unwrap();
+        let mut local = ChannelSync::new();
+        let local_signer = InMemorySigner::with_seed([1u8; 32]);
+        round(&mut local, &mut remote, &local_signer, &remote_signer);
+        round(&mut local, &mut remote, &local_signer, &remote_signer);
+        assert_eq!(
+            local.reestablish(&msg, id, &local_signer).unwrap_err(),
+            ReestablishError::UnexpectedRevocationNumber(0, 2)
+        );
+
+        let mut msg = msg;
+        msg.channel_id = ChannelId::with(OutPoint::new(none!(), 1));
+        assert_eq!(
+            local.reestablish(&msg, id, &local_signer).unwrap_err(),
+            ReestablishError::ChannelIdMismatch(msg.channel_id, id)
+        );
+    }
+}
@@ -0,0 +1,447 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Generation and compact storage of per-commitment secrets according to
+//! BOLT-3 "Efficient Per-commitment Secret Storage" section.
+
+use std::collections::BTreeMap;
+
+use bitcoin::hashes::{sha256, Hash};
+
+/// Maximal index of the per-commitment secret; the first commitment uses
+/// secret with this index and each next commitment decrements it
+pub const SHACHAIN_MAX_INDEX: u64 = (1 << 48) - 1;
+
+/// Number of bits in the per-commitment secret index
+pub const SHACHAIN_INDEX_BITS: u8 = 48;
+
+/// Generates per-commitment secret with a given index from the seed
+#[inline]
+pub fn generate_from_seed(seed: [u8; 32], index: u64) -> [u8; 32] {
+    derive_secret(seed, SHACHAIN_INDEX_BITS, index)
+}
+
+/// Derives secret with a given index from the `base` secret, which index
+/// has all lower `bits` bits set to zero, flipping and hashing each of the
+/// `bits` lower bits of the index
+pub(crate) fn derive_secret(base: [u8; 32], bits: u8, index: u64) -> [u8; 32] {
+    let mut secret = base;
+    for bit in (0..bits).rev() {
+        if index >> bit & 1 == 1 {
+            secret[bit as usize / 8] ^= 1 << (bit % 8);
+            secret = sha256::Hash::hash(&secret).into_inner();
+        }
+    }
+    secret
+}
+
+/// Converts commitment number into the per-commitment secret index
+#[inline]
+pub fn commitment_index(commitment_number: u64) -> u64 {
+    SHACHAIN_MAX_INDEX - commitment_number
+}
+
+/// Errors storing and deriving per-commitment secrets
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum Error {
+    /// per-commitment secret index {0} exceeds 48 bits
+    IndexOverflow(u64),
+
+    /// per-commitment secret #{0} is received out of order; secrets must be
+    /// received one by one in order of descending indexes
+    OutOfOrder(u64),
+
+    /// per-commitment secret #{0} does not allow to derive the previously
+    /// received secret #{1}
+    Inconsistent(u64, u64),
+
+    /// per-commitment secret #{0} can't be derived from the known secrets
+    Unknown(u64),
+}
+
+/// Secret stored in [`ShachainStore`] together with its index
+#[derive(
+    Copy,
+    Clone,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Debug,
+    StrictEncode,
+    StrictDecode,
+)]
+pub struct ShachainEntry {
+    pub index: u64,
+    pub secret: [u8; 32],
+}
+
+/// Compact storage for per-commitment secrets received from the remote
+/// peer. Keeps at most 49 secrets, from which all previously received
+/// secrets can be derived.
+///
+/// Secrets must be inserted in order of descending indexes, starting from
+/// [`SHACHAIN_MAX_INDEX`], as they are revealed by `revoke_and_ack`
+/// messages.
+#[derive(
+    Clone,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Debug,
+    Default,
+    StrictEncode,
+    StrictDecode,
+)]
+pub struct ShachainStore {
+    known: BTreeMap<u8, ShachainEntry>,
+    last_index: Option<u64>,
+}
+
+impl ShachainStore {
+    /// Constructs empty storage
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns index of the next secret which must be inserted, or `None` if
+    /// all secrets were already received
+    #[inline]
+    pub fn next_index(&self) -> Option<u64> {
+        match self.last_index {
+            None => Some(SHACHAIN_MAX_INDEX),
+            Some(index) => index.checked_sub(1),
+        }
+    }
+
+    /// Returns number of the stored secrets, which is never above 49
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.known.len()
+    }
+
+    /// Detects whether any secrets were inserted into the storage
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.known.is_empty()
+    }
+
+    /// Inserts new secret with the given index, checking that all
+    /// previously received secrets can be derived from it
+    pub fn insert(
+        &mut self,
+        index: u64,
+        secret: [u8; 32],
+    ) -> Result<(), Error> {
+        if index > SHACHAIN_MAX_INDEX {
+            return Err(Error::IndexOverflow(index));
+        }
+        if self.next_index() != Some(index) {
+            return Err(Error::OutOfOrder(index));
+        }
+
+        let position = Self::position(index);
+        for entry in self.known.range(..position).map(|(_, entry)| entry) {
+            if derive_secret(secret, position, entry.index) != entry.secret {
+                return Err(Error::Inconsistent(index, entry.index));
+            }
+        }
+
+        self.known.insert(position, ShachainEntry { index, secret });
+        self.last_index = Some(index);
+        Ok(())
+    }
+
+    /// Returns previously received secret with a given index
+    pub fn secret(&self, index: u64) -> Result<[u8; 32], Error> {
+        if index > SHACHAIN_MAX_INDEX {
+            return Err(Error::IndexOverflow(index));
+        }
+        self.known
+            .iter()
+            .find(|(position, entry)| {
+                index & !((1u64 << **position) - 1) == entry.index
+            })
+            .map(|(position, entry)| {
+                derive_secret(entry.secret, *position, index)
+            })
+            .ok_or(Error::Unknown(index))
+    }
+
+    /// Returns previously received secret for a given commitment number
+    #[inline]
+    pub fn commitment_secret(
+        &self,
+        commitment_number: u64,
+    ) -> Result<[u8; 32], Error> {
+        if commitment_number > SHACHAIN_MAX_INDEX {
+            return Err(Error::IndexOverflow(commitment_number));
+        }
+        self.secret(commitment_index(commitment_number))
+    }
+
+    /// Position in the storage for the secret with a given index, which is
+    /// equal to the number of trailing zeros in the index
+    fn position(index: u64) -> u8 {
+        (index.trailing_zeros() as u8).min(SHACHAIN_INDEX_BITS)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::hashes::hex::FromHex;
+    use strict_encoding::{strict_deserialize, strict_serialize};
+
+    fn secret(hex: &str) -> [u8; 32] {
+        let mut secret = [0u8; 32];
+        secret.copy_from_slice(&Vec::<u8>::from_hex(hex).unwrap());
+        secret
+    }
+
+    #[test]
+    fn bolt3_generation_vectors() {
+        // generate_from_seed 0 final node
+        assert_eq!(
+            generate_from_seed([0u8; 32], SHACHAIN_MAX_INDEX),
+            secret(
+                "02a40c85b6f28da08dfdbe0926c53fab2de6d28c10301f8f7c4073d5e42e3148"
+            )
+        );
+        // generate_from_seed FF final node
+        assert_eq!(
+            generate_from_seed([0xFF; 32], SHACHAIN_MAX_INDEX),
+            secret(
+                "7cc854b54e3e0dcdb010d7a3fee464a9687be6e8db3be6854c475621e007a5dc"
+            )
+        );
+        // generate_from_seed FF alternate bits 1
+        assert_eq!(
+            generate_from_seed([0xFF; 32], 0xaaaaaaaaaaa),
+            secret(
+                "56f4008fb007ca9acf0e15b054d5c9fd12ee06cea347914ddbaed70d1c13a528"
+            )
+        );
+        // generate_from_seed FF alternate bits 2
+        assert_eq!(
+            generate_from_seed([0xFF; 32], 0x555555555555),
+            secret(
+                "9015daaeb06dba4ccc05b91b2f73bd54405f2be9f217fbacd3c5ac2e62327d31"
+            )
+        );
+        // generate_from_seed 01 last nontrivial node
+        assert_eq!(
+            generate_from_seed([0x01; 32], 1),
+            secret(
+                "915c75942a26bb3a433a8ce2cb0427c29ec6c1775cfc78328b57f6ba7bfeaa9c"
+            )
+        );
+    }
+
+    const CORRECT_SEQUENCE: [&str; 8] = [
+        "7cc854b54e3e0dcdb010d7a3fee464a9687be6e8db3be6854c475621e007a5dc",
+        "c7518c8ae4660ed02894df8976fa1a3659c1a8b4b5bec0c4b872abeba4cb8964",
+        "2273e227a5b7449b6e70f1fb4652864038b1cbf9cd7c043a7d6456b7fc275ad8",
+        "27cddaa5624534cb6cb9d7da077cf2b22ab21e9b506fd4998a51d54502e99116",
+        "c65716add7aa98ba7acb236352d665cab17345fe45b55fb879ff80e6bd0c41dd",
+        "969660042a28f32d9be17344e09374b379962d03db1574df5a8a5a47e19ce3f2",
+        "a5a64476122ca0925fb344bdc1854c1c0a59fc614298e50a33e331980a220f32",
+        "05cde6323d949933f7f7b78776bcc1ea6d9b31447732e3802e1f7ac44b650e17",
+    ];
+
+    // Secrets generated from the all-zero seed, which are inconsistent with
+    // the secrets of the correct sequence
+    const INCORRECT_SEQUENCE: [&str; 8] = [
+        "02a40c85b6f28da08dfdbe0926c53fab2de6d28c10301f8f7c4073d5e42e3148",
+        "dddc3a8d14fddf2b68fa8c7fbad2748274937479dd0f8930d5ebb4ab6bd866a3",
+        "c51a18b13e8527e579ec56365482c62f180b7d5760b46e9477dae59e87ed423a",
+        "ba65d7b0ef55a3ba300d4e87af29868f394f8f138d78a7011669c79b37b936f4",
+        "631373ad5f9ef654bb3dade742d09504c567edd24320d2fcd68e3cc47e2ff6a6",
+        "b7e76a83668bde38b373970155c868a653304308f9896692f904a23731224bb1",
+        "e7971de736e01da8ed58b94c2fc216cb1dca9e326f3a96e7194fe8ea8af6c0a3",
+        "a7efbc61aac46d34f77778bac22c8a20c6a46ca460addc49009bda875ec88fa4",
+    ];
+
+    /// Inserts secrets from the correct sequence, replacing secrets at the
+    /// given positions with the secrets from the incorrect one, and returns
+    /// the result of the last insert operation
+    fn insert_sequence(incorrect: &[usize]) -> Result<(), Error> {
+        let mut store = ShachainStore::new();
+        for no in 0..CORRECT_SEQUENCE.len() {
+            let hex = if incorrect.contains(&no) {
+                INCORRECT_SEQUENCE[no]
+            } else {
+                CORRECT_SEQUENCE[no]
+            };
+            store.insert(SHACHAIN_MAX_INDEX - no as u64, secret(hex))?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn bolt3_storage_correct_sequence() {
+        let mut store = ShachainStore::new();
+        for (no, hex) in CORRECT_SEQUENCE.iter().enumerate() {
+            let index = SHACHAIN_MAX_INDEX - no as u64;
+            assert_eq!(store.next_index(), Some(index));
+            store.insert(index, secret(hex)).unwrap();
+            for (prev_no, prev_hex) in
+                CORRECT_SEQUENCE[..=no].iter().enumerate()
+            {
+                assert_eq!(
+                    store.commitment_secret(prev_no as u64),
+                    Ok(secret(prev_hex))
+                );
+            }
+        }
+        assert_eq!(store.len(), 4);
+    }
+
+    #[test]
+    fn bolt3_storage_incorrect_sequences() {
+        let max = SHACHAIN_MAX_INDEX;
+        // insert_secret #1 incorrect
+        assert_eq!(
+            insert_sequence(&[0]),
+            Err(Error::Inconsistent(max - 1, max))
+        );
+        // insert_secret #2 incorrect (#1 derived from incorrect)
+        assert_eq!(
+            insert_sequence(&[0, 1]),
+            Err(Error::Inconsistent(max - 3, max - 1))
+        );
+        // insert_secret #3 incorrect
+        assert_eq!(
+            insert_sequence(&[2]),
+            Err(Error::Inconsistent(max - 3, max - 2))
+        );
+        // insert_secret #4 incorrect (1,2,3 derived from incorrect)
+        assert_eq!(
+            insert_sequence(&[0, 1, 2, 3]),
+            Err(Error::Inconsistent(max - 7, max - 3))
+        );
+        // insert_secret #5 incorrect
+        assert_eq!(
+            insert_sequence(&[4]),
+            Err(Error::Inconsistent(max - 5, max - 4))
+        );
+        // insert_secret #6 incorrect (5 derived from incorrect)
+        assert_eq!(
+            insert_sequence(&[4, 5]),
+            Err(Error::Inconsistent(max - 7, max - 5))
+        );
+        // insert_secret #7 incorrect
+        assert_eq!(
+            insert_sequence(&[6]),
+            Err(Error::Inconsistent(max - 7, max - 6))
+        );
+        // insert_secret #8 incorrect
+        assert_eq!(
+            insert_sequence(&[7]),
+            Err(Error::Inconsistent(max - 7, max - 6))
+        );
+    }
+
+    #[test]
+    fn storage_order() {
+        let mut store = ShachainStore::new();
+        assert_eq!(
+            store.insert(SHACHAIN_MAX_INDEX - 1, [0u8; 32]),
+            Err(Error::OutOfOrder(SHACHAIN_MAX_INDEX - 1))
+        );
+        assert_eq!(
+            store.insert(SHACHAIN_MAX_INDEX + 1, [0u8; 32]),
+            Err(Error::IndexOverflow(SHACHAIN_MAX_INDEX + 1))
+        );
+        assert_eq!(
+            store.secret(SHACHAIN_MAX_INDEX),
+            Err(Error::Unknown(SHACHAIN_MAX_INDEX))
+        );
+
+        store
+            .insert(SHACHAIN_MAX_INDEX, secret(CORRECT_SEQUENCE[0]))
+            .unwrap();
+        assert_eq!(
+            store.insert(SHACHAIN_MAX_INDEX, secret(CORRECT_SEQUENCE[0])),
+            Err(Error::OutOfOrder(SHACHAIN_MAX_INDEX))
+        );
+        assert_eq!(
+            store.secret(SHACHAIN_MAX_INDEX - 1),
+            Err(Error::Unknown(SHACHAIN_MAX_INDEX - 1))
+        );
+    }
+
+    #[test]
+    fn storage_capacity() {
+        let seed = [0xFF; 32];
+        let mut store = ShachainStore::new();
+        for commitment_number in 0..1000 {
+            store
+                .insert(
+                    commitment_index(commitment_number),
+                    generate_from_seed(
+                        seed,
+                        commitment_index(commitment_number),
+                    ),
+                )
+                .unwrap();
+            assert!(store.len() <= 49);
+        }
+        for commitment_number in (0..1000).step_by(37) {
+            assert_eq!(
+                store.commitment_secret(commitment_number),
+                Ok(generate_from_seed(
+                    seed,
+                    commitment_index(commitment_number)
+                ))
+            );
+        }
+        assert_eq!(
+            store.commitment_secret(1000),
+            Err(Error::Unknown(commitment_index(1000)))
+        );
+    }
+
+    #[test]
+    fn storage_encoding() {
+        let mut store = ShachainStore::new();
+        for (no, hex) in CORRECT_SEQUENCE.iter().enumerate() {
+            store
+                .insert(SHACHAIN_MAX_INDEX - no as u64, secret(hex))
+                .unwrap();
+        }
+        let data = strict_serialize(&store).unwrap();
+        let decoded: ShachainStore = strict_deserialize(&data).unwrap();
+        assert_eq!(decoded, store);
+        assert_eq!(decoded.next_index(), Some(SHACHAIN_MAX_INDEX - 8));
+        assert_eq!(
+            decoded.commitment_secret(5),
+            Ok(secret(CORRECT_SEQUENCE[5]))
+        );
+    }
+
+    #[test]
+    fn commitment_indexes() {
+        assert_eq!(commitment_index(0), SHACHAIN_MAX_INDEX);
+        assert_eq!(commitment_index(SHACHAIN_MAX_INDEX), 0);
+        assert_eq!(
+            generate_from_seed([0xFF; 32], commitment_index(0)),
+            generate_from_seed([0xFF; 32], SHACHAIN_MAX_INDEX)
+        );
+    }
+}
@@ -0,0 +1,873 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Abstraction over the keys of the local node used in the channel. Channel
+//! extensions operate on public keys only, while all signatures and secrets
+//! are produced by the [`ChannelSigner`], which may keep the keys in memory
+//! ([`InMemorySigner`]) or in a separate process or hardware device
+//! ([`RemoteSigner`] serving [`SignerRequest`]s).
+
+use std::cell::{Cell, RefCell};
+use std::fmt::{self, Debug, Formatter};
+
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::{PublicKey, SecretKey, Signature};
+use bitcoin::util::bip143::SigHashCache;
+use bitcoin::util::psbt::PartiallySignedTransaction as Psbt;
+use bitcoin::{PubkeyHash, Script, SigHash, SigHashType};
+use strict_encoding::{strict_deserialize, strict_serialize};
+use wallet::WitnessScript;
+
+use crate::channel::{self, sign_sighash, TxGraph};
+use crate::payment::bolt3::ScriptGenerators;
+use crate::payment::keys::{
+    derive_privkey, derive_revocation_privkey, Basepoints, LocalKeyset,
+    SECP256K1,
+};
+
+/// Errors produced by channel signers
+#[derive(
+    Clone,
+    PartialEq,
+    Eq,
+    Hash,
+    Debug,
+    Display,
+    Error,
+    From,
+    StrictEncode,
+    StrictDecode,
+)]
+#[display(doc_comments)]
+pub enum SignerError {
+    /// transaction input #{0} can't be signed since it does not provide the
+    /// spent output or its script
+    InputData(usize),
+
+    /// signer has refused the request: {0}
+    Refused(String),
+
+    /// channel transactions can't be signed: {0}
+    #[from]
+    Channel(channel::Error),
+
+    /// connection to the remote signer has failed: {0}
+    Connection(String),
+
+    /// remote signer message can't be decoded: {0}
+    Encoding(String),
+
+    /// remote signer has responded with a message not matching the request
+    UnexpectedResponse,
+
+    /// per-commitment secret #{0} can't be released, since the commitment
+    /// is not revoked (the current local commitment is #{1})
+    UnrevokedCommitment(u64, u64),
+
+    /// local commitment #{0} can't replace the current commitment #{1}
+    CommitmentRollback(u64, u64),
+}
+
+impl From<strict_encoding::Error> for SignerError {
+    fn from(err: strict_encoding::Error) -> Self {
+        SignerError::Encoding(err.to_string())
+    }
+}
+
+/// Signer holding private keys of the local node for a single channel
+pub trait ChannelSigner {
+    /// Returns channel basepoints of the local node
+    fn basepoints(&self) -> Result<Basepoints, SignerError>;
+
+    /// Returns public key used in the funding output
+    fn funding_pubkey(&self) -> Result<PublicKey, SignerError>;
+
+    /// Returns public key of the P2WPKH wallet outputs spent by the funding
+    /// transaction
+    fn wallet_pubkey(&self) -> Result<PublicKey, SignerError>;
+
+    /// Returns per-commitment point for the commitment with a given number
+    fn per_commitment_point(
+        &self,
+        commitment_number: u64,
+    ) -> Result<PublicKey, SignerError>;
+
+    /// Notifies the signer that the local commitment with a given number was
+    /// signed by the remote peer and became the current one, allowing to
+    /// revoke all previous commitments
+    fn advance_commitment(
+        &self,
+        commitment_number: u64,
+    ) -> Result<(), SignerError>;
+
+    /// Releases per-commitment secret revoking the commitment with a given
+    /// number. Fails for the current and future local commitments, which
+    /// must never be revoked.
+    fn release_per_commitment_secret(
+        &self,
+        commitment_number: u64,
+    ) -> Result<SecretKey, SignerError>;
+
+    /// Signs funding transaction input spending wallet output
+    fn sign_funding(
+        &self,
+        psbt: &Psbt,
+        input_index: usize,
+    ) -> Result<Signature, SignerError>;
+
+    /// Signs commitment transaction of the remote peer and its second-stage
+    /// HTLC transactions, producing signatures for `commitment_signed`
+    fn sign_commitment(
+        &self,
+        tx_graph: &TxGraph,
+        remote_funding_pubkey: PublicKey,
+        funding_amount: u64,
+    ) -> Result<(Signature, Vec<Signature>), SignerError>;
+
+    /// Signs second-stage HTLC transaction of the local commitment with the
+    /// given per-commitment point
+    fn sign_htlc(
+        &self,
+        psbt: &Psbt,
+        per_commitment_point: PublicKey,
+    ) -> Result<Signature, SignerError>;
+
+    /// Signs all inputs of the justice transaction with the revocation key
+    /// derived from the per-commitment secret revealed by the remote peer
+    fn sign_justice(
+        &self,
+        psbt: &Psbt,
+        per_commitment_secret: SecretKey,
+    ) -> Result<Vec<Signature>, SignerError>;
+
+    /// Signs closing transaction spending the funding output
+    fn sign_closing(
+        &self,
+        psbt: &Psbt,
+        remote_funding_pubkey: PublicKey,
+        funding_amount: u64,
+    ) -> Result<Signature, SignerError>;
+}
+
+/// Signer keeping all channel keys in memory, deriving them from a seed
+#[derive(Clone, PartialEq, Eq)]
+pub struct InMemorySigner {
+    funding_key: SecretKey,
+    wallet_key: SecretKey,
+    revocation_basepoint_secret: SecretKey,
+    htlc_basepoint_secret: SecretKey,
+    keyset: LocalKeyset,
+    commitment_number: Cell<u64>,
+}
+
+// Debug output must not leak the private keys and the per-commitment seed
+impl Debug for InMemorySigner {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InMemorySigner")
+            .field("basepoints", &self.keyset.basepoints)
+            .field("commitment_number", &self.commitment_number.get())
+            .finish()
+    }
+}
+
+impl InMemorySigner {
+    /// Derives all channel keys from the seed, hashing it with a tag unique
+    /// for each of the keys
+    pub fn with_seed(seed: [u8; 32]) -> Self {
+        let derive = |tag: &str| {
+            let mut engine = sha256::Hash::engine();
+            engine.input(&seed);
+            engine.input(tag.as_bytes());
+            sha256::Hash::from_engine(engine).into_inner()
+        };
+        let secret = |tag: &str| {
+            SecretKey::from_slice(&derive(tag)).expect(
+                "negligible probability of hash value exceeding curve order",
+            )
+        };
+        let pubkey =
+            |secret: &SecretKey| PublicKey::from_secret_key(&SECP256K1, secret);
+
+        let revocation_basepoint_secret = secret("revocation_basepoint");
+        let htlc_basepoint_secret = secret("htlc_basepoint");
+        InMemorySigner {
+            funding_key: secret("funding"),
+            wallet_key: secret("wallet"),
+            keyset: LocalKeyset {
+                basepoints: Basepoints {
+                    revocation_basepoint: pubkey(&revocation_basepoint_secret),
+                    payment_basepoint: pubkey(&secret("payment_basepoint")),
+                    delayed_payment_basepoint: pubkey(&secret(
+                        "delayed_payment_basepoint",
+                    )),
+                    htlc_basepoint: pubkey(&htlc_basepoint_secret),
+                },
+                per_commitment_seed: derive("per_commitment_seed"),
+            },
+            revocation_basepoint_secret,
+            htlc_basepoint_secret,
+            commitment_number: Cell::new(0),
+        }
+    }
+
+    fn funding_script(
+        &self,
+        remote_funding_pubkey: PublicKey,
+        funding_amount: u64,
+    ) -> WitnessScript {
+        WitnessScript::ln_funding(
+            funding_amount,
+            PublicKey::from_secret_key(&SECP256K1, &self.funding_key),
+            remote_funding_pubkey,
+        )
+    }
+}
+
+impl ChannelSigner for InMemorySigner {
+    fn basepoints(&self) -> Result<Basepoints, SignerError> {
+        Ok(self.keyset.basepoints)
+    }
+
+    fn funding_pubkey(&self) -> Result<PublicKey, SignerError> {
+        Ok(PublicKey::from_secret_key(&SECP256K1, &self.funding_key))
+    }
+
+    fn wallet_pubkey(&self) -> Result<PublicKey, SignerError> {
+        Ok(PublicKey::from_secret_key(&SECP256K1, &self.wallet_key))
+    }
+
+    fn per_commitment_point(
+        &self,
+        commitment_number: u64,
+    ) -> Result<PublicKey, SignerError> {
+        Ok(self.keyset.per_commitment_point(commitment_number))
+    }
+
+    fn advance_commitment(
+        &self,
+        commitment_number: u64,
+    ) -> Result<(), SignerError> {
+        let current = self.commitment_number.get();
+        if commitment_number < current {
+            return Err(SignerError::CommitmentRollback(
+                commitment_number,
+                current,
+            ));
+        }
+        self.commitment_number.set(commitment_number);
+        Ok(())
+    }
+
+    fn release_per_commitment_secret(
+        &self,
+        commitment_number: u64,
+    ) -> Result<SecretKey, SignerError> {
+        let current = self.commitment_number.get();
+        if commitment_number >= current {
+            return Err(SignerError::UnrevokedCommitment(
+                commitment_number,
+                current,
+            ));
+        }
+        Ok(self.keyset.per_commitment_secret(commitment_number))
+    }
+
+    fn sign_funding(
+        &self,
+        psbt: &Psbt,
+        input_index: usize,
+    ) -> Result<Signature, SignerError> {
+        let sighash = input_sighash(psbt, input_index, None, SigHashType::All)?;
+        Ok(sign_sighash(sighash, &self.wallet_key))
+    }
+
+    fn sign_commitment(
+        &self,
+        tx_graph: &TxGraph,
+        remote_funding_pubkey: PublicKey,
+        funding_amount: u64,
+    ) -> Result<(Signature, Vec<Signature>), SignerError> {
+        let htlc_key = derive_privkey(
+            self.htlc_basepoint_secret,
+            tx_graph.cmt_per_commitment_point,
+        );
+        Ok(tx_graph.sign(
+            &self.funding_script(remote_funding_pubkey, funding_amount),
+            funding_amount,
+            &self.funding_key,
+            &htlc_key,
+        )?)
+    }
+
+    fn sign_htlc(
+        &self,
+        psbt: &Psbt,
+        per_commitment_point: PublicKey,
+    ) -> Result<Signature, SignerError> {
+        // Signature of the commitment owner always commits to all inputs
+        // and outputs
+        let sighash = input_sighash(psbt, 0, None, SigHashType::All)?;
+        let htlc_key =
+            derive_privkey(self.htlc_basepoint_secret, per_commitment_point);
+        Ok(sign_sighash(sighash, &htlc_key))
+    }
+
+    fn sign_justice(
+        &self,
+        psbt: &Psbt,
+        per_commitment_secret: SecretKey,
+    ) -> Result<Vec<Signature>, SignerError> {
+        let revocation_key = derive_revocation_privkey(
+            self.revocation_basepoint_secret,
+            per_commitment_secret,
+        );
+        (0..psbt.inputs.len())
+            .map(|index| {
+                input_sighash(psbt, index, None, SigHashType::All)
+                    .map(|sighash| sign_sighash(sighash, &revocation_key))
+            })
+            .collect()
+    }
+
+    fn sign_closing(
+        &self,
+        psbt: &Psbt,
+        remote_funding_pubkey: PublicKey,
+        funding_amount: u64,
+    ) -> Result<Signature, SignerError> {
+        let funding_script =
+            self.funding_script(remote_funding_pubkey, funding_amount);
+        let sighash = input_sighash(
+            psbt,
+            0,
+            Some((funding_script.into(), funding_amount)),
+            SigHashType::All,
+        )?;
+        Ok(sign_sighash(sighash, &self.funding_key))
+    }
+}
+
+/// Computes BIP-143 signature hash for the PSBT input. Unless script code
+/// and the spent amount are provided, they are taken from the PSBT input
+/// witness script (or P2WPKH spent output) and witness UTXO.
+fn input_sighash(
+    psbt: &Psbt,
+    index: usize,
+    script_code: Option<(Script, u64)>,
+    sighash_type: SigHashType,
+) -> Result<SigHash, SignerError> {
+    let (script_code, amount) = match script_code {
+        Some(script_code) => script_code,
+        None => {
+            let input = psbt
+                .inputs
+                .get(index)
+                .ok_or(SignerError::InputData(index))?;
+            let spent_output = input
+                .witness_utxo
+                .as_ref()
+                .ok_or(SignerError::InputData(index))?;
+            let script_code = match input.witness_script {
+                Some(ref witness_script) => witness_script.clone(),
+                None if spent_output.script_pubkey.is_v0_p2wpkh() => {
+                    Script::new_p2pkh(
+                        &PubkeyHash::from_slice(
+                            &spent_output.script_pubkey.as_bytes()[2..],
+                        )
+                        .expect("P2WPKH output always contains key hash"),
+                    )
+                }
+                None => return Err(SignerError::InputData(index)),
+            };
+            (script_code, spent_output.value)
+        }
+    };
+    if index >= psbt.global.unsigned_tx.input.len() {
+        return Err(SignerError::InputData(index));
+    }
+    Ok(SigHashCache::new(&psbt.global.unsigned_tx).signature_hash(
+        index,
+        &script_code,
+        amount,
+        sighash_type,
+    ))
+}
+
+/// Requests to the out-of-process signer; each of them matches one of the
+/// [`ChannelSigner`] methods
+#[derive(Clone, PartialEq, Debug, StrictEncode, StrictDecode)]
+pub enum SignerRequest {
+    Basepoints,
+    FundingPubkey,
+    WalletPubkey,
+    PerCommitmentPoint(u64),
+    ReleasePerCommitmentSecret(u64),
+    SignFunding(Psbt, usize),
+    SignCommitment(TxGraph, PublicKey, u64),
+    SignHtlc(Psbt, PublicKey),
+    SignJustice(Psbt, [u8; 32]),
+    SignClosing(Psbt, PublicKey, u64),
+    AdvanceCommitment(u64),
+}
+
+/// Responses of the out-of-process signer
+#[derive(Clone, PartialEq, Eq, Debug, StrictEncode, StrictDecode)]
+pub enum SignerResponse {
+    Basepoints(Basepoints),
+    PublicKey(PublicKey),
+    Secret([u8; 32]),
+    Signature(Signature),
+    Signatures(Vec<Signature>),
+    CommitmentSignatures(Signature, Vec<Signature>),
+    Failure(SignerError),
+    Done,
+}
+
+impl SignerRequest {
+    /// Processes the request with the given signer; used by the process
+    /// serving signer requests
+    pub fn serve(self, signer: &impl ChannelSigner) -> SignerResponse {
+        let response = match self {
+            SignerRequest::Basepoints => {
+                signer.basepoints().map(SignerResponse::Basepoints)
+            }
+            SignerRequest::FundingPubkey => {
+                signer.funding_pubkey().map(SignerResponse::PublicKey)
+            }
+            SignerRequest::WalletPubkey => {
+                signer.wallet_pubkey().map(SignerResponse::PublicKey)
+            }
+            SignerRequest::PerCommitmentPoint(commitment_number) => signer
+                .per_commitment_point(commitment_number)
+                .map(SignerResponse::PublicKey),
+            SignerRequest::ReleasePerCommitmentSecret(commitment_number) => {
+                signer.release_per_commitment_secret(commitment_number).map(
+                    |secret| {
+                        let mut data = [0u8; 32];
+                        data.copy_from_slice(&secret[..]);
+                        SignerResponse::Secret(data)
+                    },
+                )
+            }
+            SignerRequest::SignFunding(psbt, input_index) => signer
+                .sign_funding(&psbt, input_index)
+                .map(SignerResponse::Signature),
+            SignerRequest::SignCommitment(
+                tx_graph,
+                remote_funding_pubkey,
+                funding_amount,
+            ) => signer
+                .sign_commitment(
+                    &tx_graph,
+                    remote_funding_pubkey,
+                    funding_amount,
+                )
+                .map(|(signature, htlc_signatures)| {
+                    SignerResponse::CommitmentSignatures(
+                        signature,
+                        htlc_signatures,
+                    )
+                }),
+            SignerRequest::SignHtlc(psbt, per_commitment_point) => signer
+                .sign_htlc(&psbt, per_commitment_point)
+                .map(SignerResponse::Signature),
+            SignerRequest::SignJustice(psbt, per_commitment_secret) => {
+                SecretKey::from_slice(&per_commitment_secret)
+                    .map_err(|err| SignerError::Refused(err.to_string()))
+                    .and_then(|secret| signer.sign_justice(&psbt, secret))
+                    .map(SignerResponse::Signatures)
+            }
+            SignerRequest::SignClosing(
+                psbt,
+                remote_funding_pubkey,
+                funding_amount,
+            ) => signer
+                .sign_closing(&psbt, remote_funding_pubkey, funding_amount)
+                .map(SignerResponse::Signature),
+            SignerRequest::AdvanceCommitment(commitment_number) => signer
+                .advance_commitment(commitment_number)
+                .map(|_| SignerResponse::Done),
+        };
+        response.unwrap_or_else(SignerResponse::Failure)
+    }
+}
+
+/// Connection to the out-of-process signer, transferring strict-encoded
+/// [`SignerRequest`]s and [`SignerResponse`]s
+pub trait SignerConnection {
+    /// Sends request to the signer and waits for its response
+    fn exchange(&self, request: Vec<u8>) -> Result<Vec<u8>, SignerError>;
+}
+
+/// Signer running in a separate process or device, which is accessed
+/// through [`SignerConnection`]
+#[derive(Debug)]
+pub struct RemoteSigner<C>
+where
+    C: SignerConnection,
+{
+    connection: C,
+}
+
+impl<C> RemoteSigner<C>
+where
+    C: SignerConnection,
+{
+    pub fn with(connection: C) -> Self {
+        Self { connection }
+    }
+
+    /// Returns connection used by the signer
+    #[inline]
+    pub fn connection(&self) -> &C {
+        &self.connection
+    }
+
+    fn request(
+        &self,
+        request: SignerRequest,
+    ) -> Result<SignerResponse, SignerError> {
+        let response = self.connection.exchange(strict_serialize(&request)?)?;
+        match strict_deserialize(&response)? {
+            SignerResponse::Failure(err) => Err(err),
+            response => Ok(response),
+        }
+    }
+
+    fn request_pubkey(
+        &self,
+        request: SignerRequest,
+    ) -> Result<PublicKey, SignerError> {
+        match self.request(request)? {
+            SignerResponse::PublicKey(pubkey) => Ok(pubkey),
+            _ => Err(SignerError::UnexpectedResponse),
+        }
+    }
+
+    fn request_signature(
+        &self,
+        request: SignerRequest,
+    ) -> Result<Signature, SignerError> {
+        match self.request(request)? {
+            SignerResponse::Signature(signature) => Ok(signature),
+            _ => Err(SignerError::UnexpectedResponse),
+        }
+    }
+}
+
+impl<C> ChannelSigner for RemoteSigner<C>
+where
+    C: SignerConnection,
+{
+    fn basepoints(&self) -> Result<Basepoints, SignerError> {
+        match self.request(SignerRequest::Basepoints)? {
+            SignerResponse::Basepoints(basepoints) => Ok(basepoints),
+            _ => Err(SignerError::UnexpectedResponse),
+        }
+    }
+
+    fn funding_pubkey(&self) -> Result<PublicKey, SignerError> {
+        self.request_pubkey(SignerRequest::FundingPubkey)
+    }
+
+    fn wallet_pubkey(&self) -> Result<PublicKey, SignerError> {
+        self.request_pubkey(SignerRequest::WalletPubkey)
+    }
+
+    fn per_commitment_point(
+        &self,
+        commitment_number: u64,
+    ) -> Result<PublicKey, SignerError> {
+        self.request_pubkey(SignerRequest::PerCommitmentPoint(
+            commitment_number,
+        ))
+    }
+
+    fn advance_commitment(
+        &self,
+        commitment_number: u64,
+    ) -> Result<(), SignerError> {
+        match self
+            .request(SignerRequest::AdvanceCommitment(commitment_number))?
+        {
+            SignerResponse::Done => Ok(()),
+            _ => Err(SignerError::UnexpectedResponse),
+        }
+    }
+
+    fn release_per_commitment_secret(
+        &self,
+        commitment_number: u64,
+    ) -> Result<SecretKey, SignerError> {
+        match self.request(SignerRequest::ReleasePerCommitmentSecret(
+            commitment_number,
+        ))? {
+            SignerResponse::Secret(secret) => SecretKey::from_slice(&secret)
+                .map_err(|err| SignerError::Encoding(err.to_string())),
+            _ => Err(SignerError::UnexpectedResponse),
+        }
+    }
+
+    fn sign_funding(
+        &self,
+        psbt: &Psbt,
+        input_index: usize,
+    ) -> Result<Signature, SignerError> {
+        self.request_signature(SignerRequest::SignFunding(
+            psbt.clone(),
+            input_index,
+        ))
+    }
+
+    fn sign_commitment(
+        &self,
+        tx_graph: &TxGraph,
+        remote_funding_pubkey: PublicKey,
+        funding_amount: u64,
+    ) -> Result<(Signature, Vec<Signature>), SignerError> {
+        match self.request(SignerRequest::SignCommitment(
+            tx_graph.clone(),
+            remote_funding_pubkey,
+            funding_amount,
+        ))? {
+            SignerResponse::CommitmentSignatures(
+                signature,
+                htlc_signatures,
+            ) => Ok((signature, htlc_signatures)),
+            _ => Err(SignerError::UnexpectedResponse),
+        }
+    }
+
+    fn sign_htlc(
+        &self,
+        psbt: &Psbt,
+        per_commitment_point: PublicKey,
+    ) -> Result<Signature, SignerError> {
+        self.request_signature(SignerRequest::SignHtlc(
+            psbt.clone(),
+            per_commitment_point,
+        ))
+    }
+
+    fn sign_justice(
+        &self,
+        psbt: &Psbt,
+        per_commitment_secret: SecretKey,
+    ) -> Result<Vec<Signature>, SignerError> {
+        let mut secret = [0u8; 32];
+        secret.copy_from_slice(&per_commitment_secret[..]);
+        match self.request(SignerRequest::SignJustice(psbt.clone(), secret))? {
+            SignerResponse::Signatures(signatures) => Ok(signatures),
+            _ => Err(SignerError::UnexpectedResponse),
+        }
+    }
+
+    fn sign_closing(
+        &self,
+        psbt: &Psbt,
+        remote_funding_pubkey: PublicKey,
+        funding_amount: u64,
+    ) -> Result<Signature, SignerError> {
+        self.request_signature(SignerRequest::SignClosing(
+            psbt.clone(),
+            remote_funding_pubkey,
+            funding_amount,
+        ))
+    }
+}
+
+/// In-process loopback connection serving requests with [`InMemorySigner`]
+/// and recording them, so tests can check which data were signed
+#[derive(Debug)]
+pub struct MockConnection {
+    signer: InMemorySigner,
+    requests: RefCell<Vec<SignerRequest>>,
+    refuse: bool,
+}
+
+/// Signer for tests, which passes all requests through the signer protocol
+pub type MockSigner = RemoteSigner<MockConnection>;
+
+impl SignerConnection for MockConnection {
+    fn exchange(&self, request: Vec<u8>) -> Result<Vec<u8>, SignerError> {
+        let request: SignerRequest = strict_deserialize(&request)?;
+        self.requests.borrow_mut().push(request.clone());
+        let response = if self.refuse {
+            SignerResponse::Failure(SignerError::Refused(s!("mock signer")))
+        } else {
+            request.serve(&self.signer)
+        };
+        Ok(strict_serialize(&response)?)
+    }
+}
+
+impl RemoteSigner<MockConnection> {
+    /// Constructs mock signer with the keys derived from the seed
+    pub fn mock(seed: [u8; 32]) -> Self {
+        RemoteSigner::with(MockConnection {
+            signer: InMemorySigner::with_seed(seed),
+            requests: empty!(),
+            refuse: false,
+        })
+    }
+
+    /// Constructs mock signer refusing all requests
+    pub fn mock_refusing() -> Self {
+        RemoteSigner::with(MockConnection {
+            signer: InMemorySigner::with_seed([0u8; 32]),
+            requests: empty!(),
+            refuse: true,
+        })
+    }
+
+    /// Returns all requests received by the mock signer
+    pub fn requests(&self) -> Vec<SignerRequest> {
+        self.connection.requests.borrow().clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::message::CommitmentSigned;
+    use crate::payment::ChannelId;
+
+    #[test]
+    fn in_memory_keys() {
+        let signer = InMemorySigner::with_seed([7u8; 32]);
+        assert_eq!(signer, InMemorySigner::with_seed([7u8; 32]));
+        assert_ne!(signer, InMemorySigner::with_seed([8u8; 32]));
+
+        let basepoints = signer.basepoints().unwrap();
+        assert_eq!(
+            basepoints.htlc_basepoint,
+            PublicKey::from_secret_key(
+                &SECP256K1,
+                &signer.htlc_basepoint_secret
+            )
+        );
+        assert_ne!(basepoints.htlc_basepoint, basepoints.payment_basepoint);
+        signer.advance_commitment(6).unwrap();
+        assert_eq!(
+            signer.per_commitment_point(5).unwrap(),
+            PublicKey::from_secret_key(
+                &SECP256K1,
+                &signer.release_per_commitment_secret(5).unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn secret_release() {
+        let signer = InMemorySigner::with_seed([7u8; 32]);
+        assert_eq!(
+            signer.release_per_commitment_secret(0),
+            Err(SignerError::UnrevokedCommitment(0, 0))
+        );
+        signer.advance_commitment(2).unwrap();
+        assert!(signer.release_per_commitment_secret(0).is_ok());
+        assert!(signer.release_per_commitment_secret(1).is_ok());
+        assert_eq!(
+            signer.release_per_commitment_secret(2),
+            Err(SignerError::UnrevokedCommitment(2, 2))
+        );
+        assert_eq!(
+            signer.release_per_commitment_secret(3),
+            Err(SignerError::UnrevokedCommitment(3, 2))
+        );
+        assert_eq!(
+            signer.advance_commitment(1),
+            Err(SignerError::CommitmentRollback(1, 2))
+        );
+
+        let debug = format!("{:?}", signer);
+        assert!(!debug.contains(&format!("{:?}", signer.funding_key)));
+        assert!(!debug.contains("per_commitment_seed"));
+    }
+
+    #[test]
+    fn commitment_signature() {
+        let signer = InMemorySigner::with_seed([7u8; 32]);
+        let remote = InMemorySigner::with_seed([9u8; 32]);
+        let remote_funding_pubkey = remote.funding_pubkey().unwrap();
+        let tx_graph = TxGraph::default();
+
+        let (signature, htlc_signatures) = signer
+            .sign_commitment(&tx_graph, remote_funding_pubkey, 10_000_000)
+            .unwrap();
+        assert!(htlc_signatures.is_empty());
+        tx_graph
+            .verify(
+                &CommitmentSigned {
+                    channel_id: ChannelId::default(),
+                    signature,
+                    htlc_signatures,
+                },
+                &signer.funding_script(remote_funding_pubkey, 10_000_000),
+                10_000_000,
+                signer.funding_pubkey().unwrap(),
+                remote_funding_pubkey,
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn mock_signer() {
+        let signer = InMemorySigner::with_seed([7u8; 32]);
+        let mock = MockSigner::mock([7u8; 32]);
+        let remote_funding_pubkey = *wallet::SECP256K1_PUBKEY_DUMB;
+        let tx_graph = TxGraph::default();
+
+        assert_eq!(mock.basepoints(), signer.basepoints());
+        signer.advance_commitment(4).unwrap();
+        mock.advance_commitment(4).unwrap();
+        assert_eq!(
+            mock.release_per_commitment_secret(3),
+            signer.release_per_commitment_secret(3)
+        );
+        assert_eq!(
+            mock.sign_commitment(&tx_graph, remote_funding_pubkey, 1000),
+            signer.sign_commitment(&tx_graph, remote_funding_pubkey, 1000)
+        );
+        assert_eq!(
+            mock.requests(),
+            vec![
+                SignerRequest::Basepoints,
+                SignerRequest::AdvanceCommitment(4),
+                SignerRequest::ReleasePerCommitmentSecret(3),
+                SignerRequest::SignCommitment(
+                    tx_graph,
+                    remote_funding_pubkey,
+                    1000
+                )
+            ]
+        );
+
+        // Justice transaction without inputs data can't be signed
+        let psbt = TxGraph::default().render_cmt();
+        assert_eq!(
+            mock.sign_justice(
+                &psbt,
+                signer.release_per_commitment_secret(0).unwrap()
+            ),
+            Err(SignerError::InputData(0))
+        );
+
+        let refusing = MockSigner::mock_refusing();
+        assert_eq!(
+            refusing.funding_pubkey(),
+            Err(SignerError::Refused(s!("mock signer")))
+        );
+        assert_eq!(refusing.requests(), vec![SignerRequest::FundingPubkey]);
+    }
+}
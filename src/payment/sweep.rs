@@ -0,0 +1,956 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Resolution of the commitment transactions confirmed after unilateral
+//! channel close: detection of the outputs belonging to the local node and
+//! construction of the transactions sweeping them (see BOLT-5 "Unilateral
+//! Close Handling" sections).
+
+use bitcoin::consensus::encode::VarInt;
+use bitcoin::hashes::{ripemd160, sha256, Hash};
+use bitcoin::secp256k1::{PublicKey, Signature};
+use bitcoin::util::psbt::PartiallySignedTransaction as Psbt;
+use bitcoin::{OutPoint, Script, SigHashType, Transaction, TxOut, Txid};
+use wallet::{HashPreimage, PubkeyScript, WitnessScript};
+
+use crate::channel::{CommitmentOwner, TxGraph};
+use crate::payment::bolt3::{ScriptGenerators, DEFAULT_DUST_LIMIT};
+use crate::payment::cpfp::{
+    spending_psbt, tx_weight, SpendInput, MAX_SIGNATURE_LEN, SEQUENCE_RBF,
+};
+use crate::payment::fee::fee_for_weight;
+use crate::payment::keys::{Basepoints, CommitmentKeys};
+use crate::payment::TxType;
+
+/// Errors resolving confirmed commitment transactions and sweeping their
+/// outputs
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum SweepError {
+    /// transaction {0} does not match the commitment transaction of the
+    /// provided channel state
+    CommitmentMismatch(Txid),
+
+    /// none of the outputs can be swept at height {0}
+    NothingToSweep(u32),
+
+    /// swept amount of {0} sats does not cover the fee of {1} sats with the
+    /// dust limit
+    InsufficientFunds(u64, u64),
+
+    /// {0} signatures are provided for the sweep transaction with {1}
+    /// inputs
+    SignatureCount(usize, usize),
+
+    /// sweep transaction input #{0} does not spend any of the resolved
+    /// outputs or requires signature of the remote peer
+    UnknownInput(usize),
+
+    /// channel state has no CLTV expiry for HTLC output #{0} of the
+    /// commitment transaction
+    NoCltvExpiry(u32),
+}
+
+/// Type of the output which can be claimed by the local node after
+/// unilateral channel close
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Display)]
+#[display(Debug)]
+pub enum OutputKind {
+    /// `to_local` output of the local commitment or output of the confirmed
+    /// second-stage HTLC transaction, spendable after `to_self_delay`
+    DelayedToLocal,
+
+    /// `to_remote` output of the remote commitment
+    ToRemote,
+
+    /// HTLC output claimed with the payment preimage
+    HtlcSuccess,
+
+    /// HTLC output claimed after its CLTV expiry
+    HtlcTimeout,
+}
+
+/// Element of the witness stack spending an output
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum WitnessElement {
+    /// Signature made with a given public key and `SIGHASH_ALL`
+    Signature(PublicKey),
+
+    /// Signature of the remote peer, which is provided with the
+    /// `commitment_signed` message for the second-stage HTLC transactions
+    RemoteSignature,
+
+    /// Payment preimage
+    Preimage(HashPreimage),
+
+    /// Public key
+    PublicKey(PublicKey),
+
+    /// Empty vector
+    Empty,
+
+    /// Witness script of the spent output
+    WitnessScript,
+}
+
+impl WitnessElement {
+    /// Returns maximal serialized length of the element, including the
+    /// length prefix
+    fn weight(&self, witness_script: Option<&Script>) -> u64 {
+        let len = match self {
+            WitnessElement::Signature(_) | WitnessElement::RemoteSignature => {
+                MAX_SIGNATURE_LEN
+            }
+            WitnessElement::Preimage(_) => 32,
+            WitnessElement::PublicKey(_) => 33,
+            WitnessElement::Empty => 0,
+            WitnessElement::WitnessScript => witness_script
+                .map(|script| script.len() as u64)
+                .unwrap_or_default(),
+        };
+        VarInt(len).len() as u64 + len
+    }
+}
+
+/// Output of the confirmed commitment (or second-stage HTLC) transaction
+/// which can be claimed by the local node
+#[derive(Clone, PartialEq, Debug)]
+pub struct SpendableOutput {
+    /// Type of the output
+    pub kind: OutputKind,
+
+    /// Spent outpoint
+    pub outpoint: OutPoint,
+
+    /// Spent output
+    pub txout: TxOut,
+
+    /// Witness script of the P2WSH output; `None` for P2WPKH output
+    pub witness_script: Option<Script>,
+
+    /// Sequence number required for the spending input
+    pub sequence: u32,
+
+    /// Lock time required for the spending transaction
+    pub lock_time: u32,
+
+    /// Height of the first block which may include the spending transaction
+    pub earliest_height: u32,
+
+    /// Witness stack spending the output
+    pub witness_template: Vec<WitnessElement>,
+
+    /// Second-stage HTLC transaction pre-signed by the remote peer which
+    /// must be used to spend HTLC output of the local commitment
+    pub presigned_tx: Option<Psbt>,
+}
+
+impl SpendableOutput {
+    fn with(
+        kind: OutputKind,
+        outpoint: OutPoint,
+        txout: TxOut,
+        witness_script: Option<Script>,
+        witness_template: Vec<WitnessElement>,
+    ) -> Self {
+        SpendableOutput {
+            kind,
+            outpoint,
+            txout,
+            witness_script,
+            sequence: SEQUENCE_RBF,
+            lock_time: 0,
+            earliest_height: 0,
+            witness_template,
+            presigned_tx: None,
+        }
+    }
+
+    /// Sets the relative and absolute time locks for the output, computing
+    /// the earliest height of the spending transaction
+    fn locked(
+        mut self,
+        confirmation_height: u32,
+        csv: u16,
+        cltv_expiry: u32,
+    ) -> Self {
+        if csv > 0 {
+            self.sequence = csv as u32;
+        }
+        self.lock_time = cltv_expiry;
+        // Transaction is final only in the blocks above its lock time
+        self.earliest_height = (confirmation_height + csv as u32)
+            .max(if cltv_expiry > 0 { cltv_expiry + 1 } else { 0 });
+        self
+    }
+
+    /// Detects whether the output can be spent by the transaction included
+    /// into the block at the given height
+    #[inline]
+    pub fn is_mature(&self, height: u32) -> bool {
+        self.earliest_height <= height
+    }
+
+    /// Returns maximal weight of the witness spending the output
+    pub fn satisfaction_weight(&self) -> u64 {
+        VarInt(self.witness_template.len() as u64).len() as u64
+            + self
+                .witness_template
+                .iter()
+                .map(|element| element.weight(self.witness_script.as_ref()))
+                .sum::<u64>()
+    }
+
+    /// Constructs witness from the template using the provided signature;
+    /// returns `None` if the template requires signature of the remote peer
+    fn witness(&self, signature: &Signature) -> Option<Vec<Vec<u8>>> {
+        self.witness_template
+            .iter()
+            .map(|element| match element {
+                WitnessElement::Signature(_) => {
+                    let mut sig = signature.serialize_der().to_vec();
+                    sig.push(SigHashType::All.as_u32() as u8);
+                    Some(sig)
+                }
+                WitnessElement::RemoteSignature => None,
+                WitnessElement::Preimage(preimage) => {
+                    Some(preimage.as_ref().to_vec())
+                }
+                WitnessElement::PublicKey(pubkey) => {
+                    Some(pubkey.serialize().to_vec())
+                }
+                WitnessElement::Empty => Some(vec![]),
+                WitnessElement::WitnessScript => {
+                    self.witness_script.as_ref().map(|script| script.to_bytes())
+                }
+            })
+            .collect()
+    }
+}
+
+/// Outputs of the confirmed commitment transaction claimable by the local
+/// node
+#[derive(Clone, PartialEq, Debug)]
+pub struct Resolution {
+    /// Confirmed commitment transaction
+    pub commitment_txid: Txid,
+
+    /// Owner of the confirmed commitment transaction
+    pub owner: CommitmentOwner,
+
+    /// Outputs which can be claimed by the local node
+    pub outputs: Vec<SpendableOutput>,
+
+    /// Script of the `to_local` output, which is also used by the outputs
+    /// of the second-stage HTLC transactions
+    to_local_script: Script,
+    local_delayedpubkey: PublicKey,
+    to_self_delay: u16,
+}
+
+impl Resolution {
+    /// Resolves outputs of the confirmed commitment transaction for the
+    /// channel state `tx_graph`, which may be either a local or a remote
+    /// commitment. HTLC outputs which must be claimed with payment preimage
+    /// are included only if the preimage is present in `preimages`.
+    pub fn resolve(
+        commitment_tx: &Transaction,
+        confirmation_height: u32,
+        tx_graph: &TxGraph,
+        local_keys: &Basepoints,
+        remote_keys: &Basepoints,
+        preimages: &[HashPreimage],
+    ) -> Result<Resolution, SweepError> {
+        let commitment_txid = commitment_tx.txid();
+        if tx_graph.render_cmt().global.unsigned_tx.txid() != commitment_txid {
+            return Err(SweepError::CommitmentMismatch(commitment_txid));
+        }
+
+        let owner = tx_graph.cmt_owner;
+        let per_commitment_point = tx_graph.cmt_per_commitment_point;
+        let keys = match owner {
+            CommitmentOwner::Local => CommitmentKeys::derive(
+                local_keys,
+                remote_keys,
+                per_commitment_point,
+            ),
+            CommitmentOwner::Remote => CommitmentKeys::derive(
+                remote_keys,
+                local_keys,
+                per_commitment_point,
+            ),
+        };
+        let to_local_script: Script = WitnessScript::ln_to_local(
+            0,
+            keys.revocationpubkey,
+            keys.local_delayedpubkey,
+            tx_graph.cmt_to_self_delay,
+        )
+        .into();
+        let mut resolution = Resolution {
+            commitment_txid,
+            owner,
+            outputs: vec![],
+            to_local_script,
+            local_delayedpubkey: keys.local_delayedpubkey,
+            to_self_delay: tx_graph.cmt_to_self_delay,
+        };
+
+        let anchors = tx_graph.cmt_fee.anchors;
+        let htlc_csv = if anchors { 1 } else { 0 };
+        let htlc_txs = tx_graph
+            .iter()
+            .filter_map(|(role, _, psbt)| match TxType::from(role) {
+                TxType::HtlcSuccess => Some((OutputKind::HtlcSuccess, psbt)),
+                TxType::HtlcTimeout => Some((OutputKind::HtlcTimeout, psbt)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        for (vout, txout) in commitment_tx.output.iter().enumerate() {
+            let outpoint = OutPoint::new(commitment_txid, vout as u32);
+            if owner == CommitmentOwner::Local
+                && txout.script_pubkey
+                    == resolution.to_local_script.to_v0_p2wsh()
+            {
+                resolution.outputs.push(resolution.delayed_output(
+                    outpoint,
+                    txout.clone(),
+                    confirmation_height,
+                ));
+                continue;
+            }
+            if owner == CommitmentOwner::Remote {
+                if let Some(output) = to_remote_output(
+                    outpoint,
+                    txout,
+                    &keys,
+                    local_keys.payment_basepoint,
+                    confirmation_height,
+                ) {
+                    resolution.outputs.push(output);
+                    continue;
+                }
+            }
+
+            // HTLC outputs may have the same amount and script, so the
+            // second-stage transactions are matched by the spent output
+            let (role, psbt) = match htlc_txs.iter().find(|(_, psbt)| {
+                psbt.global.unsigned_tx.input[0].previous_output.vout
+                    == vout as u32
+            }) {
+                Some((role, psbt)) => (*role, *psbt),
+                // Anchors and outputs of the remote peer
+                None => continue,
+            };
+            let witness_script = psbt.inputs[0].witness_script.clone();
+            let preimage = witness_script.as_ref().and_then(|script| {
+                preimages
+                    .iter()
+                    .find(|preimage| script_has_hashlock(script, preimage))
+                    .copied()
+            });
+            let cltv_expiry =
+                tx_graph.cmt_cltv_expiry.get(&(vout as u32)).copied();
+
+            let output = match (owner, role) {
+                // Our own HTLCs are resolved with the second-stage
+                // transactions, pre-signed by the remote peer
+                (CommitmentOwner::Local, OutputKind::HtlcTimeout) => {
+                    let mut output = SpendableOutput::with(
+                        OutputKind::HtlcTimeout,
+                        outpoint,
+                        txout.clone(),
+                        witness_script,
+                        vec![
+                            WitnessElement::Empty,
+                            WitnessElement::RemoteSignature,
+                            WitnessElement::Signature(keys.local_htlcpubkey),
+                            WitnessElement::Empty,
+                            WitnessElement::WitnessScript,
+                        ],
+                    )
+                    .locked(
+                        confirmation_height,
+                        htlc_csv,
+                        psbt.global.unsigned_tx.lock_time,
+                    );
+                    output.presigned_tx = Some(psbt.clone());
+                    output
+                }
+                (CommitmentOwner::Local, _) => {
+                    let preimage = match preimage {
+                        Some(preimage) => preimage,
+                        None => continue,
+                    };
+                    let mut output = SpendableOutput::with(
+                        OutputKind::HtlcSuccess,
+                        outpoint,
+                        txout.clone(),
+                        witness_script,
+                        vec![
+                            WitnessElement::Empty,
+                            WitnessElement::RemoteSignature,
+                            WitnessElement::Signature(keys.local_htlcpubkey),
+                            WitnessElement::Preimage(preimage),
+                            WitnessElement::WitnessScript,
+                        ],
+                    )
+                    .locked(
+                        confirmation_height,
+                        htlc_csv,
+                        0,
+                    );
+                    output.presigned_tx = Some(psbt.clone());
+                    output
+                }
+                // Commitment owner is the remote peer, so its HTLC-timeout
+                // transactions spend HTLCs offered to us, which we claim
+                // directly with the preimage
+                (CommitmentOwner::Remote, OutputKind::HtlcTimeout) => {
+                    let preimage = match preimage {
+                        Some(preimage) => preimage,
+                        None => continue,
+                    };
+                    SpendableOutput::with(
+                        OutputKind::HtlcSuccess,
+                        outpoint,
+                        txout.clone(),
+                        witness_script,
+                        vec![
+                            WitnessElement::Signature(keys.remote_htlcpubkey),
+                            WitnessElement::Preimage(preimage),
+                            WitnessElement::WitnessScript,
+                        ],
+                    )
+                    .locked(
+                        confirmation_height,
+                        htlc_csv,
+                        0,
+                    )
+                }
+                (CommitmentOwner::Remote, _) => SpendableOutput::with(
+                    OutputKind::HtlcTimeout,
+                    outpoint,
+                    txout.clone(),
+                    witness_script,
+                    vec![
+                        WitnessElement::Signature(keys.remote_htlcpubkey),
+                        WitnessElement::Empty,
+                        WitnessElement::WitnessScript,
+                    ],
+                )
+                .locked(
+                    confirmation_height,
+                    htlc_csv,
+                    cltv_expiry.ok_or(SweepError::NoCltvExpiry(vout as u32))?,
+                ),
+            };
+            resolution.outputs.push(output);
+        }
+
+        Ok(resolution)
+    }
+
+    fn delayed_output(
+        &self,
+        outpoint: OutPoint,
+        txout: TxOut,
+        confirmation_height: u32,
+    ) -> SpendableOutput {
+        SpendableOutput::with(
+            OutputKind::DelayedToLocal,
+            outpoint,
+            txout,
+            Some(self.to_local_script.clone()),
+            vec![
+                WitnessElement::Signature(self.local_delayedpubkey),
+                WitnessElement::Empty,
+                WitnessElement::WitnessScript,
+            ],
+        )
+        .locked(confirmation_height, self.to_self_delay, 0)
+    }
+
+    /// Replaces HTLC output of the local commitment spent by the confirmed
+    /// second-stage HTLC transaction with the delayed output of that
+    /// transaction. Returns whether the transaction has spent any of the
+    /// resolved outputs.
+    pub fn resolve_second_stage(
+        &mut self,
+        tx: &Transaction,
+        confirmation_height: u32,
+    ) -> bool {
+        let txid = tx.txid();
+        let mut resolved = false;
+        for (index, txin) in tx.input.iter().enumerate() {
+            let pos = match self.outputs.iter().position(|output| {
+                output.presigned_tx.is_some()
+                    && output.outpoint == txin.previous_output
+            }) {
+                Some(pos) => pos,
+                None => continue,
+            };
+            resolved = true;
+            self.outputs.remove(pos);
+            if let Some(txout) = tx.output.get(index) {
+                let output = self.delayed_output(
+                    OutPoint::new(txid, index as u32),
+                    txout.clone(),
+                    confirmation_height,
+                );
+                self.outputs.push(output);
+            }
+        }
+        resolved
+    }
+
+    /// Returns outputs which can be spent by the transaction included into
+    /// the block at the given height
+    pub fn mature_outputs(&self, height: u32) -> Vec<&SpendableOutput> {
+        self.outputs
+            .iter()
+            .filter(|output| output.is_mature(height))
+            .collect()
+    }
+
+    /// Constructs transactions ready to be signed, which claim all outputs
+    /// mature at the given height: pre-signed second-stage HTLC transactions
+    /// followed by a single transaction sweeping the rest of the outputs to
+    /// the `destination`
+    pub fn sweep_txs(
+        &self,
+        height: u32,
+        destination: &PubkeyScript,
+        feerate_per_kw: u32,
+    ) -> Result<Vec<Psbt>, SweepError> {
+        let (presigned, outputs): (Vec<_>, Vec<_>) = self
+            .mature_outputs(height)
+            .into_iter()
+            .partition(|output| output.presigned_tx.is_some());
+        let mut txs = presigned
+            .into_iter()
+            .filter_map(|output| output.presigned_tx.clone())
+            .collect::<Vec<_>>();
+        if !outputs.is_empty() {
+            txs.push(sweep_psbt(&outputs, destination, feerate_per_kw)?);
+        }
+        if txs.is_empty() {
+            return Err(SweepError::NothingToSweep(height));
+        }
+        Ok(txs)
+    }
+
+    /// Constructs witnesses for all inputs of the sweep transaction from the
+    /// signatures, provided in the order of inputs. Second-stage HTLC
+    /// transactions must be finalized with
+    /// [`crate::payment::htlc::TxFinalizers`] instead.
+    pub fn finalize_sweep(
+        &self,
+        psbt: &mut Psbt,
+        signatures: &[Signature],
+    ) -> Result<(), SweepError> {
+        if signatures.len() != psbt.inputs.len() {
+            return Err(SweepError::SignatureCount(
+                signatures.len(),
+                psbt.inputs.len(),
+            ));
+        }
+        let txins = &psbt.global.unsigned_tx.input;
+        for (index, (input, signature)) in
+            psbt.inputs.iter_mut().zip(signatures).enumerate()
+        {
+            input.final_script_witness = self
+                .outputs
+                .iter()
+                .find(|output| output.outpoint == txins[index].previous_output)
+                .and_then(|output| output.witness(signature));
+            if input.final_script_witness.is_none() {
+                return Err(SweepError::UnknownInput(index));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Detects our `to_remote` output of the remote commitment, trying scripts
+/// of all commitment types
+fn to_remote_output(
+    outpoint: OutPoint,
+    txout: &TxOut,
+    keys: &CommitmentKeys,
+    payment_basepoint: PublicKey,
+    confirmation_height: u32,
+) -> Option<SpendableOutput> {
+    // Before `option_static_remotekey` the key is tweaked with the
+    // per-commitment point
+    for pubkey in &[payment_basepoint, keys.remotepubkey] {
+        if txout.script_pubkey
+            == TxOut::ln_to_remote_v1(0, *pubkey).script_pubkey
+        {
+            return Some(
+                SpendableOutput::with(
+                    OutputKind::ToRemote,
+                    outpoint,
+                    txout.clone(),
+                    None,
+                    vec![
+                        WitnessElement::Signature(*pubkey),
+                        WitnessElement::PublicKey(*pubkey),
+                    ],
+                )
+                .locked(confirmation_height, 0, 0),
+            );
+        }
+    }
+    let witness_script = WitnessScript::ln_to_remote_v2(0, payment_basepoint);
+    if txout.script_pubkey == Script::from(witness_script.to_p2wsh()) {
+        return Some(
+            SpendableOutput::with(
+                OutputKind::ToRemote,
+                outpoint,
+                txout.clone(),
+                Some(witness_script.into()),
+                vec![
+                    WitnessElement::Signature(payment_basepoint),
+                    WitnessElement::WitnessScript,
+                ],
+            )
+            .locked(confirmation_height, 1, 0),
+        );
+    }
+    None
+}
+
+/// Detects whether HTLC witness script is locked to the payment hash of the
+/// preimage
+fn script_has_hashlock(script: &Script, preimage: &HashPreimage) -> bool {
+    let payment_hash = sha256::Hash::hash(preimage.as_ref());
+    let hash = ripemd160::Hash::hash(&payment_hash[..]);
+    script
+        .as_bytes()
+        .windows(hash.len())
+        .any(|window| window == &hash[..])
+}
+
+fn sweep_psbt(
+    outputs: &[&SpendableOutput],
+    destination: &PubkeyScript,
+    feerate_per_kw: u32,
+) -> Result<Psbt, SweepError> {
+    let inputs = outputs
+        .iter()
+        .map(|output| SpendInput {
+            outpoint: output.outpoint,
+            txout: output.txout.clone(),
+            witness_script: output.witness_script.clone(),
+            sequence: output.sequence,
+            satisfaction_weight: output.satisfaction_weight(),
+            sighash_type: Some(SigHashType::All),
+            final_witness: None,
+        })
+        .collect::<Vec<_>>();
+    let amount = outputs.iter().map(|output| output.txout.value).sum::<u64>();
+    let fee = fee_for_weight(feerate_per_kw, tx_weight(&inputs, destination));
+    if amount < fee + DEFAULT_DUST_LIMIT {
+        return Err(SweepError::InsufficientFunds(amount, fee));
+    }
+    let lock_time = outputs
+        .iter()
+        .map(|output| output.lock_time)
+        .max()
+        .unwrap_or_default();
+    Ok(spending_psbt(inputs, lock_time, amount - fee, destination))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use amplify::{Slice32, Wrapper};
+    use bitcoin::secp256k1::{Message, SecretKey};
+    use wallet::HashLock;
+
+    use crate::payment::cpfp::{TXIN_BASE_WEIGHT, TX_BASE_WEIGHT};
+    use crate::payment::htlc::{ScriptGenerators as _, TxGenerators};
+    use crate::payment::keys::test::pubkey;
+    use crate::payment::keys::SECP256K1;
+
+    const CONFIRMED: u32 = 700_000;
+    const CLTV_EXPIRY: u32 = 700_050;
+
+    fn basepoints(byte: u8) -> Basepoints {
+        Basepoints {
+            revocation_basepoint: pubkey(byte),
+            payment_basepoint: pubkey(byte + 1),
+            delayed_payment_basepoint: pubkey(byte + 2),
+            htlc_basepoint: pubkey(byte + 3),
+        }
+    }
+
+    fn preimage(byte: u8) -> HashPreimage {
+        HashPreimage::from_inner(Slice32::from_inner([byte; 32]))
+    }
+
+    fn destination() -> PubkeyScript {
+        PubkeyScript::from(
+            Script::new_v0_wpkh(&bitcoin::WPubkeyHash::default()),
+        )
+    }
+
+    /// Commitment with `to_local`, `to_remote` (with static remote key),
+    /// offered HTLC (with preimage 1) and received HTLC (with preimage 2)
+    /// outputs. Keys of the commitment owner are derived from byte 0x10,
+    /// of its counterparty from 0x20.
+    fn commitment(owner: CommitmentOwner) -> TxGraph {
+        let keys = CommitmentKeys::derive(
+            &basepoints(0x10),
+            &basepoints(0x20),
+            pubkey(1),
+        );
+        let mut tx_graph = TxGraph::with_owner(owner);
+        tx_graph.set_funding_outpoint(OutPoint::default());
+        tx_graph.cmt_to_self_delay = 144;
+        tx_graph.cmt_per_commitment_point = pubkey(1);
+        tx_graph.cmt_outs = vec![
+            TxOut::ln_to_local(
+                50_000,
+                keys.revocationpubkey,
+                keys.local_delayedpubkey,
+                144,
+            ),
+            TxOut::ln_to_remote_v1(30_000, basepoints(0x20).payment_basepoint),
+        ];
+
+        let offered = WitnessScript::ln_offered_htlc(
+            20_000,
+            keys.revocationpubkey,
+            keys.local_htlcpubkey,
+            keys.remote_htlcpubkey,
+            HashLock::from(preimage(1)),
+            false,
+        );
+        let received = WitnessScript::ln_received_htlc(
+            10_000,
+            keys.revocationpubkey,
+            keys.local_htlcpubkey,
+            keys.remote_htlcpubkey,
+            CLTV_EXPIRY,
+            HashLock::from(preimage(2)),
+            false,
+        );
+        let htlcs = vec![
+            (TxType::HtlcTimeout, offered, 20_000, CLTV_EXPIRY),
+            (TxType::HtlcSuccess, received, 10_000, 0),
+        ];
+        for (role, witness_script, amount, cltv_expiry) in htlcs {
+            let vout = tx_graph.cmt_outs.len() as u32;
+            let txout = TxOut {
+                value: amount,
+                script_pubkey: witness_script.to_p2wsh().into(),
+            };
+            tx_graph.cmt_outs.push(txout.clone());
+            tx_graph.cmt_cltv_expiry.insert(vout, CLTV_EXPIRY);
+            let mut htlc_tx = Psbt::ln_htlc(
+                amount - 1000,
+                tx_graph.cmt_outpoint(vout),
+                cltv_expiry,
+                keys.revocationpubkey,
+                keys.local_delayedpubkey,
+                144,
+            );
+            htlc_tx.inputs[0].witness_utxo = Some(txout);
+            htlc_tx.inputs[0].witness_script = Some(witness_script.into());
+            let index = tx_graph.last_index(role);
+            tx_graph.insert_tx(role, index as u64, htlc_tx);
+        }
+        tx_graph.link_cmt_txid();
+        tx_graph
+    }
+
+    fn resolve(owner: CommitmentOwner) -> Resolution {
+        let tx_graph = commitment(owner);
+        let (local_keys, remote_keys) = match owner {
+            CommitmentOwner::Local => (basepoints(0x10), basepoints(0x20)),
+            CommitmentOwner::Remote => (basepoints(0x20), basepoints(0x10)),
+        };
+        Resolution::resolve(
+            &tx_graph.render_cmt().global.unsigned_tx,
+            CONFIRMED,
+            &tx_graph,
+            &local_keys,
+            &remote_keys,
+            &[preimage(1), preimage(2)],
+        )
+        .unwrap()
+    }
+
+    fn summary(resolution: &Resolution) -> Vec<(OutputKind, u32, u32, bool)> {
+        resolution
+            .outputs
+            .iter()
+            .map(|output| {
+                (
+                    output.kind,
+                    output.outpoint.vout,
+                    output.earliest_height,
+                    output.presigned_tx.is_some(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn local_commitment() {
+        let mut resolution = resolve(CommitmentOwner::Local);
+        assert_eq!(
+            summary(&resolution),
+            vec![
+                (OutputKind::DelayedToLocal, 0, CONFIRMED + 144, false),
+                (OutputKind::HtlcTimeout, 2, CLTV_EXPIRY + 1, true),
+                (OutputKind::HtlcSuccess, 3, CONFIRMED, true),
+            ]
+        );
+
+        // Only HTLC-success transaction can be published right away
+        let txs = resolution
+            .sweep_txs(CONFIRMED, &destination(), 253)
+            .unwrap();
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].global.unsigned_tx.input[0].previous_output.vout, 3);
+
+        let txs = resolution
+            .sweep_txs(CONFIRMED + 144, &destination(), 253)
+            .unwrap();
+        assert_eq!(txs.len(), 3);
+        assert_eq!(txs[2].global.unsigned_tx.input[0].sequence, 144);
+
+        // Confirmed HTLC-success transaction output becomes delayed
+        let htlc_tx = txs[1].global.unsigned_tx.clone();
+        assert!(resolution.resolve_second_stage(&htlc_tx, CONFIRMED + 10));
+        assert!(!resolution.resolve_second_stage(&htlc_tx, CONFIRMED + 10));
+        assert_eq!(
+            resolution.outputs.last().map(|output| (
+                output.kind,
+                output.outpoint,
+                output.earliest_height
+            )),
+            Some((
+                OutputKind::DelayedToLocal,
+                OutPoint::new(htlc_tx.txid(), 0),
+                CONFIRMED + 154
+            ))
+        );
+    }
+
+    #[test]
+    fn no_cltv_expiry() {
+        let mut tx_graph = commitment(CommitmentOwner::Remote);
+        tx_graph.cmt_cltv_expiry.clear();
+        assert_eq!(
+            Resolution::resolve(
+                &tx_graph.render_cmt().global.unsigned_tx,
+                CONFIRMED,
+                &tx_graph,
+                &basepoints(0x20),
+                &basepoints(0x10),
+                &[preimage(1), preimage(2)],
+            )
+            .err(),
+            Some(SweepError::NoCltvExpiry(3))
+        );
+    }
+
+    #[test]
+    fn remote_commitment() {
+        let resolution = resolve(CommitmentOwner::Remote);
+        assert_eq!(
+            summary(&resolution),
+            vec![
+                (OutputKind::ToRemote, 1, CONFIRMED, false),
+                (OutputKind::HtlcSuccess, 2, CONFIRMED, false),
+                (OutputKind::HtlcTimeout, 3, CLTV_EXPIRY + 1, false),
+            ]
+        );
+
+        let txs = resolution
+            .sweep_txs(CONFIRMED, &destination(), 253)
+            .unwrap();
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].global.unsigned_tx.input.len(), 2);
+        assert_eq!(txs[0].global.unsigned_tx.lock_time, 0);
+
+        let mut txs = resolution
+            .sweep_txs(CLTV_EXPIRY + 1, &destination(), 253)
+            .unwrap();
+        let tx = &txs[0].global.unsigned_tx;
+        assert_eq!(tx.input.len(), 3);
+        assert_eq!(tx.lock_time, CLTV_EXPIRY);
+        let weight = TX_BASE_WEIGHT
+            + 4 * (8 + 1 + 22)
+            + resolution
+                .outputs
+                .iter()
+                .map(|output| TXIN_BASE_WEIGHT + output.satisfaction_weight())
+                .sum::<u64>();
+        assert_eq!(tx.output[0].value, 60_000 - fee_for_weight(253, weight));
+
+        let message = Message::from_slice(&[1u8; 32]).unwrap();
+        let signature = SECP256K1
+            .sign(&message, &SecretKey::from_slice(&[1u8; 32]).unwrap());
+        assert_eq!(
+            resolution.finalize_sweep(&mut txs[0], &[signature]),
+            Err(SweepError::SignatureCount(1, 3))
+        );
+        resolution
+            .finalize_sweep(&mut txs[0], &[signature; 3])
+            .unwrap();
+        let witness = txs[0].inputs[0].final_script_witness.clone().unwrap();
+        assert_eq!(witness.len(), 2);
+        assert_eq!(
+            witness[1],
+            basepoints(0x20).payment_basepoint.serialize().to_vec()
+        );
+        let witness = txs[0].inputs[1].final_script_witness.clone().unwrap();
+        assert_eq!(witness[1], preimage(1).as_ref().to_vec());
+        let witness = txs[0].inputs[2].final_script_witness.clone().unwrap();
+        assert_eq!(witness[1], Vec::<u8>::new());
+    }
+
+    #[test]
+    fn errors() {
+        let tx_graph = commitment(CommitmentOwner::Remote);
+        let mut tx = tx_graph.render_cmt().global.unsigned_tx;
+        tx.lock_time += 1;
+        assert_eq!(
+            Resolution::resolve(
+                &tx,
+                CONFIRMED,
+                &tx_graph,
+                &basepoints(0x20),
+                &basepoints(0x10),
+                &[],
+            ),
+            Err(SweepError::CommitmentMismatch(tx.txid()))
+        );
+
+        let resolution = resolve(CommitmentOwner::Local);
+        assert_eq!(
+            resolution.sweep_txs(CONFIRMED - 1, &destination(), 253),
+            Err(SweepError::NothingToSweep(CONFIRMED - 1))
+        );
+        assert!(matches!(
+            resolution.sweep_txs(CONFIRMED + 144, &destination(), 1_000_000),
+            Err(SweepError::InsufficientFunds(50_000, _))
+        ));
+    }
+}
@@ -31,13 +31,23 @@ use strict_encoding::{
 };
 use wallet::Slice32;
 
-use crate::{channel, extension};
+use crate::extension::{ExtensionFactory, ExtensionKind};
+use crate::factories::Factory;
+use crate::payment::bip96::Bip96;
+use crate::payment::{
+    AnchorOut, Bolt3, Bolt3Ordering, Direction, Eltoo, Htlc, MultiParty,
+};
+use crate::{channel, extension, ChannelExtension};
 
 use lightning_encoding::{LightningDecode, LightningEncode}; 
 
 /// Shorthand for representing asset - amount pairs
 pub type AssetsBalance = BTreeMap<AssetId, u64>;
 
+/// Extension ids are persisted as a part of the channel state, so new
+/// extensions must be added to the end of the list only. Variant order also
+/// defines the order in which the channel applies extensions of the same
+/// kind.
 #[derive(
     Clone,
     Copy,
@@ -69,6 +79,10 @@ pub enum ExtensionId {
 
     Bip96,
     Rgb,
+
+    MultiParty,
+    Factory,
+    Bolt3Ordering,
 }
 
 impl Default for ExtensionId {
@@ -98,6 +112,71 @@ impl TryFrom<u16> for ExtensionId {
 
 impl extension::Nomenclature for ExtensionId {}
 
+impl ExtensionFactory for ExtensionId {
+    fn kind(self) -> ExtensionKind {
+        match self {
+            // The channel itself is the root of the transaction graph
+            // construction
+            ExtensionId::Channel
+            | ExtensionId::Bolt3
+            | ExtensionId::Eltoo
+            | ExtensionId::Taproot
+            | ExtensionId::MultiParty
+            | ExtensionId::Factory => ExtensionKind::Constructor,
+
+            ExtensionId::Htlc
+            | ExtensionId::Ptlc
+            | ExtensionId::ShutdownScript
+            | ExtensionId::AnchorOut
+            | ExtensionId::Dlc
+            | ExtensionId::Lightspeed => ExtensionKind::Extender,
+
+            ExtensionId::Bip96
+            | ExtensionId::Bolt3Ordering
+            | ExtensionId::Rgb => ExtensionKind::Modifier,
+        }
+    }
+
+    fn restore(
+        self,
+        state: &[u8],
+    ) -> Result<Box<dyn ChannelExtension<Identity = Self>>, channel::Error>
+    {
+        let extension: Box<dyn ChannelExtension<Identity = Self>> = match self {
+            ExtensionId::Bolt3 => {
+                Box::new(strict_deserialize::<Bolt3>(&state)?)
+            }
+            ExtensionId::Eltoo => {
+                Box::new(strict_deserialize::<Eltoo>(&state)?)
+            }
+            ExtensionId::MultiParty => {
+                Box::new(strict_deserialize::<MultiParty>(&state)?)
+            }
+            ExtensionId::Factory => {
+                Box::new(strict_deserialize::<Factory>(&state)?)
+            }
+            ExtensionId::Htlc => Box::new(strict_deserialize::<Htlc>(&state)?),
+            ExtensionId::AnchorOut => {
+                Box::new(strict_deserialize::<AnchorOut>(&state)?)
+            }
+            ExtensionId::Bip96 => {
+                strict_deserialize::<()>(&state)?;
+                Box::new(Bip96)
+            }
+            ExtensionId::Bolt3Ordering => {
+                strict_deserialize::<()>(&state)?;
+                Box::new(Bolt3Ordering)
+            }
+            other => {
+                return Err(channel::Error::UnsupportedExtension(
+                    other.to_string(),
+                ))
+            }
+        };
+        Ok(extension)
+    }
+}
+
 #[derive(
     Clone,
     Copy,
@@ -116,6 +195,12 @@ impl extension::Nomenclature for ExtensionId {}
 pub enum TxType {
     HtlcSuccess,
     HtlcTimeout,
+    /// Channel factory transaction allocating funds to the sub-channels
+    FactoryAllocation,
+    /// Eltoo update transaction rebound to a previous update
+    EltooUpdate,
+    /// Eltoo settlement transaction spending the update transaction
+    EltooSettlement,
     Unknown(u16),
 }
 
@@ -124,6 +209,9 @@ impl From<TxType> for u16 {
         match ty {
             TxType::HtlcSuccess => 0x0,
             TxType::HtlcTimeout => 0x1,
+            TxType::FactoryAllocation => 0x2,
+            TxType::EltooUpdate => 0x3,
+            TxType::EltooSettlement => 0x4,
             TxType::Unknown(x) => x,
         }
     }
@@ -134,6 +222,9 @@ impl From<u16> for TxType {
         match ty {
             0x00 => TxType::HtlcSuccess,
             0x01 => TxType::HtlcTimeout,
+            0x02 => TxType::FactoryAllocation,
+            0x03 => TxType::EltooUpdate,
+            0x04 => TxType::EltooSettlement,
             x => TxType::Unknown(x),
         }
     }
@@ -164,18 +255,44 @@ impl channel::TxRole for TxType {}
 #[repr(u8)]
 pub enum Lifecycle {
     Initial,
-    Proposed,                 // Sent or got `open_channel`
-    Accepted,                 // Sent or got `accept_channel`
-    Funding,                  // One party signed funding tx
-    Signed,                   // Other peer signed funding tx
-    Funded,                   // Funding tx is published but not mined
-    Locked,                   // Funding tx mining confirmed by one peer
-    Active,                   // Both peers confirmed lock, channel active
-    Reestablishing,           // Reestablishing connectivity
-    Shutdown,                 // Shutdown proposed but not yet accepted
-    Closing { round: usize }, // Shutdown agreed, exchanging `closing_signed`
-    Closed,                   // Cooperative closing
-    Aborted,                  // Non-cooperative unilateral closing
+    /// Sent or got `open_channel`
+    Proposed,
+    /// Sent or got `accept_channel`
+    Accepted,
+    /// One party signed funding tx
+    Funding,
+    /// Other peer signed funding tx
+    Signed,
+    /// Funding tx is published but not mined
+    Funded,
+    /// Funding tx mining confirmed by one peer, which has sent
+    /// (`Outbound`) or received (`Inbound`) `funding_locked`
+    Locked {
+        by: Direction,
+    },
+    /// Both peers confirmed lock, channel active
+    Active,
+    /// Reestablishing connectivity; `channel_reestablish` was passed in the
+    /// given direction only
+    Reestablishing {
+        by: Direction,
+    },
+    /// Shutdown proposed in the given direction; `agreed` once the other
+    /// peer replied with its own `shutdown`
+    Shutdown {
+        by: Direction,
+        agreed: bool,
+    },
+    /// Shutdown agreed, exchanging `closing_signed`; `by` is the direction
+    /// of the last one
+    Closing {
+        round: usize,
+        by: Direction,
+    },
+    /// Cooperative closing
+    Closed,
+    /// Non-cooperative unilateral closing
+    Aborted,
 }
 
 impl Default for Lifecycle {
@@ -833,6 +950,105 @@ mod test {
     use bitcoin::hashes::hex::FromHex;
     use lightning_encoding::{LightningDecode, LightningEncode};
 
+    use crate::channel::{Channel, IntegralState};
+
+    #[test]
+    fn extension_id_encoding() {
+        let ids = [
+            ExtensionId::Channel,
+            ExtensionId::Bolt3,
+            ExtensionId::Eltoo,
+            ExtensionId::Taproot,
+            ExtensionId::Htlc,
+            ExtensionId::Ptlc,
+            ExtensionId::ShutdownScript,
+            ExtensionId::AnchorOut,
+            ExtensionId::Dlc,
+            ExtensionId::Lightspeed,
+            ExtensionId::Bip96,
+            ExtensionId::Rgb,
+            ExtensionId::MultiParty,
+            ExtensionId::Factory,
+            ExtensionId::Bolt3Ordering,
+        ];
+        for (discriminant, id) in ids.iter().enumerate() {
+            assert_eq!(
+                strict_serialize(id).unwrap(),
+                strict_serialize(&(discriminant as u16)).unwrap(),
+                "extension id {} is encoded with a wrong discriminant",
+                id
+            );
+            assert_eq!(ExtensionId::try_from(u16::from(*id)).unwrap(), *id);
+        }
+    }
+
+    #[test]
+    fn extension_state_roundtrip() {
+        let bolt3 = Bolt3::new(true, 7_000_000, 3_000_000, 144);
+        assert_eq!(
+            strict_deserialize::<Bolt3>(&bolt3.channel_state()).unwrap(),
+            bolt3
+        );
+
+        let htlc = Htlc::dumb_default();
+        assert_eq!(
+            strict_deserialize::<Htlc>(&htlc.channel_state()).unwrap(),
+            htlc
+        );
+
+        let anchor_out = AnchorOut::dumb_default();
+        assert_eq!(
+            strict_deserialize::<AnchorOut>(&anchor_out.channel_state())
+                .unwrap(),
+            anchor_out
+        );
+
+        assert_eq!(Bip96.channel_state(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn channel_restore() {
+        let channel = Channel::with(
+            Bolt3::new(false, 3_000_000, 7_000_000, 144),
+            vec![Htlc::dumb_default()],
+            vec![Bip96],
+        );
+        let state = channel.integral_state();
+        assert_eq!(state.len(), 3);
+
+        let data = channel.channel_state();
+        let decoded: IntegralState<ExtensionId> =
+            strict_deserialize(&data).unwrap();
+        assert_eq!(decoded, state);
+
+        let restored = Channel::restore(decoded).unwrap();
+        assert_eq!(restored.integral_state(), state);
+        assert_eq!(restored.channel_state(), data);
+    }
+
+    #[test]
+    fn channel_restore_failures() {
+        let mut state = IntegralState::<ExtensionId>::new();
+        state.insert(ExtensionId::Bip96, vec![]);
+        assert_eq!(
+            Channel::restore(state.clone()).err(),
+            Some(channel::Error::ConstructorCount(0))
+        );
+
+        state.insert(ExtensionId::Bolt3, vec![0u8; 3]);
+        assert!(matches!(
+            Channel::restore(state.clone()),
+            Err(channel::Error::StateEncoding(_))
+        ));
+
+        state.remove(&ExtensionId::Bolt3);
+        state.insert(ExtensionId::Eltoo, vec![]);
+        assert_eq!(
+            Channel::restore(state).err(),
+            Some(channel::Error::UnsupportedExtension(s!("Eltoo")))
+        );
+    }
+
     #[test]
     fn test_address_encodings() {
         // Test vectors taken from https://github.com/rust-bitcoin/rust-lightning/blob/main/lightning/src/ln/msgs.rs
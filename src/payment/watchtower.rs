@@ -0,0 +1,451 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Outsourcing of the justice transactions to the watchtowers, following
+//! BOLT-13 draft and LND wtclient: encrypted justice blobs are keyed by the
+//! txid of the revoked commitment, so the tower learns nothing about the
+//! channel until the commitment is published, and matches them with the
+//! transactions it sees using the hint made of the first 16 bytes of the
+//! txid.
+
+use std::collections::BTreeMap;
+use std::io;
+
+use amplify::Wrapper;
+use bitcoin::hashes::Hash;
+use bitcoin::secp256k1::rand::{thread_rng, RngCore};
+use bitcoin::{Transaction, Txid};
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use strict_encoding::{
+    strict_deserialize, strict_serialize, StrictDecode, StrictEncode,
+};
+use wallet::PubkeyScript;
+
+use crate::payment::justice::{Breach, JusticeError, JusticeFinalizer};
+use crate::payment::keys::Basepoints;
+use crate::payment::signer::{ChannelSigner, SignerError};
+
+/// Length of the breach hint
+pub const HINT_LEN: usize = 16;
+
+/// Length of the XChaCha20-Poly1305 nonce prefixing encrypted blob
+pub const NONCE_LEN: usize = 24;
+
+/// Errors creating and decrypting justice blobs
+#[derive(Clone, PartialEq, Eq, Debug, Display, Error, From)]
+#[display(doc_comments)]
+pub enum WatchtowerError {
+    /// justice transactions can't be constructed: {0}
+    #[from]
+    Justice(JusticeError),
+
+    /// justice transactions can't be signed: {0}
+    #[from]
+    Signer(SignerError),
+
+    /// justice kit can't be encoded: {0}
+    Encoding(String),
+
+    /// justice blob can't be decrypted with the key of transaction {0}
+    Decryption(Txid),
+}
+
+impl From<strict_encoding::Error> for WatchtowerError {
+    fn from(err: strict_encoding::Error) -> Self {
+        WatchtowerError::Encoding(err.to_string())
+    }
+}
+
+/// Hint identifying the revoked commitment transaction, made of the first
+/// 16 bytes of its txid
+#[derive(
+    Wrapper, Clone, Copy, Debug, From, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub struct BreachHint([u8; HINT_LEN]);
+
+impl BreachHint {
+    /// Constructs hint for the commitment transaction with the given txid
+    pub fn with(txid: Txid) -> Self {
+        let mut hint = [0u8; HINT_LEN];
+        hint.copy_from_slice(&txid[..HINT_LEN]);
+        BreachHint(hint)
+    }
+}
+
+impl StrictEncode for BreachHint {
+    fn strict_encode<E: io::Write>(
+        &self,
+        mut e: E,
+    ) -> Result<usize, strict_encoding::Error> {
+        let len = e.write(self.as_inner())?;
+        Ok(len)
+    }
+}
+
+impl StrictDecode for BreachHint {
+    fn strict_decode<D: io::Read>(
+        mut d: D,
+    ) -> Result<Self, strict_encoding::Error> {
+        let mut buf = [0u8; HINT_LEN];
+        d.read_exact(&mut buf)?;
+        Ok(Self::from_inner(buf))
+    }
+}
+
+/// Signed justice transactions which must be published by the tower once
+/// the revoked commitment is seen
+#[derive(Clone, PartialEq, Eq, Debug, StrictEncode, StrictDecode)]
+pub struct JusticeKit {
+    /// Fully signed justice transactions spending the revoked commitment
+    pub justice_txs: Vec<Transaction>,
+}
+
+impl JusticeKit {
+    /// Constructs and signs justice transactions for the breach using the
+    /// justice transaction builder. Each output of the revoked commitment is
+    /// swept by its own transaction: the remote peer may still spend HTLC
+    /// outputs with the second-stage transactions, which must not invalidate
+    /// the justice transaction sweeping `to_local`.
+    pub fn with(
+        breach: &Breach,
+        local_keys: &Basepoints,
+        remote_keys: &Basepoints,
+        signer: &impl ChannelSigner,
+        destination: &PubkeyScript,
+        feerate_per_kw: u32,
+    ) -> Result<Self, WatchtowerError> {
+        let revocationpubkey =
+            breach.revocation_pubkey(local_keys.revocation_basepoint);
+        let justice_txs = breach
+            .justice_txs(
+                local_keys,
+                remote_keys,
+                &[],
+                destination,
+                feerate_per_kw,
+                // Any single input exceeds zero weight, so the inputs are
+                // never combined
+                0,
+            )?
+            .into_iter()
+            .map(|mut psbt| {
+                let signatures =
+                    signer.sign_justice(&psbt, breach.per_commitment_secret)?;
+                psbt.ln_finalize_justice(&signatures, revocationpubkey)?;
+                Ok(psbt.extract_tx())
+            })
+            .collect::<Result<_, WatchtowerError>>()?;
+        Ok(JusticeKit { justice_txs })
+    }
+
+    /// Encrypts the kit with the txid of the revoked commitment
+    pub fn encrypt(
+        &self,
+        commitment_txid: Txid,
+    ) -> Result<JusticeBlob, WatchtowerError> {
+        let mut nonce = [0u8; NONCE_LEN];
+        thread_rng().fill_bytes(&mut nonce);
+        let plaintext = strict_serialize(self)?;
+        let mut encrypted = nonce.to_vec();
+        encrypted.extend(
+            cipher(commitment_txid)
+                .encrypt(XNonce::from_slice(&nonce), plaintext.as_slice())
+                .expect("encryption of in-memory data does not fail"),
+        );
+        Ok(JusticeBlob {
+            hint: BreachHint::with(commitment_txid),
+            encrypted,
+        })
+    }
+}
+
+/// Justice kit encrypted with the txid of the revoked commitment, which is
+/// sent to the tower
+#[derive(Clone, PartialEq, Eq, Debug, StrictEncode, StrictDecode)]
+pub struct JusticeBlob {
+    /// Hint used by the tower to match the blob with the published
+    /// transactions
+    pub hint: BreachHint,
+
+    /// Nonce followed by the encrypted justice kit
+    pub encrypted: Vec<u8>,
+}
+
+impl JusticeBlob {
+    /// Decrypts the blob with the txid of the published commitment
+    pub fn decrypt(&self, txid: Txid) -> Result<JusticeKit, WatchtowerError> {
+        if self.encrypted.len() < NONCE_LEN {
+            return Err(WatchtowerError::Decryption(txid));
+        }
+        let (nonce, ciphertext) = self.encrypted.split_at(NONCE_LEN);
+        let plaintext = cipher(txid)
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| WatchtowerError::Decryption(txid))?;
+        Ok(strict_deserialize(&plaintext)?)
+    }
+}
+
+fn cipher(txid: Txid) -> XChaCha20Poly1305 {
+    XChaCha20Poly1305::new(Key::from_slice(&txid.into_inner()))
+}
+
+/// Tower-side storage of the justice blobs
+#[derive(Clone, PartialEq, Eq, Debug, Default, StrictEncode, StrictDecode)]
+pub struct Tower {
+    blobs: BTreeMap<BreachHint, Vec<JusticeBlob>>,
+}
+
+impl Tower {
+    /// Constructs empty tower storage
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns number of the stored blobs
+    pub fn len(&self) -> usize {
+        self.blobs.values().map(Vec::len).sum()
+    }
+
+    /// Detects whether the tower stores any blobs
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.blobs.is_empty()
+    }
+
+    /// Stores justice blob received from the client
+    pub fn insert(&mut self, blob: JusticeBlob) {
+        self.blobs
+            .entry(blob.hint)
+            .or_insert_with(Vec::new)
+            .push(blob);
+    }
+
+    /// Decrypts justice kits for the transaction seen by the tower. Blobs
+    /// which hint matches the transaction but can't be decrypted with its
+    /// txid (i.e. hint collisions or malformed blobs) are skipped.
+    pub fn match_tx(&self, tx: &Transaction) -> Vec<JusticeKit> {
+        let txid = tx.txid();
+        self.blobs
+            .get(&BreachHint::with(txid))
+            .map(|blobs| {
+                blobs
+                    .iter()
+                    .filter_map(|blob| blob.decrypt(txid).ok())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Removes all blobs matching the transaction, i.e. after its justice
+    /// transactions were published
+    pub fn remove(&mut self, txid: Txid) -> Vec<JusticeBlob> {
+        self.blobs
+            .remove(&BreachHint::with(txid))
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use amplify::Slice32;
+    use bitcoin::util::psbt::PartiallySignedTransaction as Psbt;
+    use bitcoin::{OutPoint, Script, TxIn, TxOut};
+    use wallet::{HashLock, HashPreimage, WitnessScript};
+
+    use crate::channel::{CommitmentOwner, TxGraph};
+    use crate::payment::bolt3::ScriptGenerators;
+    use crate::payment::htlc::ScriptGenerators as _;
+    use crate::payment::keys::{derive_pubkey, derive_revocation_pubkey};
+    use crate::payment::signer::InMemorySigner;
+    use crate::payment::TxType;
+
+    /// Revoked remote commitment with `to_local` and an offered HTLC output
+    fn breach(local: &InMemorySigner, remote: &InMemorySigner) -> Breach {
+        let local_keys = local.basepoints().unwrap();
+        let remote_keys = remote.basepoints().unwrap();
+        let per_commitment_point = remote.per_commitment_point(0).unwrap();
+        let revocationpubkey = derive_revocation_pubkey(
+            local_keys.revocation_basepoint,
+            per_commitment_point,
+        );
+        let remote_htlcpubkey =
+            derive_pubkey(remote_keys.htlc_basepoint, per_commitment_point);
+        let local_htlcpubkey =
+            derive_pubkey(local_keys.htlc_basepoint, per_commitment_point);
+        let hashlock = HashLock::from(HashPreimage::from_inner(
+            Slice32::from_inner([1u8; 32]),
+        ));
+        let mut tx_graph = TxGraph::with_owner(CommitmentOwner::Remote);
+        tx_graph.cmt_to_self_delay = 144;
+        tx_graph.cmt_per_commitment_point = per_commitment_point;
+        tx_graph.cmt_outs = vec![
+            TxOut::ln_to_local(
+                50_000,
+                revocationpubkey,
+                derive_pubkey(
+                    remote_keys.delayed_payment_basepoint,
+                    per_commitment_point,
+                ),
+                144,
+            ),
+            TxOut::ln_offered_htlc(
+                20_000,
+                revocationpubkey,
+                remote_htlcpubkey,
+                local_htlcpubkey,
+                hashlock,
+                false,
+            ),
+        ];
+        let mut htlc_tx = Psbt::from_unsigned_tx(Transaction {
+            version: 2,
+            lock_time: 500,
+            input: vec![TxIn {
+                previous_output: tx_graph.cmt_outpoint(1),
+                script_sig: empty!(),
+                sequence: 0,
+                witness: empty!(),
+            }],
+            output: vec![],
+        })
+        .unwrap();
+        htlc_tx.inputs[0].witness_utxo = Some(tx_graph.cmt_outs[1].clone());
+        htlc_tx.inputs[0].witness_script = Some(
+            WitnessScript::ln_offered_htlc(
+                20_000,
+                revocationpubkey,
+                remote_htlcpubkey,
+                local_htlcpubkey,
+                hashlock,
+                false,
+            )
+            .into(),
+        );
+        tx_graph.insert_tx(TxType::HtlcTimeout, 0u64, htlc_tx);
+        remote.advance_commitment(1).unwrap();
+        Breach {
+            commitment_tx: tx_graph.render_cmt().global.unsigned_tx,
+            commitment_number: 0,
+            per_commitment_secret: remote
+                .release_per_commitment_secret(0)
+                .unwrap(),
+            tx_graph,
+        }
+    }
+
+    fn blob() -> (Breach, JusticeBlob) {
+        let local = InMemorySigner::with_seed([1u8; 32]);
+        let remote = InMemorySigner::with_seed([2u8; 32]);
+        let breach = breach(&local, &remote);
+        let kit = JusticeKit::with(
+            &breach,
+            &local.basepoints().unwrap(),
+            &remote.basepoints().unwrap(),
+            &local,
+            &PubkeyScript::from(Script::new_v0_wpkh(
+                &bitcoin::WPubkeyHash::default(),
+            )),
+            253,
+        )
+        .unwrap();
+        let blob = kit.encrypt(breach.commitment_tx.txid()).unwrap();
+        (breach, blob)
+    }
+
+    #[test]
+    fn encryption() {
+        let (breach, blob) = blob();
+        let txid = breach.commitment_tx.txid();
+        assert_eq!(blob.hint.as_inner()[..], txid[..HINT_LEN]);
+
+        let kit = blob.decrypt(txid).unwrap();
+        assert_eq!(kit.justice_txs.len(), 2);
+        let tx = &kit.justice_txs[0];
+        assert_eq!(tx.input[0].previous_output, OutPoint::new(txid, 0));
+        assert_eq!(tx.input[0].witness.len(), 3);
+        assert_eq!(tx.input[0].witness[1], vec![1u8]);
+
+        assert_eq!(
+            blob.decrypt(Txid::default()),
+            Err(WatchtowerError::Decryption(Txid::default()))
+        );
+        let mut tampered = blob.clone();
+        *tampered.encrypted.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            tampered.decrypt(txid),
+            Err(WatchtowerError::Decryption(txid))
+        );
+
+        let data = strict_serialize(&blob).unwrap();
+        let decoded: JusticeBlob = strict_deserialize(&data).unwrap();
+        assert_eq!(decoded, blob);
+    }
+
+    #[test]
+    fn justice_tx_per_output() {
+        let (breach, blob) = blob();
+        let txid = breach.commitment_tx.txid();
+        let kit = blob.decrypt(txid).unwrap();
+        let revocationpubkey = breach.revocation_pubkey(
+            InMemorySigner::with_seed([1u8; 32])
+                .basepoints()
+                .unwrap()
+                .revocation_basepoint,
+        );
+        // `to_local` is swept separately from the HTLC output, which may be
+        // spent by the second-stage HTLC transaction
+        assert_eq!(
+            kit.justice_txs
+                .iter()
+                .map(|tx| tx
+                    .input
+                    .iter()
+                    .map(|txin| txin.previous_output)
+                    .collect::<Vec<_>>())
+                .collect::<Vec<_>>(),
+            vec![vec![OutPoint::new(txid, 0)], vec![OutPoint::new(txid, 1)]]
+        );
+        assert_eq!(
+            kit.justice_txs[1].input[0].witness[1],
+            revocationpubkey.serialize().to_vec()
+        );
+    }
+
+    #[test]
+    fn tower() {
+        let (breach, blob) = blob();
+        let mut tower = Tower::new();
+        assert!(tower.is_empty());
+        tower.insert(blob.clone());
+        let mut tampered = blob.clone();
+        tampered.encrypted[NONCE_LEN] ^= 1;
+        tower.insert(tampered);
+        assert_eq!(tower.len(), 2);
+
+        let mut unrelated = breach.commitment_tx.clone();
+        unrelated.lock_time += 1;
+        assert!(tower.match_tx(&unrelated).is_empty());
+
+        let kits = tower.match_tx(&breach.commitment_tx);
+        assert_eq!(
+            kits,
+            vec![blob.decrypt(breach.commitment_tx.txid()).unwrap()]
+        );
+
+        assert_eq!(tower.remove(breach.commitment_tx.txid()).len(), 2);
+        assert!(tower.is_empty());
+    }
+}